@@ -0,0 +1,65 @@
+// Benchmarks for the execution of call-heavy programs.
+//
+// Each program is run several times and the fastest run is reported, along with the time per call.
+// Registers of all active calls live in a single vector, with each call using a window into it,
+// so the time per call should not depend on the depth of the call stack.
+
+use std::time::{Duration, Instant};
+
+use hissy_lib::compiler::Compiler;
+use hissy_lib::vm::gc::GCHeap;
+use hissy_lib::vm::VM;
+
+
+const RUNS: usize = 5;
+
+// Naive recursive Fibonacci; computing fib(n) takes calls(n-1) + calls(n-2) + 1 calls
+fn fib(n: u32) -> (String, u64) {
+	let src = format!("let fib(n: Int) -> Int:\n\tif n < 2:\n\t\treturn n\n\treturn fib(n - 1) + fib(n - 2)\nfib({})\n", n);
+	let (mut a, mut b) = (1u64, 1u64); // Number of calls for fib(0) and fib(1)
+	for _ in 1..n {
+		let c = a + b + 1;
+		a = b;
+		b = c;
+	}
+	(src, b)
+}
+
+// Calls to a small function from a loop, at the given depth in the call stack
+fn loop_calls(depth: usize, calls: usize) -> (String, u64) {
+	let mut src = String::from("let add(a: Int, b: Int) -> Int:\n\treturn a + b\n");
+	src += &format!("let deep(d: Int):\n\tif d > 0:\n\t\tdeep(d - 1)\n\t\treturn\n\tlet s = 0\n\tfor i in range(0, {}):\n\t\ts = add(s, i)\ndeep({})\n", calls, depth);
+	(src, (calls + depth + 1) as u64)
+}
+
+// Calls to native methods from a loop
+fn native_calls(calls: usize) -> (String, u64) {
+	let src = format!("let l = []\nfor i in range(0, {}):\n\tl.add(l.size())\n", calls);
+	(src, 2 * calls as u64)
+}
+
+fn bench(name: &str, (src, calls): (String, u64)) {
+	let program = Compiler::new(false).compile_program(&src)
+		.unwrap_or_else(|err| panic!("Failed to compile {}: {}", name, err));
+	let mut heap = GCHeap::new();
+	let mut best = Duration::MAX;
+	for _ in 0..RUNS {
+		let start = Instant::now();
+		let mut vm = VM::new(&mut heap, program.clone());
+		vm.run(&mut heap).unwrap_or_else(|err| panic!("Failed to run {}: {}", name, err));
+		best = best.min(start.elapsed());
+		drop(vm);
+		heap.collect();
+	}
+	println!("{:<24} {:>9} calls {:>10.2?} {:>8.2?}/call", name, calls, best, best / calls as u32);
+}
+
+fn main() {
+	for &n in &[15, 22] {
+		bench(&format!("fib({})", n), fib(n));
+	}
+	for &depth in &[0, 100] {
+		bench(&format!("loop_calls(depth {})", depth), loop_calls(depth, 200_000));
+	}
+	bench("native_calls", native_calls(200_000));
+}
@@ -0,0 +1,86 @@
+
+// Benchmarks for the compilation of large generated programs.
+//
+// Each program is compiled several times and the fastest run is reported,
+// along with the time per source line, which should stay roughly constant as programs grow.
+
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+use hissy_lib::compiler::Compiler;
+use hissy_lib::vm::Encoding;
+
+
+const RUNS: usize = 5;
+
+// Many functions, each defining many locals in a sequence of blocks, and calling the previous function
+fn many_functions(functions: usize, locals: usize) -> String {
+	let mut src = String::from("let f0(x: Int) -> Int:\n\treturn x\n");
+	for f in 1..functions {
+		writeln!(src, "let f{}(x: Int) -> Int:", f).unwrap();
+		for l in 0..locals {
+			if l % 10 == 0 {
+				src.push_str("\tif x > 0:\n");
+			}
+			writeln!(src, "\t\tlet v{} = x * {}", l, l).unwrap();
+			writeln!(src, "\t\tx = x + v{}", l).unwrap();
+		}
+		writeln!(src, "\treturn f{}(x)", f - 1).unwrap();
+	}
+	src
+}
+
+// Deeply nested blocks, with many bindings in scope, and statements referencing the outermost one
+fn deep_blocks(depth: usize, statements: usize) -> String {
+	let mut src = String::from("let f(a: Int):\n\tlet x = a\n");
+	for d in 1..=depth {
+		let indent = "\t".repeat(d);
+		writeln!(src, "{}while x < {}:", indent, d).unwrap();
+		writeln!(src, "{}\tlet y{} = x", indent, d).unwrap();
+	}
+	let indent = "\t".repeat(depth + 1);
+	for _ in 0..statements {
+		writeln!(src, "{}x = x + a", indent).unwrap();
+	}
+	src
+}
+
+// Deeply nested functions, each capturing the variables of all enclosing functions through upvalues
+fn nested_closures(depth: usize, statements: usize) -> String {
+	let mut src = String::new();
+	for d in 0..depth {
+		let indent = "\t".repeat(d);
+		writeln!(src, "{}let v{} = {}", indent, d, d).unwrap();
+		writeln!(src, "{}let f{}():", indent, d).unwrap();
+	}
+	let indent = "\t".repeat(depth);
+	for s in 0..statements {
+		writeln!(src, "{}log(v{} + v{})", indent, s % depth, (s * 7) % depth).unwrap();
+	}
+	src
+}
+
+fn bench(name: &str, src: &str) {
+	let mut best = Duration::MAX;
+	for _ in 0..RUNS {
+		let start = Instant::now();
+		let mut compiler = Compiler::new(true);
+		compiler.set_encoding(Encoding::Wide); // for long jumps
+		compiler.compile_program(src).unwrap_or_else(|err| panic!("Failed to compile {}: {}", name, err));
+		best = best.min(start.elapsed());
+	}
+	let lines = src.lines().count();
+	println!("{:<24} {:>7} lines {:>10.2?} {:>8.2?}/line", name, lines, best, best / lines as u32);
+}
+
+fn main() {
+	for &n in &[40, 120] {
+		bench(&format!("many_functions({})", n), &many_functions(n, 100));
+	}
+	for &n in &[20, 100] {
+		bench(&format!("deep_blocks({})", n), &deep_blocks(n, 2000));
+	}
+	for &n in &[20, 100] {
+		bench(&format!("nested_closures({})", n), &nested_closures(n, 2000));
+	}
+}
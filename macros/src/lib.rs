@@ -0,0 +1,72 @@
+//! Procedural macros compiling Hissy scripts at Rust build time.
+//! 
+//! Both macros expand to an expression of type `hissy_lib::compiler::Program`,
+//! so the crate using them must also depend on `hissy`.
+//! Compilation errors in the script are reported as Rust compilation errors.
+
+extern crate proc_macro;
+
+use std::env;
+use std::fs::read_to_string;
+use std::path::PathBuf;
+
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{parse_macro_input, LitStr};
+
+use hissy_lib::HissyError;
+use hissy_lib::compiler::Compiler;
+
+
+fn describe_error(HissyError(ty, msg, line): HissyError) -> String {
+	if line != 0 {
+		format!("Hissy {:?} error at line {}: {}", ty, line, msg)
+	} else {
+		format!("Hissy {:?} error: {}", ty, msg)
+	}
+}
+
+fn compile(code: &str, lit: &LitStr) -> Result<proc_macro2::TokenStream, syn::Error> {
+	let bytes = Compiler::new(true).compile_program(code).and_then(|program| program.to_bytes())
+		.map_err(|err| syn::Error::new(lit.span(), describe_error(err)))?;
+	Ok(quote! {
+		::hissy_lib::compiler::Program::from_bytes(&[#(#bytes),*])
+			.expect("Invalid embedded bytecode")
+	})
+}
+
+/// Compiles a string literal containing Hissy code into a `Program`.
+/// 
+/// ```ignore
+/// let program = hissy!("log(1 + 2)\n");
+/// ```
+#[proc_macro]
+pub fn hissy(input: TokenStream) -> TokenStream {
+	let lit = parse_macro_input!(input as LitStr);
+	compile(&lit.value(), &lit).unwrap_or_else(|err| err.to_compile_error()).into()
+}
+
+/// Compiles a Hissy source file into a `Program`.
+/// The path is relative to the root of the crate being built.
+/// 
+/// ```ignore
+/// let program = include_hissy!("scripts/main.hsy");
+/// ```
+#[proc_macro]
+pub fn include_hissy(input: TokenStream) -> TokenStream {
+	let lit = parse_macro_input!(input as LitStr);
+	let root = env::var("CARGO_MANIFEST_DIR").map(PathBuf::from).unwrap_or_default();
+	let path = root.join(lit.value());
+	let res = read_to_string(&path)
+		.map_err(|err| syn::Error::new(lit.span(), format!("Unable to read {:?}: {}", path, err)))
+		.and_then(|code| compile(&code, &lit))
+		.map(|program| {
+			// Makes the crate depend on the script, so that it is rebuilt when the script changes
+			let path = path.to_string_lossy().into_owned();
+			quote! {{
+				const _: &str = include_str!(#path);
+				#program
+			}}
+		});
+	res.unwrap_or_else(|err| err.to_compile_error()).into()
+}
@@ -0,0 +1,16 @@
+use hissy_lib::vm::{gc::GCHeap, run_program};
+use hissy_macros::{hissy, include_hissy};
+
+#[test]
+fn test_inline_script() {
+	let program = hissy!("let x = 1 + 2\nlog(x)\n");
+	let mut heap = GCHeap::new();
+	run_program(&mut heap, &program).unwrap();
+}
+
+#[test]
+fn test_included_script() {
+	let program = include_hissy!("tests/script.hsy");
+	let mut heap = GCHeap::new();
+	run_program(&mut heap, &program).unwrap();
+}
@@ -0,0 +1,451 @@
+
+use std::collections::{HashSet, HashMap, VecDeque};
+
+use crate::{HissyError, ErrorType};
+use crate::parser::{parse_with, ast::Block, symbol::Symbol, lexer::{self, BlockStyle, Span, Token, TokenClass}};
+use super::{Compiler, Context, Binding, Type, PrimitiveType};
+
+
+/// Where a binding comes from, as seen from some point of the program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BindingKind {
+	/// A variable or argument of the current function (or of the main program, outside of functions).
+	Local,
+	/// A variable of an enclosing function or of the main program, accessed through an upvalue.
+	Upvalue,
+	/// A global value from the standard library.
+	External,
+}
+
+/// A binding in scope at some point of the program, with its inferred type.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Completion {
+	pub name: String,
+	pub kind: BindingKind,
+	pub ty: Type,
+}
+
+// Lists the bindings visible in the current state of the compiler context, skipping shadowed ones
+fn scope(ctx: &Context) -> Vec<Completion> {
+	let mut seen = HashSet::new();
+	let mut res = vec![];
+	let mut add = |name: &str, kind: BindingKind, ty: &Type| {
+		// Names which cannot be written in code are hidden locals, eg. deferred closures
+		if !name.starts_with('<') && seen.insert(name.to_string()) {
+			res.push(Completion { name: name.to_string(), kind, ty: ty.clone() });
+		}
+	};
+	for (i, chunk) in ctx.stack.iter().enumerate().rev() {
+		let kind = if i + 1 == ctx.stack.len() { BindingKind::Local } else { BindingKind::Upvalue };
+		for block in chunk.blocks.iter().rev() {
+			for (name, local) in block {
+				add(name, kind, &local.ty);
+			}
+		}
+		for upv in &chunk.upvalues {
+			add(&upv.name, BindingKind::Upvalue, &upv.ty);
+		}
+	}
+	for (name, ty) in &ctx.external {
+		add(name, BindingKind::External, ty);
+	}
+	res.sort_by(|a, b| (a.kind, &a.name).cmp(&(b.kind, &b.name)));
+	res
+}
+
+
+// Records the bindings in scope at the cursor, and what each identifier refers to,
+// while the program is being compiled.
+//
+// The scope is captured before every statement starting before the cursor, and after every
+// statement ending before it. Since statement ends are not known, a statement is considered to
+// end before the cursor if the cursor's line comes later and is indented less or as much,
+// which means the cursor is not inside one of its blocks.
+#[derive(Default)]
+pub(super) struct Probe {
+	line: usize,
+	column: usize,
+	indent: usize, // Column of the first non-blank character on the cursor's line
+	scope: Option<Vec<Completion>>,
+	
+	// For each identifier in compilation order, its name and the index of the identifier defining
+	// its binding (None for externals). Identifiers are recorded in source order for each name.
+	refs: Vec<(Symbol, Option<usize>)>,
+	pending: Vec<usize>, // Identifiers declaring bindings which are not local yet
+	locals: HashMap<(usize, u8), Option<usize>>, // Definition of the local in each register, by chunk depth
+	enums: HashMap<Symbol, usize>, // Identifier declaring each enum
+	pub(super) imported: bool, // Whether the program imports modules, whose bindings are treated as externals
+}
+
+impl Probe {
+	fn new(input: &str, offset: usize) -> Probe {
+		let offset = snap_offset(input, offset);
+		let line_start = input[..offset].rfind('\n').map_or(0, |i| i + 1);
+		let blank = input[line_start..].len() - input[line_start..].trim_start_matches([' ', '\t']).len();
+		Probe {
+			line: input[..offset].matches('\n').count() + 1,
+			column: offset - line_start + 1,
+			indent: blank + 1,
+			..Probe::default()
+		}
+	}
+
+	pub(super) fn visit(&mut self, ctx: &Context, (line, column): (usize, usize), after: bool) {
+		let visible = if after {
+			line < self.line && self.indent <= column
+		} else {
+			(line, column) <= (self.line, self.column)
+		};
+		if visible {
+			self.scope = Some(scope(ctx));
+		}
+	}
+	
+	pub(super) fn declare(&mut self, name: Symbol) {
+		let idx = self.refs.len();
+		self.refs.push((name, Some(idx)));
+		self.pending.push(idx);
+	}
+	
+	// Associates a new local with the latest declaration of its name
+	pub(super) fn define(&mut self, ctx: &Context, name: Symbol, reg: u8) {
+		if let Some(i) = self.pending.iter().rposition(|&idx| self.refs[idx].0 == name) {
+			let def = self.pending.remove(i);
+			self.locals.insert((ctx.stack.len() - 1, reg), Some(def));
+		}
+	}
+	
+	pub(super) fn resolve(&mut self, ctx: &Context, name: Symbol, binding: &Binding) {
+		let def = ctx.defining_local(binding).and_then(|local| self.locals.get(&local).copied().flatten());
+		self.refs.push((name, def));
+	}
+	
+	pub(super) fn declare_enum(&mut self, name: Symbol) {
+		let idx = self.refs.len();
+		self.refs.push((name, Some(idx)));
+		self.enums.insert(name, idx);
+	}
+	
+	// Records the name of an enum used to access one of its variants
+	pub(super) fn use_enum(&mut self, name: Symbol) {
+		self.refs.push((name, self.enums.get(&name).copied()));
+	}
+	
+	// Makes a register holding a variable captured by value refer to the variable resolved last
+	pub(super) fn capture(&mut self, ctx: &Context, reg: u8) {
+		let def = self.refs.last().and_then(|(_, def)| *def);
+		self.locals.insert((ctx.stack.len() - 1, reg), def);
+	}
+}
+
+// Editors may send offsets past the end of the input, or inside a character (eg. when counting UTF-16 units),
+// which are moved back to the closest character boundary
+fn snap_offset(input: &str, offset: usize) -> usize {
+	let offset = offset.min(input.len());
+	(0..=offset).rev().find(|i| input.is_char_boundary(*i)).unwrap()
+}
+
+// Replaces the line containing offset by a placeholder statement with the same indentation
+fn replace_line(input: &str, offset: usize) -> String {
+	let offset = snap_offset(input, offset);
+	let line_start = input[..offset].rfind('\n').map_or(0, |i| i + 1);
+	let line_end = input[offset..].find('\n').map_or(input.len(), |i| offset + i);
+	let line = &input[line_start..line_end];
+	let indent = &line[..line.len() - line.trim_start_matches([' ', '\t']).len()];
+	format!("{}{}nil{}", &input[..line_start], indent, &input[line_end..])
+}
+
+
+/// Lists the bindings in scope at a byte offset in Hissy code with indentation-based blocks,
+/// along with their types, as a basis for auto-completion.
+pub fn completions(input: &str, offset: usize) -> Result<Vec<Completion>, HissyError> {
+	completions_with(input, offset, BlockStyle::Indentation)
+}
+
+/// Lists the bindings in scope at a byte offset in Hissy code, with the given block style.
+///
+/// Bindings are resolved the same way as in the compiler, and sorted by kind, then by name.
+/// Since the code is usually being edited, the line containing the cursor is ignored if it cannot be parsed,
+/// and compilation errors after the cursor are ignored.
+pub fn completions_with(input: &str, offset: usize, style: BlockStyle) -> Result<Vec<Completion>, HissyError> {
+	completions_in(input, offset, style, vec![])
+}
+
+// Same as completions_with, for code which is the body of a function taking the given arguments,
+// such as an input of a session
+pub(crate) fn completions_in(input: &str, offset: usize, style: BlockStyle, args: Vec<(Symbol, Type)>) -> Result<Vec<Completion>, HissyError> {
+	let ast = match parse_with(input, style) {
+		Err(HissyError(ErrorType::Syntax, _, _)) => parse_with(&replace_line(input, offset), style)?,
+		res => res?,
+	};
+
+	let mut compiler = Compiler::new(false);
+	compiler.probe = Some(Probe::new(input, offset));
+	let res = compiler.compile_chunk(String::from("<main>"), ast, args.clone(), vec![], Type::Any);
+	let probe = compiler.probe.take().unwrap();
+	if let Err(err) = res {
+		if usize::from(err.2) < probe.line {
+			return Err(err);
+		}
+	}
+	Ok(probe.scope.unwrap_or_else(|| {
+		let mut res: Vec<Completion> = args.into_iter()
+			.map(|(id, ty)| Completion { name: id.to_string(), kind: BindingKind::Local, ty })
+			.collect();
+		res.sort_by(|a, b| a.name.cmp(&b.name));
+		res.extend(scope(&Context::new()));
+		res
+	}))
+}
+
+// Lists the top-level bindings after the last statement of a program whose main chunk takes the given arguments
+pub(super) fn final_scope(ast: Block, args: Vec<(Symbol, Type)>) -> Result<Vec<Completion>, HissyError> {
+	let mut compiler = Compiler::new(false);
+	// With the cursor after the end of the program, the last scope captured is the one after the last top-level statement
+	compiler.probe = Some(Probe { line: usize::MAX, column: usize::MAX, indent: 1, ..Probe::default() });
+	compiler.compile_chunk(String::from("<main>"), ast, args, vec![], Type::Any)?;
+	Ok(compiler.probe.take().unwrap().scope.unwrap_or_default())
+}
+
+
+fn error(s: String) -> HissyError {
+	HissyError(ErrorType::Compilation, s, 0)
+}
+
+// Resolves every identifier in the source code (except property and type names), returning their spans,
+// and the index of the identifier defining their binding (None for externals)
+fn resolve_identifiers(input: &str, style: BlockStyle) -> Result<Vec<(Span, Option<usize>)>, HissyError> {
+	let ast = parse_with(input, style)?;
+	let mut compiler = Compiler::new(false);
+	compiler.probe = Some(Probe::default());
+	compiler.compile_chunk(String::from("<main>"), ast, vec![], vec![], prim_ty!(Nil))?;
+	let refs = compiler.probe.take().unwrap().refs;
+	
+	// Match identifiers in the source with those seen by the compiler, in order for each name
+	let mut by_name: HashMap<&str, VecDeque<usize>> = HashMap::new();
+	for (i, (name, _)) in refs.iter().enumerate() {
+		by_name.entry(name).or_default().push_back(i);
+	}
+	let mut spans: Vec<(Span, usize)> = vec![];
+	let mut prev = 0..0;
+	let mut in_variants = false; // Whether we are in the list of variants of an enum declaration
+	for (span, class) in lexer::highlight_with(input, style) {
+		let text = &input[span.clone()];
+		let same_line = !input[prev.end..span.start].contains('\n');
+		in_variants &= same_line;
+		// Type names follow a ':' or '->' on the same line (a ':' at the end of a line opens a block)
+		let is_type = matches!(&input[prev.clone()], ":" | "->") && same_line;
+		// Names of properties follow a '.', names of annotations an '@', and names of modules 'import'
+		let is_name = matches!(&input[prev.clone()], "." | "@" | "import");
+		if class == TokenClass::Identifier && !is_name && !is_type && !in_variants {
+			let i = by_name.get_mut(text).and_then(VecDeque::pop_front)
+				.ok_or_else(|| error(format!("Unable to resolve identifier '{}'", text)))?;
+			spans.push((span.clone(), i));
+		}
+		in_variants |= class == TokenClass::Identifier && &input[prev.clone()] == "enum";
+		if class != TokenClass::Comment && class != TokenClass::DocComment {
+			prev = span;
+		}
+	}
+	if let Some((name, _)) = by_name.iter().find(|(_, left)| !left.is_empty()) {
+		return Err(error(format!("Unable to find identifier '{}' in the source", name)));
+	}
+	
+	let mut positions = vec![0; refs.len()];
+	for (pos, (_, i)) in spans.iter().enumerate() {
+		positions[*i] = pos;
+	}
+	Ok(spans.into_iter().map(|(span, i)| (span, refs[i].1.map(|def| positions[def]))).collect())
+}
+
+// Returns the index of the identifier at offset, and of the identifier defining its binding
+fn find_identifier(idents: &[(Span, Option<usize>)], offset: usize) -> Result<(usize, Option<usize>), HissyError> {
+	idents.iter().position(|(span, _)| span.start <= offset && offset <= span.end)
+		.map(|i| (i, idents[i].1))
+		.ok_or_else(|| error(String::from("No binding at this position")))
+}
+
+/// Finds all references to the binding named at a byte offset in Hissy code with indentation-based blocks.
+pub fn references(input: &str, offset: usize) -> Result<Vec<Span>, HissyError> {
+	references_with(input, offset, BlockStyle::Indentation)
+}
+
+/// Finds all references to the binding named at a byte offset in Hissy code, with the given block style.
+///
+/// The code must compile. The spans of the references, including the definition, are returned in order.
+/// Bindings are resolved as in the compiler, so shadowed variables are told apart, and variables
+/// used in inner functions or captured by value are found as well.
+pub fn references_with(input: &str, offset: usize, style: BlockStyle) -> Result<Vec<Span>, HissyError> {
+	let idents = resolve_identifiers(input, style)?;
+	let (i, def) = find_identifier(&idents, snap_offset(input, offset))?;
+	let name = &input[idents[i].0.clone()];
+	Ok(idents.into_iter()
+		.filter(|(span, def2)| *def2 == def && (def.is_some() || &input[span.clone()] == name))
+		.map(|(span, _)| span)
+		.collect())
+}
+
+/// Computes the edits renaming the binding named at a byte offset in Hissy code with indentation-based blocks.
+pub fn rename(input: &str, offset: usize, new_name: &str) -> Result<Vec<(Span, String)>, HissyError> {
+	rename_with(input, offset, new_name, BlockStyle::Indentation)
+}
+
+/// Computes the edits renaming the binding named at a byte offset in Hissy code, with the given block style.
+///
+/// Edits replace spans of the original code, and are returned in order.
+/// Fails if the binding is external, or if the new name would change what any identifier refers to,
+/// for example by shadowing another variable used in the binding's scope.
+pub fn rename_with(input: &str, offset: usize, new_name: &str, style: BlockStyle) -> Result<Vec<(Span, String)>, HissyError> {
+	let tokens = lexer::read_tokens(new_name)?;
+	let tokens: Vec<&Token> = tokens.tokens.iter().filter(|t| **t != Token::Newline && **t != Token::EOF).collect();
+	if tokens != [&Token::Id(Symbol::intern(new_name))] {
+		return Err(error(format!("'{}' is not a valid identifier", new_name)));
+	}
+	
+	let idents = resolve_identifiers(input, style)?;
+	let (i, def) = find_identifier(&idents, snap_offset(input, offset))?;
+	let name = &input[idents[i].0.clone()];
+	if def.is_none() {
+		return Err(error(format!("Cannot rename external binding '{}'", name)));
+	}
+	let edits: Vec<(Span, String)> = idents.iter()
+		.filter(|(_, def2)| *def2 == def)
+		.map(|(span, _)| (span.clone(), String::from(new_name)))
+		.collect();
+	
+	// Check that the renamed code resolves identically
+	let mut renamed = String::from(input);
+	for (span, text) in edits.iter().rev() {
+		renamed.replace_range(span.clone(), text);
+	}
+	let same = resolve_identifiers(&renamed, style).is_ok_and(|idents2| {
+		idents.iter().map(|(_, def)| def).eq(idents2.iter().map(|(_, def)| def))
+	});
+	if !same {
+		return Err(error(format!("Renaming '{}' to '{}' would change the meaning of the program", name, new_name)));
+	}
+	Ok(edits)
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn names(input: &str, cursor: &str) -> Vec<(String, BindingKind)> {
+		let offset = input.find(cursor).unwrap();
+		completions(input, offset).unwrap().into_iter()
+			.filter(|c| c.kind != BindingKind::External)
+			.map(|c| (c.name, c.kind))
+			.collect()
+	}
+
+	#[test]
+	fn test_completions() {
+		use BindingKind::*;
+		let src = "let x = 1\nlet f(a: Int) -> Int:\n\tlet y = a + x\n\treturn y\nlet z = f(x)\n";
+		assert_eq!(names(src, "let f"), vec![(String::from("x"), Local)]);
+		assert_eq!(names(src, "return"), vec![
+			(String::from("a"), Local), (String::from("y"), Local),
+			(String::from("f"), Upvalue), (String::from("x"), Upvalue),
+		]);
+		assert_eq!(names(src, "let z"), vec![(String::from("f"), Local), (String::from("x"), Local)]);
+
+		// The line being edited does not parse, and refers to undefined bindings
+		let src = "let s = \"abc\"\nwhile true:\n\tlet n = 2\n\tlog(s.\nlog(n)\n";
+		let res = completions(src, src.find("s.").unwrap() + 2).unwrap();
+		let s = res.iter().find(|c| c.name == "s").unwrap();
+		assert_eq!((s.kind, &s.ty), (Local, &prim_ty!(String)));
+		assert!(res.iter().any(|c| c.name == "n"));
+		assert!(res.iter().any(|c| c.name == "log" && c.kind == External));
+		assert!(completions("log(undefined)\nlet a = 1\n", 15).is_err());
+		assert_eq!(names("let a = 1\ndefer log(a)\nlet b = 2\n", "let b"), vec![(String::from("a"), Local)]);
+	}
+	
+	#[test]
+	fn test_rename() {
+		let src = "let x = 1\nlet f(x: Int) -> Int:\n\treturn x + 1\nlet g() capture [x]:\n\tlog(x)\nlet h():\n\tx = f(x)\nlet l = [x]\nlog(l.size())\n";
+		let spans = |ident: &str| -> Vec<Span> {
+			src.match_indices(ident).map(|(i, _)| i..i + ident.len()).collect()
+		};
+		
+		// The argument of f shadows x
+		let arg = src.find("x: Int").unwrap();
+		let used = src.find("x + 1").unwrap();
+		assert_eq!(references(src, used).unwrap(), vec![arg..arg + 1, used..used + 1]);
+		let mut outer = spans("x");
+		outer.retain(|span| span.start != arg && span.start != used);
+		assert_eq!(references(src, src.rfind("[x]").unwrap() + 1).unwrap(), outer);
+		
+		let edits = rename(src, 4, "count").unwrap();
+		let mut renamed = String::from(src);
+		for (span, text) in edits.iter().rev() {
+			renamed.replace_range(span.clone(), text);
+		}
+		assert_eq!(renamed, "let count = 1\nlet f(x: Int) -> Int:\n\treturn x + 1\nlet g() capture [count]:\n\tlog(count)\nlet h():\n\tcount = f(count)\nlet l = [count]\nlog(l.size())\n");
+		
+		assert!(rename(src, 4, "f").is_err()); // Would shadow f in h
+		assert!(rename(src, arg, "g").is_ok());
+		assert!(rename(src, 4, "1x").is_err());
+		assert!(rename(src, src.find("log").unwrap(), "print").is_err());
+		assert_eq!(references(src, src.find("log").unwrap()).unwrap(), spans("log"));
+	}
+	
+	#[test]
+	fn test_rename_with_enums() {
+		let src = "enum Color: Red, Green\nlet x = 1\nlet c = Color.Red\nmatch c:\n\tColor.Green:\n\t\tlog(x)\n\telse:\n\t\tpass\n";
+		let edits = rename(src, src.find("x = 1").unwrap(), "y").unwrap();
+		let x = src.find("x = 1").unwrap();
+		let used = src.find("x)").unwrap();
+		assert_eq!(edits, vec![(x..x + 1, String::from("y")), (used..used + 1, String::from("y"))]);
+		
+		// The enum's name is a binding, but not its variants
+		let colors: Vec<Span> = src.match_indices("Color").map(|(i, _)| i..i + 5).collect();
+		assert_eq!(references(src, src.find("Color.Red").unwrap()).unwrap(), colors);
+		assert!(references(src, src.find("Green").unwrap()).is_err());
+		assert!(references(src, src.find("Red").unwrap()).is_err());
+		assert_eq!(rename(src, 5, "Hue").unwrap().len(), 3);
+	}
+	
+	#[test]
+	fn test_references_with_annotations() {
+		let src = "@inline\nlet f(a: Int) -> Int:\n\treturn a + 1\n@deprecated(\"use f\")\nlet inline(a: Int) -> Int:\n\treturn f(a)\nlog(inline(1))\n";
+		let def = src.find("f(a").unwrap();
+		let used = src.find("f(a)").unwrap();
+		assert_eq!(references(src, def).unwrap(), vec![def..def + 1, used..used + 1]);
+		// The annotation is not a reference to the function named like it
+		let inline: Vec<Span> = src.match_indices("inline").skip(1).map(|(i, _)| i..i + 6).collect();
+		assert_eq!(references(src, src.rfind("inline").unwrap()).unwrap(), inline);
+		assert!(references(src, 1).is_err());
+	}
+	
+	#[test]
+	fn test_rename_with_imports() {
+		let src = "import util.math\nlet x = 1\nlog(square(x))\n";
+		let x = src.find("x = 1").unwrap();
+		let used = src.find("x))").unwrap();
+		assert_eq!(rename(src, x, "y").unwrap(), vec![(x..x + 1, String::from("y")), (used..used + 1, String::from("y"))]);
+		// Bindings of imported modules are external
+		let square = src.find("square").unwrap();
+		assert_eq!(references(src, square).unwrap(), vec![square..square + 6]);
+		assert!(rename(src, square, "sq").is_err());
+		assert!(completions(src, used).unwrap().iter().any(|c| c.name == "x"));
+		
+		let err = references("if true:\n\timport util\n", 0).unwrap_err();
+		assert_eq!(err.1, "Module 'util' must be imported at the top level");
+	}
+	
+	#[test]
+	fn test_offsets_inside_characters() {
+		// Offset 10 is in the middle of 'é', and is moved back to its start
+		let src = "let s = \"é\"\nlog(s)\n";
+		assert!(completions(src, 10).unwrap().iter().all(|c| c.name != "s"));
+		// Only the line of the cursor can be ignored when it cannot be parsed
+		assert_eq!(completions("let s = \"é\"\nlog(s.\n", 10).unwrap_err().0, ErrorType::Syntax);
+		let src = "let é = 1\nlog(é)\n";
+		let used = src.find("é)").unwrap();
+		assert_eq!(references(src, 5).unwrap(), vec![4..6, used..used + 2]);
+		assert_eq!(rename(src, used + 1, "e").unwrap(), vec![(4..6, String::from("e")), (used..used + 2, String::from("e"))]);
+		assert!(completions(src, 1000).is_ok());
+	}
+}
@@ -0,0 +1,64 @@
+
+use std::fmt::Write;
+
+use crate::HissyError;
+use super::Program;
+
+
+/// Generates the source of a Rust program which embeds the bytecode of `program`,
+/// and runs it with the Hissy VM when executed.
+/// 
+/// The generated file is meant to be the `main.rs` of a binary crate depending on `hissy`.
+pub fn generate_rust(program: &Program, source_name: &str) -> Result<String, HissyError> {
+	let bytes = program.to_bytes()?;
+	
+	let mut res = String::new();
+	writeln!(res, "// Generated by `hissy aot` from {:?}", source_name).unwrap();
+	writeln!(res, "// Build as a binary crate depending on `hissy` to obtain a standalone executable.").unwrap();
+	writeln!(res).unwrap();
+	writeln!(res, "use hissy_lib::compiler::Program;").unwrap();
+	writeln!(res, "use hissy_lib::vm::{{gc::GCHeap, run_program}};").unwrap();
+	writeln!(res).unwrap();
+	writeln!(res, "static BYTECODE: &[u8] = &[").unwrap();
+	for line in bytes.chunks(16) {
+		let line: Vec<String> = line.iter().map(|b| format!("0x{:02x},", b)).collect();
+		writeln!(res, "\t{}", line.join(" ")).unwrap();
+	}
+	writeln!(res, "];").unwrap();
+	writeln!(res).unwrap();
+	res.push_str(concat!(
+		"fn main() {\n",
+		"\tlet program = Program::from_bytes(BYTECODE).expect(\"Invalid embedded bytecode\");\n",
+		"\tlet mut heap = GCHeap::new();\n",
+		"\tif let Err(err) = run_program(&mut heap, &program) {\n",
+		"\t\teprintln!(\"{}\", err);\n",
+		"\t\tstd::process::exit(1);\n",
+		"\t}\n",
+		"}\n",
+	));
+	Ok(res)
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::compiler::Compiler;
+
+	#[test]
+	fn test_embedded_bytecode() {
+		let program = Compiler::new(false).compile_program("log(1 + 2)\n").unwrap();
+		let source = generate_rust(&program, "test.hsy").unwrap();
+		assert!(source.contains("fn main()"));
+		
+		// Parse the bytes back from the generated array
+		let start = source.find("= &[").unwrap() + 4;
+		let end = source.find("];").unwrap();
+		let bytes: Vec<u8> = source[start..end].split(',')
+			.map(str::trim).filter(|b| !b.is_empty())
+			.map(|b| u8::from_str_radix(&b[2..], 16).unwrap())
+			.collect();
+		assert_eq!(bytes, program.to_bytes().unwrap());
+		assert!(Program::from_bytes(&bytes).is_ok());
+	}
+}
@@ -3,11 +3,16 @@ use std::collections::HashMap;
 use std::path::Path;
 use std::convert::TryFrom;
 use std::fs;
+use std::io::{Read, Write};
+use std::ops::Range;
+use std::sync::{Arc, OnceLock};
 use std::slice;
 
 use crate::{HissyError, ErrorType};
-use crate::vm::{MAX_REGISTERS, InstrType, InstrType::*, value::{NIL, Value}, gc::GCHeap};
+use crate::vm::{MAX_REGISTERS, Instr, OperandType, Encoding, prelude, value::{NIL, Value}, gc::GCHeap};
 use crate::serial::*;
+use crate::parser::symbol::Symbol;
+use super::Warning;
 
 
 fn error(s: String) -> HissyError {
@@ -26,15 +31,19 @@ enum ConstantType {
 	Int,
 	Real,
 	String,
+	Char,
+	Symbol,
 }
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Clone)]
 pub(crate) enum ChunkConstant {
 	Nil,
 	Bool(bool),
 	Int(i32),
 	Real(f64),
 	String(String),
+	Char(char),
+	Symbol(Symbol),
 }
 
 impl ChunkConstant {
@@ -44,7 +53,9 @@ impl ChunkConstant {
 			ChunkConstant::Bool(b) => Value::from(*b),
 			ChunkConstant::Int(i) => Value::from(*i),
 			ChunkConstant::Real(r) => Value::from(*r),
-			ChunkConstant::String(s) => heap.make_value(s.clone()),
+			ChunkConstant::String(s) => heap.make_string(s),
+			ChunkConstant::Char(c) => Value::from(*c),
+			ChunkConstant::Symbol(s) => Value::from(*s),
 		}
 	}
 	
@@ -55,39 +66,87 @@ impl ChunkConstant {
 			ChunkConstant::Int(i) => format!("{}", *i),
 			ChunkConstant::Real(r) => format!("{}", *r),
 			ChunkConstant::String(s) => format!("{:?}", s),
+			ChunkConstant::Char(c) => format!("{:?}", c),
+			ChunkConstant::Symbol(s) => format!(":{}", s),
 		}
 	}
 }
 
 
-#[derive(Default)]
+#[derive(Default, Clone)]
 pub(crate) struct ChunkInfo {
 	pub name: String,
+	pub doc: String, // doc comment of the declaration
+	pub annotations: Vec<(String, Vec<String>)>, // annotations of the declaration, with their arguments
 	pub upvalue_names: Vec<String>,
 	pub line_numbers: Vec<(u16, u16)>, // (position in bytecode, line)
+	pub locals: Vec<LocalInfo>, // named locals, in order of definition
 }
 
+// A named local, which is in its register from `start` (included) to `end` (excluded) in the bytecode
+#[derive(Clone)]
+pub(crate) struct LocalInfo {
+	pub reg: u8,
+	pub start: u16,
+	pub end: u16,
+	pub name: String,
+}
+
+impl ChunkInfo {
+	// Starts the range of a local; a previous local in the same register, which it redefines, ends there
+	pub fn start_local(&mut self, reg: u8, pos: u16, name: String) {
+		self.end_local(reg, pos);
+		self.locals.push(LocalInfo { reg, start: pos, end: u16::MAX, name });
+	}
+	
+	pub fn end_local(&mut self, reg: u8, pos: u16) {
+		if let Some(local) = self.locals.iter_mut().rev().find(|local| local.reg == reg && local.end == u16::MAX) {
+			local.end = pos;
+		}
+	}
+	
+	// Lists the named locals at a position in the bytecode
+	pub fn locals_at(&self, pos: usize) -> impl Iterator<Item = &LocalInfo> {
+		self.locals.iter().filter(move |local| usize::from(local.start) <= pos && pos < usize::from(local.end))
+	}
+}
+
+#[derive(Clone)]
 pub(crate) struct Chunk {
 	pub nb_registers: u16,
+	pub nb_params: u8,
 	pub constants: Vec<ChunkConstant>,
-	pub upvalues: Vec<u8>,
+	pub upvalues: Vec<u8>, // Registers of the parent chunk, or its upvalues offset by MAX_REGISTERS
 	pub code: Vec<u8>,
+	pub encoding: Encoding,
 	pub debug_info: ChunkInfo,
 }
 
 
 impl Chunk {
-	pub fn new() -> Chunk {
-		Chunk { nb_registers: 0, constants: vec![], upvalues: vec![], code: vec![], debug_info: ChunkInfo::default() }
+	pub fn new(encoding: Encoding) -> Chunk {
+		Chunk { nb_registers: 0, nb_params: 0, constants: vec![], upvalues: vec![], code: vec![], encoding, debug_info: ChunkInfo::default() }
 	}
 	
-	pub fn from_bytes(it: &mut slice::Iter<u8>, debug_info: bool) -> Result<Chunk, HissyError> {
-		let mut chunk = Chunk::new();
+	pub fn from_bytes(it: &mut slice::Iter<u8>, debug_info: bool, encoding: Encoding) -> Result<Chunk, HissyError> {
+		let mut chunk = Chunk::new(encoding);
 		if debug_info {
 			chunk.debug_info.name = read_small_str(it)?;
+			chunk.debug_info.doc = read_str(it)?;
+			let nb_annotations = read_u16(it)?;
+			for _ in 0..nb_annotations {
+				let name = read_small_str(it)?;
+				let nb_args = read_u16(it)?;
+				let args = (0..nb_args).map(|_| read_str(it)).collect::<Result<Vec<String>, HissyError>>()?;
+				chunk.debug_info.annotations.push((name, args));
+			}
 		}
 		
 		chunk.nb_registers = read_u16(it)?;
+		chunk.nb_params = read_u8(it)?;
+		if u16::from(chunk.nb_params) > chunk.nb_registers {
+			return Err(error_str("Chunk has more parameters than registers"));
+		}
 		
 		let nb_constants = read_u16(it)?;
 		for _ in 0..nb_constants {
@@ -98,6 +157,9 @@ impl Chunk {
 				ConstantType::Int => ChunkConstant::Int(read_i32(it)?),
 				ConstantType::Real => ChunkConstant::Real(read_f64(it)?),
 				ConstantType::String => ChunkConstant::String(read_str(it)?),
+				ConstantType::Char => ChunkConstant::Char(char::from_u32(read_i32(it)? as u32)
+					.ok_or_else(|| error_str("Invalid character constant"))?),
+				ConstantType::Symbol => ChunkConstant::Symbol(Symbol::intern(&read_str(it)?)), // Indices differ between processes
 			};
 			chunk.constants.push(value);
 		}
@@ -116,9 +178,17 @@ impl Chunk {
 			for _ in 0..nb_line_numbers {
 				chunk.debug_info.line_numbers.push((read_u16(it)?, read_u16(it)?));
 			}
+			let nb_locals = read_u16(it)?;
+			for _ in 0..nb_locals {
+				let (reg, start, end) = (read_u8(it)?, read_u16(it)?, read_u16(it)?);
+				chunk.debug_info.locals.push(LocalInfo { reg, start, end, name: read_small_str(it)? });
+			}
 		}
 		
 		let code_size = usize::from(read_u16(it)?);
+		if it.len() < code_size {
+			return Err(error_str("Unexpected EOF"));
+		}
 		chunk.code.extend(&it.take(code_size).copied().collect::<Vec<u8>>());
 		Ok(chunk)
 	}
@@ -126,9 +196,19 @@ impl Chunk {
 	pub fn to_bytes(&self, bytes: &mut Vec<u8>, debug_info: bool) -> Result<(), HissyError> {
 		if debug_info {
 			write_small_str(bytes, &self.debug_info.name);
+			write_str(bytes, &self.debug_info.doc)?;
+			write_into_u16(bytes, self.debug_info.annotations.len(), error_str("Too many annotations to serialize"))?;
+			for (name, args) in &self.debug_info.annotations {
+				write_small_str(bytes, name);
+				write_into_u16(bytes, args.len(), error_str("Too many annotation arguments to serialize"))?;
+				for arg in args {
+					write_str(bytes, arg)?;
+				}
+			}
 		}
 		
 		write_u16(bytes, self.nb_registers);
+		write_u8(bytes, self.nb_params);
 		
 		write_into_u16(bytes, self.constants.len(), error_str("Too many constants to serialize"))?;
 		for cst in &self.constants {
@@ -152,6 +232,14 @@ impl Chunk {
 					write_u8(bytes, ConstantType::String as u8);
 					write_str(bytes, s)?;
 				},
+				ChunkConstant::Char(c) => {
+					write_u8(bytes, ConstantType::Char as u8);
+					write_i32(bytes, *c as i32);
+				},
+				ChunkConstant::Symbol(s) => {
+					write_u8(bytes, ConstantType::Symbol as u8);
+					write_str(bytes, s)?;
+				},
 			}
 		}
 		
@@ -169,6 +257,13 @@ impl Chunk {
 				write_u16(bytes, *pos);
 				write_u16(bytes, *line);
 			}
+			write_into_u16(bytes, self.debug_info.locals.len(), error_str("Too many locals to serialize"))?;
+			for local in &self.debug_info.locals {
+				write_u8(bytes, local.reg);
+				write_u16(bytes, local.start);
+				write_u16(bytes, local.end);
+				write_small_str(bytes, &local.name);
+			}
 		}
 		
 		write_into_u16(bytes, self.code.len(), error_str("Code too long to serialize"))?;
@@ -177,12 +272,39 @@ impl Chunk {
 		Ok(())
 	}
 	
-	pub fn emit_instr(&mut self, instr: InstrType) {
-		self.code.push(instr as u8);
+	// Encodes an instruction at the end of the chunk, and returns its position
+	pub fn emit(&mut self, instr: Instr) -> usize {
+		let pos = self.code.len();
+		instr.encode(&mut self.code, self.encoding);
+		pos
+	}
+	
+	// Sets the relative address of the jump instruction at pos, re-encoding it in place
+	pub fn patch_jump(&mut self, pos: usize, rel: i16) -> Result<(), HissyError> {
+		let mut instr = Instr::decode(&mut self.code[pos..].iter(), self.encoding)?;
+		instr.set_rel_add(rel);
+		let mut bytes = vec![];
+		instr.encode(&mut bytes, self.encoding);
+		self.code[pos..pos + bytes.len()].copy_from_slice(&bytes);
+		Ok(())
 	}
 	
-	pub fn emit_byte(&mut self, byte: u8) {
-		self.code.push(byte);
+	// Shifts the chunk indices of Func instructions by offset, re-encoding them in place
+	pub fn offset_chunks(&mut self, offset: usize) -> Result<(), HissyError> {
+		let mut pos = 0;
+		while pos < self.code.len() {
+			let mut it = self.code[pos..].iter();
+			let instr = Instr::decode(&mut it, self.encoding)?;
+			let next = self.code.len() - it.len();
+			if let Instr::Func { chunk, dst } = instr {
+				let chunk = u8::try_from(usize::from(chunk) + offset).map_err(|_| error_str("Too many chunks"))?;
+				let mut bytes = vec![];
+				Instr::Func { chunk, dst }.encode(&mut bytes, self.encoding);
+				self.code[pos..pos + bytes.len()].copy_from_slice(&bytes);
+			}
+			pos = next;
+		}
+		Ok(())
 	}
 	
 	// Adds constant to the list of constants in the chunk, and return the constant's register index
@@ -193,38 +315,243 @@ impl Chunk {
 			.map_err(|_| HissyError(ErrorType::Compilation, String::from("Too many constants required"), 0))
 	}
 	
-	fn format_reg(&self, it: &mut slice::Iter<u8>) -> Result<String, HissyError> {
-		let reg = read_u8(it)?;
+	fn format_reg(&self, reg: u8) -> String {
 		if reg < MAX_REGISTERS {
-			Ok(format!("r{}", reg))
+			format!("r{}", reg)
 		} else {
 			let cst = usize::try_from(reg - MAX_REGISTERS).unwrap();
-			Ok(self.constants[cst].repr())
+			self.constants.get(cst).map_or_else(|| format!("k{}", cst), ChunkConstant::repr)
+		}
+	}
+	
+	/// Checks that the bytecode decodes into valid instructions, whose operands
+	/// refer to existing registers, constants, upvalues and chunks, and whose
+	/// jumps land on instruction boundaries.
+	pub fn verify(&self, nb_chunks: usize) -> Result<(), HissyError> {
+		let mut instrs = vec![];
+		let mut it = self.code.iter();
+		while it.len() > 0 {
+			let pos = self.code.len() - it.len();
+			instrs.push((pos, Instr::decode(&mut it, self.encoding)?));
+		}
+		
+		let boundaries: Vec<usize> = instrs.iter().map(|(pos, _)| *pos).collect();
+		for (pos, instr) in instrs {
+			let invalid = |what: &str| error(format!("Invalid {} in {:?} at {}", what, instr.instr_type(), pos));
+			let operands = instr.operand_values();
+			for (i, &(ty, val)) in operands.iter().enumerate() {
+				// A register followed by a count is the start of a (possibly empty) register range
+				let range_len = match operands.get(i + 1) {
+					Some(&(OperandType::Count, n)) if ty == OperandType::Reg => n,
+					_ => 1,
+				};
+				match ty {
+					OperandType::Reg if val + range_len > i32::from(self.nb_registers) => return Err(invalid("register")),
+					OperandType::RegOrCst if val < i32::from(MAX_REGISTERS) && val >= i32::from(self.nb_registers) =>
+						return Err(invalid("register")),
+					OperandType::RegOrCst if val >= i32::from(MAX_REGISTERS)
+						&& (val - i32::from(MAX_REGISTERS)) as usize >= self.constants.len() => return Err(invalid("constant")),
+					OperandType::Upvalue if val as usize >= self.upvalues.len() => return Err(invalid("upvalue")),
+					OperandType::Chunk if val as usize >= nb_chunks => return Err(invalid("chunk")),
+					OperandType::RelAdd => {
+						let target = pos as i32 + 1 + val;
+						if target != self.code.len() as i32 && !boundaries.contains(&(target as usize)) {
+							return Err(invalid("jump target"));
+						}
+					},
+					_ => (),
+				}
+			}
+		}
+		Ok(())
+	}
+	
+	/// Checks that the upvalues of closures of the chunk refer to existing registers and
+	/// upvalues of the chunk creating them, which is only known when they are created.
+	pub fn verify_upvalues(&self, parent: &Chunk) -> Result<(), HissyError> {
+		for &upv in &self.upvalues {
+			let valid = if upv < MAX_REGISTERS {
+				u16::from(upv) < parent.nb_registers
+			} else {
+				usize::from(upv - MAX_REGISTERS) < parent.upvalues.len()
+			};
+			if !valid {
+				return Err(error(format!("Invalid upvalue {} of closure", upv)));
+			}
+		}
+		Ok(())
+	}
+}
+
+
+// A serialized chunk, along with what is needed to decode it
+#[derive(Clone)]
+struct SerializedChunk {
+	bytes: Arc<[u8]>, // Shared by all chunks of a program
+	range: Range<usize>,
+	debug_info: bool,
+	encoding: Encoding,
+	nb_chunks: usize,
+}
+
+// A chunk of a program loaded from bytecode is only decoded and verified the first time it is used,
+// so that loading a program with many rarely used functions stays fast.
+#[derive(Clone)]
+pub(crate) struct LazyChunk {
+	serialized: Option<SerializedChunk>, // None if the chunk was compiled or modified
+	decoded: OnceLock<Chunk>,
+}
+
+impl LazyChunk {
+	fn serialized(serialized: SerializedChunk) -> LazyChunk {
+		LazyChunk { serialized: Some(serialized), decoded: OnceLock::new() }
+	}
+	
+	pub fn get(&self) -> Result<&Chunk, HissyError> {
+		if let Some(chunk) = self.decoded.get() {
+			return Ok(chunk);
+		}
+		let ser = self.serialized.as_ref().expect("Chunk has no contents");
+		let mut it = ser.bytes[ser.range.clone()].iter();
+		let chunk = Chunk::from_bytes(&mut it, ser.debug_info, ser.encoding)?;
+		if it.len() > 0 {
+			return Err(error_str("Unexpected data at end of chunk"));
 		}
+		chunk.verify(ser.nb_chunks)?;
+		Ok(self.decoded.get_or_init(|| chunk))
 	}
 	
-	fn format_rel_add(&self, it: &mut slice::Iter<u8>) -> String {
-		let pos = isize::try_from(self.code.len() - it.len()).unwrap();
-		let rel_add = isize::from(i8::from_le_bytes([*it.next().unwrap()]));
-		format!("@{}", pos + rel_add)
+	// For chunks which are known to be decoded already, eg. those of closures
+	pub fn decoded(&self) -> &Chunk {
+		self.decoded.get().expect("Chunk has not been decoded")
+	}
+	
+	pub fn get_mut(&mut self) -> Result<&mut Chunk, HissyError> {
+		self.get()?;
+		self.serialized = None;
+		Ok(self.decoded.get_mut().unwrap())
+	}
+	
+	// Chunks which were not modified are copied as is, without decoding them
+	fn to_bytes(&self, bytes: &mut Vec<u8>, debug_info: bool) -> Result<(), HissyError> {
+		match &self.serialized {
+			Some(ser) if ser.debug_info == debug_info => {
+				bytes.extend(&ser.bytes[ser.range.clone()]);
+				Ok(())
+			},
+			_ => self.get()?.to_bytes(bytes, debug_info),
+		}
 	}
 }
 
+impl From<Chunk> for LazyChunk {
+	fn from(chunk: Chunk) -> LazyChunk {
+		LazyChunk { serialized: None, decoded: OnceLock::from(chunk) }
+	}
+}
+
+
+/// Statistics about a chunk of a [`Program`], for use by build tooling.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkMetadata {
+	/// The name of the function, if the program has debug info.
+	pub name: Option<String>,
+	/// The number of registers required to run the chunk.
+	pub registers: u16,
+	/// The number of parameters of the function.
+	pub params: u8,
+	/// The size of the constant pool.
+	pub constants: usize,
+	/// The number of upvalues captured by closures of the chunk.
+	pub upvalues: usize,
+	/// The size of the bytecode, in bytes.
+	pub code_size: usize,
+	/// The annotations of the function declaration (eg. `@test`) with their arguments, if the program has debug info.
+	pub annotations: Vec<(String, Vec<String>)>,
+}
+
 /// A data structure representing a compiled program (ie. Hissy bytecode).
 /// Can be serialized to and from a file (usually under the extension .hic, for Hissy Instruction Code).
+/// 
+/// The serialized format is the same on all platforms: multi-byte numbers (including
+/// instruction operands and real constants) are little-endian, and nothing is aligned.
+/// 
+/// When a program is loaded from bytecode, only its main chunk is decoded at first;
+/// functions are decoded and verified when their closures are first created.
+/// 
+/// Each chunk lists the variables captured by its closures, as one byte per upvalue, referring to
+/// the chunk creating them: values below 128 are its registers, and values from 128 are its own
+/// upvalues, plus 128. A variable of a function is thus captured by a closure nested several levels
+/// deeper through an upvalue of each function in between.
+#[derive(Clone)]
 pub struct Program {
 	pub(crate) debug_info: bool,
-	pub(crate) chunks: Vec<Chunk>,
+	pub(crate) encoding: Encoding,
+	pub(crate) chunks: Vec<LazyChunk>,
+	pub(crate) source: Option<String>,
+	pub(crate) warnings: Vec<Warning>, // Not serialized
+	pub(crate) tests: Vec<(String, u16)>, // Not serialized
+	pub(crate) benches: Vec<(String, u16)>, // Not serialized
+}
+
+const OPTION_DEBUG_INFO: u8 = 1;
+const OPTION_WIDE: u8 = 2;
+const OPTION_SOURCE: u8 = 4;
+const OPTION_COMPRESSED: u8 = 8;
+
+// Reads the rest of compressed bytecode, after the header
+#[cfg(feature = "compression")]
+fn decompress_body(it: &mut slice::Iter<u8>) -> Result<Vec<u8>, HissyError> {
+	let len = read_u32(it)? as usize;
+	crate::compress::decompress(it, len)
+}
+#[cfg(not(feature = "compression"))]
+fn decompress_body(_it: &mut slice::Iter<u8>) -> Result<Vec<u8>, HissyError> {
+	Err(error_str("Compressed bytecode requires the 'compression' feature"))
 }
 
 const MAGIC_BYTES: &[u8; 4] = b"hsyc";
-const FORMAT_VER: u16 = 4;
+const FORMAT_VER: u16 = 11;
 
 impl Program {
 	/// Reads a `Program` from a bytecode file.
 	pub fn from_file<T: AsRef<Path>>(path: T) -> Result<Program, HissyError> {
-		let contents = fs::read(path).map_err(|_| error_str("Unable to read chunk"))?;
-		
+		let contents = fs::read(path).map_err(|e| error(format!("Unable to read bytecode file: {}", e)))?;
+		Program::from_bytes(&contents)
+	}
+	
+	/// Reads a `Program` from any reader, eg. an entry in an asset archive.
+	pub fn from_reader<R: Read>(mut reader: R) -> Result<Program, HissyError> {
+		let mut contents = vec![];
+		reader.read_to_end(&mut contents).map_err(|e| error(format!("Unable to read bytecode: {}", e)))?;
+		Program::from_bytes(&contents)
+	}
+	
+	/// Reads a `Program` from a bytecode file, after checking its contents with `verify`.
+	/// 
+	/// See [`Program::from_bytes_verified`].
+	pub fn from_file_verified<T: AsRef<Path>, F>(path: T, verify: F) -> Result<Program, HissyError>
+			where F: FnOnce(&[u8]) -> Result<(), String> {
+		let contents = fs::read(path).map_err(|e| error(format!("Unable to read bytecode file: {}", e)))?;
+		Program::from_bytes_verified(&contents, verify)
+	}
+	
+	/// Reads a `Program` from bytecode, after checking it with `verify`.
+	/// 
+	/// The callback receives the raw bytecode before anything is decoded, and can reject it
+	/// by returning an error message, eg. if its hash or signature does not match what the host expects.
+	pub fn from_bytes_verified<F>(contents: &[u8], verify: F) -> Result<Program, HissyError>
+			where F: FnOnce(&[u8]) -> Result<(), String> {
+		verify(contents).map_err(|err| error(format!("Bytecode rejected: {}", err)))?;
+		Program::from_bytes(contents)
+	}
+	
+	/// Reads a `Program` from bytecode, in the format of a bytecode file.
+	/// 
+	/// Compressed bytecode is decompressed transparently, if the `compression` feature is enabled.
+	/// Chunks other than the main one are decoded later; see [`Program::decode_all`] to detect
+	/// invalid bytecode right away.
+	pub fn from_bytes(contents: &[u8]) -> Result<Program, HissyError> {
 		let mut it = contents.iter();
 		
 		let first_bytes: [u8; 4] = read_u8s(&mut it, MAGIC_BYTES.len())?;
@@ -237,38 +564,223 @@ impl Program {
 		}
 		
 		let options = read_u8(&mut it)?;
-		if options > 1 {
+		if options & !(OPTION_DEBUG_INFO | OPTION_WIDE | OPTION_SOURCE | OPTION_COMPRESSED) != 0 {
 			return Err(error_str("Unexpected options byte in .hsyc file"));
 		}
-		let debug_info = options == 1;
+		let debug_info = options & OPTION_DEBUG_INFO != 0;
+		let encoding = if options & OPTION_WIDE != 0 { Encoding::Wide } else { Encoding::Compact };
+		let natives = read_u8(&mut it)?;
+		if natives != prelude::optional_natives() {
+			return Err(error(format!("Bytecode was compiled with optional natives: {}, but this build has: {}",
+				prelude::describe_optional_natives(natives), prelude::describe_optional_natives(prelude::optional_natives()))));
+		}
 		
-		let mut chunks = vec![];
+		let body: Arc<[u8]> = if options & OPTION_COMPRESSED != 0 {
+			Arc::from(decompress_body(&mut it)?)
+		} else {
+			Arc::from(it.as_slice())
+		};
+		let mut it = body.iter();
+		let source = if options & OPTION_SOURCE != 0 { Some(read_long_str(&mut it)?) } else { None };
+		
+		// Chunks are preceded by their size, so they can be skipped until they are needed
+		let mut ranges = vec![];
 		while it.len() > 0 {
-			chunks.push(Chunk::from_bytes(&mut it, debug_info)?);
+			let size = read_u32(&mut it)? as usize;
+			let start = body.len() - it.len();
+			if it.len() < size {
+				return Err(error_str("Unexpected EOF"));
+			}
+			it = body[start + size..].iter();
+			ranges.push(start..start + size);
+		}
+		let nb_chunks = ranges.len();
+		let chunks: Vec<LazyChunk> = ranges.into_iter().map(|range| LazyChunk::serialized(SerializedChunk {
+			bytes: body.clone(), range, debug_info, encoding, nb_chunks,
+		})).collect();
+		if let Some(main) = chunks.first() {
+			main.get()?;
 		}
 		
-		Ok(Program { debug_info, chunks })
+		Ok(Program { debug_info, encoding, chunks, source, warnings: vec![], tests: vec![], benches: vec![] })
+	}
+	
+	/// Decodes and verifies all chunks which have not been used yet.
+	pub fn decode_all(&self) -> Result<(), HissyError> {
+		for chunk in &self.chunks {
+			chunk.get()?;
+		}
+		Ok(())
 	}
 	
 	/// Serializes a `Program` object to a bytecode file.
 	pub fn to_file<T: AsRef<Path>>(&self, path: T) -> Result<(), HissyError> {
+		let bytes = self.to_bytes()?;
+		fs::write(path, &bytes).map_err(|e| error(format!("Unable to write bytecode file: {}", e)))
+	}
+	
+	/// Serializes a `Program` object to any writer, in the format of a bytecode file.
+	pub fn write_to<W: Write>(&self, mut writer: W) -> Result<(), HissyError> {
+		let bytes = self.to_bytes()?;
+		writer.write_all(&bytes).map_err(|e| error(format!("Unable to write bytecode: {}", e)))
+	}
+	
+	/// Serializes a `Program` object to a compressed bytecode file.
+	#[cfg(feature = "compression")]
+	pub fn to_compressed_file<T: AsRef<Path>>(&self, path: T) -> Result<(), HissyError> {
+		let bytes = self.to_compressed_bytes()?;
+		fs::write(path, &bytes).map_err(|e| error(format!("Unable to write bytecode file: {}", e)))
+	}
+	
+	/// Serializes a `Program` object to bytecode, in the format of a bytecode file.
+	pub fn to_bytes(&self) -> Result<Vec<u8>, HissyError> {
+		self.serialize(false)
+	}
+	
+	/// Serializes a `Program` object to bytecode, in the format of a bytecode file,
+	/// compressing everything after the header.
+	#[cfg(feature = "compression")]
+	pub fn to_compressed_bytes(&self) -> Result<Vec<u8>, HissyError> {
+		self.serialize(true)
+	}
+	
+	fn serialize(&self, compressed: bool) -> Result<Vec<u8>, HissyError> {
 		let mut bytes = vec![];
 		
 		bytes.extend(MAGIC_BYTES);
 		write_u16(&mut bytes, FORMAT_VER);
 		
-		let options = if self.debug_info { 1 } else { 0 };
+		let mut options = 0;
+		if self.debug_info {
+			options |= OPTION_DEBUG_INFO;
+		}
+		if self.encoding == Encoding::Wide {
+			options |= OPTION_WIDE;
+		}
+		if self.source.is_some() {
+			options |= OPTION_SOURCE;
+		}
+		if compressed {
+			options |= OPTION_COMPRESSED;
+		}
 		bytes.push(options);
+		bytes.push(prelude::optional_natives());
 		
+		let mut body = vec![];
+		if let Some(source) = &self.source {
+			write_long_str(&mut body, source)?;
+		}
 		for chunk in &self.chunks {
-			chunk.to_bytes(&mut bytes, self.debug_info)?;
+			let mut chunk_bytes = vec![];
+			chunk.to_bytes(&mut chunk_bytes, self.debug_info)?;
+			write_into_u32(&mut body, chunk_bytes.len(), error_str("Chunk too large to serialize"))?;
+			body.extend(chunk_bytes);
+		}
+		
+		if compressed {
+			#[cfg(feature = "compression")] {
+				write_into_u32(&mut bytes, body.len(), error_str("Program too large to compress"))?;
+				bytes.extend(crate::compress::compress(&body));
+			}
+		} else {
+			bytes.extend(body);
+		}
+		Ok(bytes)
+	}
+	
+	/// Returns the source code embedded in the program, if any.
+	pub fn source(&self) -> Option<&str> {
+		self.source.as_deref()
+	}
+	
+	/// Returns the warnings found while compiling the program; programs loaded from bytecode have none.
+	pub fn warnings(&self) -> &[Warning] {
+		&self.warnings
+	}
+	
+	/// Returns the tests of a program compiled with [`Compiler::compile_tests`](super::Compiler::compile_tests),
+	/// as their name and line, in the order of their index for [`run_test`](crate::vm::run_test).
+	pub fn tests(&self) -> &[(String, u16)] {
+		&self.tests
+	}
+	
+	/// Returns the benchmarks of a program compiled with [`Compiler::compile_benches`](super::Compiler::compile_benches),
+	/// as their name and line, in the order of their index for [`run_bench`](crate::vm::run_bench).
+	pub fn benches(&self) -> &[(String, u16)] {
+		&self.benches
+	}
+	
+	/// Returns whether the program contains debug info (names, line numbers, doc comments and annotations).
+	pub fn has_debug_info(&self) -> bool {
+		self.debug_info
+	}
+	
+	/// Returns the instruction encoding of the bytecode.
+	pub fn encoding(&self) -> Encoding {
+		self.encoding
+	}
+	
+	/// Returns the number of chunks (functions) in the program, including the main chunk.
+	pub fn chunk_count(&self) -> usize {
+		self.chunks.len()
+	}
+	
+	/// Returns statistics about a chunk, if it exists and is valid.
+	pub fn chunk_metadata(&self, chunk_id: usize) -> Option<ChunkMetadata> {
+		self.chunks.get(chunk_id).and_then(|chunk| chunk.get().ok()).map(|chunk| ChunkMetadata {
+			name: if self.debug_info { Some(chunk.debug_info.name.clone()) } else { None },
+			registers: chunk.nb_registers,
+			params: chunk.nb_params,
+			constants: chunk.constants.len(),
+			upvalues: chunk.upvalues.len(),
+			code_size: chunk.code.len(),
+			annotations: chunk.debug_info.annotations.clone(),
+		})
+	}
+	
+	/// Removes debug info and embedded source code from the program.
+	/// 
+	/// Fails if a chunk which was not decoded yet is invalid.
+	pub fn strip_debug_info(&mut self) -> Result<(), HissyError> {
+		if !self.debug_info {
+			return Ok(());
+		}
+		for chunk in &mut self.chunks {
+			chunk.get_mut()?.debug_info = ChunkInfo::default();
 		}
-		fs::write(path, &bytes).map_err(|_| error_str("Could not write file"))
+		self.debug_info = false;
+		self.source = None;
+		Ok(())
 	}
 	
+	/// Appends the chunks of another program to this one, and returns the index
+	/// of the other program's main chunk, which can then be called from the host.
+	/// 
+	/// Both programs must use the same encoding. The result keeps debug info only if both have it,
+	/// and the embedded source code is dropped, as it would only match part of the program.
+	pub fn merge(&mut self, other: &Program) -> Result<usize, HissyError> {
+		if self.encoding != other.encoding {
+			return Err(error_str("Cannot merge programs with different encodings"));
+		}
+		let offset = self.chunks.len();
+		if offset + other.chunks.len() > usize::from(u8::MAX) + 1 {
+			return Err(error_str("Too many chunks"));
+		}
+		for chunk in &other.chunks {
+			let mut chunk = chunk.get()?.clone();
+			chunk.offset_chunks(offset)?;
+			self.chunks.push(LazyChunk::from(chunk));
+		}
+		if !other.debug_info {
+			self.strip_debug_info()?;
+		}
+		self.source = None;
+		Ok(offset)
+	}
+		
 	fn format_chunk_name(&self, chunk_id: usize) -> Result<String, HissyError> {
 		if self.debug_info {
-			Ok(self.chunks.get(chunk_id).ok_or_else(|| error_str("Invalid chunk ID"))?.debug_info.name.clone())
+			Ok(self.chunks.get(chunk_id).ok_or_else(|| error_str("Invalid chunk ID"))?.get()?.debug_info.name.clone())
 		} else {
 			Ok(format!("chunk{}", chunk_id))
 		}
@@ -277,13 +789,33 @@ impl Program {
 	/// Inspects the `Program`, printing to standard output.
 	/// Corresponds to the CLI's "list" output.
 	pub fn disassemble(&self) -> Result<(), HissyError> {
-		if !self.debug_info {
+		if self.debug_info {
+			println!("[debug info: chunk names, upvalue names, line numbers, doc comments, annotations]");
+		} else {
 			println!("[no debug info]");
 		}
+		if let Some(source) = &self.source {
+			println!("[embedded source: {} lines]", source.lines().count());
+		}
+		if self.encoding == Encoding::Wide {
+			println!("[wide encoding]");
+		}
 		
 		for (chunk_id, chunk) in self.chunks.iter().enumerate() {
+			let chunk = chunk.get()?;
 			println!("{} ({} registers; {} constants)", self.format_chunk_name(chunk_id)?,
 				chunk.nb_registers, chunk.constants.len());
+			for line in chunk.debug_info.doc.lines() {
+				println!("## {}", line);
+			}
+			for (name, args) in &chunk.debug_info.annotations {
+				if args.is_empty() {
+					println!("@{}", name);
+				} else {
+					let args: Vec<String> = args.iter().map(|arg| format!("{:?}", arg)).collect();
+					println!("@{}({})", name, args.join(", "));
+				}
+			}
 			
 			if !chunk.upvalues.is_empty() {
 				print!("(upvalues: ");
@@ -304,57 +836,28 @@ impl Program {
 			
 			let mut it = chunk.code.iter();
 			let mut pos = 0;
-			while let Some(b) = it.next() {
-				let instr = InstrType::try_from(*b).map_err(|_| error_str("Invalid instruction in bytecode"))?;
+			while it.len() > 0 {
+				let instr = Instr::decode(&mut it, chunk.encoding)?;
 				print!("{:<5}", pos);
 				if let Some(line) = u16::try_from(pos).ok().and_then(|pos| line_numbers.get(&pos)) {
 					print!("l{:<5}", line);
 				} else {
 					print!("      ");
 				}
-				print!("{:?}(", instr);
-				match instr {
-					Nop => {},
-					Cpy | Neg | Not => {
-						print!("{}, {}", chunk.format_reg(&mut it)?, chunk.format_reg(&mut it)?);
-					},
-					Add | Sub | Mul | Div | Mod | Pow | Or | And
-						| Eq | Neq | Lth | Leq | Gth | Geq
-						| ListGet | ListSet => {
-						print!("{}, {}, {}", chunk.format_reg(&mut it)?, chunk.format_reg(&mut it)?, chunk.format_reg(&mut it)?);
-					},
-					Func => {
-						print!("{}, {}", self.format_chunk_name(read_u8(&mut it)? as usize)?, chunk.format_reg(&mut it)?);
-					},
-					Call => {
-						print!("{}, {}, {}, {}", chunk.format_reg(&mut it)?, chunk.format_reg(&mut it)?, read_u8(&mut it)?, chunk.format_reg(&mut it)?);
-					},
-					Ret | ListNew | CloseUp => {
-						print!("{}", chunk.format_reg(&mut it)?);
-					},
-					Jmp => {
-						print!("{}", chunk.format_rel_add(&mut it));
-					},
-					Jit | Jif | Jin => {
-						print!("{}, {}", chunk.format_rel_add(&mut it), chunk.format_reg(&mut it)?);
-					},
-					GetUp | SetUp => {
-						print!("u{}, {}", read_u8(&mut it)?, chunk.format_reg(&mut it)?);
-					},
-					GetExt => {
-						print!("e{}, {}", read_u16(&mut it)?, chunk.format_reg(&mut it)?);
-					},
-					MakeMethod => {
-						print!("e{}, .{}, {}, {}", read_u16(&mut it)?, read_u8(&mut it)?, chunk.format_reg(&mut it)?, chunk.format_reg(&mut it)?);
-					},
-					CallMethod => {
-						print!("e{}, .{}, {}, {}, {}, {}", read_u16(&mut it)?, read_u8(&mut it)?, chunk.format_reg(&mut it)?, chunk.format_reg(&mut it)?, read_u8(&mut it)?, chunk.format_reg(&mut it)?);
-					},
-					ListExtend => {
-						print!("{}, {}, {}", chunk.format_reg(&mut it)?, chunk.format_reg(&mut it)?, read_u8(&mut it)?);
-					},
-					#[allow(unreachable_patterns)]
-					_ => unimplemented!("Unimplemented disassembly for instruction: {:?}", instr)
+				print!("{:?}(", instr.instr_type());
+				for (i, (ty, val)) in instr.operand_values().into_iter().enumerate() {
+					if i != 0 {
+						print!(", ");
+					}
+					match ty {
+						OperandType::Reg | OperandType::RegOrCst => print!("{}", chunk.format_reg(val as u8)),
+						OperandType::RelAdd => print!("@{}", pos as i32 + 1 + val),
+						OperandType::Upvalue => print!("u{}", val),
+						OperandType::Chunk => print!("{}", self.format_chunk_name(val as usize)?),
+						OperandType::External => print!("e{}", val),
+						OperandType::Prop => print!(".{}", val),
+						OperandType::Count => print!("{}", val),
+					}
 				}
 				println!(")");
 				pos = chunk.code.len() - it.len();
@@ -365,3 +868,185 @@ impl Program {
 		Ok(())
 	}
 }
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn make_chunk(extra: Option<Instr>) -> Chunk {
+		let mut chunk = Chunk::new(Encoding::Compact);
+		chunk.nb_registers = 2;
+		chunk.constants.push(ChunkConstant::Int(1));
+		let jmp = chunk.emit(Instr::Jif { rel: 0, cond: 0 });
+		chunk.emit(Instr::Add { a: 0, b: MAX_REGISTERS, dst: 1 });
+		chunk.patch_jump(jmp, (chunk.code.len() - jmp - 1) as i16).unwrap();
+		chunk.emit(Instr::Ret { src: 1 });
+		if let Some(instr) = extra {
+			chunk.emit(instr);
+		}
+		chunk
+	}
+
+	#[test]
+	fn test_verify() {
+		assert!(make_chunk(None).verify(1).is_ok());
+		assert!(make_chunk(Some(Instr::Call { func: 0, args: 2, n: 0, dst: 0 })).verify(1).is_ok());
+
+		let bad = |instr| make_chunk(Some(instr)).verify(1).is_err();
+		assert!(bad(Instr::Cpy { src: 0, dst: 2 }));
+		assert!(bad(Instr::Ret { src: MAX_REGISTERS + 1 }));
+		assert!(bad(Instr::Func { chunk: 1, dst: 0 }));
+		assert!(bad(Instr::GetUp { upv: 0, dst: 0 }));
+		assert!(bad(Instr::Jmp { rel: -2 }));
+	}
+	
+	#[test]
+	fn test_doc_comments() {
+		let program = crate::compiler::Compiler::new(true).compile_program("## Does nothing.\nlet f():\n\tpass\n").unwrap();
+		let program = Program::from_bytes(&program.to_bytes().unwrap()).unwrap();
+		assert_eq!(program.chunks[1].get().unwrap().debug_info.name, "f");
+		assert_eq!(program.chunks[1].get().unwrap().debug_info.doc, "Does nothing.");
+	}
+	
+	#[test]
+	fn test_long_symbols() {
+		// Both symbols have a character spanning the 256th byte, and differ after it
+		let (a, b) = ("a".repeat(254) + "éx", "a".repeat(254) + "éy");
+		let src = format!("let {}: Symbol = :{}\nlog(:{})\n", a, a, b);
+		let program = crate::compiler::Compiler::new(true).compile_program(&src).unwrap();
+		let program = Program::from_bytes(&program.to_bytes().unwrap()).unwrap();
+		let constants = &program.chunks[0].get().unwrap().constants;
+		assert!(constants.contains(&ChunkConstant::Symbol(Symbol::intern(&a))));
+		assert!(constants.contains(&ChunkConstant::Symbol(Symbol::intern(&b))));
+		// Names in debug info are truncated
+		assert_eq!(program.chunks[0].get().unwrap().debug_info.locals[0].name, "a".repeat(254));
+	}
+	
+	#[test]
+	fn test_annotations() {
+		let src = "## Does nothing.\n@test\n@deprecated(\"Use g\")\nlet f():\n\tpass\nlet g():\n\tf()\n";
+		let program = crate::compiler::Compiler::new(true).compile_program(src).unwrap();
+		assert_eq!(program.warnings(), [Warning(String::from("Use of deprecated function 'f': Use g"), 7)]);
+		let program = Program::from_bytes(&program.to_bytes().unwrap()).unwrap();
+		assert_eq!(program.chunks[1].get().unwrap().debug_info.doc, "Does nothing.");
+		let expected = vec![(String::from("test"), vec![]), (String::from("deprecated"), vec![String::from("Use g")])];
+		assert_eq!(program.chunk_metadata(1).unwrap().annotations, expected);
+		assert!(program.chunk_metadata(2).unwrap().annotations.is_empty());
+		
+		let errors = ["@inline\nlet a = 1\n", "@unknown\nlet f():\n\tpass\n", "@test(\"a\")\nlet f():\n\tpass\n"];
+		for src in &errors {
+			assert!(crate::compiler::Compiler::new(true).compile_program(src).is_err(), "{}", src);
+		}
+	}
+	
+	#[test]
+	fn test_embedded_source() {
+		let src = "log(1)\n";
+		let mut compiler = crate::compiler::Compiler::new(true);
+		compiler.set_embed_source(true);
+		let program = Program::from_bytes(&compiler.compile_program(src).unwrap().to_bytes().unwrap()).unwrap();
+		assert_eq!(program.source(), Some(src));
+		let program = crate::compiler::Compiler::new(false).compile_program(src).unwrap();
+		assert_eq!(Program::from_bytes(&program.to_bytes().unwrap()).unwrap().source(), None);
+	}
+	
+	#[test]
+	fn test_merge() {
+		let compile = |src| crate::compiler::Compiler::new(true).compile_program(src).unwrap();
+		let mut program = compile("let f() -> Int:\n\treturn 1\nlog(f())\n");
+		let other = compile("let g() -> Int:\n\treturn 2\nlog(g())\n");
+		assert_eq!(program.merge(&other).unwrap(), 2);
+		let program = Program::from_bytes(&program.to_bytes().unwrap()).unwrap();
+		assert_eq!(program.chunk_count(), 4);
+		assert_eq!(program.chunk_metadata(3).unwrap().name.as_deref(), Some("g"));
+		
+		let mut it = program.chunks[2].get().unwrap().code.iter();
+		let mut funcs = vec![];
+		while it.len() > 0 {
+			if let Instr::Func { chunk, .. } = Instr::decode(&mut it, program.encoding).unwrap() {
+				funcs.push(chunk);
+			}
+		}
+		assert_eq!(funcs, vec![3]);
+		
+		let mut stripped = program.clone();
+		stripped.strip_debug_info().unwrap();
+		assert!(!stripped.has_debug_info());
+		assert_eq!(stripped.chunk_metadata(3).unwrap(), ChunkMetadata { name: None, ..program.chunk_metadata(3).unwrap() });
+		assert!(stripped.to_bytes().unwrap().len() < program.to_bytes().unwrap().len());
+	}
+	
+	#[test]
+	fn test_portable_format() {
+		let mut chunk = Chunk::new(Encoding::Wide);
+		chunk.nb_registers = 1;
+		chunk.constants = vec![ChunkConstant::Int(0x0102_0304), ChunkConstant::Real(1.5), ChunkConstant::String(String::from("hi"))];
+		chunk.emit(Instr::Jmp { rel: 3 });
+		chunk.emit(Instr::Ret { src: MAX_REGISTERS });
+		let program = Program { debug_info: false, encoding: Encoding::Wide, chunks: vec![chunk.into()], source: None, warnings: vec![], tests: vec![], benches: vec![] };
+		
+		// The bytes expected on any host, whatever its endianness or pointer width
+		let (jmp, ret) = (Instr::Jmp { rel: 0 }.instr_type() as u8, Instr::Ret { src: 0 }.instr_type() as u8);
+		let mut expected = vec![b'h', b's', b'y', b'c', FORMAT_VER as u8, 0, OPTION_WIDE, prelude::optional_natives(), 36, 0, 0, 0, 1, 0, 0, 3, 0];
+		expected.extend(&[ConstantType::Int as u8, 0x04, 0x03, 0x02, 0x01]);
+		expected.extend(&[ConstantType::Real as u8, 0, 0, 0, 0, 0, 0, 0xf8, 0x3f]);
+		expected.extend(&[ConstantType::String as u8, 2, 0, b'h', b'i']);
+		expected.extend(&[0, 0, 8, 0, jmp, 3, 0, 0, ret, MAX_REGISTERS, 0, 0]);
+		assert_eq!(program.to_bytes().unwrap(), expected);
+		
+		let loaded = Program::from_bytes(&expected).unwrap();
+		assert!(loaded.chunks[0].get().unwrap().constants[..2] == [ChunkConstant::Int(0x0102_0304), ChunkConstant::Real(1.5)]);
+		assert_eq!(loaded.chunks[0].get().unwrap().code, program.chunks[0].get().unwrap().code);
+		assert!(Program::from_bytes(&expected[..expected.len() - 1]).is_err());
+		
+		// Programs compiled with other optional natives would call the wrong natives
+		expected[7] ^= 1;
+		let err = Program::from_bytes(&expected).err().unwrap();
+		assert!(err.1.starts_with("Bytecode was compiled with optional natives: "), "{}", err.1);
+	}
+	
+	#[test]
+	fn test_verified_loading() {
+		let bytes = crate::compiler::Compiler::new(false).compile_program("log(1)\n").unwrap().to_bytes().unwrap();
+		let checksum = |bytes: &[u8]| bytes.iter().fold(0u32, |sum, b| sum.rotate_left(5) ^ u32::from(*b));
+		let expected = checksum(&bytes);
+		let verify = |bytes: &[u8]| if checksum(bytes) == expected { Ok(()) } else { Err(String::from("checksum mismatch")) };
+		assert!(Program::from_bytes_verified(&bytes, verify).is_ok());
+		
+		let mut tampered = bytes.clone();
+		*tampered.last_mut().unwrap() ^= 1;
+		let err = Program::from_bytes_verified(&tampered, verify).err().unwrap();
+		assert_eq!(err.1, "Bytecode rejected: checksum mismatch");
+	}
+	
+	#[test]
+	fn test_streams() {
+		let program = crate::compiler::Compiler::new(true).compile_program("log(1)\n").unwrap();
+		let mut buffer = vec![];
+		program.write_to(&mut buffer).unwrap();
+		assert_eq!(buffer, program.to_bytes().unwrap());
+		let loaded = Program::from_reader(std::io::Cursor::new(buffer)).unwrap();
+		assert_eq!(loaded.chunk_count(), program.chunk_count());
+		assert!(Program::from_file("does/not/exist.hsyc").is_err());
+	}
+	
+	#[test]
+	fn test_lazy_loading() {
+		let src = "let f() -> Int:\n\treturn 1\nif f() == 2:\n\tlet g():\n\t\tlog(1)\n";
+		let program = crate::compiler::Compiler::new(false).compile_program(src).unwrap();
+		let code_size = program.chunks[2].get().unwrap().code.len();
+		let mut bytes = program.to_bytes().unwrap();
+		let loaded = Program::from_bytes(&bytes).unwrap();
+		assert!(loaded.chunks[0].decoded.get().is_some() && loaded.chunks[1].decoded.get().is_none());
+		assert_eq!(loaded.to_bytes().unwrap(), bytes);
+		assert!(loaded.chunks[2].decoded.get().is_none());
+		
+		// g is never created, so its invalid instruction is only noticed when decoding everything
+		let len = bytes.len();
+		bytes[len - code_size] = 0xff;
+		let corrupted = Program::from_bytes(&bytes).unwrap();
+		crate::vm::run_program(&mut GCHeap::new(), &corrupted).unwrap();
+		assert!(corrupted.decode_all().is_err());
+	}
+}
@@ -2,21 +2,31 @@
 pub(crate) mod chunk;
 #[macro_use]
 pub(crate) mod types;
+/// Ahead-of-time compilation of Hissy programs into standalone Rust sources.
+pub mod aot;
+/// Resolving the bindings in Hissy code for editor features: completion, references and renaming.
+pub mod analysis;
+mod partial;
+mod modules;
 
 
-pub use chunk::Program;
+pub use chunk::{Program, ChunkMetadata};
 pub use types::{Type, PrimitiveType};
+pub use modules::ModuleResolver;
 
 use std::ops::{Deref, DerefMut};
-use std::cmp::Reverse;
 use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::iter;
+use std::fmt;
 
 use crate::{HissyError, ErrorType};
-use crate::serial::write_u16;
-use crate::parser::{parse, ast, ast::*};
-use crate::vm::{MAX_REGISTERS, InstrType, prelude};
-use chunk::{Chunk, ChunkConstant};
+use crate::parser::{parse_with, check_depth, ast, ast::*, lexer::BlockStyle};
+use crate::vm::{VM, MAX_REGISTERS, Instr, Encoding, prelude};
+use crate::vm::gc::{GCHeap, GCRef};
+use crate::vm::object::List;
+use crate::vm::value::Value;
+use chunk::{Chunk, ChunkConstant, LazyChunk};
 
 
 
@@ -27,23 +37,101 @@ fn error_str(s: &str) -> HissyError {
 	error(String::from(s))
 }
 
+/// A problem in Hissy code which does not prevent its compilation, such as a `match` on an enum
+/// which does not handle all of its variants. Holds a message and a line number.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Warning(pub String, pub u16);
+
+impl fmt::Display for Warning {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let Warning(s, line) = self;
+		write!(f, "{}Warning at line {}:{} {}", crate::YELLOW, line, crate::RESET, s)
+	}
+}
+
+// Explains how an operation was parsed, if its shape suggests the precedence of its operators was
+// misunderstood; parentheses are not kept in the AST, so this also fires if they were written explicitly
+fn precedence_warning(expr: &Expr) -> Option<String> {
+	match expr {
+		Expr::UnaOp(UnaOp::Not, e) => match &**e {
+			Expr::BinOp(op @ BinOp::Equal, _, _) | Expr::BinOp(op @ BinOp::NEq, _, _) => {
+				let negated = if *op == BinOp::Equal { "!=" } else { "==" };
+				Some(format!("'not a {} b' is parsed as 'not (a {} b)'; write 'a {} b', or '(not a) {} b' to compare a negated value",
+					op.symbol(), op.symbol(), negated, op.symbol()))
+			},
+			_ => None,
+		},
+		Expr::UnaOp(UnaOp::Minus, e) => match &**e {
+			Expr::BinOp(BinOp::Power, _, _) =>
+				Some(String::from("'-a ^ b' is parsed as '-(a ^ b)'; write '(-a) ^ b' to raise a negative number to a power")),
+			_ => None,
+		},
+		Expr::BinOp(op2, e, _) if op2.is_comparison() => match &**e {
+			Expr::BinOp(op1, _, _) if op1.is_comparison() =>
+				Some(format!("'a {} b {} c' is parsed as '(a {} b) {} c', which compares a Bool; write 'a {} b and b {} c'",
+					op1.symbol(), op2.symbol(), op1.symbol(), op2.symbol(), op1.symbol(), op2.symbol())),
+			_ => None,
+		},
+		_ => None,
+	}
+}
+
+// A compiled operand: the register holding it, and its type
+type Operand = (u8, Type);
+
+// Estimates the number of temporary registers needed to compute an expression which only reads
+// values (see reorderable), as in Sethi-Ullman numbering: locals and constants need none
+fn register_need(expr: &Expr) -> u16 {
+	// Peak usage when computing b while holding the result of a
+	fn then(a: u16, b: u16) -> u16 {
+		a.max(u16::from(a > 0) + b).max(1)
+	}
+	match expr {
+		Expr::BinOp(op, a, b) if op.is_commutative() => {
+			let (a, b) = (register_need(a), register_need(b));
+			then(a.max(b), a.min(b))
+		},
+		Expr::BinOp(_, a, b) | Expr::Index(a, b) => then(register_need(a), register_need(b)),
+		Expr::UnaOp(_, a) | Expr::Prop(a, _) => register_need(a).max(1),
+		Expr::Chain(first, rest) => rest.iter().fold(register_need(first), |need, (_, e)| then(need, register_need(e))),
+		_ => 0,
+	}
+}
+
+// Whether computing an expression has no effect besides its result (and possibly an error),
+// so that it can be computed earlier or later
+fn reorderable(expr: &Expr) -> bool {
+	match expr {
+		Expr::Nil | Expr::Bool(_) | Expr::Int(_) | Expr::Real(_) | Expr::Char(_) | Expr::String(_) | Expr::Symbol(_) | Expr::Id(_) => true,
+		Expr::BinOp(_, a, b) | Expr::Index(a, b) => reorderable(a) && reorderable(b),
+		Expr::UnaOp(_, a) | Expr::Prop(a, _) => reorderable(a),
+		Expr::Chain(first, rest) => reorderable(first) && rest.iter().all(|(_, e)| reorderable(e)),
+		_ => false,
+	}
+}
+
+
+// Relative address from the jump instruction at instr_pos to add
+// (relative addresses are based on the address byte, which follows the opcode)
+fn rel_jump(chunk: &Chunk, instr_pos: usize, add: usize) -> Result<i16, HissyError> {
+	let rel_jmp = add as isize - (instr_pos + 1) as isize;
+	let (min, max) = chunk.encoding.max_jump();
+	if rel_jmp < isize::from(min) || rel_jmp > isize::from(max) {
+		return Err(error_str("Jump too large"));
+	}
+	Ok(rel_jmp as i16)
+}
 
 fn emit_jump_to(chunk: &mut Chunk, add: usize) -> Result<(), HissyError> {
-	let from = chunk.code.len();
-	let to = add;
-	let rel_jmp = to as isize - from as isize;
-	let rel_jmp = i8::try_from(rel_jmp).map_err(|_| error_str("Jump too large"))?;
-	chunk.emit_byte(rel_jmp as u8);
+	let rel = rel_jump(chunk, chunk.code.len(), add)?;
+	chunk.emit(Instr::Jmp { rel });
 	Ok(())
 }
 
-fn fill_in_jump_from(chunk: &mut Chunk, add: usize) -> Result<(), HissyError> {
-	let from = add;
-	let to = chunk.code.len();
-	let rel_jmp = to as isize - from as isize;
-	let rel_jmp = i8::try_from(rel_jmp).map_err(|_| error_str("Jump too large"))?;
-	chunk.code[add] = rel_jmp as u8;
-	Ok(())
+// Patches the jump instruction at instr_pos to jump to the current position
+fn fill_in_jump_from(chunk: &mut Chunk, instr_pos: usize) -> Result<(), HissyError> {
+	let rel = rel_jump(chunk, instr_pos, chunk.code.len())?;
+	chunk.patch_jump(instr_pos, rel)
 }
 
 struct ChunkRegisters {
@@ -87,6 +175,10 @@ impl ChunkRegisters {
 		self.local_cnt += 1;
 	}
 	
+	pub fn is_local(&self, i: u8) -> bool {
+		u16::from(i) < self.local_cnt
+	}
+	
 	// Marks register as freed
 	pub fn free_reg(&mut self, i: u8) {
 		assert!(u16::from(i) == self.used - 1, "Registers are not freed in FIFO order: {}, {}", i, self.used);
@@ -133,10 +225,13 @@ struct Local {
 	closed_over: bool,
 }
 
-type BlockContext = HashMap<String, Local>;
+type BlockContext = HashMap<Symbol, Local>;
+
+// Names and types of the top-level variables of a session
+pub(crate) type SessionBindings = Vec<(Symbol, Type)>;
 
 struct UpvalueBinding {
-	name: String,
+	name: Symbol,
 	reg: u8,
 	ty: Type,
 }
@@ -144,8 +239,10 @@ struct UpvalueBinding {
 struct ChunkContext {
 	regs: ChunkRegisters,
 	blocks: Vec<BlockContext>,
+	defers: Vec<Vec<u8>>, // Registers holding the closures deferred in each block
 	upvalues: Vec<UpvalueBinding>,
 	ret_ty: Type,
+	returned: Option<Type>, // Most precise type of the values returned so far
 }
 
 impl ChunkContext {
@@ -153,80 +250,94 @@ impl ChunkContext {
 		ChunkContext {
 			regs: ChunkRegisters::new(),
 			blocks: Vec::new(),
+			defers: Vec::new(),
 			upvalues: Vec::new(),
 			ret_ty,
+			returned: None,
 		}
 	}
 	
+	fn add_return(&mut self, ty: Type) {
+		self.returned = Some(match self.returned.take() {
+			None => ty,
+			Some(prev) if prev.can_assign(&ty) => prev,
+			Some(prev) if ty.can_assign(&prev) => ty,
+			Some(_) => Type::Any,
+		});
+	}
+	
 	fn enter_block(&mut self) {
 		self.blocks.push(BlockContext::new());
+		self.defers.push(Vec::new());
 	}
 	
+	// Since loop bodies are blocks, their locals are closed at the end of every iteration,
+	// so closures created in a loop capture a fresh binding for each iteration.
 	fn leave_block(&mut self, chunk: &mut Chunk) {
-		let to_close: Vec<u8> = self.blocks.last().unwrap().values()
-			.filter_map(|l| if l.closed_over { Some(l.reg) } else { None }).collect();
-		for reg in to_close {
-			chunk.emit_instr(InstrType::CloseUp);
-			chunk.emit_byte(reg);
+		self.defers.pop();
+		let mut locals: Vec<Local> = self.blocks.pop().unwrap().into_values().collect();
+		locals.sort_by_key(|l| l.reg);
+		for l in locals.iter().filter(|l| l.closed_over) {
+			chunk.emit(Instr::CloseUp { reg: l.reg });
 		}
-		
-		let mut to_free: Vec<u8> = self.blocks.last().unwrap().values().map(|l| l.reg).collect();
-		to_free.sort_by_key(|&x| Reverse(x));
-		for reg in to_free {
-			self.regs.free_reg(reg);
+		let end = u16::try_from(chunk.code.len()).unwrap_or(u16::MAX);
+		for l in locals.iter().rev() {
+			chunk.debug_info.end_local(l.reg, end);
+			self.regs.free_reg(l.reg);
 		}
-		self.blocks.pop();
 	}
 	
-	fn find_block_local(&self, id: &str) -> Option<Local> {
-		self.blocks.last().unwrap().get(id).cloned()
+	fn find_block_local(&self, id: Symbol) -> Option<Local> {
+		self.blocks.last().unwrap().get(&id).cloned()
 	}
 	
-	fn find_chunk_binding(&self, id: &str) -> Option<Binding> {
-		for ctx in self.blocks.iter().rev() {
-			if let Some(local) = ctx.get(id).cloned() {
-				return Some(Binding::Local(local.reg, local.ty));
-			}
-		}
-		if let Some((i,u)) = self.upvalues.iter().enumerate().find(|(_,u)| u.name == id) {
-			return Some(Binding::Upvalue(u8::try_from(i).unwrap(), u.ty.clone()));
+	// Redefinitions in the same block reuse the register of the previous binding
+	fn make_local(&mut self, id: Symbol, reg: u8, ty: Type) {
+		let prev = self.blocks.last_mut().unwrap().insert(id, Local { reg, ty, closed_over: false });
+		match prev {
+			Some(prev) if prev.reg == reg => self.blocks.last_mut().unwrap().get_mut(&id).unwrap().closed_over = prev.closed_over,
+			_ => self.regs.make_local(reg),
 		}
-		None
 	}
 	
-	fn make_local(&mut self, id: String, reg: u8, ty: Type) {
-		self.blocks.last_mut().unwrap().insert(id, Local { reg, ty, closed_over: false });
-		self.regs.make_local(reg);
-	}
-	
-	fn make_upvalue(&mut self, id: String, reg: u8, ty: Type) -> Result<u8, HissyError> {
+	fn make_upvalue(&mut self, id: Symbol, reg: u8, ty: Type) -> Result<u8, HissyError> {
 		let upv = u8::try_from(self.upvalues.len()).map_err(|_| error_str("Too many upvalues in chunk"));
 		self.upvalues.push(UpvalueBinding { name: id, reg, ty });
 		upv
 	}
-	
-	fn close_over(&mut self, id: &str) {
-		for ctx in self.blocks.iter_mut().rev() {
-			if let Some(local) = ctx.get_mut(id) {
-				local.closed_over = true;
-				return;
-			}
-		}
-		panic!("Trying to close over unknown local binding {}", id);
-	}
 }
 
 
+// Where a name is bound in a chunk
+#[derive(Clone, Copy, PartialEq)]
+enum Scope {
+	Block(usize),
+	Upvalue(u8),
+}
+
 struct Context {
 	stack: Vec<ChunkContext>,
-	external: Vec<(String, Type)>,
+	external: Vec<(Symbol, Type)>,
+	external_idx: HashMap<Symbol, u16>,
+	// The chunks and scopes in which each name is bound, innermost last, so that bindings can be found
+	// without going through every block of every chunk. Since inner chunks are compiled while
+	// the outer ones are suspended, the bindings of a name are always sorted by chunk.
+	scopes: HashMap<Symbol, Vec<(usize, Scope)>>,
+	enums: HashMap<Symbol, Vec<Symbol>>, // Variants of each enum, visible after its declaration
 }
 
 impl Context {
 	pub fn new() -> Context {
+		let external: Vec<(Symbol, Type)> = prelude::list().into_iter().map(|(id, ty)| (Symbol::intern(&id), ty)).collect();
+		let external_idx = external.iter().enumerate()
+			.map(|(i, (id, _))| (*id, u16::try_from(i).expect("External index is too high")))
+			.collect();
 		Context {
 			stack: Vec::new(),
-			external: prelude::list(),
+			external,
+			external_idx,
+			scopes: HashMap::new(),
+			enums: HashMap::new(),
 		}
 	}
 	
@@ -234,44 +345,150 @@ impl Context {
 		self.stack.push(ChunkContext::new(ret_ty));
 	}
 	
-	fn leave(&mut self) {
-		self.stack.pop().expect("Cannot leave main chunk");
+	// Makes an unknown name refer to a new external value of unknown type
+	fn add_external(&mut self, id: Symbol) -> Result<Binding, HissyError> {
+		let ext_idx = u16::try_from(self.external.len()).map_err(|_| error_str("Too many external values"))?;
+		self.external.push((id, Type::Any));
+		self.external_idx.insert(id, ext_idx);
+		Ok(Binding::External(ext_idx, Type::Any))
+	}
+	
+	// Returns the type of the values returned by the chunk
+	fn leave(&mut self) -> Type {
+		let ctx = self.stack.pop().expect("Cannot leave main chunk");
+		for upv in &ctx.upvalues {
+			self.unbind(upv.name);
+		}
+		ctx.returned.unwrap_or(prim_ty!(Nil))
+	}
+	
+	fn leave_block(&mut self, chunk: &mut Chunk) {
+		let names: Vec<Symbol> = self.blocks.last().unwrap().keys().copied().collect();
+		for id in names {
+			self.unbind(id);
+		}
+		self.stack.last_mut().unwrap().leave_block(chunk);
+	}
+	
+	fn bind(&mut self, id: Symbol, depth: usize, scope: Scope) {
+		let scopes = self.scopes.entry(id).or_default();
+		if scopes.last() != Some(&(depth, scope)) { // Redefinitions in the same block reuse the binding
+			scopes.push((depth, scope));
+		}
+	}
+	
+	fn unbind(&mut self, id: Symbol) {
+		let scopes = self.scopes.get_mut(&id).unwrap();
+		scopes.pop();
+		if scopes.is_empty() {
+			self.scopes.remove(&id);
+		}
+	}
+	
+	fn make_local(&mut self, id: Symbol, reg: u8, ty: Type) {
+		let depth = self.stack.len() - 1;
+		self.bind(id, depth, Scope::Block(self.blocks.len() - 1));
+		self.stack[depth].make_local(id, reg, ty);
+	}
+	
+	// Adds an upvalue to the chunk at the given depth in the stack
+	fn add_upvalue(&mut self, depth: usize, id: Symbol, reg: u8, ty: Type) -> Result<u8, HissyError> {
+		let upv = self.stack[depth].make_upvalue(id, reg, ty)?;
+		self.bind(id, depth, Scope::Upvalue(upv));
+		Ok(upv)
 	}
 	
-	fn get_binding(&mut self, id: &str) -> Result<Option<Binding>, HissyError> {
-		// Find a binding (local or known upvalue) in current chunk, otherwise...
-		if let Some(binding) = self.find_chunk_binding(id) {
-			Ok(Some(binding))
+	fn resolve_type(&self, ty: &ast::Type) -> Result<Type, HissyError> {
+		match ty {
+			ast::Type::Named(name) => {
+				match name.deref() {
+					"Any" => Ok(Type::Any),
+					"Nil" => Ok(prim_ty!(Nil)),
+					"Bool" => Ok(prim_ty!(Bool)),
+					"Int" => Ok(prim_ty!(Int)),
+					"Real" => Ok(prim_ty!(Real)),
+					"Char" => Ok(prim_ty!(Char)),
+					"String" => Ok(prim_ty!(String)),
+					"Symbol" => Ok(prim_ty!(Symbol)),
+					"Vec2" => Ok(Type::Vector(2)),
+					"Vec3" => Ok(Type::Vector(3)),
+					"Channel" => Ok(Type::Channel),
+					"Bytes" => Ok(Type::Bytes),
+					#[cfg(feature = "tensor")]
+					"Tensor" => Ok(Type::Tensor),
+					#[cfg(feature = "net")]
+					"Connection" => Ok(Type::Connection),
+					_ if self.enums.contains_key(name) => Ok(Type::Enum(*name)),
+					_ => Err(error(format!("Unknown type name '{}'", name)))
+				}
+			},
+			ast::Type::Function(args, res) => {
+				let args: Result<Vec<Type>, HissyError> = args.iter().map(|t| self.resolve_type(t)).collect();
+				Ok(Type::TypedFunction(args?, Box::new(self.resolve_type(res)?)))
+			},
+		}
+	}
+	
+	fn resolve_function_type(&self, args: &[(Symbol, ast::Type)], res_ty: &ast::Type) -> Result<Type, HissyError> {
+		let args_ty: Result<Vec<Type>, HissyError> = args.iter().map(|(_,t)| self.resolve_type(t)).collect();
+		let args_ty = args_ty?;
+		let res_ty = self.resolve_type(res_ty)?;
+		Ok(Type::TypedFunction(args_ty, Box::new(res_ty)))
+	}
+	
+	fn get_binding(&mut self, id: Symbol) -> Result<Option<Binding>, HissyError> {
+		// Find the innermost local or known upvalue, otherwise look for an external value
+		let (i, scope) = if let Some(&innermost) = self.scopes.get(&id).and_then(|scopes| scopes.last()) {
+			innermost
 		} else {
-			// Look for a binding in surrounding chunks, and if found...
-			let binding = self.stack.iter().enumerate().rev().skip(1).find_map(|(i, ctx)| {
-				ctx.find_chunk_binding(id).map(|b| (i, b))
-			});
-			if let Some((i, mut binding)) = binding {
-				if let Binding::Local(_,_) = binding {
-					self.stack[i].close_over(id);
+			return Ok(self.external_idx.get(&id).map(|&ext_idx| {
+				Binding::External(ext_idx, self.external[usize::from(ext_idx)].1.clone())
+			}));
+		};
+		let depth = self.stack.len() - 1;
+		let mut binding = match scope {
+			Scope::Block(block) => {
+				let local = self.stack[i].blocks[block].get_mut(&id).unwrap();
+				if i < depth {
+					local.closed_over = true;
 				}
-				
-				// Set it as an upvalue in all inner chunks successively.
-				for ctx in self.stack[i+1..].iter_mut() {
-					let (encoded, ty) = match binding {
-						Binding::Local(reg, ty) => (reg, ty),
-						Binding::Upvalue(upv, ty) => (upv + MAX_REGISTERS, ty),
-						_ => unreachable!(),
-					};
-					// Note: registers 128-255 correspond to constants in bytecode,
-					// but correspond to upvalues in the parent chunk in upvalue tables.
-					let upv = ctx.make_upvalue(id.to_string(), encoded, ty.clone())?;
-					binding = Binding::Upvalue(upv, ty);
+				Binding::Local(local.reg, local.ty.clone())
+			},
+			Scope::Upvalue(upv) => Binding::Upvalue(upv, self.stack[i].upvalues[usize::from(upv)].ty.clone()),
+		};
+		
+		// If it is in a surrounding chunk, set it as an upvalue in all inner chunks successively.
+		for j in i+1..=depth {
+			let (encoded, ty) = match binding {
+				Binding::Local(reg, ty) => (reg, ty),
+				Binding::Upvalue(upv, ty) => (upv + MAX_REGISTERS, ty),
+				_ => unreachable!(),
+			};
+			// Note: registers 128-255 correspond to constants in bytecode,
+			// but correspond to upvalues in the parent chunk in upvalue tables.
+			let upv = self.add_upvalue(j, id, encoded, ty.clone())?;
+			binding = Binding::Upvalue(upv, ty);
+		}
+		Ok(Some(binding))
+	}
+	
+	// Returns the chunk depth and register of the local a binding refers to, following upvalues
+	fn defining_local(&self, binding: &Binding) -> Option<(usize, u8)> {
+		let mut depth = self.stack.len() - 1;
+		match binding {
+			Binding::Local(reg, _) => Some((depth, *reg)),
+			Binding::Upvalue(upv, _) => {
+				let mut upv = *upv;
+				loop {
+					let reg = self.stack[depth].upvalues[usize::from(upv)].reg;
+					depth -= 1;
+					if reg < MAX_REGISTERS {
+						break Some((depth, reg));
+					}
+					upv = reg - MAX_REGISTERS;
 				}
-				Ok(Some(binding))
-			} else if let Some(ext_idx) = self.external.iter().position(|(id2, _)| id == id2) {
-				let ty = self.external[ext_idx].1.clone();
-				let ext_idx = u16::try_from(ext_idx).expect("External index is too high");
-				Ok(Some(Binding::External(ext_idx, ty)))
-			} else {
-				Ok(None)
-			}
+			},
+			Binding::External(_, _) => None,
 		}
 	}
 }
@@ -294,16 +511,17 @@ impl DerefMut for Context {
 struct ChunkManager {
 	chunks: Vec<Chunk>,
 	stack: Vec<usize>,
+	encoding: Encoding,
 }
 
 impl ChunkManager {
 	fn new() -> ChunkManager {
-		ChunkManager { chunks: vec![], stack: vec![] }
+		ChunkManager { chunks: vec![], stack: vec![], encoding: Encoding::Compact }
 	}
 	
 	fn enter(&mut self) -> usize {
 		let idx = self.chunks.len();
-		self.chunks.push(Chunk::new());
+		self.chunks.push(Chunk::new(self.encoding));
 		self.stack.push(idx);
 		idx
 	}
@@ -311,8 +529,8 @@ impl ChunkManager {
 		self.stack.pop().unwrap();
 	}
 	
-	fn finish(self) -> Vec<Chunk> {
-		self.chunks
+	fn finish(self) -> Vec<LazyChunk> {
+		self.chunks.into_iter().map(LazyChunk::from).collect()
 	}
 }
 
@@ -330,34 +548,6 @@ impl DerefMut for ChunkManager {
 }
 
 
-fn resolve_type(ty: &ast::Type) -> Result<Type, HissyError> {
-	match ty {
-		ast::Type::Named(name) => {
-			match name.deref() {
-				"Any" => Ok(Type::Any),
-				"Nil" => Ok(prim_ty!(Nil)),
-				"Bool" => Ok(prim_ty!(Bool)),
-				"Int" => Ok(prim_ty!(Int)),
-				"Real" => Ok(prim_ty!(Real)),
-				"String" => Ok(prim_ty!(String)),
-				_ => Err(error(format!("Unknown type name '{}'", name)))
-			}
-		},
-		ast::Type::Function(args, res) => {
-			let args: Result<Vec<Type>, HissyError> = args.iter().map(resolve_type).collect();
-			Ok(Type::TypedFunction(args?, Box::new(resolve_type(res)?)))
-		},
-	}
-}
-
-fn resolve_function_type(args: &[(String, ast::Type)], res_ty: &ast::Type) -> Result<Type, HissyError> {
-	let args_ty: Result<Vec<Type>, HissyError> = args.iter().map(|(_,t)| Ok(resolve_type(t)?)).collect();
-	let args_ty = args_ty?;
-	let res_ty = resolve_type(res_ty)?;
-	Ok(Type::TypedFunction(args_ty, Box::new(res_ty)))
-}
-
-
 fn can_reach_end(block: &Block) -> bool {
 	for Positioned(stat, _) in block {
 		match stat {
@@ -368,6 +558,8 @@ fn can_reach_end(block: &Block) -> bool {
 					}
 				}
 			},
+			Stat::Match(_, arms, Some(else_block))
+				if arms.iter().all(|(_, block2)| !can_reach_end(block2)) && !can_reach_end(else_block) => return false,
 			Stat::Return(_) => return false,
 			_ => {},
 		}
@@ -381,11 +573,119 @@ enum ObjectProp {
 }
 
 
+// Checks the type of a value passed by the host, which the compiler cannot check
+fn has_type(val: &Value, ty: &Type) -> bool {
+	let name = val.type_name();
+	match ty {
+		Type::Primitive(pt) => name == format!("{:?}", pt),
+		Type::Vector(n) => name == format!("Vec{}", n),
+		Type::Tensor => name == "Tensor",
+		Type::Channel => name == "Channel",
+		Type::Bytes => name == "Bytes",
+		Type::Connection => name == "Connection",
+		Type::List(el_ty) => GCRef::<List>::try_from(val.clone())
+			.is_ok_and(|list| list.get_copy().iter().all(|el| has_type(el, el_ty))),
+		Type::Iterator(_) => name == "Iterator",
+		Type::TypedFunction(_, _) | Type::UntypedFunction(_) => matches!(name, "Function" | "NativeFunction" | "Method"),
+		Type::Namespace(_) => name == "Namespace",
+		Type::Enum(_) => name == "Symbol",
+		Type::Any => true,
+	}
+}
+
+// Infers the type of a value passed by the host; lists get the type of their elements if they all have the same
+pub(crate) fn value_type(val: &Value) -> Type {
+	match val.type_name() {
+		"Nil" => prim_ty!(Nil),
+		"Bool" => prim_ty!(Bool),
+		"Int" => prim_ty!(Int),
+		"Real" => prim_ty!(Real),
+		"Char" => prim_ty!(Char),
+		"String" => prim_ty!(String),
+		"Symbol" => prim_ty!(Symbol),
+		"Vec2" => Type::Vector(2),
+		"Vec3" => Type::Vector(3),
+		"Tensor" => Type::Tensor,
+		"Channel" => Type::Channel,
+		"Bytes" => Type::Bytes,
+		"Connection" => Type::Connection,
+		"List" => {
+			let values = GCRef::<List>::try_from(val.clone()).unwrap().get_copy();
+			let mut types = values.iter().map(value_type);
+			let el_ty = types.next().filter(|first| types.all(|ty| ty == *first));
+			Type::List(Box::new(el_ty.unwrap_or(Type::Any)))
+		},
+		"Iterator" => Type::Iterator(Box::new(Type::Any)),
+		"Function" | "NativeFunction" | "Method" => Type::UntypedFunction(Box::new(Type::Any)),
+		_ => Type::Any,
+	}
+}
+
+/// A function compiled from a snippet of Hissy code, which a host can call like a closure.
+#[derive(Clone)]
+pub struct CompiledFunction {
+	program: Program,
+	params: Vec<(String, Type)>,
+	ret_ty: Type,
+}
+
+impl CompiledFunction {
+	/// Returns the program executing the function, whose main chunk takes the parameters as arguments.
+	pub fn program(&self) -> &Program {
+		&self.program
+	}
+	
+	/// Returns the names and types of the parameters of the function.
+	pub fn params(&self) -> &[(String, Type)] {
+		&self.params
+	}
+	
+	/// Returns the type of the values returned by the function, as inferred by the type checker
+	/// (`Any` if it returns values of unrelated types).
+	pub fn ret_type(&self) -> &Type {
+		&self.ret_ty
+	}
+	
+	/// Calls the function with the given arguments, and returns its result.
+	///
+	/// Fails if the arguments do not match the parameters, or if the function waits on a pending operation;
+	/// use [`VM::with_args`] to handle these.
+	pub fn call(&self, heap: &mut GCHeap, args: Vec<Value>) -> Result<Value, HissyError> {
+		if args.len() != self.params.len() {
+			return Err(HissyError(ErrorType::Execution,
+				format!("Expected {} arguments in function call, got {}", self.params.len(), args.len()), 0));
+		}
+		if let Some(((id, ty), arg)) = self.params.iter().zip(&args).find(|((_, ty), arg)| !has_type(arg, ty)) {
+			return Err(HissyError(ErrorType::Execution,
+				format!("Expected argument '{}' of type {:?}, got {}", id, ty, arg.type_name()), 0));
+		}
+		let mut vm = VM::with_args(heap, self.program.clone(), args);
+		vm.run(heap)?;
+		if vm.is_suspended() {
+			return Err(HissyError(ErrorType::Execution, String::from("Function is waiting on a pending operation"), 0));
+		}
+		let res = vm.result().clone();
+		drop(vm);
+		heap.collect();
+		Ok(res)
+	}
+}
+
+
 /// A struct holding state necessary to compilation.
 pub struct Compiler {
 	debug_info: bool,
+	embed_source: bool,
+	block_style: BlockStyle,
+	partial_eval: bool,
 	ctx: Context,
 	chunk: ChunkManager,
+	probe: Option<analysis::Probe>,
+	warnings: Vec<Warning>,
+	line: u16, // Line of the statement being compiled, for warnings
+	deprecated: HashMap<(usize, u8), String>, // Message of each deprecated function, by chunk depth and register
+	resolver: Option<Box<dyn ModuleResolver>>,
+	untrusted: bool,
 }
 
 impl Compiler {
@@ -393,25 +693,126 @@ impl Compiler {
 	pub fn new(debug_info: bool) -> Compiler {
 		Compiler {
 			debug_info,
+			embed_source: false,
+			block_style: BlockStyle::Indentation,
+			partial_eval: false,
 			ctx: Context::new(),
 			chunk: ChunkManager::new(),
+			probe: None,
+			warnings: Vec::new(),
+			line: 0,
+			deprecated: HashMap::new(),
+			resolver: None,
+			untrusted: false,
+		}
+	}
+	
+	/// Sets the instruction encoding of the compiled bytecode (compact by default).
+	pub fn set_encoding(&mut self, encoding: Encoding) {
+		self.chunk.encoding = encoding;
+	}
+	
+	/// Sets whether the source code is embedded in the compiled program, alongside debug info (no by default).
+	pub fn set_embed_source(&mut self, embed_source: bool) {
+		self.embed_source = embed_source;
+	}
+	
+	/// Sets how blocks are delimited in the source code (by indentation by default).
+	pub fn set_block_style(&mut self, style: BlockStyle) {
+		self.block_style = style;
+	}
+	
+	/// Sets whether the statements at the start of the program which only compute values from literals,
+	/// such as tables of constants, are run at compile time, to reduce the startup time (no by default).
+	/// 
+	/// The resulting bindings are then defined directly from literals, in place of these statements.
+	pub fn set_partial_eval(&mut self, partial_eval: bool) {
+		self.partial_eval = partial_eval;
+	}
+	
+	/// Sets how the modules imported by `import` statements are found (by default, programs cannot import modules).
+	pub fn set_resolver(&mut self, resolver: impl ModuleResolver + 'static) {
+		self.resolver = Some(Box::new(resolver));
+	}
+	
+	/// Sets whether the source code is untrusted (no by default), in which case `import` statements and
+	/// `embed()` expressions are refused even if a resolver is set, so that the program cannot read files of the host.
+	/// 
+	/// Untrusted programs should also be run in a [`Sandbox::strict`](crate::vm::sandbox::Sandbox::strict).
+	pub fn set_untrusted(&mut self, untrusted: bool) {
+		self.untrusted = untrusted;
+	}
+	
+	// Parses a program, including the modules it imports
+	fn parse(&mut self, input: &str) -> Result<ProgramAST, HissyError> {
+		let ast = parse_with(input, self.block_style)?;
+		self.expand_imports(ast)
+	}
+	
+	fn expand_imports(&mut self, ast: ProgramAST) -> Result<ProgramAST, HissyError> {
+		let mut untrusted = modules::UntrustedResolver;
+		let resolver = if self.untrusted {
+			Some(&mut untrusted as &mut dyn ModuleResolver)
+		} else {
+			self.resolver.as_mut().map(|resolver| resolver.as_mut() as &mut dyn ModuleResolver)
+		};
+		modules::expand_imports(ast, resolver, self.block_style)
+	}
+	
+	// The following wrap binding operations on the context, to let the probe (if any) track
+	// which definition each identifier refers to.
+	
+	// Called where a binding appears in the source code, before it is made local
+	fn declare(&mut self, id: Symbol) {
+		if let Some(probe) = &mut self.probe {
+			probe.declare(id);
+		}
+	}
+	
+	fn make_local(&mut self, id: Symbol, reg: u8, ty: Type) {
+		if let Some(probe) = &mut self.probe {
+			probe.define(&self.ctx, id, reg);
+		}
+		self.deprecated.remove(&(self.ctx.stack.len() - 1, reg));
+		// Hidden locals, whose names cannot be written in code, are left out of the debug info
+		if self.debug_info && !id.starts_with('<') {
+			let pos = u16::try_from(self.chunk.code.len()).unwrap_or(u16::MAX);
+			self.chunk.debug_info.start_local(reg, pos, String::from(id));
+		}
+		self.ctx.make_local(id, reg, ty);
+	}
+	
+	fn get_binding(&mut self, id: Symbol) -> Result<Option<Binding>, HissyError> {
+		let mut binding = self.ctx.get_binding(id)?;
+		// Modules are not resolved during analysis, so unknown names may be declared by imported modules
+		if binding.is_none() && self.probe.as_ref().is_some_and(|probe| probe.imported) {
+			binding = Some(self.ctx.add_external(id)?);
+		}
+		if let (Some(probe), Some(binding)) = (&mut self.probe, &binding) {
+			probe.resolve(&self.ctx, id, binding);
+		}
+		let local = binding.as_ref().and_then(|binding| self.ctx.defining_local(binding));
+		if let Some(msg) = local.and_then(|local| self.deprecated.get(&local)) {
+			let msg = if msg.is_empty() {
+				format!("Use of deprecated function '{}'", id)
+			} else {
+				format!("Use of deprecated function '{}': {}", id, msg)
+			};
+			self.warnings.push(Warning(msg, self.line));
 		}
+		Ok(binding)
 	}
 	
-	// Emits register to chunk; dest if Some, else new_reg()
-	fn emit_reg(&mut self, dest: Option<u8>) -> Result<u8, HissyError> {
-		let reg = dest.map_or_else(|| self.ctx.regs.new_reg(), Ok)?;
-		self.chunk.emit_byte(reg);
-		Ok(reg)
+	// Returns the destination register of an instruction; dest if Some, else new_reg()
+	fn dest_reg(&mut self, dest: Option<u8>) -> Result<u8, HissyError> {
+		dest.map_or_else(|| self.ctx.regs.new_reg(), Ok)
 	}
 	
 	fn find_method(&self, ty: Type, prop: &str) -> Result<Option<(u16, u8, Type)>, HissyError> {
 		let ns_name = if let Some(ns_name) = ty.get_method_namespace() { ns_name }
 			else { return Ok(None); };
-		let ns_idx = if let Some(ns_idx) = self.ctx.external.iter().position(|(id, _)| id == &ns_name) { ns_idx }
+		let ns_idx = if let Some(ns_idx) = self.ctx.external_idx.get(&Symbol::intern(&ns_name)) { *ns_idx }
 			else { return Ok(None); };
-		let ns_idx = u16::try_from(ns_idx)
-			.map_err(|_| error(format!("Too many externals")))?;
 		let props = if let Type::Namespace(props) = &self.ctx.external[ns_idx as usize].1 { props }
 			else { return Err(error(format!("Namespace name {} for type {:?} is assigned to a non-namespace", ns_name, ty))); };
 		let prop_idx = if let Some(prop_idx) = props.iter().position(|(id, _)| id == prop) { prop_idx }
@@ -422,6 +823,14 @@ impl Compiler {
 		Ok(Some((ns_idx, prop_idx, prop_ty)))
 	}
 	
+	// Returns the enum named by an expression, unless the name is shadowed by a local
+	fn find_enum(&self, e: &Expr) -> Option<Symbol> {
+		match e {
+			Expr::Id(id) if !self.ctx.scopes.contains_key(id) && self.ctx.enums.contains_key(id) => Some(*id),
+			_ => None,
+		}
+	}
+	
 	fn find_prop(&mut self, val: Expr, prop: &str) -> Result<(Type, Option<(u8, ObjectProp)>), HissyError> {
 		let (val, ty) = self.compile_expr(val, None, None)?;
 		
@@ -440,6 +849,8 @@ impl Compiler {
 				(Some(args_ty), res_ty)
 			},
 			Type::UntypedFunction(res_ty) => (None, res_ty),
+			// During analysis, bindings from imported modules have unknown types, but may be functions
+			Type::Any if self.probe.as_ref().is_some_and(|probe| probe.imported) => (None, Box::new(Type::Any)),
 			_ => return Err(error(format!("Cannot call non-function type {:?}", fun_ty))),
 		};
 		let n = u8::try_from(args.len()).map_err(|_| error_str("Too many function arguments"))?;
@@ -456,11 +867,86 @@ impl Compiler {
 		Ok((arg_range, n, *res_ty))
 	}
 	
+	// Compiles the operands of a binary operation, and frees their registers; the operands of commutative
+	// operators are computed in the order which uses the fewest registers, when the order does not matter
+	// (the instruction still gets them in order)
+	fn compile_operands(&mut self, op: &BinOp, e1: Expr, e2: Expr) -> Result<(Operand, Operand), HissyError> {
+		if op.is_commutative() && reorderable(&e1) && reorderable(&e2) && register_need(&e2) > register_need(&e1) {
+			let right = self.compile_expr(e2, None, None)?;
+			let left = self.compile_expr(e1, None, None)?;
+			self.ctx.regs.free_temp_reg(left.0);
+			self.ctx.regs.free_temp_reg(right.0);
+			Ok((left, right))
+		} else {
+			let left = self.compile_expr(e1, None, None)?;
+			let right = self.compile_expr(e2, None, None)?;
+			self.ctx.regs.free_temp_reg(right.0);
+			self.ctx.regs.free_temp_reg(left.0);
+			Ok((left, right))
+		}
+	}
+	
+	// Emits a binary operation on two compiled operands, whose registers must already be freed,
+	// into dest if given; returns the destination register and the type of the result
+	fn compile_binop(&mut self, op: BinOp, (r1, t1): Operand, (r2, t2): Operand, dest: Option<u8>) -> Result<Operand, HissyError> {
+		let ty = match op {
+			BinOp::Plus | BinOp::Minus if t1.is_vector() && t1 == t2 => t1,
+			BinOp::Times | BinOp::Divides if t1.is_vector() && (t1 == t2 || t2.is_numeric()) => t1,
+			BinOp::Times if t2.is_vector() && t1.is_numeric() => t2,
+			  BinOp::Plus | BinOp::Minus | BinOp::Times | BinOp::Divides
+			| BinOp::Modulo | BinOp::Power => {
+				if !t1.is_numeric() || !t2.is_numeric() {
+					return Err(error(format!("Cannot use numeric operator on {:?} and {:?}", t1, t2)));
+				}
+				if t1 == prim_ty!(Int) && t2 == prim_ty!(Int) && !matches!(op, BinOp::Divides | BinOp::Power) {
+					prim_ty!(Int)
+				} else {
+					prim_ty!(Real)
+				}
+			},
+			BinOp::LEq | BinOp::GEq | BinOp::Less | BinOp::Greater => {
+				if !t1.is_numeric() || !t2.is_numeric() {
+					return Err(error(format!("Cannot use comparison operator on {:?} and {:?}", t1, t2)));
+				}
+				prim_ty!(Bool)
+			},
+			BinOp::Equal | BinOp::NEq => prim_ty!(Bool),
+			BinOp::And | BinOp::Or => {
+				if t1 != prim_ty!(Bool) || t2 != prim_ty!(Bool) {
+					return Err(error(format!("Cannot compare {:?} and {:?}", t1, t2)));
+				}
+				prim_ty!(Bool)
+			},
+		};
+		let (a, b, dst) = (r1, r2, self.dest_reg(dest)?);
+		self.chunk.emit(match op {
+			BinOp::Plus => Instr::Add { a, b, dst },
+			BinOp::Minus => Instr::Sub { a, b, dst },
+			BinOp::Times => Instr::Mul { a, b, dst },
+			BinOp::Divides => Instr::Div { a, b, dst },
+			BinOp::Modulo => Instr::Mod { a, b, dst },
+			BinOp::Power => Instr::Pow { a, b, dst },
+			BinOp::LEq => Instr::Leq { a, b, dst },
+			BinOp::GEq => Instr::Geq { a, b, dst },
+			BinOp::Less => Instr::Lth { a, b, dst },
+			BinOp::Greater => Instr::Gth { a, b, dst },
+			BinOp::Equal => Instr::Eq { a, b, dst },
+			BinOp::NEq => Instr::Neq { a, b, dst },
+			BinOp::And => Instr::And { a, b, dst },
+			BinOp::Or => Instr::Or { a, b, dst },
+		});
+		Ok((dst, ty))
+	}
+	
 	// Compile computation of expr (into dest if given), and returns final register
 	// Warning: If no dest is given, do not assume the final register is a new, temporary one,
 	// it may be a local or a constant!
+	// name is the name of the function declared by a statement, if expr is one
 	fn compile_expr(&mut self, expr: Expr, dest: Option<u8>, name: Option<String>) -> Result<(u8, Type), HissyError> {
 		let mut needs_copy = true;
+		if let Some(msg) = precedence_warning(&expr) {
+			self.warnings.push(Warning(msg, self.line));
+		}
 		
 		let (mut reg, ty) = match expr {
 			Expr::Nil =>
@@ -471,87 +957,56 @@ impl Compiler {
 				(self.chunk.compile_constant(ChunkConstant::Int(i))?, prim_ty!(Int)),
 			Expr::Real(r) =>
 				(self.chunk.compile_constant(ChunkConstant::Real(r))?, prim_ty!(Real)),
+			Expr::Char(c) =>
+				(self.chunk.compile_constant(ChunkConstant::Char(c))?, prim_ty!(Char)),
 			Expr::String(s) => 
 				(self.chunk.compile_constant(ChunkConstant::String(s))?, prim_ty!(String)),
+			Expr::Symbol(s) =>
+				(self.chunk.compile_constant(ChunkConstant::Symbol(s))?, prim_ty!(Symbol)),
+			Expr::Embed(path) => return Err(error(format!("Cannot embed '{}': no module resolver is set", path))),
 			Expr::Id(s) => {
-				let binding = self.ctx.get_binding(&s)?
+				let binding = self.get_binding(s)?
 					.ok_or_else(|| error(format!("Referencing undefined binding '{}'", s)))?;
 				match binding {
 					Binding::Local(reg, t) => (reg, t),
 					Binding::Upvalue(upv, t) => {
-						self.chunk.emit_instr(InstrType::GetUp);
-						self.chunk.emit_byte(upv);
+						let dst = self.dest_reg(dest)?;
+						self.chunk.emit(Instr::GetUp { upv, dst });
 						needs_copy = false;
-						(self.emit_reg(dest)?, t)
+						(dst, t)
 					},
 					Binding::External(ext_idx, t) => {
-						self.chunk.emit_instr(InstrType::GetExt);
-						write_u16(&mut self.chunk.code, ext_idx);
+						let dst = self.dest_reg(dest)?;
+						self.chunk.emit(Instr::GetExt { ext: ext_idx, dst });
 						needs_copy = false;
-						(self.emit_reg(dest)?, t)
+						(dst, t)
 					}
 				}
 			},
 			Expr::BinOp(op, e1, e2) => {
-				let (r1, t1) = self.compile_expr(*e1, None, None)?;
-				let (r2, t2) = self.compile_expr(*e2, None, None)?;
-				self.ctx.regs.free_temp_reg(r2);
-				self.ctx.regs.free_temp_reg(r1);
-				let instr = match op {
-					BinOp::Plus => InstrType::Add,
-					BinOp::Minus => InstrType::Sub,
-					BinOp::Times => InstrType::Mul,
-					BinOp::Divides => InstrType::Div,
-					BinOp::Modulo => InstrType::Mod,
-					BinOp::Power => InstrType::Pow,
-					BinOp::LEq => InstrType::Leq,
-					BinOp::GEq => InstrType::Geq,
-					BinOp::Less => InstrType::Lth,
-					BinOp::Greater => InstrType::Gth,
-					BinOp::Equal => InstrType::Eq,
-					BinOp::NEq => InstrType::Neq,
-					BinOp::And => InstrType::And,
-					BinOp::Or => InstrType::Or,
-				};
-				let ty = match op {
-					  BinOp::Plus | BinOp::Minus | BinOp::Times | BinOp::Divides
-					| BinOp::Modulo | BinOp::Power => {
-						if !t1.is_numeric() || !t2.is_numeric() {
-							return Err(error(format!("Cannot use numeric operator on {:?} and {:?}", t1, t2)));
-						}
-						if t1 == prim_ty!(Int) && t2 == prim_ty!(Int) && op != BinOp::Power {
-							prim_ty!(Int)
-						} else {
-							prim_ty!(Real)
-						}
-					},
-					BinOp::LEq | BinOp::GEq | BinOp::Less | BinOp::Greater => {
-						if !t1.is_numeric() || !t2.is_numeric() {
-							return Err(error(format!("Cannot use comparison operator on {:?} and {:?}", t1, t2)));
-						}
-						prim_ty!(Bool)
-					},
-					BinOp::Equal | BinOp::NEq => prim_ty!(Bool),
-					BinOp::And | BinOp::Or => {
-						if t1 != prim_ty!(Bool) || t2 != prim_ty!(Bool) {
-							return Err(error(format!("Cannot compare {:?} and {:?}", t1, t2)));
-						}
-						prim_ty!(Bool)
-					},
-				};
-				self.chunk.emit_instr(instr);
-				self.chunk.emit_byte(r1);
-				self.chunk.emit_byte(r2);
+				let (left, right) = self.compile_operands(&op, *e1, *e2)?;
+				let dst = self.compile_binop(op, left, right, dest)?;
 				needs_copy = false;
-				(self.emit_reg(dest)?, ty)
+				dst
+			},
+			Expr::Chain(first, rest) => {
+				// Compiled in a loop rather than recursively, since generated code can have very long chains
+				let (mut r1, mut t1) = self.compile_expr(*first, None, None)?;
+				let last = rest.len().checked_sub(1);
+				for (i, (op, e)) in rest.into_iter().enumerate() {
+					let (r2, t2) = self.compile_expr(e, None, None)?;
+					self.ctx.regs.free_temp_reg(r2);
+					self.ctx.regs.free_temp_reg(r1);
+					let (r, t) = self.compile_binop(op, (r1, t1), (r2, t2), if Some(i) == last { dest } else { None })?;
+					r1 = r;
+					t1 = t;
+				}
+				needs_copy = last.is_none();
+				(r1, t1)
 			},
 			Expr::UnaOp(op, e) => {
-				let (r, t) = self.compile_expr(*e, dest, None)?;
+				let (r, t) = self.compile_expr(*e, None, None)?;
 				self.ctx.regs.free_temp_reg(r);
-				let instr = match op {
-					UnaOp::Not => InstrType::Not,
-					UnaOp::Minus => InstrType::Neg,
-				};
 				let ty = match op {
 					UnaOp::Not => {
 						if t != prim_ty!(Bool) {
@@ -560,16 +1015,19 @@ impl Compiler {
 						prim_ty!(Bool)
 					},
 					UnaOp::Minus => {
-						if !t.is_numeric() {
+						if !t.is_numeric() && !t.is_vector() {
 							return Err(error(format!("Cannot use numeric operator on {:?}", t)));
 						}
 						t.clone()
 					},
 				};
-				self.chunk.emit_instr(instr);
-				self.chunk.emit_byte(r);
+				let dst = self.dest_reg(dest)?;
+				self.chunk.emit(match op {
+					UnaOp::Not => Instr::Not { a: r, dst },
+					UnaOp::Minus => Instr::Neg { a: r, dst },
+				});
 				needs_copy = false;
-				(self.emit_reg(dest)?, ty)
+				(dst, ty)
 			},
 			Expr::Call(e, args) => {
 				if let Expr::Prop(val, prop) = *e { // Try method call shortcut
@@ -578,14 +1036,10 @@ impl Compiler {
 							let (arg_range, n, res_ty) = self.compile_arguments(prop_ty, args)?;
 							self.ctx.regs.free_temp_range(arg_range, n);
 							self.ctx.regs.free_temp_reg(val);
-							self.chunk.emit_instr(InstrType::CallMethod);
-							write_u16(&mut self.chunk.code, ns_idx as u16);
-							self.chunk.emit_byte(prop_idx);
-							self.chunk.emit_byte(val);
-							self.chunk.emit_byte(arg_range);
-							self.chunk.emit_byte(n);
+							let dst = self.dest_reg(dest)?;
+							self.chunk.emit(Instr::CallMethod { ns: ns_idx, prop: prop_idx, this: val, args: arg_range, n, dst });
 							needs_copy = false;
-							(self.emit_reg(dest)?, res_ty)
+							(dst, res_ty)
 						},
 						(ty, None) => return Err(error(format!("Cannot call undefined property {} of type {:?}", prop, ty)))
 					}
@@ -595,29 +1049,51 @@ impl Compiler {
 					let (arg_range, n, res_ty) = self.compile_arguments(func_ty, args)?;
 					self.ctx.regs.free_temp_range(arg_range, n);
 					self.ctx.regs.free_temp_reg(func);
-					self.chunk.emit_instr(InstrType::Call);
-					self.chunk.emit_byte(func);
-					self.chunk.emit_byte(arg_range);
-					self.chunk.emit_byte(n);
+					let dst = self.dest_reg(dest)?;
+					self.chunk.emit(Instr::Call { func, args: arg_range, n, dst });
 					needs_copy = false;
-					(self.emit_reg(dest)?, res_ty)
+					(dst, res_ty)
 				}
 			},
-			Expr::Function(args, ret_ty, bl) =>  {
-				let ty = resolve_function_type(&args, &ret_ty)?;
-				let ret_ty = resolve_type(&ret_ty)?;
-				let args: Result<Vec<(String, Type)>, HissyError> = args.iter().map(|(n,t)| Ok((n.clone(), resolve_type(t)?))).collect();
+			Expr::Function(args, captures, ret_ty, bl) =>  {
+				let ty = self.ctx.resolve_function_type(&args, &ret_ty)?;
+				let ret_ty = self.ctx.resolve_type(&ret_ty)?;
+				let args: Result<Vec<(Symbol, Type)>, HissyError> = args.iter().map(|(n,t)| Ok((*n, self.ctx.resolve_type(t)?))).collect();
 				let args = args?;
-				let new_chunk = self.compile_chunk(name.unwrap_or_else(|| String::from("<func>")), bl, args, ret_ty)?;
-				self.chunk.emit_instr(InstrType::Func);
-				self.chunk.emit_byte(new_chunk);
+				let dst = self.dest_reg(dest)?;
+				
+				// Variables captured by value are copied into temporary registers, which the new
+				// closure captures as upvalues, and which are closed right after its creation.
+				let mut captured = vec![];
+				for (id, _) in &args {
+					self.declare(*id);
+				}
+				for id in captures {
+					if captured.iter().any(|(id2, _, _)| *id2 == id) {
+						return Err(error(format!("Variable '{}' is captured twice", id)));
+					}
+					let reg = self.ctx.regs.new_reg()?;
+					let (_, ty) = self.compile_expr(Expr::Id(id), Some(reg), None)?;
+					if let Some(probe) = &mut self.probe {
+						probe.capture(&self.ctx, reg);
+					}
+					captured.push((id, reg, ty));
+				}
+				let regs: Vec<u8> = captured.iter().map(|(_, reg, _)| *reg).collect();
+				
+				let new_chunk = self.compile_chunk(name.unwrap_or_else(|| String::from("<func>")), bl, args, captured, ret_ty)?;
+				self.chunk.emit(Instr::Func { chunk: new_chunk, dst });
+				for reg in regs.into_iter().rev() {
+					self.chunk.emit(Instr::CloseUp { reg });
+					self.ctx.regs.free_reg(reg);
+				}
 				needs_copy = false;
-				(self.emit_reg(dest)?, ty)
+				(dst, ty)
 			},
 			Expr::List(mut values) => {
-				self.chunk.emit_instr(InstrType::ListNew);
+				let reg = self.dest_reg(dest)?;
+				self.chunk.emit(Instr::ListNew { dst: reg });
 				needs_copy = false;
-				let reg = self.emit_reg(dest)?;
 				
 				let mut el_ty: Option<Type> = None;
 				
@@ -640,10 +1116,7 @@ impl Compiler {
 						}
 					}
 					self.ctx.regs.free_temp_range(val_range, n);
-					self.chunk.emit_instr(InstrType::ListExtend);
-					self.chunk.emit_byte(reg);
-					self.chunk.emit_byte(val_range);
-					self.chunk.emit_byte(n);
+					self.chunk.emit(Instr::ListExtend { list: reg, vals: val_range, n });
 				}
 				
 				(reg, Type::List(Box::new(el_ty.unwrap_or(Type::Any))))
@@ -659,23 +1132,30 @@ impl Compiler {
 				}
 				self.ctx.regs.free_temp_reg(list);
 				self.ctx.regs.free_temp_reg(index);
-				self.chunk.emit_instr(InstrType::ListGet);
-				self.chunk.emit_byte(list);
-				self.chunk.emit_byte(index);
+				let dst = self.dest_reg(dest)?;
+				self.chunk.emit(Instr::ListGet { list, idx: index, dst });
 				needs_copy = false;
-				(self.emit_reg(dest)?, tr)
+				(dst, tr)
 			},
 			Expr::Prop(val, prop) => {
+				if let Some(name) = self.find_enum(&val) {
+					if !self.ctx.enums[&name].contains(&prop) {
+						return Err(error(format!("Enum {} has no variant '{}'", name, prop)));
+					}
+					if let Some(probe) = &mut self.probe {
+						probe.use_enum(name);
+					}
+					return self.compile_expr(Expr::Symbol(prop), dest, None)
+						.map(|(reg, _)| (reg, Type::Enum(name)));
+				}
 				let (val, ty) = self.compile_expr(*val, None, None)?;
 				
 				if let Some((ns_idx, prop_idx, prop_ty)) = self.find_method(ty.clone(), &prop)? {
 					self.ctx.regs.free_temp_reg(val);
-					self.chunk.emit_instr(InstrType::MakeMethod);
-					write_u16(&mut self.chunk.code, ns_idx as u16);
-					self.chunk.emit_byte(prop_idx);
-					self.chunk.emit_byte(val);
+					let dst = self.dest_reg(dest)?;
+					self.chunk.emit(Instr::MakeMethod { ns: ns_idx, prop: prop_idx, this: val, dst });
 					needs_copy = false;
-					(self.emit_reg(dest)?, prop_ty)
+					(dst, prop_ty)
 				} else {
 					return Err(error(format!("Type {:?} does not have a property {}", ty, prop)));
 				}
@@ -686,9 +1166,7 @@ impl Compiler {
 		
 		if needs_copy {
 			if let Some(dest) = dest {
-				self.chunk.emit_instr(InstrType::Cpy);
-				self.chunk.emit_byte(reg);
-				self.chunk.emit_byte(dest);
+				self.chunk.emit(Instr::Cpy { src: reg, dst: dest });
 				reg = dest;
 			}
 		}
@@ -697,144 +1175,43 @@ impl Compiler {
 	}
 
 
-	fn compile_block(&mut self, locals: Vec<(String, u8, Type)>, stats: Block) -> Result<u16, HissyError> {
+	fn compile_block(&mut self, locals: Vec<(Symbol, u8, Type)>, stats: Block) -> Result<u16, HissyError> {
 		let used_before = self.ctx.regs.used - (locals.len() as u16);
 		
 		self.ctx.enter_block();
 		for (id, reg, ty) in locals {
-			self.ctx.make_local(id, reg, ty);
+			self.make_local(id, reg, ty);
 		}
 		
+		let outer_line = self.line;
 		let mut line = 0;
-		for Positioned(stat, (line2, _)) in stats {
-			line = u16::try_from(line2).map_err(|_| error_str("Line number too large"))?;
+		for Positioned(stat, pos) in stats {
+			line = u16::try_from(pos.0).map_err(|_| error_str("Line number too large"))?;
+			self.line = line;
+			if let Some(probe) = &mut self.probe {
+				probe.visit(&self.ctx, pos, false);
+			}
 			if self.debug_info {
-				let pos = u16::try_from(self.chunk.code.len()).unwrap(); // (The code size is already bounded by the serialization)
+				let pos = u16::try_from(self.chunk.code.len()).map_err(|_| error_str("Code too long for line numbers"))?;
 				self.chunk.debug_info.line_numbers.push((pos, line));
 			}
 			
+			// Const functions are defined like other functions; compile_program folds calls to them
+			// with literal arguments beforehand
+			let stat = match stat {
+				Stat::Const(id, f, doc, annotations) => Stat::Let(id, None, f, doc, annotations),
+				stat => stat,
+			};
+			
 			let compile_stat = || -> Result<(), HissyError> {
 				match stat {
 					Stat::ExprStat(e) => {
 						let (reg, _t) = self.compile_expr(e, None, None)?;
 						self.ctx.regs.free_temp_reg(reg);
 					},
-					Stat::Let(id, ty, e) => {
-						let ty = ty.map(|ty| resolve_type(&ty)).transpose()?;
-						if let Some(local) = self.ctx.find_block_local(&id) { // if binding already exists
-							self.ctx.regs.free_reg(local.reg);
-						}
-						let reg = self.ctx.regs.new_reg()?;
-						let forwarded = {
-							if let Expr::Function(args, res_ty, _) = &e {
-								self.ctx.make_local(id.clone(), reg, resolve_function_type(args, res_ty)?);
-								true
-							} else {
-								false
-							}
-						};
-						let (_, ty2) = self.compile_expr(e, Some(reg), Some(id.clone()))?;
-						let ty = if let Some(ty) = ty {
-							if !ty.can_assign(&ty2) {
-								return Err(error(format!("Cannot define variable of type {:?} with expression of type {:?}", ty, ty2)));
-							}
-							ty
-						} else {
-							ty2
-						};
-						if !forwarded {
-							self.ctx.make_local(id, reg, ty);
-						}
-					},
-					Stat::Set(LExpr::Id(id), e) => {
-						let binding = self.ctx.get_binding(&id)?
-							.ok_or_else(|| error(format!("Referencing undefined binding '{}'", id)))?;
-						let (ty, ty2) = match binding {
-							Binding::Local(reg, ty) => {
-								let (_, ty2) = self.compile_expr(e, Some(reg), None)?;
-								(ty, ty2)
-							},
-							Binding::Upvalue(upv, ty) => {
-								let (reg, ty2) = self.compile_expr(e, None, None)?;
-								self.ctx.regs.free_temp_reg(reg);
-								self.chunk.emit_instr(InstrType::SetUp);
-								self.chunk.emit_byte(upv);
-								self.chunk.emit_byte(reg);
-								(ty, ty2)
-							},
-							Binding::External(_, _) => {
-								return Err(error(format!("Cannot set external value '{}'", id)));
-							},
-						};
-						if !ty.can_assign(&ty2) {
-							return Err(error(format!("Cannot assign type {:?} to variable of type {:?}", ty2, ty)));
-						}
-					},
-					Stat::Set(LExpr::Index(lst, idx), e) => {
-						let (lst, tl) = self.compile_expr(*lst, None, None)?;
-						let te = if let Type::List(te) = tl { *te } else {
-							return Err(error(format!("Cannot index object of type {:?}", tl)));
-						};
-						let (idx, ti) = self.compile_expr(*idx, None, None)?;
-						if ti != prim_ty!(Int) {
-							return Err(error(format!("Cannot index list with {:?}", ti)));
-						}
-						let (e, te2) = self.compile_expr(e, None, None)?;
-						if !te.can_assign(&te2) {
-							return Err(error(format!("Cannot assign type {:?} into list of {:?}", te2, te)));
-						}
-						self.ctx.regs.free_temp_reg(lst);
-						self.ctx.regs.free_temp_reg(idx);
-						self.ctx.regs.free_temp_reg(e);
-						self.chunk.emit_instr(InstrType::ListSet);
-						self.chunk.emit_byte(lst);
-						self.chunk.emit_byte(idx);
-						self.chunk.emit_byte(e);
-					},
-					Stat::Cond(mut branches) => {
-						let mut end_jmps = vec![];
-						let last_branch = branches.len() - 1;
-						for (i, (cond, bl)) in branches.drain(..).enumerate() {
-							let mut after_jmp = None;
-							match cond {
-								Cond::If(e) => {
-									let (cond_reg, t) = self.compile_expr(e, None, None)?;
-									if t != prim_ty!(Bool) {
-										return Err(error(format!("Expected boolean in condition, got {:?}", t)))
-									}
-									
-									// Jump to next branch if false
-									self.ctx.regs.free_temp_reg(cond_reg);
-									self.chunk.emit_instr(InstrType::Jif);
-									after_jmp = Some(self.chunk.code.len());
-									self.chunk.emit_byte(0); // Placeholder
-									self.chunk.emit_byte(cond_reg);
-									
-									self.compile_block(vec![], bl)?;
-									
-									if i != last_branch {
-										// Jump out of condition at end of block
-										self.chunk.emit_instr(InstrType::Jmp);
-										let from2 = self.chunk.code.len();
-										self.chunk.emit_byte(0); // Placeholder 2
-										end_jmps.push(from2);
-									}
-								},
-								Cond::Else => {
-									self.compile_block(vec![], bl)?;
-								}
-							}
-							
-							if let Some(from) = after_jmp {
-								fill_in_jump_from(&mut self.chunk, from)?;
-							}
-						}
-						
-						// Fill in jumps to end
-						for from in end_jmps {
-							fill_in_jump_from(&mut self.chunk, from)?;
-						}
-					},
+					Stat::Let(id, ty, e, doc, annotations) => self.compile_let(id, ty, e, doc, annotations)?,
+					Stat::Set(lexpr, e) => self.compile_set(lexpr, e)?,
+					Stat::Cond(branches) => self.compile_cond(branches)?,
 					Stat::While(e, bl) => {
 						let begin = self.chunk.code.len();
 						let (cond_reg, t) = self.compile_expr(e, None, None)?;
@@ -843,75 +1220,45 @@ impl Compiler {
 						}
 						
 						self.ctx.regs.free_temp_reg(cond_reg);
-						self.chunk.emit_instr(InstrType::Jif);
-						let placeholder = self.chunk.code.len();
-						self.chunk.emit_byte(0); // Placeholder
-						self.chunk.emit_byte(cond_reg);
+						let placeholder = self.chunk.emit(Instr::Jif { rel: 0, cond: cond_reg });
 						
 						self.compile_block(vec![], bl)?;
 						
-						self.chunk.emit_instr(InstrType::Jmp);
-						emit_jump_to(&mut self.chunk, begin)?;
-						fill_in_jump_from(&mut self.chunk, placeholder)?;
-					},
-					Stat::For(id, el_ty, e, bl) => {
-						let el_ty = el_ty.map(|ty| resolve_type(&ty)).transpose()?;
-						
-						let res = match self.find_prop(e, "next")? {
-							(it_ty, Some((it_reg, ObjectProp::Method { ns_idx, prop_idx, prop_ty: _prop_ty }))) => {
-								if let Type::Iterator(el_ty2) = it_ty {
-									let el_ty = if let Some(el_ty) = el_ty {
-										if !el_ty.can_assign(&el_ty2) {
-											return Err(error(format!("Cannot define variable of type {:?} from iterator on type {:?}", el_ty, el_ty2)));
-										}
-										el_ty
-									} else {
-										*el_ty2
-									};
-									
-									// Hacky way of making the iterator a "persistent temporary"
-									self.ctx.regs.make_local(it_reg);
-									let var_reg = self.ctx.regs.new_reg()?;
-									
-									let begin = self.chunk.code.len();
-									self.chunk.emit_instr(InstrType::CallMethod);
-									write_u16(&mut self.chunk.code, ns_idx as u16);
-									self.chunk.emit_byte(prop_idx);
-									self.chunk.emit_byte(it_reg);
-									self.chunk.emit_byte(it_reg + 1);
-									self.chunk.emit_byte(0);
-									self.chunk.emit_byte(var_reg);
-									Ok((it_reg, var_reg, el_ty, begin))
-								} else {
-									Err(it_ty)
-								}
-							},
-							(it_ty, None) => Err(it_ty),
-						};
-						let (it_reg, var_reg, el_ty, begin) = res.map_err(|ty| error(format!("{:?} is not an iterable type", ty)))?;
-						
-						self.chunk.emit_instr(InstrType::Jin);
-						let placeholder = self.chunk.code.len();
-						self.chunk.emit_byte(0); // Placeholder
-						self.chunk.emit_byte(var_reg);
-						
-						self.compile_block(vec![(id, var_reg, el_ty)], bl)?;
-						
-						self.chunk.emit_instr(InstrType::Jmp);
 						emit_jump_to(&mut self.chunk, begin)?;
-						
-						self.ctx.regs.free_reg(it_reg);
-						
 						fill_in_jump_from(&mut self.chunk, placeholder)?;
 					},
+					Stat::For(id, el_ty, e, bl) => self.compile_for(id, el_ty, e, bl)?,
 					Stat::Return(e) => {
-						let (reg, tr) = self.compile_expr(e, None, None)?;
+						// Deferred closures run after the value is computed, and cannot modify it
+						let deferred: Vec<u8> = self.ctx.defers.iter().flatten().copied().collect();
+						let dest = if deferred.is_empty() { None } else { Some(self.ctx.regs.new_reg()?) };
+						let (reg, tr) = self.compile_expr(e, dest, None)?;
 						if !self.ctx.ret_ty.can_assign(&tr) {
 							return Err(error(format!("Trying to return {:?}, expected {:?}", tr, self.ctx.ret_ty)));
 						}
+						self.ctx.add_return(tr);
+						self.call_deferred(&deferred)?;
 						self.ctx.regs.free_temp_reg(reg);
-						self.chunk.emit_instr(InstrType::Ret);
-						self.chunk.emit_byte(reg);
+						self.chunk.emit(Instr::Ret { src: reg });
+					},
+					Stat::Defer(e) => {
+						let reg = self.ctx.regs.new_reg()?;
+						let func = Expr::Function(vec![], vec![], ast::Type::Named(Symbol::intern("Nil")), vec![Positioned(Stat::ExprStat(e), pos)]);
+						let (_, ty) = self.compile_expr(func, Some(reg), Some(String::from("<defer>")))?;
+						self.make_local(Symbol::intern(&format!("<defer {}>", reg)), reg, ty);
+						self.ctx.defers.last_mut().unwrap().push(reg);
+					},
+					Stat::Enum(id, variants) => self.declare_enum(id, variants)?,
+					Stat::Match(e, arms, else_block) => self.compile_match(e, arms, else_block, line)?,
+					Stat::Import(name) => {
+						if self.ctx.stack.len() > 1 || self.ctx.blocks.len() > 1 {
+							return Err(error(format!("Module '{}' must be imported at the top level", name)));
+						}
+						// Top-level imports are expanded before compilation, except during analysis
+						match &mut self.probe {
+							Some(probe) => probe.imported = true,
+							None => return Err(error(format!("Cannot import '{}': no module resolver is set", name))),
+						}
 					},
 					#[allow(unreachable_patterns)]
 					_ => return Err(error(format!("Unimplemented statement type: {:?}", stat)))
@@ -924,9 +1271,16 @@ impl Compiler {
 				res = Err(HissyError(ErrorType::Compilation, err, line));
 			}
 			res?;
+			
+			if let Some(probe) = &mut self.probe {
+				probe.visit(&self.ctx, pos, true);
+			}
 		}
 		
+		let deferred = self.ctx.defers.last().unwrap().clone();
+		self.call_deferred(&deferred)?;
 		self.ctx.leave_block(&mut self.chunk);
+		self.line = outer_line;
 		
 		assert!(used_before == self.ctx.regs.used, "Leaked registers: {} -> {}", used_before, self.ctx.regs.used);
 		// Basic check to make sure no registers have been "leaked"
@@ -935,9 +1289,348 @@ impl Compiler {
 	}
 
 
-	fn compile_chunk(&mut self, name: String, ast: Block, args: Vec<(String, Type)>, ret_ty: Type) -> Result<u8, HissyError> {
+	fn compile_set(&mut self, lexpr: LExpr, e: Expr) -> Result<(), HissyError> {
+		match lexpr {
+			LExpr::Id(id) => {
+				let binding = self.get_binding(id)?
+					.ok_or_else(|| error(format!("Referencing undefined binding '{}'", id)))?;
+				let (ty, ty2) = match binding {
+					Binding::Local(reg, ty) => {
+						let (_, ty2) = self.compile_expr(e, Some(reg), None)?;
+						(ty, ty2)
+					},
+					Binding::Upvalue(upv, ty) => {
+						let (reg, ty2) = self.compile_expr(e, None, None)?;
+						self.ctx.regs.free_temp_reg(reg);
+						self.chunk.emit(Instr::SetUp { upv, src: reg });
+						(ty, ty2)
+					},
+					Binding::External(_, _) => {
+						return Err(error(format!("Cannot set external value '{}'", id)));
+					},
+				};
+				if !ty.can_assign(&ty2) {
+					return Err(error(format!("Cannot assign type {:?} to variable of type {:?}", ty2, ty)));
+				}
+			},
+			LExpr::Index(lst, idx) => {
+				let (lst, tl) = self.compile_expr(*lst, None, None)?;
+				let te = if let Type::List(te) = tl { *te } else {
+					return Err(error(format!("Cannot index object of type {:?}", tl)));
+				};
+				let (idx, ti) = self.compile_expr(*idx, None, None)?;
+				if ti != prim_ty!(Int) {
+					return Err(error(format!("Cannot index list with {:?}", ti)));
+				}
+				let (e, te2) = self.compile_expr(e, None, None)?;
+				if !te.can_assign(&te2) {
+					return Err(error(format!("Cannot assign type {:?} into list of {:?}", te2, te)));
+				}
+				self.ctx.regs.free_temp_reg(e);
+				self.ctx.regs.free_temp_reg(idx);
+				self.ctx.regs.free_temp_reg(lst);
+				self.chunk.emit(Instr::ListSet { list: lst, idx, src: e });
+			},
+		}
+		Ok(())
+	}
+	
+	fn compile_cond(&mut self, mut branches: Vec<Branch>) -> Result<(), HissyError> {
+		let mut end_jmps = vec![];
+		let last_branch = branches.len() - 1;
+		for (i, (cond, bl)) in branches.drain(..).enumerate() {
+			let mut after_jmp = None;
+			match cond {
+				Cond::If(e) => {
+					let (cond_reg, t) = self.compile_expr(e, None, None)?;
+					if t != prim_ty!(Bool) {
+						return Err(error(format!("Expected boolean in condition, got {:?}", t)))
+					}
+					
+					// Jump to next branch if false
+					self.ctx.regs.free_temp_reg(cond_reg);
+					after_jmp = Some(self.chunk.emit(Instr::Jif { rel: 0, cond: cond_reg })); // Placeholder
+					
+					self.compile_block(vec![], bl)?;
+					
+					if i != last_branch {
+						// Jump out of condition at end of block
+						let from2 = self.chunk.emit(Instr::Jmp { rel: 0 }); // Placeholder 2
+						end_jmps.push(from2);
+					}
+				},
+				Cond::Else => {
+					self.compile_block(vec![], bl)?;
+				}
+			}
+			
+			if let Some(from) = after_jmp {
+				fill_in_jump_from(&mut self.chunk, from)?;
+			}
+		}
+		
+		// Fill in jumps to end
+		for from in end_jmps {
+			fill_in_jump_from(&mut self.chunk, from)?;
+		}
+		Ok(())
+	}
+	
+	fn compile_for(&mut self, id: Symbol, el_ty: Option<ast::Type>, e: Expr, bl: Block) -> Result<(), HissyError> {
+		self.declare(id);
+		let el_ty = el_ty.map(|ty| self.ctx.resolve_type(&ty)).transpose()?;
+		
+		if self.is_int_range(&e) {
+			return self.compile_int_loop(id, el_ty, e, bl);
+		}
+		
+		let res = match self.find_prop(e, "next")? {
+			(it_ty, Some((it_reg, ObjectProp::Method { ns_idx, prop_idx, prop_ty: _prop_ty }))) => {
+				if let Type::Iterator(el_ty2) = it_ty {
+					let el_ty = if let Some(el_ty) = el_ty {
+						if !el_ty.can_assign(&el_ty2) {
+							return Err(error(format!("Cannot define variable of type {:?} from iterator on type {:?}", el_ty, el_ty2)));
+						}
+						el_ty
+					} else {
+						*el_ty2
+					};
+					
+					// An iterator stored in a variable is copied to a temporary, which the loop keeps until its end
+					let it_reg = if self.ctx.regs.is_local(it_reg) {
+						let copy = self.ctx.regs.new_reg()?;
+						self.chunk.emit(Instr::Cpy { src: it_reg, dst: copy });
+						copy
+					} else {
+						it_reg
+					};
+					// Hacky way of making the iterator a "persistent temporary"
+					self.ctx.regs.make_local(it_reg);
+					let var_reg = self.ctx.regs.new_reg()?;
+					
+					let begin = self.chunk.emit(Instr::CallMethod {
+						ns: ns_idx, prop: prop_idx, this: it_reg, args: it_reg + 1, n: 0, dst: var_reg });
+					Ok((it_reg, var_reg, el_ty, begin))
+				} else {
+					Err(it_ty)
+				}
+			},
+			(it_ty, None) => Err(it_ty),
+		};
+		let (it_reg, var_reg, el_ty, begin) = res.map_err(|ty| error(format!("{:?} is not an iterable type", ty)))?;
+		
+		let placeholder = self.chunk.emit(Instr::Jin { rel: 0, val: var_reg });
+		
+		self.compile_block(vec![(id, var_reg, el_ty)], bl)?;
+		
+		emit_jump_to(&mut self.chunk, begin)?;
+		
+		self.ctx.regs.free_reg(it_reg);
+		
+		fill_in_jump_from(&mut self.chunk, placeholder)?;
+		Ok(())
+	}
+	
+	fn compile_let(&mut self, id: Symbol, ty: Option<ast::Type>, e: Expr, doc: Option<String>, annotations: Vec<Annotation>) -> Result<(), HissyError> {
+		self.declare(id);
+		let ty = ty.map(|ty| self.ctx.resolve_type(&ty)).transpose()?;
+		let reg = match self.ctx.find_block_local(id) {
+			Some(local) => local.reg, // if binding already exists
+			None => self.ctx.regs.new_reg()?,
+		};
+		let forwarded = {
+			if let Expr::Function(args, _, res_ty, _) = &e {
+				self.make_local(id, reg, self.ctx.resolve_function_type(args, res_ty)?);
+				true
+			} else {
+				false
+			}
+		};
+		let chunk_id = self.chunk.chunks.len(); // the function's chunk, if e is one
+		let name = forwarded.then(|| String::from(id));
+		let (_, ty2) = self.compile_expr(e, Some(reg), name)?;
+		if forwarded {
+			self.annotate(chunk_id, reg, doc, annotations)?;
+		}
+		let ty = if let Some(ty) = ty {
+			if !ty.can_assign(&ty2) {
+				return Err(error(format!("Cannot define variable of type {:?} with expression of type {:?}", ty, ty2)));
+			}
+			ty
+		} else {
+			ty2
+		};
+		if !forwarded {
+			self.make_local(id, reg, ty);
+		}
+		Ok(())
+	}
+	
+	// Checks the annotations of a function declaration, and records them in debug info with its doc comment
+	fn annotate(&mut self, chunk_id: usize, reg: u8, doc: Option<String>, annotations: Vec<Annotation>) -> Result<(), HissyError> {
+		for (name, args) in &annotations {
+			let max_args = match name.deref() {
+				"test" | "bench" if self.ctx.stack.len() > 1 || self.ctx.blocks.len() > 1 => {
+					let kind = if name.deref() == "test" { "Test" } else { "Benchmark" };
+					return Err(error(format!("{} functions must be declared at the top level", kind)));
+				},
+				"inline" | "test" | "bench" => 0,
+				"deprecated" => 1,
+				_ => return Err(error(format!("Unknown annotation '@{}'", name))),
+			};
+			if args.len() > max_args {
+				return Err(error(format!("Annotation '@{}' takes at most {} argument(s)", name, max_args)));
+			}
+			if name.deref() == "deprecated" {
+				let depth = self.ctx.stack.len() - 1;
+				self.deprecated.insert((depth, reg), args.first().cloned().unwrap_or_default());
+			}
+		}
+		if self.debug_info {
+			let info = &mut self.chunk.chunks[chunk_id].debug_info;
+			info.doc = doc.unwrap_or_default();
+			info.annotations = annotations.into_iter().map(|(name, args)| (String::from(name), args)).collect();
+		}
+		Ok(())
+	}
+	
+	fn declare_enum(&mut self, id: Symbol, variants: Vec<Symbol>) -> Result<(), HissyError> {
+		if self.ctx.resolve_type(&ast::Type::Named(id)).is_ok() {
+			return Err(error(format!("Type '{}' is already defined", id)));
+		}
+		if let Some(variant) = variants.iter().enumerate().find(|(i, v)| variants[..*i].contains(v)).map(|(_, v)| v) {
+			return Err(error(format!("Variant '{}' is declared twice in enum {}", variant, id)));
+		}
+		if let Some(probe) = &mut self.probe {
+			probe.declare_enum(id);
+		}
+		self.ctx.enums.insert(id, variants);
+		Ok(())
+	}
+	
+	// The arms of a match statement compare the value against each of their patterns in turn
+	fn compile_match(&mut self, e: Expr, arms: Vec<MatchArm>, else_block: Option<Block>, line: u16) -> Result<(), HissyError> {
+		// The matched value is kept in a "persistent temporary", like for loop iterators
+		let val_reg = self.ctx.regs.new_reg()?;
+		let (_, ty) = self.compile_expr(e, Some(val_reg), None)?;
+		self.ctx.regs.make_local(val_reg);
+		
+		let mut covered = vec![];
+		let mut end_jmps = vec![];
+		let last_arm = arms.len() - 1;
+		for (i, (patterns, bl)) in arms.into_iter().enumerate() {
+			let cond_reg = self.ctx.regs.new_reg()?;
+			for (j, pattern) in patterns.into_iter().enumerate() {
+				if let Expr::Prop(_, variant) | Expr::Symbol(variant) = &pattern {
+					covered.push(*variant);
+				}
+				let (reg, t) = self.compile_expr(pattern, None, None)?;
+				if !ty.can_assign(&t) && !t.can_assign(&ty) {
+					return Err(error(format!("Cannot match value of type {:?} against {:?}", ty, t)));
+				}
+				self.ctx.regs.free_temp_reg(reg);
+				if j == 0 {
+					self.chunk.emit(Instr::Eq { a: val_reg, b: reg, dst: cond_reg });
+				} else {
+					let eq_reg = self.ctx.regs.new_reg()?;
+					self.chunk.emit(Instr::Eq { a: val_reg, b: reg, dst: eq_reg });
+					self.chunk.emit(Instr::Or { a: cond_reg, b: eq_reg, dst: cond_reg });
+					self.ctx.regs.free_reg(eq_reg);
+				}
+			}
+			
+			// Jump to next arm if no pattern matches
+			self.ctx.regs.free_reg(cond_reg);
+			let placeholder = self.chunk.emit(Instr::Jif { rel: 0, cond: cond_reg });
+			self.compile_block(vec![], bl)?;
+			if i != last_arm || else_block.is_some() {
+				end_jmps.push(self.chunk.emit(Instr::Jmp { rel: 0 }));
+			}
+			fill_in_jump_from(&mut self.chunk, placeholder)?;
+		}
+		
+		if let Some(bl) = else_block {
+			self.compile_block(vec![], bl)?;
+		} else if let Type::Enum(name) = &ty {
+			let missing: Vec<String> = self.ctx.enums[name].iter().filter(|v| !covered.contains(v)).map(|v| String::from(*v)).collect();
+			if !missing.is_empty() {
+				self.warnings.push(Warning(format!("Match on {} does not handle {}", name, missing.join(", ")), line));
+			}
+		}
+		for jmp in end_jmps {
+			fill_in_jump_from(&mut self.chunk, jmp)?;
+		}
+		self.ctx.regs.free_reg(val_reg);
+		Ok(())
+	}
+	
+	// Checks whether an expression is a call to the built-in range(), which integer loops are specialized for
+	fn is_int_range(&self, e: &Expr) -> bool {
+		let range = Symbol::intern("range");
+		let shadowed = self.ctx.scopes.contains_key(&range) || !self.ctx.external_idx.contains_key(&range);
+		!shadowed && matches!(e, Expr::Call(func, args) if **func == Expr::Id(range) && args.len() == 2)
+	}
+	
+	// Compiles a loop over range(start, end), with the counter, limit and step in consecutive registers,
+	// followed by the loop variable
+	fn compile_int_loop(&mut self, id: Symbol, el_ty: Option<Type>, range: Expr, bl: Block) -> Result<(), HissyError> {
+		let (func, args) = if let Expr::Call(func, args) = range { (func, args) } else { unreachable!() };
+		if let Expr::Id(func) = *func {
+			self.get_binding(func)?; // Record the reference to range()
+		}
+		if let Some(el_ty) = &el_ty {
+			if !el_ty.can_assign(&prim_ty!(Int)) {
+				return Err(error(format!("Cannot define variable of type {:?} from iterator on type {:?}", el_ty, prim_ty!(Int))));
+			}
+		}
+		let el_ty = el_ty.unwrap_or(prim_ty!(Int));
+		
+		let base = self.ctx.regs.new_reg_range(3)?;
+		for (reg, e) in (base..).zip(args.into_iter().chain(iter::once(Expr::Int(1)))) {
+			let (_, ty) = self.compile_expr(e, Some(reg), None)?;
+			if ty != prim_ty!(Int) {
+				return Err(error(format!("Expected Int as bound of range, got {:?}", ty)));
+			}
+		}
+		for reg in base..base + 3 {
+			self.ctx.regs.make_local(reg);
+		}
+		let var_reg = self.ctx.regs.new_reg()?;
+		
+		let placeholder = self.chunk.emit(Instr::ForPrep { rel: 0, base });
+		let begin = self.chunk.code.len();
+		self.compile_block(vec![(id, var_reg, el_ty)], bl)?;
+		let rel = rel_jump(&self.chunk, self.chunk.code.len(), begin)?;
+		self.chunk.emit(Instr::ForLoop { rel, base });
+		fill_in_jump_from(&mut self.chunk, placeholder)?;
+		
+		self.ctx.regs.free_reg_range(base, 3);
+		Ok(())
+	}
+	
+	// Calls deferred closures, most recent first
+	fn call_deferred(&mut self, deferred: &[u8]) -> Result<(), HissyError> {
+		for &func in deferred.iter().rev() {
+			let dst = self.ctx.regs.new_reg()?;
+			self.chunk.emit(Instr::Call { func, args: dst, n: 0, dst });
+			self.ctx.regs.free_reg(dst);
+		}
+		Ok(())
+	}
+	
+	// captures are the variables captured by value, as (name, register in parent chunk, type)
+	fn compile_chunk(&mut self, name: String, ast: Block, args: Vec<(Symbol, Type)>, captures: Vec<(Symbol, u8, Type)>, ret_ty: Type) -> Result<u8, HissyError> {
+		self.compile_chunk_typed(name, ast, args, captures, ret_ty).map(|(chunk_id, _)| chunk_id)
+	}
+	
+	// Also returns the type of the values returned by the chunk, which is more precise than its declared return type
+	fn compile_chunk_typed(&mut self, name: String, ast: Block, args: Vec<(Symbol, Type)>, captures: Vec<(Symbol, u8, Type)>, ret_ty: Type) -> Result<(u8, Type), HissyError> {
 		let chunk_id = self.chunk.enter();
 		self.ctx.enter(ret_ty);
+		let depth = self.ctx.stack.len() - 1;
+		for (id, reg, ty) in captures {
+			self.ctx.add_upvalue(depth, id, reg, ty)?;
+		}
 		
 		if self.debug_info {
 			self.chunk.debug_info.name = name;
@@ -947,6 +1640,7 @@ impl Compiler {
 			.map(|(id, ty)| Ok((id, self.ctx.regs.new_reg()?, ty)))
 			.collect();
 		let args = args?;
+		self.chunk.nb_params = args.len() as u8;
 		
 		let implicit_return = can_reach_end(&ast);
 		let last_line = self.compile_block(args, ast)?;
@@ -955,25 +1649,216 @@ impl Compiler {
 				format!("Implicit nil return at end of function, but expected {:?}", self.ctx.ret_ty),
 				last_line));
 		}
+		if implicit_return {
+			self.ctx.add_return(prim_ty!(Nil));
+		}
 		
 		self.chunk.nb_registers = self.ctx.regs.required;
 		self.chunk.upvalues = self.ctx.upvalues.iter().map(|b| b.reg).collect();
 		if self.debug_info {
-			self.chunk.debug_info.upvalue_names = self.ctx.upvalues.iter().map(|b| b.name.clone()).collect();
+			self.chunk.debug_info.upvalue_names = self.ctx.upvalues.iter().map(|b| String::from(b.name)).collect();
 		}
 		
-		self.ctx.leave();
+		let returned = self.ctx.leave();
 		self.chunk.leave();
 		
-		u8::try_from(chunk_id).map_err(|_| error_str("Too many chunks"))
+		let chunk_id = u8::try_from(chunk_id).map_err(|_| error_str("Too many chunks"))?;
+		Ok((chunk_id, returned))
 	}
 	
 	/// Compiles a string slice containing Hissy code into a [`Program`], consuming the `Compiler`.
+	///
+	/// Calls to top-level `const` functions whose arguments are all literals are run at compile time,
+	/// and replaced by their result.
 	pub fn compile_program(mut self, input: &str) -> Result<Program, HissyError> {
-		let ast = parse(input)?;
+		let ast = self.parse(input)?;
+		self.compile_expanded(Some(input), ast)
+	}
+	
+	/// Compiles an Abstract Syntax Tree into a [`Program`], consuming the `Compiler`, eg. for tools which
+	/// build or transform programs (see [`parser::visit`](crate::parser::visit)), like [`Compiler::compile_program`].
+	///
+	/// The tree is checked like parsed code: syntax trees nested deeper than [`MAX_NESTING`](crate::parser::MAX_NESTING)
+	/// are rejected, and its imports are resolved; no source is embedded in the program.
+	pub fn compile_ast(mut self, ast: ProgramAST) -> Result<Program, HissyError> {
+		check_depth(&ast)?;
+		let ast = self.expand_imports(ast)?;
+		self.compile_expanded(None, ast)
+	}
+	
+	fn compile_expanded(self, input: Option<&str>, ast: ProgramAST) -> Result<Program, HissyError> {
+		let const_folded = partial::fold_const_calls(&ast)?;
+		let prefix_folded = if self.partial_eval { partial::fold_prefix(const_folded.as_ref().unwrap_or(&ast)) } else { None };
+		let folded = prefix_folded.or(const_folded);
+		let folded_compiler = Compiler {
+			debug_info: self.debug_info,
+			embed_source: self.embed_source,
+			block_style: self.block_style,
+			partial_eval: false,
+			ctx: Context::new(),
+			chunk: ChunkManager { encoding: self.chunk.encoding, ..ChunkManager::new() },
+			probe: None,
+			warnings: Vec::new(),
+			line: 0,
+			deprecated: HashMap::new(),
+			resolver: None,
+			untrusted: false,
+		};
+		
+		let program = self.compile_main(input, ast, Vec::new())?;
+		// Literals may have more precise types than the original expressions, so the folded program
+		// is only used if it compiles, and the original program always needs to compile.
+		if let Some(folded) = folded {
+			if let Ok(folded) = folded_compiler.compile_main(input, folded, Vec::new()) {
+				return Ok(Program { warnings: program.warnings, ..folded });
+			}
+		}
+		Ok(program)
+	}
+	
+	/// Compiles a snippet of Hissy code into a function of the given parameters, consuming the `Compiler`,
+	/// eg. to evaluate formulas provided by users.
+	///
+	/// The snippet is the body of the function, which has access to the standard library; if it ends with
+	/// an expression, its value is returned, so that a single expression like `price * (1 + tax)` is a valid snippet.
+	pub fn compile_function(self, input: &str, params: &[(&str, Type)]) -> Result<CompiledFunction, HissyError> {
+		self.compile_function_in(input, &[], params)
+	}
+	
+	// Same as compile_function, with declarations (eg. of enums) inserted before the snippet
+	pub(crate) fn compile_function_in(mut self, input: &str, decls: &[Positioned<Stat>], params: &[(&str, Type)]) -> Result<CompiledFunction, HissyError> {
+		let mut ast = self.parse(input)?;
+		let last = ast.pop().map(|Positioned(stat, pos)| match stat {
+			Stat::ExprStat(e) => Positioned(Stat::Return(e), pos),
+			stat => Positioned(stat, pos),
+		});
+		ast.extend(last);
+		let ast = decls.iter().cloned().chain(ast).collect();
+		let args = params.iter().map(|(id, ty)| (Symbol::intern(id), ty.clone())).collect();
+		let (_, ret_ty) = self.compile_chunk_typed(String::from("<function>"), ast, args, Vec::new(), Type::Any)?;
+		
+		let source = if self.embed_source { Some(String::from(input)) } else { None };
+		let program = Program { debug_info: self.debug_info, encoding: self.chunk.encoding, chunks: self.chunk.finish(), source, warnings: self.warnings, tests: vec![], benches: vec![] };
+		let params = params.iter().map(|(id, ty)| (String::from(*id), ty.clone())).collect();
+		Ok(CompiledFunction { program, params, ret_ty })
+	}
+
+	// Compiles an input of a session (see vm::session), consuming the `Compiler`. The main chunk takes the values
+	// of the bindings of the session as arguments, and returns a list of the value of the input (nil if it does not
+	// end with an expression), followed by the values of the top-level bindings at its end, whose names and types
+	// are returned as well. The enums declared by previous inputs are given as statements, and those declared by
+	// this input are returned.
+	pub(crate) fn compile_session_input(mut self, input: &str, enums: &[Positioned<Stat>], bindings: &[(Symbol, Type)])
+			-> Result<(Program, Block, SessionBindings), HissyError> {
+		let mut ast = self.parse(input)?;
+		let new_enums: Block = ast.iter().filter(|stat| matches!(stat.0, Stat::Enum(_, _))).cloned().collect();
+		let result = Symbol::intern("<result>");
+		let last_pos = ast.last().map_or((1, 1), |Positioned(_, pos)| *pos);
+		let has_result = matches!(ast.last(), Some(Positioned(Stat::ExprStat(_), _)));
+		if has_result {
+			if let Some(Positioned(Stat::ExprStat(e), pos)) = ast.pop() {
+				ast.push(Positioned(Stat::Let(result, None, e, None, vec![]), pos));
+			}
+		}
+		let mut ast: Block = enums.iter().cloned().chain(ast).collect();
+
+		let names: SessionBindings = if ast.is_empty() {
+			bindings.to_vec()
+		} else {
+			analysis::final_scope(ast.clone(), bindings.to_vec())?.into_iter()
+				.filter(|binding| binding.kind == analysis::BindingKind::Local)
+				.map(|binding| (Symbol::intern(&binding.name), binding.ty))
+				.collect()
+		};
+		let mut values = vec![if has_result { Expr::Id(result) } else { Expr::Nil }];
+		values.extend(names.iter().map(|(id, _)| Expr::Id(*id)));
+		ast.push(Positioned(Stat::Return(Expr::List(values)), last_pos));
+		self.compile_chunk(String::from("<input>"), ast, bindings.to_vec(), Vec::new(), Type::Any)?;
+
+		let source = if self.embed_source { Some(String::from(input)) } else { None };
+		let program = Program { debug_info: self.debug_info, encoding: self.chunk.encoding, chunks: self.chunk.finish(), source, warnings: self.warnings, tests: vec![], benches: vec![] };
+		Ok((program, new_enums, names))
+	}
+	
+	/// Compiles a string slice containing Hissy code into a [`Program`] whose tests can be run
+	/// one at a time with [`run_test`](crate::vm::run_test), consuming the `Compiler`.
+	///
+	/// Tests are the top-level functions without arguments which are annotated with `@test`,
+	/// or whose name starts with `test_`; they are listed by [`Program::tests`]. The main chunk
+	/// takes the index of a test as argument, and calls it after running the top-level code.
+	pub fn compile_tests(self, input: &str) -> Result<Program, HissyError> {
+		let (program, tests) = self.compile_entry_points(input, "test", "Test", false)?;
+		Ok(Program { tests, ..program })
+	}
+	
+	/// Compiles a string slice containing Hissy code into a [`Program`] whose benchmarks can be run
+	/// one at a time with [`run_bench`](crate::vm::run_bench), consuming the `Compiler`.
+	///
+	/// Benchmarks are the top-level functions without arguments which are annotated with `@bench`,
+	/// or whose name starts with `bench_`; they are listed by [`Program::benches`]. The main chunk
+	/// takes the index of a benchmark and a number of iterations as arguments, and calls the benchmark
+	/// in a loop after running the top-level code.
+	pub fn compile_benches(self, input: &str) -> Result<Program, HissyError> {
+		let (program, benches) = self.compile_entry_points(input, "bench", "Benchmark", true)?;
+		Ok(Program { benches, ..program })
+	}
+	
+	// Entry points are the top-level functions without arguments which are annotated with `@<annotation>`,
+	// or whose name starts with `<annotation>_`; they are returned with their line. The main chunk calls
+	// the entry point of the index it receives as argument, in a loop if `repeat` is set.
+	fn compile_entry_points(mut self, input: &str, annotation: &str, kind: &str, repeat: bool) -> Result<(Program, Vec<(String, u16)>), HissyError> {
+		let mut ast = self.parse(input)?;
+		let prefix = format!("{}_", annotation);
+		let mut entries: Vec<(Symbol, (usize, usize))> = vec![];
+		for Positioned(stat, pos) in &ast {
+			let (id, f, annotations) = match stat {
+				Stat::Let(id, _, f, _, annotations) | Stat::Const(id, f, _, annotations) => (*id, f, annotations),
+				_ => continue,
+			};
+			let annotated = annotations.iter().any(|(name, _)| name.deref() == annotation);
+			match f {
+				Expr::Function(args, ..) if args.is_empty() && (annotated || id.starts_with(&prefix)) => {
+					entries.retain(|(entry, _)| *entry != id); // Redefinitions replace the entry point
+					entries.push((id, *pos));
+				},
+				_ if annotated => return Err(HissyError(ErrorType::Compilation,
+					format!("{} function '{}' cannot take arguments", kind, id), pos.0 as u16)),
+				_ => {},
+			}
+		}
+		
+		let index = Symbol::intern("<index>");
+		let iterations = Symbol::intern("<iterations>");
+		let branches: Vec<Branch> = entries.iter().enumerate().map(|(i, (id, pos))| {
+			let cond = Expr::BinOp(BinOp::Equal, Box::new(Expr::Id(index)), Box::new(Expr::Int(i as i32)));
+			let call = Positioned(Stat::ExprStat(Expr::Call(Box::new(Expr::Id(*id)), vec![])), *pos);
+			let body = if repeat {
+				let remaining = Expr::BinOp(BinOp::Greater, Box::new(Expr::Id(iterations)), Box::new(Expr::Int(0)));
+				let decrement = Expr::BinOp(BinOp::Minus, Box::new(Expr::Id(iterations)), Box::new(Expr::Int(1)));
+				let decrement = Positioned(Stat::Set(LExpr::Id(iterations), decrement), *pos);
+				vec![Positioned(Stat::While(remaining, vec![call, decrement]), *pos)]
+			} else {
+				vec![call]
+			};
+			(Cond::If(cond), body)
+		}).collect();
+		if let Some((_, pos)) = entries.first() {
+			ast.push(Positioned(Stat::Cond(branches), *pos));
+		}
 		
-		self.compile_chunk(String::from("<main>"), ast, Vec::new(), prim_ty!(Nil))?;
+		let mut args = vec![(index, prim_ty!(Int))];
+		if repeat {
+			args.push((iterations, prim_ty!(Int)));
+		}
+		let entries = entries.into_iter().map(|(id, pos)| (String::from(id), pos.0 as u16)).collect();
+		Ok((self.compile_main(Some(input), ast, args)?, entries))
+	}
+	
+	fn compile_main(mut self, input: Option<&str>, ast: ProgramAST, args: Vec<(Symbol, Type)>) -> Result<Program, HissyError> {
+		self.compile_chunk(String::from("<main>"), ast, args, Vec::new(), prim_ty!(Nil))?;
 		
-		Ok(Program { debug_info: self.debug_info, chunks: self.chunk.finish() })
+		let encoding = self.chunk.encoding;
+		let source = if self.embed_source { input.map(String::from) } else { None };
+		Ok(Program { debug_info: self.debug_info, encoding, chunks: self.chunk.finish(), source, warnings: self.warnings, tests: vec![], benches: vec![] })
 	}
 }
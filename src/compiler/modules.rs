@@ -0,0 +1,166 @@
+// Resolution of imports and embedded files.
+//
+// Imports are resolved before compilation, by replacing each top-level `import` statement with the
+// top-level statements of the imported module, so that its declarations are visible to the importer.
+// Each module is only included the first time it is imported, and circular imports are rejected.
+//
+// Similarly, `embed("path")` expressions are replaced by the contents of the file as a string literal,
+// which ends up in the constant pool of the program.
+
+use std::collections::HashSet;
+
+use crate::{HissyError, ErrorType};
+use crate::parser::{parse_with, ast::*, lexer::BlockStyle};
+
+
+/// A strategy for finding the modules imported by programs, set with
+/// [`Compiler::set_resolver`](super::Compiler::set_resolver).
+pub trait ModuleResolver {
+	/// Finds the module imported under a dotted name (eg. `utils.strings`) by the module of
+	/// the given identifier, or by the main program if `importer` is `None`.
+	///
+	/// Returns an identifier which is unique to the module, such as its path, and its source code.
+	fn resolve(&mut self, name: &str, importer: Option<&str>) -> Result<(String, String), String>;
+	
+	/// Reads a file embedded with `embed("path")` in the module of the given identifier,
+	/// or in the main program if `importer` is `None`.
+	///
+	/// By default, embedding files is not supported.
+	fn embed(&mut self, _path: &str, _importer: Option<&str>) -> Result<String, String> {
+		Err(String::from("embedding files is not supported by this module resolver"))
+	}
+}
+
+// Refuses all imports and embedded files, see Compiler::set_untrusted
+pub(super) struct UntrustedResolver;
+
+impl ModuleResolver for UntrustedResolver {
+	fn resolve(&mut self, _name: &str, _importer: Option<&str>) -> Result<(String, String), String> {
+		Err(String::from("imports are not allowed in untrusted code"))
+	}
+	
+	fn embed(&mut self, _path: &str, _importer: Option<&str>) -> Result<String, String> {
+		Err(String::from("embedding files is not allowed in untrusted code"))
+	}
+}
+
+struct Expander<'a> {
+	resolver: Option<&'a mut dyn ModuleResolver>,
+	style: BlockStyle,
+	importing: Vec<String>, // Modules being expanded, to detect cycles
+	included: HashSet<String>,
+}
+
+impl Expander<'_> {
+	fn expand(&mut self, mut ast: Block, importer: Option<&str>) -> Result<Block, HissyError> {
+		self.embed_block(&mut ast, importer)?;
+		let mut res = vec![];
+		for Positioned(stat, pos) in ast {
+			let name = match stat {
+				Stat::Import(name) => name,
+				stat => {
+					res.push(Positioned(stat, pos));
+					continue;
+				},
+			};
+			let error = |s: String| HissyError(ErrorType::Compilation, s, pos.0 as u16);
+			let resolver = self.resolver.as_mut()
+				.ok_or_else(|| error(format!("Cannot import '{}': no module resolver is set", name)))?;
+			let (id, source) = resolver.resolve(&name, importer)
+				.map_err(|err| error(format!("Cannot import '{}': {}", name, err)))?;
+			if self.importing.contains(&id) {
+				return Err(error(format!("Circular import of '{}'", name)));
+			}
+			if !self.included.insert(id.clone()) {
+				continue;
+			}
+			let module = parse_with(&source, self.style).map_err(|HissyError(ty, err, line)|
+				HissyError(ty, format!("In module '{}' at line {}: {}", name, line, err), pos.0 as u16))?;
+			self.importing.push(id.clone());
+			res.extend(self.expand(module, Some(&id))?);
+			self.importing.pop();
+		}
+		Ok(res)
+	}
+	
+	fn embed_block(&mut self, block: &mut Block, importer: Option<&str>) -> Result<(), HissyError> {
+		for Positioned(stat, pos) in block.iter_mut() {
+			let line = pos.0 as u16;
+			match stat {
+				Stat::ExprStat(e) | Stat::Return(e) | Stat::Defer(e) | Stat::Let(_, _, e, _, _) | Stat::Const(_, e, _, _)
+					=> self.embed_expr(e, line, importer)?,
+				Stat::Set(lexpr, e) => {
+					if let LExpr::Index(list, index) = lexpr {
+						self.embed_expr(list, line, importer)?;
+						self.embed_expr(index, line, importer)?;
+					}
+					self.embed_expr(e, line, importer)?;
+				},
+				Stat::Cond(branches) => for (cond, block) in branches.iter_mut() {
+					if let Cond::If(e) = cond {
+						self.embed_expr(e, line, importer)?;
+					}
+					self.embed_block(block, importer)?;
+				},
+				Stat::While(e, block) | Stat::For(_, _, e, block) => {
+					self.embed_expr(e, line, importer)?;
+					self.embed_block(block, importer)?;
+				},
+				Stat::Match(e, arms, else_block) => {
+					self.embed_expr(e, line, importer)?;
+					for (patterns, block) in arms.iter_mut() {
+						for pattern in patterns.iter_mut() {
+							self.embed_expr(pattern, line, importer)?;
+						}
+						self.embed_block(block, importer)?;
+					}
+					if let Some(block) = else_block {
+						self.embed_block(block, importer)?;
+					}
+				},
+				Stat::Enum(_, _) | Stat::Import(_) => {},
+			}
+		}
+		Ok(())
+	}
+	
+	fn embed_expr(&mut self, e: &mut Expr, line: u16, importer: Option<&str>) -> Result<(), HissyError> {
+		match e {
+			Expr::Embed(path) => {
+				let error = |s: String| HissyError(ErrorType::Compilation, format!("Cannot embed '{}': {}", path, s), line);
+				let resolver = self.resolver.as_mut().ok_or_else(|| error(String::from("no module resolver is set")))?;
+				let contents = resolver.embed(path, importer).map_err(error)?;
+				*e = Expr::String(contents);
+			},
+			Expr::List(values) => for e in values.iter_mut() {
+				self.embed_expr(e, line, importer)?;
+			},
+			Expr::BinOp(_, a, b) | Expr::Index(a, b) => {
+				self.embed_expr(a, line, importer)?;
+				self.embed_expr(b, line, importer)?;
+			},
+			Expr::UnaOp(_, a) | Expr::Prop(a, _) => self.embed_expr(a, line, importer)?,
+			Expr::Chain(first, rest) => {
+				self.embed_expr(first, line, importer)?;
+				for (_, e) in rest.iter_mut() {
+					self.embed_expr(e, line, importer)?;
+				}
+			},
+			Expr::Call(f, args) => {
+				self.embed_expr(f, line, importer)?;
+				for e in args.iter_mut() {
+					self.embed_expr(e, line, importer)?;
+				}
+			},
+			Expr::Function(_, _, _, body) => self.embed_block(body, importer)?,
+			_ => {},
+		}
+		Ok(())
+	}
+}
+
+// Replaces the top-level imports of a program with the modules they refer to, and embeds files
+pub(super) fn expand_imports(ast: Block, resolver: Option<&mut dyn ModuleResolver>, style: BlockStyle) -> Result<Block, HissyError> {
+	let mut expander = Expander { resolver, style, importing: vec![], included: HashSet::new() };
+	expander.expand(ast, None)
+}
@@ -0,0 +1,394 @@
+
+// Partial evaluation of the start of programs.
+//
+// The longest sequence of top-level statements at the start of the program which only compute values
+// from literals (eg. tables of constants, or lookup lists filled by loops) is run at compile time,
+// and replaced by definitions of the resulting bindings as literals.
+//
+// Similarly, calls with literal arguments to the const functions declared at the top level are run
+// at compile time, and replaced by their result as a literal.
+
+use std::convert::TryFrom;
+
+use crate::{HissyError, ErrorType};
+use crate::parser::ast::*;
+use crate::vm::{VM, RunStatus};
+use crate::vm::gc::{GCHeap, GCRef};
+use crate::vm::value::Value;
+use crate::vm::object::List;
+use super::{Compiler, Program, Type};
+
+
+// Maximum number of instructions executed at compile time
+const BUDGET: usize = 1_000_000;
+
+// Checks whether an expression only depends on literals and on bindings defined so far.
+// Methods can only be called on values computed in the prefix, so they cannot have outside effects.
+fn is_pure_expr(e: &Expr, defined: &[Symbol]) -> bool {
+	let pure = |e: &Expr| is_pure_expr(e, defined);
+	match e {
+		Expr::Nil | Expr::Bool(_) | Expr::Int(_) | Expr::Real(_) | Expr::Char(_) | Expr::String(_) | Expr::Symbol(_) => true,
+		Expr::Id(id) => defined.contains(id),
+		Expr::List(values) => values.iter().all(pure),
+		Expr::BinOp(_, a, b) | Expr::Index(a, b) => pure(a) && pure(b),
+		Expr::UnaOp(_, a) => pure(a),
+		Expr::Chain(first, rest) => pure(first) && rest.iter().all(|(_, e)| pure(e)),
+		Expr::Call(f, args) => args.iter().all(pure) && match &**f {
+			Expr::Prop(obj, _) => pure(obj),
+			Expr::Id(id) => defined.contains(id), // Only const functions are defined as functions
+			_ => false,
+		},
+		Expr::Prop(_, _) | Expr::Function(_, _, _, _) | Expr::Embed(_) => false,
+	}
+}
+
+fn is_pure_block(block: &Block, defined: &mut Vec<Symbol>) -> bool {
+	let outer = defined.len();
+	let pure = block.iter().all(|stat| is_pure_stat(stat, defined));
+	defined.truncate(outer);
+	pure
+}
+
+fn is_pure_stat(stat: &Stat, defined: &mut Vec<Symbol>) -> bool {
+	match stat {
+		Stat::ExprStat(e) => is_pure_expr(e, defined),
+		Stat::Let(id, _, e, _, _) => {
+			let pure = is_pure_expr(e, defined);
+			defined.push(*id);
+			pure
+		},
+		Stat::Set(LExpr::Id(id), e) => defined.contains(id) && is_pure_expr(e, defined),
+		Stat::Set(LExpr::Index(list, idx), e) =>
+			is_pure_expr(list, defined) && is_pure_expr(idx, defined) && is_pure_expr(e, defined),
+		Stat::Cond(branches) => branches.iter().all(|(cond, block)| {
+			let pure_cond = match cond {
+				Cond::If(e) => is_pure_expr(e, defined),
+				Cond::Else => true,
+			};
+			pure_cond && is_pure_block(block, defined)
+		}),
+		Stat::While(e, block) => is_pure_expr(e, defined) && is_pure_block(block, defined),
+		Stat::For(id, _, e, block) => {
+			if !is_pure_expr(e, defined) {
+				return false;
+			}
+			defined.push(*id);
+			let pure = is_pure_block(block, defined);
+			defined.pop();
+			pure
+		},
+		Stat::Match(e, arms, else_block) => is_pure_expr(e, defined)
+			&& arms.iter().all(|(patterns, block)| patterns.iter().all(|p| is_pure_expr(p, defined)) && is_pure_block(block, defined))
+			&& else_block.iter().all(|block| is_pure_block(block, defined)),
+		Stat::Const(id, f, _, _) => {
+			defined.push(*id);
+			is_pure_function(f, defined)
+		},
+		// Only happens in function bodies: a return in the prefix makes its evaluation fail
+		Stat::Return(e) => is_pure_expr(e, defined),
+		Stat::Defer(_) | Stat::Enum(_, _) | Stat::Import(_) => false,
+	}
+}
+
+// Checks whether a function only depends on its arguments and on the given bindings
+fn is_pure_function(f: &Expr, defined: &mut Vec<Symbol>) -> bool {
+	match f {
+		Expr::Function(args, captures, _, body) if captures.is_empty() => {
+			let outer = defined.len();
+			defined.extend(args.iter().map(|(arg, _)| *arg));
+			let pure = is_pure_block(body, defined);
+			defined.truncate(outer);
+			pure
+		},
+		_ => false,
+	}
+}
+
+// Converts a value back into a literal; fails for other objects, and for lists which are
+// referenced several times, since each literal would create a distinct list.
+fn to_literal(val: &Value, lists: &mut Vec<Value>) -> Option<Expr> {
+	if val.is_nil() {
+		Some(Expr::Nil)
+	} else if let Ok(b) = bool::try_from(val) {
+		Some(Expr::Bool(b))
+	} else if let Ok(i) = i32::try_from(val) {
+		Some(Expr::Int(i))
+	} else if let Ok(r) = f64::try_from(val) {
+		Some(Expr::Real(r))
+	} else if let Ok(c) = char::try_from(val) {
+		Some(Expr::Char(c))
+	} else if let Ok(s) = Symbol::try_from(val) {
+		Some(Expr::Symbol(s))
+	} else if let Some(s) = val.as_str() {
+		Some(Expr::String(String::from(&*s)))
+	} else if let Ok(list) = GCRef::<List>::try_from(val.clone()) {
+		if lists.contains(val) {
+			return None;
+		}
+		lists.push(val.clone());
+		let values: Option<Vec<Expr>> = list.get_copy().iter().map(|val| to_literal(val, lists)).collect();
+		values.map(Expr::List)
+	} else {
+		None
+	}
+}
+
+// Runs the prefix, returning the final value of each of the given bindings as a literal
+fn evaluate(prefix: &[Positioned<Stat>], names: &[Symbol]) -> Option<Vec<Expr>> {
+	let mut stats = prefix.to_vec();
+	let results = Expr::List(names.iter().map(|&id| Expr::Id(id)).collect());
+	stats.push(Positioned(Stat::Return(results), (0, 0)));
+
+	let mut compiler = Compiler::new(false);
+	compiler.compile_chunk(String::from("<main>"), stats, vec![], vec![], Type::Any).ok()?;
+	let program = Program { debug_info: false, encoding: compiler.chunk.encoding, chunks: compiler.chunk.finish(), source: None, warnings: vec![], tests: vec![], benches: vec![] };
+
+	let mut heap = GCHeap::new();
+	let mut vm = VM::new(&mut heap, program);
+	let literals = match vm.run_for(&mut heap, BUDGET) {
+		Ok(RunStatus::Done) => GCRef::<List>::try_from(vm.result().clone()).ok().and_then(|results| {
+			let mut lists = vec![];
+			results.get_copy().iter().map(|val| to_literal(val, &mut lists)).collect()
+		}),
+		_ => None,
+	};
+	drop(vm);
+	heap.collect();
+	literals
+}
+
+fn is_literal(e: &Expr) -> bool {
+	match e {
+		Expr::Nil | Expr::Bool(_) | Expr::Int(_) | Expr::Real(_) | Expr::Char(_) | Expr::String(_) | Expr::Symbol(_) => true,
+		Expr::UnaOp(UnaOp::Minus, e) => matches!(**e, Expr::Int(_) | Expr::Real(_)),
+		Expr::List(values) => values.iter().all(is_literal),
+		_ => false,
+	}
+}
+
+// Replaces calls to const functions by their results, while walking the program in order
+struct ConstFolder {
+	decls: Block, // Declarations of the const functions seen so far
+	consts: Vec<Symbol>, // Names of the const functions which are still bound at the top level
+	shadowed: Vec<Symbol>, // Names of const functions bound to something else in the current scope
+	folded: usize,
+}
+
+impl ConstFolder {
+	fn bind(&mut self, id: Symbol, top_level: bool) {
+		if top_level {
+			self.consts.retain(|c| *c != id);
+		} else if self.consts.contains(&id) {
+			self.shadowed.push(id);
+		}
+	}
+	
+	fn fold_call(&mut self, call: &mut Expr) {
+		let foldable = match call {
+			Expr::Call(f, args) => args.iter().all(is_literal)
+				&& matches!(**f, Expr::Id(id) if self.consts.contains(&id) && !self.shadowed.contains(&id)),
+			_ => false,
+		};
+		if !foldable {
+			return;
+		}
+		let result = Symbol::intern("<result>");
+		let mut stats = self.decls.clone();
+		stats.push(Positioned(Stat::Let(result, None, call.clone(), None, vec![]), (0, 0)));
+		if let Some(literal) = evaluate(&stats, &[result]).and_then(|mut literals| literals.pop()) {
+			*call = literal;
+			self.folded += 1;
+		}
+	}
+	
+	fn fold_expr(&mut self, e: &mut Expr) {
+		match e {
+			Expr::List(values) => values.iter_mut().for_each(|e| self.fold_expr(e)),
+			Expr::BinOp(_, a, b) | Expr::Index(a, b) => {
+				self.fold_expr(a);
+				self.fold_expr(b);
+			},
+			Expr::UnaOp(_, a) | Expr::Prop(a, _) => self.fold_expr(a),
+			Expr::Chain(first, rest) => {
+				self.fold_expr(first);
+				rest.iter_mut().for_each(|(_, e)| self.fold_expr(e));
+			},
+			Expr::Call(f, args) => {
+				self.fold_expr(f);
+				args.iter_mut().for_each(|e| self.fold_expr(e));
+				self.fold_call(e);
+			},
+			Expr::Function(args, _, _, body) => {
+				let outer = self.shadowed.len();
+				for (arg, _) in args.iter() {
+					self.bind(*arg, false);
+				}
+				self.fold_block(body, false).expect("Only top-level declarations are checked");
+				self.shadowed.truncate(outer);
+			},
+			_ => {},
+		}
+	}
+	
+	fn fold_nested(&mut self, block: &mut Block, bound: Option<Symbol>) {
+		let outer = self.shadowed.len();
+		if let Some(id) = bound {
+			self.bind(id, false);
+		}
+		self.fold_block(block, false).expect("Only top-level declarations are checked");
+		self.shadowed.truncate(outer);
+	}
+	
+	fn fold_block(&mut self, block: &mut Block, top_level: bool) -> Result<(), HissyError> {
+		let outer = self.shadowed.len();
+		for Positioned(stat, pos) in block.iter_mut() {
+			match stat {
+				Stat::ExprStat(e) | Stat::Return(e) | Stat::Defer(e) | Stat::Set(LExpr::Id(_), e) => self.fold_expr(e),
+				Stat::Let(id, _, e, _, _) => {
+					let recursive = matches!(e, Expr::Function(_, _, _, _));
+					if recursive {
+						self.bind(*id, top_level);
+					}
+					self.fold_expr(e);
+					if !recursive {
+						self.bind(*id, top_level);
+					}
+				},
+				Stat::Const(id, f, _, _) if top_level => {
+					let mut consts = self.consts.clone();
+					consts.push(*id);
+					if !is_pure_function(f, &mut consts) {
+						return Err(HissyError(ErrorType::Compilation,
+							format!("Const function '{}' can only use its arguments, literals and other const functions", id),
+							pos.0 as u16));
+					}
+					self.consts.retain(|c| c != id);
+					self.consts.push(*id);
+					self.decls.push(Positioned(stat.clone(), *pos));
+					if let Stat::Const(_, f, _, _) = stat {
+						self.fold_expr(f);
+					}
+				},
+				Stat::Const(id, f, _, _) => {
+					self.bind(*id, false);
+					self.fold_expr(f);
+				},
+				Stat::Set(LExpr::Index(list, idx), e) => {
+					self.fold_expr(list);
+					self.fold_expr(idx);
+					self.fold_expr(e);
+				},
+				Stat::Cond(branches) => for (cond, block) in branches.iter_mut() {
+					if let Cond::If(e) = cond {
+						self.fold_expr(e);
+					}
+					self.fold_nested(block, None);
+				},
+				Stat::While(e, block) => {
+					self.fold_expr(e);
+					self.fold_nested(block, None);
+				},
+				Stat::For(id, _, e, block) => {
+					self.fold_expr(e);
+					self.fold_nested(block, Some(*id));
+				},
+				Stat::Match(e, arms, else_block) => {
+					self.fold_expr(e);
+					for (patterns, block) in arms.iter_mut() {
+						patterns.iter_mut().for_each(|e| self.fold_expr(e));
+						self.fold_nested(block, None);
+					}
+					if let Some(block) = else_block {
+						self.fold_nested(block, None);
+					}
+				},
+				Stat::Enum(_, _) | Stat::Import(_) => {},
+			}
+		}
+		self.shadowed.truncate(outer);
+		Ok(())
+	}
+}
+
+// Returns the program with calls to const functions evaluated, if it has any which can be evaluated
+pub(super) fn fold_const_calls(ast: &Block) -> Result<Option<Block>, HissyError> {
+	let mut folder = ConstFolder { decls: vec![], consts: vec![], shadowed: vec![], folded: 0 };
+	let mut folded = ast.clone();
+	folder.fold_block(&mut folded, true)?;
+	Ok(if folder.folded > 0 { Some(folded) } else { None })
+}
+
+// Returns the program with its pure prefix evaluated, if it has one which can be evaluated
+pub(super) fn fold_prefix(ast: &Block) -> Option<Block> {
+	let mut defined = vec![];
+	let len = ast.iter().take_while(|stat| is_pure_stat(stat, &mut defined)).count();
+	let prefix = &ast[..len];
+
+	// The last definition of each top-level binding is replaced by one with its final value
+	let lets: Vec<&Positioned<Stat>> = prefix.iter().enumerate()
+		.filter(|(i, stat)| match &stat.0 {
+			Stat::Let(id, _, _, _, _) => !prefix[i+1..].iter().any(|stat2| matches!(&stat2.0, Stat::Let(id2, _, _, _, _) if id2 == id)),
+			_ => false,
+		})
+		.map(|(_, stat)| stat)
+		.collect();
+	if lets.is_empty() {
+		return None;
+	}
+	let names: Vec<Symbol> = lets.iter().map(|stat| match &stat.0 {
+		Stat::Let(id, _, _, _, _) => *id,
+		_ => unreachable!(),
+	}).collect();
+
+	let literals = evaluate(prefix, &names)?;
+	let mut folded: Block = lets.into_iter().zip(literals).map(|(stat, literal)| match &stat.0 {
+		Stat::Let(id, ty, _, doc, annotations) => Positioned(Stat::Let(*id, ty.clone(), literal, doc.clone(), annotations.clone()), stat.1),
+		_ => unreachable!(),
+	}).collect();
+	folded.extend_from_slice(&ast[len..]);
+	Some(folded)
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::parser::parse;
+
+	fn folded_stats(src: &str) -> Option<Vec<Stat>> {
+		fold_prefix(&parse(src).unwrap()).map(|block| block.into_iter().map(|stat| stat.0).collect())
+	}
+
+	#[test]
+	fn test_fold_prefix() {
+		let src = "let n = 4\nlet squares = []\nlet i = 0\nwhile i < n:\n\tsquares.add(i * i)\n\ti = i + 1\nlet name = \"sq\"\nlog(squares)\nlet m = n\n";
+		let stats = folded_stats(src).unwrap();
+		let expected = parse("let n = 4\nlet squares = [0, 1, 4, 9]\nlet i = 4\nlet name = \"sq\"\nlog(squares)\nlet m = n\n").unwrap();
+		assert_eq!(stats, expected.into_iter().map(|stat| stat.0).collect::<Vec<Stat>>());
+
+		let mut compiler = Compiler::new(false);
+		compiler.set_partial_eval(true);
+		let program = compiler.compile_program(src).unwrap();
+		assert!(program.chunks[0].decoded().code.len() < Compiler::new(false).compile_program(src).unwrap().chunks[0].decoded().code.len());
+
+		assert_eq!(folded_stats("log(1)\nlet a = 1\n"), None); // No pure prefix
+		assert_eq!(folded_stats("let a = [1]\nlet b = [a]\nlog(b)\n"), None); // Shared list
+		assert_eq!(folded_stats("let a = 1\nwhile true:\n\ta = a + 1\n"), None); // Infinite loop
+		assert_eq!(folded_stats("let a = [1]\nlet b = a[3]\n"), None); // Runtime error
+	}
+	
+	#[test]
+	fn test_fold_const_calls() {
+		let consts = "const fact(n: Int) -> Int:\n\tif n <= 1:\n\t\treturn 1\n\treturn n * fact(n - 1)\nconst squares(n: Int) -> Any:\n\tlet l = []\n\twhile l.size() < n:\n\t\tl.add(fact(l.size()))\n\treturn l\n";
+		let src = format!("{}let x = 3\nlog(fact(5), fact(x), squares(3))\nlet f() -> Int:\n\tlet fact = fun(n: Int) -> Int:\n\t\treturn n\n\treturn fact(4)\n", consts);
+		let folded = fold_const_calls(&parse(&src).unwrap()).unwrap().unwrap();
+		let expected = parse(&format!("{}let x = 3\nlog(120, fact(x), [1, 1, 2])\nlet f() -> Int:\n\tlet fact = fun(n: Int) -> Int:\n\t\treturn n\n\treturn fact(4)\n", consts)).unwrap();
+		assert_eq!(folded.into_iter().map(|stat| stat.0).collect::<Vec<Stat>>(), expected.into_iter().map(|stat| stat.0).collect::<Vec<Stat>>());
+		assert!(Compiler::new(false).compile_program(&src).is_ok());
+		
+		assert_eq!(fold_const_calls(&parse("const f(n: Int) -> Int:\n\treturn f(n)\nlog(f(1))\n").unwrap()).unwrap(), None); // Infinite recursion
+		let err = fold_const_calls(&parse("let a = 1\nconst f(n: Int) -> Int:\n\treturn n + a\n").unwrap()).unwrap_err();
+		assert_eq!((err.1.as_str(), err.2), ("Const function 'f' can only use its arguments, literals and other const functions", 2));
+		assert!(fold_const_calls(&parse("const f(n: Int) -> Nil:\n\tlog(n)\n").unwrap()).is_err());
+	}
+}
@@ -1,18 +1,27 @@
 
 use std::fmt;
 
+use crate::parser::symbol::Symbol;
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum PrimitiveType {
 	Nil,
 	Bool,
 	Int,
 	Real,
+	Char,
 	String,
+	Symbol,
 }
 
 #[derive(Clone, PartialEq, Eq)]
 pub enum Type {
 	Primitive(PrimitiveType),
+	Vector(u8),
+	Tensor,
+	Channel,
+	Bytes,
+	Connection,
 	
 	List(Box<Type>),
 	Iterator(Box<Type>),
@@ -21,6 +30,9 @@ pub enum Type {
 	
 	Namespace(Vec<(String, Type)>),
 	
+	// Values are symbols named after the variants, which are only known to the compiler
+	Enum(Symbol),
+	
 	Any,
 }
 
@@ -33,6 +45,11 @@ impl fmt::Debug for Type {
 	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
 		match self {
 			Type::Primitive(pt) => write!(f, "{:?}", pt),
+			Type::Vector(n) => write!(f, "Vec{}", n),
+			Type::Tensor => write!(f, "Tensor"),
+			Type::Channel => write!(f, "Channel"),
+			Type::Bytes => write!(f, "Bytes"),
+			Type::Connection => write!(f, "Connection"),
 			Type::List(ty) => write!(f, "List<{:?}>", ty),
 			Type::TypedFunction(args_ty, res_ty) => {
 				write!(f, "(")?;
@@ -47,6 +64,7 @@ impl fmt::Debug for Type {
 			Type::UntypedFunction(res_ty) => write!(f, "(...) -> {:?}", res_ty),
 			Type::Iterator(ty) => write!(f, "Iterator<{:?}>", ty),
 			Type::Namespace(_) => write!(f, "Namespace"),
+			Type::Enum(name) => write!(f, "{}", name),
 			Type::Any => write!(f, "Any"),
 		}
 	}
@@ -60,15 +78,24 @@ impl Type {
 		}
 	}
 	
+	pub fn is_vector(&self) -> bool {
+		matches!(self, Type::Vector(_))
+	}
+	
 	pub fn can_assign(&self, other: &Type) -> bool {
 		match self {
 			Type::Primitive(t1) => {
-				if let Type::Primitive(t2) = other {
-					t1 == t2
-				} else {
-					false
+				match other {
+					Type::Primitive(t2) => t1 == t2,
+					Type::Enum(_) => t1 == &PrimitiveType::Symbol,
+					_ => false,
 				}
 			},
+			Type::Vector(n1) => other == &Type::Vector(*n1),
+			Type::Tensor => other == &Type::Tensor,
+			Type::Channel => other == &Type::Channel,
+			Type::Bytes => other == &Type::Bytes,
+			Type::Connection => other == &Type::Connection,
 			Type::List(t1) => {
 				if let Type::List(t2) = other {
 					t1.can_assign(t2)
@@ -101,6 +128,7 @@ impl Type {
 				}
 			},
 			Type::Namespace(_) => false,
+			Type::Enum(_) => other == self,
 			Type::Any => true,
 		}
 	}
@@ -109,6 +137,12 @@ impl Type {
 		match self {
 			Type::List(_) => Some(String::from("List")),
 			Type::Iterator(_) => Some(String::from("Iterator")),
+			Type::Vector(n) => Some(format!("Vec{}", n)),
+			Type::Tensor => Some(String::from("Tensor")),
+			Type::Channel => Some(String::from("Channel")),
+			Type::Bytes => Some(String::from("Bytes")),
+			Type::Connection => Some(String::from("Connection")),
+			prim_ty!(String) => Some(String::from("String")),
 			_ => None,
 		}
 	}
@@ -0,0 +1,137 @@
+
+// A small LZSS compressor for bytecode files, which avoids depending on an external library.
+//
+// The compressed stream is made of groups of up to 8 items, each group preceded by a flag byte:
+// if bit i is set, item i is a back-reference into the output (two bytes, little-endian,
+// holding the distance minus 1 in the upper 12 bits and the length minus MIN_MATCH in the lower 4),
+// otherwise it is a literal byte.
+
+use crate::{HissyError, ErrorType};
+use crate::serial::{read_u8, read_u16};
+
+
+fn error_str(s: &str) -> HissyError {
+	HissyError(ErrorType::IO, String::from(s), 0)
+}
+
+const WINDOW: usize = 1 << 12;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = MIN_MATCH + 15;
+const HASH_BITS: usize = 12;
+
+fn hash(bytes: &[u8]) -> usize {
+	let h = u32::from(bytes[0]) << 16 | u32::from(bytes[1]) << 8 | u32::from(bytes[2]);
+	(h.wrapping_mul(0x9e37_79b1) >> (32 - HASH_BITS)) as usize
+}
+
+pub fn compress(input: &[u8]) -> Vec<u8> {
+	let mut out = vec![];
+	let mut last_seen = vec![usize::MAX; 1 << HASH_BITS]; // Last position of each 3-byte sequence hash
+	let mut flags_pos = 0;
+	let mut item = 8;
+	let mut pos = 0;
+	while pos < input.len() {
+		if item == 8 {
+			flags_pos = out.len();
+			out.push(0);
+			item = 0;
+		}
+
+		let mut len = 0;
+		let mut dist = 0;
+		if pos + MIN_MATCH <= input.len() {
+			let h = hash(&input[pos..]);
+			let candidate = last_seen[h];
+			last_seen[h] = pos;
+			if candidate != usize::MAX && pos - candidate <= WINDOW {
+				let max_len = MAX_MATCH.min(input.len() - pos);
+				while len < max_len && input[candidate + len] == input[pos + len] {
+					len += 1;
+				}
+				dist = pos - candidate;
+			}
+		}
+
+		if len >= MIN_MATCH {
+			out[flags_pos] |= 1 << item;
+			out.extend(&(((dist - 1) << 4 | (len - MIN_MATCH)) as u16).to_le_bytes());
+			for p in pos + 1 .. (pos + len).min(input.len() + 1 - MIN_MATCH) {
+				last_seen[hash(&input[p..])] = p;
+			}
+			pos += len;
+		} else {
+			out.push(input[pos]);
+			pos += 1;
+		}
+		item += 1;
+	}
+	out
+}
+
+// Decompresses data whose decompressed length is expected to be len, as read from an untrusted header
+pub fn decompress<'a>(it: &mut impl ExactSizeIterator<Item = &'a u8>, len: usize) -> Result<Vec<u8>, HissyError> {
+	// No input byte expands to more than MAX_MATCH bytes
+	let mut out = Vec::with_capacity(len.min(it.len().saturating_mul(MAX_MATCH)));
+	while out.len() < len {
+		let flags = read_u8(it)?;
+		for item in 0..8 {
+			if out.len() >= len {
+				break;
+			}
+			if flags & (1 << item) != 0 {
+				let code = usize::from(read_u16(it)?);
+				let dist = (code >> 4) + 1;
+				if dist > out.len() {
+					return Err(error_str("Invalid back-reference in compressed data"));
+				}
+				for _ in 0..(code & 0xf) + MIN_MATCH {
+					out.push(out[out.len() - dist]);
+				}
+			} else {
+				out.push(read_u8(it)?);
+			}
+		}
+	}
+	if out.len() != len {
+		return Err(error_str("Invalid length of compressed data"));
+	}
+	Ok(out)
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_round_trip() {
+		let inputs: Vec<Vec<u8>> = vec![
+			vec![],
+			b"ab".to_vec(),
+			b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa".to_vec(),
+			b"log(\"hello\")\nlog(\"hello, world\")\nlog(\"hello, world\")\n".repeat(20),
+			(0..10000u32).map(|i| (i * 7 % 251) as u8).collect(),
+		];
+		for input in inputs {
+			let compressed = compress(&input);
+			assert_eq!(decompress(&mut compressed.iter(), input.len()).unwrap(), input);
+		}
+		let text = b"let greeting = \"hello\"\n".repeat(50);
+		assert!(compress(&text).len() < text.len() / 4);
+		assert!(decompress(&mut [1, 0, 0].iter(), 3).is_err());
+		// The length comes from the file, and must match the data
+		assert!(decompress(&mut [0, 1].iter(), u32::MAX as usize).is_err());
+		assert!(decompress(&mut [0b10, 1, 0, 0].iter(), 2).is_err());
+		assert_eq!(decompress(&mut [0b10, 1, 0, 0].iter(), 4).unwrap(), [1, 1, 1, 1]);
+	}
+	
+	#[test]
+	fn test_compressed_program() {
+		let src = "log(\"a string which is long enough to compress\")\n".repeat(20);
+		let program = crate::compiler::Compiler::new(true).compile_program(&src).unwrap();
+		let compressed = program.to_compressed_bytes().unwrap();
+		assert!(compressed.len() < program.to_bytes().unwrap().len() / 2);
+		let loaded = crate::compiler::Program::from_bytes(&compressed).unwrap();
+		assert_eq!(loaded.to_bytes().unwrap(), program.to_bytes().unwrap());
+	}
+}
@@ -0,0 +1,655 @@
+
+use std::sync::{Arc, OnceLock, RwLock};
+
+use crate::{HissyError, ErrorType};
+
+
+/// An entry of the error catalog: a stable code shared by related errors, and its explanation.
+#[derive(Debug)]
+pub struct ErrorInfo {
+	/// Code of the error, eg. `E101`
+	pub code: &'static str,
+	pub ty: ErrorType,
+	/// Short description, printed in error messages
+	pub summary: &'static str,
+	/// Templates of the messages of errors with this code, where `{}` stands for any text;
+	/// the entry of each error type without templates gathers the remaining errors of that type
+	pub messages: &'static [&'static str],
+	/// Extended description, with examples, printed by `hissy explain`
+	pub explanation: &'static str,
+}
+
+/// All the error codes, grouped by error type: `E0xx` for syntax errors, `E1xx` for compilation
+/// errors, `E2xx` for execution errors, `E3xx` for interruptions, and `E4xx` for IO errors.
+///
+/// Codes are never reused or renumbered, so they can be searched for and relied upon by tools.
+pub static CATALOG: &[ErrorInfo] = &[
+	ErrorInfo {
+		code: "E000", ty: ErrorType::Syntax, summary: "Syntax error", messages: &[],
+		explanation: "The source code is not valid Hissy code.",
+	},
+	ErrorInfo {
+		code: "E001", ty: ErrorType::Syntax, summary: "Unexpected token",
+		messages: &["Near {}, expected {}"],
+		explanation: "\
+The parser found a token which cannot appear at this point of the program.
+The message lists the tokens which would have been accepted instead.
+
+	let x = (1 + 2
+	log(x)
+
+Here, the parenthesis is never closed, so a ')' is expected before the end of the line.",
+	},
+	ErrorInfo {
+		code: "E002", ty: ErrorType::Syntax, summary: "Unexpected character",
+		messages: &["Unexpected character {}"],
+		explanation: "\
+The source code contains a character which is not part of any token, outside of strings and comments.
+
+	let price = 5$
+
+Identifiers are made of letters, digits and underscores; '$' cannot be used in them.",
+	},
+	ErrorInfo {
+		code: "E003", ty: ErrorType::Syntax, summary: "Unfinished literal or comment",
+		messages: &["Unfinished string literal", "EOL in the middle of string", "Unfinished character literal", "Unfinished block comment"],
+		explanation: "\
+A string, character literal or block comment is still open at the end of the line or file.
+
+	log(\"Hello)
+
+Strings cannot span several lines: close them on the line they start on, and use \\n for line breaks.",
+	},
+	ErrorInfo {
+		code: "E004", ty: ErrorType::Syntax, summary: "Invalid literal",
+		messages: &["Empty character literal", "Character literal must contain a single character",
+			"Invalid escape sequence '\\{}' in character", "Invalid escape sequence '\\{}' in string"],
+		explanation: "\
+A character or string literal is malformed.
+
+	let c = 'ab'
+	let s = \"C:\\dir\"
+
+Character literals hold exactly one character, and backslashes start escape sequences
+(\\n, \\t, \\\\, \\', \\\"), so a literal backslash must be written \\\\.",
+	},
+	ErrorInfo {
+		code: "E005", ty: ErrorType::Syntax, summary: "Invalid block structure",
+		messages: &["Invalid indentation {}", "Missing 'end' at end of file", "Unexpected '{}' outside of a block", "Unexpected closing delimiter"],
+		explanation: "\
+The blocks of the program are not properly nested.
+
+	if x > 0:
+		log(x)
+	  log(-x)
+
+With indentation-based blocks, each line must be indented with tabs, at the level of an enclosing block.
+With --end-blocks, each block must be closed by 'end', and 'end' can only close an open block.",
+	},
+	ErrorInfo {
+		code: "E006", ty: ErrorType::Syntax, summary: "Invalid manifest",
+		messages: &["Invalid manifest: {}"],
+		explanation: "\
+The hissy.toml manifest of a package could not be parsed.
+
+	[package]
+	name = \"app\"
+	[dependencies]
+	utils { path = \"../utils\" }
+
+Keys must be followed by '=' and a string or an inline table, and the package must have a name.",
+	},
+
+	ErrorInfo {
+		code: "E007", ty: ErrorType::Syntax, summary: "Code nested too deeply",
+		messages: &["Code nested too deeply (more than {} levels)"],
+		explanation: "\
+Expressions and blocks are nested deeper than the parser and compiler allow, eg. with thousands
+of parentheses or prefix operators, or a very long chain of operators in a single expression.
+
+	let x = ((((((((...(1)...))))))))  # with more than 128 parentheses
+
+This usually happens with generated code: split deep expressions into several variables,
+and long sums into several statements.",
+	},
+
+	ErrorInfo {
+		code: "E100", ty: ErrorType::Compilation, summary: "Compilation error", messages: &[],
+		explanation: "The program is syntactically valid, but could not be compiled.",
+	},
+	ErrorInfo {
+		code: "E101", ty: ErrorType::Compilation, summary: "Undefined name",
+		messages: &["Referencing undefined binding '{}'", "Unknown type name '{}'", "Enum {} has no variant '{}'"],
+		explanation: "\
+A variable, function, type or enum variant is used, but is not defined at this point.
+
+	let total = count + 1
+	let count = 2
+
+Variables must be defined before their use, in the current block or an enclosing one;
+check the spelling of the name, and that it was imported if it comes from another module.",
+	},
+	ErrorInfo {
+		code: "E102", ty: ErrorType::Compilation, summary: "Invalid operand types",
+		messages: &["Cannot use numeric operator on {}", "Cannot use comparison operator on {} and {}",
+			"Cannot use boolean operator on {}", "Cannot compare {} and {}"],
+		explanation: "\
+An operator is applied to values of types it does not support.
+
+	let n = 2
+	if n and true:
+		log(n)
+
+Arithmetic operators and ordering comparisons take numbers, while 'and', 'or' and 'not'
+take Bools; convert the values, or compare them explicitly (eg. 'n != 0').",
+	},
+	ErrorInfo {
+		code: "E103", ty: ErrorType::Compilation, summary: "Type mismatch",
+		messages: &["Cannot define variable of type {} with expression of type {}", "Cannot assign type {} to variable of type {}",
+			"Cannot assign type {} into list of {}", "Trying to return {}, expected {}", "Expected argument of type {}, got {}",
+			"Expected boolean in condition, got {}", "Expected Int as bound of range, got {}",
+			"Cannot match value of type {} against {}", "Cannot define variable of type {} from iterator on type {}"],
+		explanation: "\
+A value does not have the type required where it is used.
+
+	let f(x: Int) -> Int:
+		return x * 2
+	log(f(\"3\"))
+
+The types of variables, arguments and return values are checked during compilation;
+convert the value (eg. with int() or string()), or change the declared type.",
+	},
+	ErrorInfo {
+		code: "E104", ty: ErrorType::Compilation, summary: "Wrong number of arguments",
+		messages: &["Expected {} arguments in function call, got {}"],
+		explanation: "\
+A function is called with more or fewer arguments than it declares.
+
+	let add(a: Int, b: Int) -> Int:
+		return a + b
+	log(add(1))
+
+Hissy has no default arguments: pass a value for each parameter.",
+	},
+	ErrorInfo {
+		code: "E105", ty: ErrorType::Compilation, summary: "Invalid call, property or index",
+		messages: &["Cannot call non-function type {}", "Cannot call undefined property {} of type {}",
+			"Type {} does not have a property {}", "Cannot index object of type {}", "Cannot index list with {}",
+			"{} is not an iterable type"],
+		explanation: "\
+A value is called, indexed, iterated on, or has a property read, which its type does not allow.
+
+	let n = 3
+	log(n.size())
+	for x in n:
+		log(x)
+
+Only functions can be called, lists indexed with Ints and iterated on, and properties must
+exist on the type; see the documentation of the type for its methods.",
+	},
+	ErrorInfo {
+		code: "E106", ty: ErrorType::Compilation, summary: "Duplicate definition",
+		messages: &["Type '{}' is already defined", "Variant '{}' is declared twice in enum {}", "Variable '{}' is captured twice"],
+		explanation: "\
+The same name is declared twice where names must be unique.
+
+	enum Color: Red, Green, Red
+
+Rename or remove one of the declarations.",
+	},
+	ErrorInfo {
+		code: "E107", ty: ErrorType::Compilation, summary: "Invalid annotation",
+		messages: &["Unknown annotation '@{}'", "Annotation '@{}' takes at most {} argument(s)"],
+		explanation: "\
+A function declaration has an annotation which does not exist, or too many arguments.
+
+	@tset
+	let check():
+		pass
+
+The supported annotations are @test, @bench, @inline and @deprecated(message).",
+	},
+	ErrorInfo {
+		code: "E108", ty: ErrorType::Compilation, summary: "Import error",
+		messages: &["Module '{}' must be imported at the top level", "Cannot import '{}': {}", "Circular import of '{}'", "Cannot embed '{}': {}"],
+		explanation: "\
+A module could not be imported, or a file could not be embedded.
+
+	let setup():
+		import utils
+
+Imports are only allowed at the top level of a module, modules cannot import each other in a cycle,
+and imported modules and embedded files are only found if the host sets a module resolver
+(which the hissy command does for packages).",
+	},
+	ErrorInfo {
+		code: "E109", ty: ErrorType::Compilation, summary: "Declaration outside of the top level",
+		messages: &["{} functions must be declared at the top level"],
+		explanation: "\
+A function with a special role (eg. a test or a constant function) is declared inside a block.
+
+	let suite():
+		@test
+		let check():
+			pass
+
+Move the declaration to the top level of the module.",
+	},
+	ErrorInfo {
+		code: "E110", ty: ErrorType::Compilation, summary: "Program too large",
+		messages: &["Cannot compile: Too many registers required", "Too many chunks", "Too many function arguments",
+			"Too many upvalues in chunk", "Too many values in list", "Too many constants required", "Jump too large",
+			"Namespace has too many methods", "Code too long for line numbers", "Line number too large"],
+		explanation: "\
+A function exceeds one of the limits of the bytecode format, such as 256 registers per function.
+
+	let big = [1, 2, 3, ...]
+
+Split large functions and list literals into smaller ones, or compile with --wide,
+which allows longer jumps.",
+	},
+	ErrorInfo {
+		code: "E111", ty: ErrorType::Compilation, summary: "Assignment to external value",
+		messages: &["Cannot set external value '{}'"],
+		explanation: "\
+A value provided by the standard library or the host is assigned to.
+
+	log = 1
+
+External values are read-only; define a new variable with 'let' instead.",
+	},
+	ErrorInfo {
+		code: "E112", ty: ErrorType::Compilation, summary: "Invalid rename",
+		messages: &["'{}' is not a valid identifier", "Cannot rename external binding '{}'",
+			"Renaming '{}' to '{}' would change the meaning of the program",
+			"Unable to find identifier '{}' in the source", "Unable to resolve identifier '{}'"],
+		explanation: "\
+An identifier could not be renamed by the editor tooling.
+
+	let x = 1
+	let y = 2
+	log(x + y)
+
+Renaming x to y here would make both references point to the same variable, so it is refused.",
+	},
+
+	ErrorInfo {
+		code: "E200", ty: ErrorType::Execution, summary: "Execution error", messages: &[],
+		explanation: "The program was compiled, but an error happened while running it.",
+	},
+	ErrorInfo {
+		code: "E201", ty: ErrorType::Execution, summary: "Invalid argument",
+		messages: &["Expected {} value, got {}", "Expected integer index, got {}", "Expected tensor, got {}",
+			"Expected tensor or number, got {}", "Expected non-negative integer dimension, got {}",
+			"Expected positive byte count, got {}", "Expected byte value between 0 and 255, got {}",
+			"Invalid character code point {}", "Invalid clamping interval [{}, {}]", "Invalid sleep duration {}",
+			"Expected argument '{}' of type {}, got {}"],
+		explanation: "\
+A native function received an argument of the wrong type, or outside of the values it accepts.
+
+	let f = fun(x):
+		return x
+	log(char(f(-1)))
+
+Arguments whose type is not known during compilation are checked when the function is called.",
+	},
+	ErrorInfo {
+		code: "E202", ty: ErrorType::Execution, summary: "Wrong number of arguments",
+		messages: &["Expected {} argument, got {}", "Expected {} arguments, got {}", "Expected {} arguments in function call, got {}"],
+		explanation: "\
+A function received the wrong number of arguments: a native function, a compiled function called by the host,
+or a Hissy function called through a value whose type is not known during compilation.
+
+Calls to functions whose type is not known during compilation are only checked when they happen;
+check the signature of the function in its documentation.",
+	},
+	ErrorInfo {
+		code: "E203", ty: ErrorType::Execution, summary: "Index out of bounds",
+		messages: &["Can't get value at index {} in list of length {}", "Can't set value at index {} in list of length {}",
+			"Index {} out of bounds for {} bytes", "Cannot index list with negative integer", "Can't get index {} in namespace with {} elements"],
+		explanation: "\
+A list or buffer is accessed at an index which does not exist.
+
+	let l = [1, 2, 3]
+	log(l[3])
+
+Indices start at 0, so the last element of a list is at index l.size() - 1.",
+	},
+	ErrorInfo {
+		code: "E204", ty: ErrorType::Execution, summary: "Invalid operand",
+		messages: &["Cannot index list with non-integer", "Cannot index non-list value", "Cannot negate value!",
+			"Cannot apply logical NOT to value", "Cannot div these values", "Non-bool used in condition",
+			"Bounds of for loop must be integers", "Step of for loop cannot be zero", "Cannot call value {}",
+			"Cannot call method {}", "{} is not a method", "Cannot send {} through a channel",
+			"Cannot send a list containing itself through a channel",
+			"Cannot use ListExtend on non-List value"],
+		explanation: "\
+An operation was applied to a value of a type it does not support, which could not be detected
+during compilation because the type of the value was unknown.
+
+	let f = fun(x):
+		return -x
+	log(f(\"a\"))
+
+Add type annotations to catch these errors during compilation.",
+	},
+	ErrorInfo {
+		code: "E205", ty: ErrorType::Execution, summary: "Division by zero",
+		messages: &["Integer division by zero", "Integer modulo by zero"],
+		explanation: "\
+An Int is divided by zero, or its remainder by zero is computed.
+
+	let n = 0
+	log(10 % n)
+
+Check the divisor first; dividing Reals by zero gives an infinity or NaN instead.",
+	},
+	ErrorInfo {
+		code: "E206", ty: ErrorType::Execution, summary: "Operation not allowed",
+		messages: &["Native '{}' is not allowed by the host", "Native '{}' is not allowed in this sandbox", "Call to native '{}' was denied",
+			"Native '{}' cannot run while a native hook is set"],
+		explanation: "\
+The program called a native function that the host does not allow.
+
+	log(env(\"HOME\"))
+
+The hissy command denies access to the environment, the process exit code, other programs and
+the network unless --allow-env, --allow-exit, --allow-exec or --allow-net is passed.",
+	},
+	ErrorInfo {
+		code: "E207", ty: ErrorType::Execution, summary: "Resource limit exceeded",
+		messages: &["Fuel limit of {} instructions exceeded", "Memory limit of {}B exceeded", "Recursion limit of {} nested calls exceeded"],
+		explanation: "\
+The program used more instructions, memory or nested calls than its sandbox allows.
+
+	let f(n: Int) -> Int:
+		return f(n + 1)
+	f(0)
+
+Look for unbounded recursion or loops; if the usage is expected, the host can raise the limits.",
+	},
+	ErrorInfo {
+		code: "E208", ty: ErrorType::Execution, summary: "Native function failed",
+		messages: &["Native function panicked: {}", "Command '{}' failed with exit code {}: {}", "Unable to run '{}': {}"],
+		explanation: "\
+A native function, or a program it ran, failed.
+
+	exec(\"ls\", [\"/missing\"])
+
+The message includes the error reported by the function or the program.",
+	},
+	ErrorInfo {
+		code: "E209", ty: ErrorType::Execution, summary: "Network error",
+		messages: &["Connection error: {}", "Unable to connect to {}:{}: {}", "Request to '{}' failed{}",
+			"Malformed HTTP response from '{}'", "Unsupported URL '{}'{}", "Invalid port in URL '{}'"],
+		explanation: "\
+A network request failed.
+
+	http_get(\"https://example.com\")
+
+Only http:// URLs are supported; check that the server is reachable and that the URL is correct.",
+	},
+	ErrorInfo {
+		code: "E210", ty: ErrorType::Execution, summary: "Invalid tensor shape",
+		messages: &["Cannot broadcast tensors of shapes {} and {}", "Cannot make tensor from {}", "Cannot make tensor of shape {} from {} values",
+			"Cannot multiply matrices of shapes {} and {}", "Cannot transpose tensor of shape {}", "Ragged nested lists cannot be made into a tensor",
+			"Cannot make tensor from a list containing itself", "Tensor of shape {} has more than {} values"],
+		explanation: "\
+A tensor operation received tensors whose shapes are incompatible, or would make a tensor too large to be stored.
+
+	tensor([[1, 2], [3]])
+
+Nested lists must all have the same length, and the dimensions of operands must match or be 1.
+A tensor can hold up to 2^27 values.",
+	},
+	ErrorInfo {
+		code: "E211", ty: ErrorType::Execution, summary: "Invalid pending operation",
+		messages: &["Unsupported pending operation: {}", "Script is waiting on a pending operation", "Script is not suspended",
+			"Function is waiting on a pending operation", "par_map function cannot wait on pending operations"],
+		explanation: "\
+A script waited on an operation (eg. a sleep or a network request) that the host cannot perform there.
+
+	par_map([1, 2]) fun(x):
+		sleep(1)
+		return x
+
+Functions run in parallel, and functions called directly by the host, cannot wait on operations.",
+	},
+	ErrorInfo {
+		code: "E212", ty: ErrorType::Execution, summary: "Invalid bytecode",
+		messages: &["Invalid register", "Invalid constant", "Invalid chunk id", "Invalid external value", "Invalid namespace",
+			"Invalid channel", "Invalid upvalue {} of closure", "Jumped back too far", "Jumped forward too far"],
+		explanation: "\
+The VM met bytecode which refers to something that does not exist.
+
+This only happens with bytecode that was not produced by the compiler of this version of Hissy,
+or that was modified; recompile the program from its source.",
+	},
+	ErrorInfo {
+		code: "E213", ty: ErrorType::Execution, summary: "Tests failed",
+		messages: &["{} of {} test(s) failed: {}"],
+		explanation: "\
+Some of the functions annotated with @test raised an error when run by 'hissy test'.
+
+	@test
+	let check():
+		assert(1 + 1 == 3)
+
+The errors of the failing tests are printed above the summary.",
+	},
+
+	ErrorInfo {
+		code: "E300", ty: ErrorType::Interrupt, summary: "Script interrupted", messages: &[],
+		explanation: "\
+The host interrupted the script before it finished, eg. because Ctrl+C was pressed in the shell,
+or its time limit elapsed.",
+	},
+
+	ErrorInfo {
+		code: "E400", ty: ErrorType::IO, summary: "IO error", messages: &[],
+		explanation: "An input or output operation failed.",
+	},
+	ErrorInfo {
+		code: "E401", ty: ErrorType::IO, summary: "File access error",
+		messages: &["Unable to open {}", "Unable to read {}", "Unable to write {}", "Unable to create directory: {}"],
+		explanation: "\
+A file or directory could not be read or written.
+
+	hissy run missing.hsyc
+
+Check that the path exists, and that the process has permission to access it.",
+	},
+	ErrorInfo {
+		code: "E402", ty: ErrorType::IO, summary: "Invalid bytecode file",
+		messages: &["Invalid .hsyc file", "Bytecode file format version is {}, expected {}", "Bytecode rejected: {}",
+			"Unexpected EOF", "Unexpected data at end of chunk", "Unexpected options byte in .hsyc file",
+			"Unrecognized constant type", "Invalid instruction in bytecode", "Invalid UTF8 in string",
+			"Invalid chunk ID", "Invalid character constant", "Invalid {} in {} at {}", "Too many {} to serialize",
+			"Chunk too large to serialize", "Code too long to serialize", "Cannot serialise string: string too long",
+			"Program too large to compress", "Cannot merge programs with different encodings",
+			"Bytecode was compiled with optional natives: {}, but this build has: {}"],
+		explanation: "\
+A bytecode file could not be read or written, because it is truncated, corrupted, was produced by
+another version of Hissy or with other optional natives (see the Cargo features of Hissy),
+or exceeds the limits of the format.
+
+	hissy run program.hsy
+
+'hissy run' expects bytecode; use 'hissy interpret' to run source files, and recompile
+bytecode files after updating Hissy.",
+	},
+	ErrorInfo {
+		code: "E403", ty: ErrorType::IO, summary: "Package error",
+		messages: &["Unable to find package {}: {}", "Unable to fetch {}: git {} failed", "Unable to run git: {}", "Invalid path {}"],
+		explanation: "\
+A dependency of a package could not be found or fetched.
+
+	[dependencies]
+	utils = { git = \"https://example.com/utils.git\" }
+
+Check the paths and URLs in hissy.toml; git dependencies require git to be installed.",
+	},
+	ErrorInfo {
+		code: "E404", ty: ErrorType::IO, summary: "Missing feature",
+		messages: &["Compressed bytecode requires the 'compression' feature", "Compression requires building with the 'compression' feature"],
+		explanation: "\
+The operation requires an optional feature which this build of Hissy does not include.
+
+	cargo install hissy --features compression
+
+Rebuild Hissy with the feature named in the message.",
+	},
+	ErrorInfo {
+		code: "E405", ty: ErrorType::IO, summary: "Invalid option",
+		messages: &["Options --strip and --debug are incompatible", "Unknown log level '{}'{}"],
+		explanation: "\
+A command-line option has an invalid value, or conflicts with another option.
+
+	hissy compile app.hsy --strip --debug
+
+See 'hissy --help' for the accepted options and values.",
+	},
+];
+
+// If a message was produced by a template, where `{}` (or `{:?}`) stands for any text,
+// returns the text of each placeholder
+fn match_template<'a>(message: &'a str, template: &str) -> Option<Vec<&'a str>> {
+	let template = template.replace("{:?}", "{}");
+	let mut parts = template.split("{}");
+	let mut rest = message.strip_prefix(parts.next().unwrap_or(""))?;
+	let parts: Vec<&str> = parts.collect();
+	let mut params = vec![];
+	match parts.split_last() {
+		None if rest.is_empty() => Some(params),
+		None => None,
+		Some((last, middle)) => {
+			for part in middle {
+				let i = rest.find(part)?;
+				params.push(&rest[..i]);
+				rest = &rest[i + part.len()..];
+			}
+			params.push(rest.strip_suffix(last)?);
+			Some(params)
+		},
+	}
+}
+
+/// Returns the catalog entry of an error code, ignoring case.
+pub fn lookup(code: &str) -> Option<&'static ErrorInfo> {
+	CATALOG.iter().find(|info| info.code.eq_ignore_ascii_case(code))
+}
+
+
+/// An error broken down for translation: its code, and the template of its message with
+/// the values filled in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic<'a> {
+	pub code: &'static str,
+	/// Template of the message (see [`ErrorInfo::messages`]), or `None` if the error only has a generic code
+	pub template: Option<&'static str>,
+	/// Values of the placeholders of the template, in order (eg. the name of an undefined variable)
+	pub params: Vec<&'a str>,
+	/// The original, English message
+	pub message: &'a str,
+}
+
+type TranslateFn = dyn Fn(&Diagnostic) -> Option<String> + Send + Sync;
+
+fn translator() -> &'static RwLock<Option<Arc<TranslateFn>>> {
+	static TRANSLATOR: OnceLock<RwLock<Option<Arc<TranslateFn>>>> = OnceLock::new();
+	TRANSLATOR.get_or_init(|| RwLock::new(None))
+}
+
+/// Sets the callback used by [`HissyError::localized_message`], and so when displaying errors,
+/// to translate error messages, eg. to show errors in scripts to the users of a product in their language.
+///
+/// The callback returns `None` for messages it has no translation for, which are left in English.
+pub fn set_translator(translate: impl Fn(&Diagnostic) -> Option<String> + Send + Sync + 'static) {
+	*translator().write().unwrap() = Some(Arc::new(translate));
+}
+
+/// Removes the callback set by [`set_translator`].
+pub fn clear_translator() {
+	*translator().write().unwrap() = None;
+}
+
+impl HissyError {
+	/// Returns the catalog entry of this error, the template of its message, and the values
+	/// of the placeholders of the template.
+	pub fn diagnostic(&self) -> (&'static ErrorInfo, Diagnostic<'_>) {
+		let HissyError(ty, message, _) = self;
+		let mut entries = CATALOG.iter().filter(|info| info.ty == *ty);
+		let specific = entries.clone().find_map(|info| info.messages.iter()
+			.find_map(|template| match_template(message, template).map(|params| (info, template, params))));
+		match specific {
+			Some((info, template, params)) => (info, Diagnostic { code: info.code, template: Some(template), params, message }),
+			None => {
+				let info = entries.find(|info| info.messages.is_empty()).unwrap();
+				(info, Diagnostic { code: info.code, template: None, params: vec![], message })
+			},
+		}
+	}
+
+	/// Returns the catalog entry of this error; errors without a more specific entry get the
+	/// generic entry of their type.
+	pub fn info(&self) -> &'static ErrorInfo {
+		self.diagnostic().0
+	}
+
+	/// Returns the stable code of this error, eg. `E101`.
+	pub fn code(&self) -> &'static str {
+		self.info().code
+	}
+
+	/// Returns the message of this error, translated by the callback set with [`set_translator`] if any.
+	pub fn localized_message(&self) -> String {
+		let translate = translator().read().unwrap().clone();
+		translate.and_then(|translate| translate(&self.diagnostic().1)).unwrap_or_else(|| self.1.clone())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{CATALOG, lookup, match_template, set_translator, clear_translator};
+	use crate::{HissyError, ErrorType};
+	use crate::compiler::Compiler;
+
+	#[test]
+	fn test_error_codes() {
+		for (i, info) in CATALOG.iter().enumerate() {
+			assert!(CATALOG[..i].iter().all(|other| other.code != info.code), "Duplicate code {}", info.code);
+		}
+		assert_eq!(match_template("Unknown type name 'Foo'", "Unknown type name '{}'"), Some(vec!["Foo"]));
+		assert_eq!(match_template("Expected 2 arguments, got 3", "Expected {} arguments, got {}"), Some(vec!["2", "3"]));
+		assert_eq!(match_template("Expected 2 arguments in function call, got 3", "Expected {} arguments, got {}"), None);
+
+		let err = Compiler::new(true).compile_program("let x = y + 1\n").err().unwrap();
+		assert_eq!(err.code(), "E101");
+		let err = Compiler::new(true).compile_program("let x = (1 + 2\n").err().unwrap();
+		assert_eq!(err.code(), "E001");
+		assert_eq!(HissyError(ErrorType::Execution, String::from("Integer modulo by zero"), 0).code(), "E205");
+		// Errors without a specific entry get the generic code of their type
+		assert_eq!(HissyError(ErrorType::IO, String::from("Integer modulo by zero"), 0).code(), "E400");
+		assert_eq!(lookup("e205").unwrap().summary, "Division by zero");
+		assert!(lookup("E999").is_none());
+	}
+
+	#[test]
+	fn test_localized_messages() {
+		let err = Compiler::new(true).compile_program("let x = y + 1\n").err().unwrap();
+		let (_, diagnostic) = err.diagnostic();
+		assert_eq!(diagnostic.template, Some("Referencing undefined binding '{}'"));
+		assert_eq!(diagnostic.params, ["y"]);
+		assert_eq!(err.localized_message(), "Referencing undefined binding 'y'");
+
+		set_translator(|diagnostic| match diagnostic.template? {
+			"Referencing undefined binding '{}'" => Some(format!("Variable '{}' non définie", diagnostic.params[0])),
+			_ => None,
+		});
+		assert_eq!(err.localized_message(), "Variable 'y' non définie");
+		let other = Compiler::new(true).compile_program("let x = (1 + 2\n").err().unwrap();
+		assert_eq!(other.localized_message(), other.1);
+		clear_translator();
+		assert_eq!(err.localized_message(), "Referencing undefined binding 'y'");
+	}
+}
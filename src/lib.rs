@@ -1,6 +1,5 @@
 //! `hissy` is a WIP compiler and virtual machine for the Hissy programming language.
 
-#![feature(raw)]
 
 #[macro_use]
 extern crate num_enum;
@@ -9,22 +8,33 @@ extern crate peg;
 extern crate smallstr;
 
 mod serial;
+#[cfg(feature = "compression")]
+mod compress;
 
 /// Lexing and parsing of Hissy code.
 pub mod parser;
 /// Compilation of Hissy code into bytecode.
 pub mod compiler;
 pub mod vm;
+/// Manifests of script packages, and resolution of the modules they import.
+pub mod package;
+/// Stable codes of errors, and their explanations.
+pub mod errors;
 
 
+use std::collections::HashMap;
 use std::fmt;
 use std::error::Error;
 
-#[derive(Debug)]
+use vm::gc::GCHeap;
+use vm::value::Value;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ErrorType {
 	Syntax,
 	Compilation,
 	Execution,
+	Interrupt,
 	IO,
 }
 
@@ -32,16 +42,30 @@ pub enum ErrorType {
 pub struct HissyError(pub ErrorType, pub String, pub u16);
 
 const RED: &str = "\u{001b}[31;1m";
+const YELLOW: &str = "\u{001b}[33;1m";
 const RESET: &str = "\u{001b}[0m";
 
 impl fmt::Display for HissyError {
 	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
 		write!(f, "{}", RED)?;
-		let HissyError(ty, s, line) = self;
+		let HissyError(ty, _, line) = self;
 		let line_str = if *line != 0 { format!(" at line {}", line) } else { String::new() };
-		write!(f, "{:?} error{}:{} {}", ty, line_str, RESET, s)
+		write!(f, "{:?} error[{}]{}:{} {}", ty, self.code(), line_str, RESET, self.localized_message())
 	}
 }
 
 impl Error for HissyError {}
 
+
+/// Evaluates a Hissy expression with variables provided by the host, eg. for computed values in configuration files.
+///
+/// The types of the variables are inferred from their values. As with [`compiler::Compiler::compile_function`],
+/// the expression can also be a block of statements, whose final expression or `return` gives the result.
+pub fn eval(expr: &str, vars: &HashMap<String, Value>, heap: &mut GCHeap) -> Result<Value, HissyError> {
+	let (names, values): (Vec<&String>, Vec<Value>) = vars.iter().map(|(id, val)| (id, val.clone())).unzip();
+	let params: Vec<(&str, compiler::Type)> = names.iter().zip(&values)
+		.map(|(id, val)| (id.as_str(), compiler::value_type(val)))
+		.collect();
+	let function = compiler::Compiler::new(true).compile_function(expr, &params)?;
+	function.call(heap, values)
+}
@@ -1,15 +1,23 @@
 
 use std::collections::{HashMap, HashSet};
 use std::fmt::{Display, Debug};
-use std::fs::read_to_string;
+use std::fs::{read_to_string, write, read_dir, remove_file, create_dir_all};
 use std::path::{Path, PathBuf};
 use std::env;
+use std::rc::Rc;
+use std::cell::Cell;
+use std::time::{Duration, Instant};
 
 use hissy_lib::{HissyError, ErrorType};
+use hissy_lib::errors;
 use hissy_lib::parser;
-use hissy_lib::parser::{lexer::{Tokens, read_tokens}, ast::ProgramAST};
-use hissy_lib::compiler::{Program, Compiler};
-use hissy_lib::vm::{gc::GCHeap, run_program};
+use hissy_lib::parser::{lexer::{Tokens, read_tokens_with, BlockStyle}, ast::ProgramAST, dot::to_dot, doc::{to_doc, to_doc_index, DocFormat}};
+use hissy_lib::compiler::{Program, Compiler, ModuleResolver, aot};
+use hissy_lib::package::{Manifest, PackageResolver};
+use hissy_lib::vm::{gc::GCHeap, host::{HostEnv, LogLevel}, run_program_with, run_program_with_stats, run_test, run_bench, instruction_set_reference, Encoding};
+use hissy_lib::vm::stats::{VmStats, GC_PAUSE_BUCKETS};
+
+mod shell;
 
 
 fn error(s: String) -> HissyError {
@@ -23,90 +31,541 @@ const RED: &str = "\u{001b}[31;1m";
 const GREEN: &str = "\u{001b}[32;1m";
 const RESET: &str = "\u{001b}[0m";
 
-fn display_result<T: Display>(r: Result<T, HissyError>) {
-	match r {
-		Ok(r) => println!("{}Success:{} {}", GREEN, RESET, r),
-		Err(e) => eprintln!("{}", e),
+#[derive(Clone, Copy, PartialEq)]
+enum OutputMode {
+	Decorated,
+	Quiet, // Only errors, without colors
+	Json, // One JSON object on standard output, for build scripts and editors
+}
+
+const EXIT_USAGE: i32 = 2;
+
+fn exit_code(err: &HissyError) -> i32 {
+	match err.0 {
+		ErrorType::Execution => 1,
+		ErrorType::Syntax => 3,
+		ErrorType::Compilation => 4,
+		ErrorType::IO => 5,
+		ErrorType::Interrupt => 6,
+	}
+}
+
+fn json_string(s: &str) -> String {
+	let mut res = String::from("\"");
+	for c in s.chars() {
+		match c {
+			'"' => res.push_str("\\\""),
+			'\\' => res.push_str("\\\\"),
+			'\n' => res.push_str("\\n"),
+			'\t' => res.push_str("\\t"),
+			c if c.is_control() => res.push_str(&format!("\\u{:04x}", c as u32)),
+			c => res.push(c),
+		}
 	}
+	res.push('"');
+	res
 }
 
-fn debug_result<T: Debug>(r: Result<T, HissyError>) {
+fn json_error(ty: &str, code: Option<&str>, message: &str, line: u16) -> String {
+	let code = code.map_or_else(|| String::from("null"), json_string);
+	let line = if line != 0 { line.to_string() } else { String::from("null") };
+	format!("{{\"success\": false, \"type\": {}, \"code\": {}, \"message\": {}, \"line\": {}}}", json_string(ty), code, json_string(message), line)
+}
+
+// Prints the result of a command, and returns the exit code
+fn report<T>(mode: OutputMode, r: Result<T, HissyError>, show: impl FnOnce(T) -> Option<String>) -> i32 {
 	match r {
-		Ok(r) => println!("{}Success:{} {:#?}", GREEN, RESET, r),
-		Err(e) => eprintln!("{}", e),
+		Ok(r) => {
+			let message = show(r);
+			match mode {
+				OutputMode::Decorated => if let Some(message) = message {
+					println!("{}Success:{} {}", GREEN, RESET, message)
+				},
+				OutputMode::Quiet => (),
+				OutputMode::Json => println!("{{\"success\": true, \"message\": {}}}",
+					message.as_deref().map_or_else(|| String::from("null"), json_string)),
+			}
+			0
+		},
+		Err(e) => {
+			let HissyError(ty, message, line) = &e;
+			match mode {
+				OutputMode::Decorated => eprintln!("{}", e),
+				OutputMode::Quiet if *line != 0 => eprintln!("{:?} error[{}] at line {}: {}", ty, e.code(), line, message),
+				OutputMode::Quiet => eprintln!("{:?} error[{}]: {}", ty, e.code(), message),
+				OutputMode::Json => println!("{}", json_error(&format!("{:?}", ty), Some(e.code()), message, *line)),
+			}
+			exit_code(&e)
+		},
 	}
 }
 
-fn display_error(r: Result<(), HissyError>) {
-	if let Err(e) = r {
-		eprintln!("{}", e);
+fn display_result<T: Display>(mode: OutputMode, r: Result<T, HissyError>) -> i32 {
+	report(mode, r, |r| Some(r.to_string()))
+}
+
+fn debug_result<T: Debug>(mode: OutputMode, r: Result<T, HissyError>) -> i32 {
+	report(mode, r, |r| Some(format!("{:#?}", r)))
+}
+
+fn display_error(mode: OutputMode, r: Result<(), HissyError>) -> i32 {
+	report(mode, r, |()| None)
+}
+
+// Scripts which called exit() end the process with the code they passed
+fn exit_status(mode: OutputMode, r: Result<Option<i32>, HissyError>) -> i32 {
+	match r {
+		Ok(Some(code)) => code,
+		r => report(mode, r, |_| None),
 	}
 }
 
 
-fn lex(file: &str) -> Result<Tokens, HissyError> {
+fn lex(file: &str, style: BlockStyle) -> Result<Tokens, HissyError> {
 	let contents = read_to_string(file).map_err(|_| error_str("Unable to open file"))?;
-	read_tokens(&contents)
+	read_tokens_with(&contents, style)
 }
 
-fn parse(file: &str) -> Result<ProgramAST, HissyError> {
+fn parse(file: &str, style: BlockStyle) -> Result<ProgramAST, HissyError> {
 	let contents = read_to_string(file).map_err(|_| error_str("Unable to open file"))?;
-	parser::parse(&contents)
+	parser::parse_with(&contents, style)
 }
 
-fn compile(input: &str, output: Option<String>, debug_info: bool) -> Result<String, HissyError> {
+#[derive(PartialEq)]
+enum DebugLevel {
+	Strip, // No debug info
+	Default, // Names and line numbers
+	Full, // Also embed the source code
+}
+
+#[cfg(feature = "compression")]
+fn write_program(program: &Program, output: &Path, compress: bool) -> Result<(), HissyError> {
+	if compress { program.to_compressed_file(output) } else { program.to_file(output) }
+}
+#[cfg(not(feature = "compression"))]
+fn write_program(program: &Program, output: &Path, compress: bool) -> Result<(), HissyError> {
+	if compress {
+		return Err(error_str("Compression requires building with the 'compression' feature"));
+	}
+	program.to_file(output)
+}
+
+// Warnings go to stderr, so that they do not interfere with JSON output
+fn print_warnings(program: &Program) {
+	for warning in program.warnings() {
+		eprintln!("{}", warning);
+	}
+}
+
+fn compile(input: &str, output: Option<String>, debug_level: DebugLevel, encoding: Encoding, style: BlockStyle, compress: bool, precompute: bool) -> Result<String, HissyError> {
 	let code = read_to_string(input).map_err(|_| error_str("Unable to open file"))?;
-	let compiler = Compiler::new(debug_info);
+	let mut compiler = Compiler::new(debug_level != DebugLevel::Strip);
+	compiler.set_embed_source(debug_level == DebugLevel::Full);
+	compiler.set_encoding(encoding);
+	compiler.set_block_style(style);
+	compiler.set_partial_eval(precompute);
+	compiler.set_resolver(PackageResolver::for_script(input));
 	
 	let program = compiler.compile_program(&code)?;
+	print_warnings(&program);
 	let output = output.map_or_else(|| Path::new(input).with_extension("hsyc"), PathBuf::from);
-	program.to_file(output.clone())
+	write_program(&program, &output, compress)
 		.map(|_| format!("Compiled into {:?}", output))
+}
+
+fn compile_aot(input: &str, output: Option<String>, debug_info: bool, style: BlockStyle) -> Result<String, HissyError> {
+	let code = read_to_string(input).map_err(|_| error_str("Unable to open file"))?;
+	let mut compiler = Compiler::new(debug_info);
+	compiler.set_block_style(style);
+	compiler.set_resolver(PackageResolver::for_script(input));
+	let program = compiler.compile_program(&code)?;
+	let source = aot::generate_rust(&program, input)?;
+	let output = output.map_or_else(|| Path::new(input).with_extension("rs"), PathBuf::from);
+	write(&output, source)
+		.map(|_| format!("Generated {:?}", output))
 		.map_err(|e| error(format!("Unable to write file: {}", e)))
 }
 
+// Compiles the entry point of a package, along with the modules it imports, into a single bytecode file
+fn build(dir: &str, output: Option<String>, debug_level: DebugLevel, style: BlockStyle) -> Result<String, HissyError> {
+	let manifest = Manifest::from_dir(dir)?;
+	let entry = manifest.entry_path();
+	let code = read_to_string(&entry).map_err(|_| error(format!("Unable to open entry point {:?}", entry)))?;
+	let mut compiler = Compiler::new(debug_level != DebugLevel::Strip);
+	compiler.set_embed_source(debug_level == DebugLevel::Full);
+	compiler.set_block_style(style);
+	compiler.set_resolver(PackageResolver::new(&manifest)?);
+	
+	let program = compiler.compile_program(&code)?;
+	print_warnings(&program);
+	let name = if manifest.version.is_empty() { manifest.name.clone() } else { format!("{}-{}", manifest.name, manifest.version) };
+	let output = output.map_or_else(|| manifest.dir.join(format!("{}.hsyc", name)), PathBuf::from);
+	program.to_file(&output).map(|_| format!("Built {:?}", output))
+}
+
 fn list(file: &str) -> Result<(), HissyError> {
 	let program = Program::from_file(file)?;
 	program.disassemble()
 }
 
-fn interpret(file: &str) -> Result<(), HissyError> {
-	let code = read_to_string(file).map_err(|_| error_str("Unable to open file"))?;
-	let compiler = Compiler::new(true); // Always output debug info when interpreting
-	let program = compiler.compile_program(&code)?;
+// Compiled programs are cached in this directory, next to their source files
+const CACHE_DIR: &str = ".hissy-cache";
+
+// FNV-1a, which is simple and stable across Rust versions, unlike the standard library's hasher
+fn content_hash(parts: &[&[u8]]) -> u64 {
+	parts.iter().fold(0xcbf2_9ce4_8422_2325, |hash, part| {
+		// Hash the length of each part, so that they cannot run into each other
+		part.len().to_le_bytes().iter().chain(part.iter())
+			.fold(hash, |hash, b| (hash ^ u64::from(*b)).wrapping_mul(0x0100_0000_01b3))
+	})
+}
+
+// Path of the cached bytecode of a source file, which depends on its contents, the compiler version and the block style
+fn cache_path(file: &Path, code: &str, style: BlockStyle) -> Option<PathBuf> {
+	let name = file.file_name()?.to_str()?;
+	let style: &[u8] = if style == BlockStyle::End { b"end" } else { b"indentation" };
+	let hash = content_hash(&[env!("CARGO_PKG_VERSION").as_bytes(), style, code.as_bytes()]);
+	Some(file.with_file_name(CACHE_DIR).join(format!("{}.{:016x}.hsyc", name, hash)))
+}
+
+// Writes a program to the cache, removing older versions of the same source file; failures are ignored,
+// since the cache is only an optimization
+fn write_cache(file: &Path, path: &Path, program: &Program) {
+	let (dir, source_name) = match (path.parent(), file.file_name().and_then(|name| name.to_str())) {
+		(Some(dir), Some(name)) => (dir, name),
+		_ => return,
+	};
+	if create_dir_all(dir).is_err() {
+		return;
+	}
+	for entry in read_dir(dir).into_iter().flatten().flatten() {
+		let other = entry.file_name();
+		let is_stale = other.to_str().and_then(|other| other.strip_prefix(source_name)?.strip_prefix('.')?.strip_suffix(".hsyc"))
+			.is_some_and(|hash| hash.len() == 16 && hash.chars().all(|c| c.is_ascii_hexdigit()));
+		if is_stale {
+			let _ = remove_file(entry.path());
+		}
+	}
+	let _ = program.to_file(path);
+}
+
+// Records whether a script imports modules or embeds files, since its cached bytecode would not be updated when they change
+struct TrackedResolver(PackageResolver, Rc<Cell<bool>>);
+
+impl ModuleResolver for TrackedResolver {
+	fn resolve(&mut self, name: &str, importer: Option<&str>) -> Result<(String, String), String> {
+		self.1.set(true);
+		self.0.resolve(name, importer)
+	}
 	
+	fn embed(&mut self, path: &str, importer: Option<&str>) -> Result<String, String> {
+		self.1.set(true);
+		self.0.embed(path, importer)
+	}
+}
+
+// Only the most frequent instructions and most called functions are listed
+const STATS_ENTRIES: usize = 10;
+
+// Prints execution statistics to the standard error, to keep them apart from the output of the script
+fn print_stats(stats: &VmStats, program: &Program) {
+	let total = stats.total_instructions();
+	let mix: Vec<String> = stats.instruction_mix().iter().take(STATS_ENTRIES)
+		.map(|(name, count)| format!("{} {:.1}%", name, *count as f64 * 100.0 / total as f64))
+		.collect();
+	eprintln!("{} instructions: {}", total, mix.join(", "));
+	let mut calls: Vec<(usize, u64)> = stats.calls.iter().copied().enumerate().filter(|(_, count)| *count > 0).collect();
+	calls.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+	let calls: Vec<String> = calls.iter().take(STATS_ENTRIES).map(|(chunk, count)| {
+		let name = program.chunk_metadata(*chunk).and_then(|meta| meta.name).unwrap_or_else(|| format!("chunk {}", chunk));
+		format!("{} {}", name, count)
+	}).collect();
+	eprintln!("{} calls: {}", stats.calls.iter().sum::<u64>(), calls.join(", "));
+	eprintln!("{} native calls", stats.native_calls);
+	let pauses: Vec<String> = stats.gc_pauses.iter().enumerate().filter(|(_, count)| **count > 0).map(|(i, count)| match GC_PAUSE_BUCKETS.get(i) {
+		Some(bound) => format!("<{:?} {}", bound, count),
+		None => format!(">={:?} {}", GC_PAUSE_BUCKETS[i - 1], count),
+	}).collect();
+	eprintln!("{} collections in {:.2?}: {}", stats.gc_pauses.iter().sum::<u64>(), stats.gc_time, pauses.join(", "));
+}
+
+// Runs a program, printing execution statistics afterwards if asked to
+fn run_with_stats(program: &Program, host: &HostEnv, show_stats: bool) -> Result<Option<i32>, HissyError> {
 	let mut heap = GCHeap::new();
-	run_program(&mut heap, &program)?;
-	Ok(())
+	if !show_stats {
+		return run_program_with(&mut heap, program, host);
+	}
+	let mut stats = VmStats::default();
+	let res = run_program_with_stats(&mut heap, program, host, &mut stats);
+	print_stats(&stats, program);
+	res
 }
 
-fn run(file: &str) -> Result<(), HissyError> {
+fn interpret(file: &str, style: BlockStyle, use_cache: bool, host: &HostEnv, show_stats: bool) -> Result<Option<i32>, HissyError> {
+	let code = read_to_string(file).map_err(|_| error_str("Unable to open file"))?;
+	let cache = if use_cache { cache_path(Path::new(file), &code, style) } else { None };
+	let cached = cache.as_ref().and_then(|path| Program::from_file(path).ok());
+	let program = if let Some(program) = cached { program } else {
+		let mut compiler = Compiler::new(true); // Always output debug info when interpreting
+		compiler.set_block_style(style);
+		let uses_files = Rc::new(Cell::new(false));
+		compiler.set_resolver(TrackedResolver(PackageResolver::for_script(file), uses_files.clone()));
+		let program = compiler.compile_program(&code)?;
+		print_warnings(&program);
+		// Warnings are not kept in bytecode, so scripts with warnings are compiled again to show them on every run
+		if let (Some(path), false, true) = (&cache, uses_files.get(), program.warnings().is_empty()) {
+			write_cache(Path::new(file), path, &program);
+		}
+		program
+	};
+	
+	run_with_stats(&program, host, show_stats)
+}
+
+fn run(file: &str, host: &HostEnv, show_stats: bool) -> Result<Option<i32>, HissyError> {
 	let program = Program::from_file(file)?;
+	run_with_stats(&program, host, show_stats)
+}
+
+// Runs each test in a fresh heap; failures are reported as they happen, except in JSON mode
+fn test(file: &str, style: BlockStyle, mode: OutputMode) -> Result<String, HissyError> {
+	let code = read_to_string(file).map_err(|_| error_str("Unable to open file"))?;
+	let mut compiler = Compiler::new(true);
+	compiler.set_block_style(style);
+	compiler.set_resolver(PackageResolver::for_script(file));
+	let program = compiler.compile_tests(&code)?;
+	print_warnings(&program);
+	
+	let mut failed = vec![];
+	for (i, (name, line)) in program.tests().iter().enumerate() {
+		let start = Instant::now();
+		let res = run_test(&mut GCHeap::new(), &program, i);
+		let time = start.elapsed();
+		match (&res, mode) {
+			(Ok(()), OutputMode::Decorated) => println!("{}ok{}     {} ({:.2?})", GREEN, RESET, name, time),
+			(Err(e), OutputMode::Decorated) => println!("{}FAILED{} {} ({:.2?})\n  {}", RED, RESET, name, time, e),
+			(Err(e), OutputMode::Quiet) => eprintln!("Test {} (line {}) failed: {}", name, line, e.1),
+			_ => (),
+		}
+		if res.is_err() {
+			failed.push(name.as_str());
+		}
+	}
+	
+	let total = program.tests().len();
+	if failed.is_empty() {
+		Ok(format!("{} test(s) passed", total))
+	} else {
+		Err(HissyError(ErrorType::Execution, format!("{} of {} test(s) failed: {}", failed.len(), total, failed.join(", ")), 0))
+	}
+}
+
+// Benchmarks are calibrated to run for at least this long, then the fastest of several runs is kept
+const BENCH_TARGET: Duration = Duration::from_millis(100);
+const BENCH_RUNS: usize = 5;
+
+fn time_bench(heap: &mut GCHeap, program: &Program, index: usize, iterations: u32) -> Result<Duration, HissyError> {
+	let mut best = Duration::MAX;
+	for _ in 0..BENCH_RUNS {
+		let start = Instant::now();
+		run_bench(heap, program, index, iterations)?;
+		best = best.min(start.elapsed());
+	}
+	Ok(best)
+}
+
+// The number of iterations is doubled until a run is long enough, which also serves as a warmup;
+// the time of the top-level code, measured with 0 iterations, is subtracted from the result
+fn bench(file: &str, style: BlockStyle, mode: OutputMode) -> Result<String, HissyError> {
+	let code = read_to_string(file).map_err(|_| error_str("Unable to open file"))?;
+	let mut compiler = Compiler::new(true);
+	compiler.set_block_style(style);
+	compiler.set_resolver(PackageResolver::for_script(file));
+	let program = compiler.compile_benches(&code)?;
+	print_warnings(&program);
 	
 	let mut heap = GCHeap::new();
-	run_program(&mut heap, &program)?;
+	let mut results = vec![];
+	for (i, (name, _)) in program.benches().iter().enumerate() {
+		let failed = |HissyError(ty, message, line)| HissyError(ty, format!("Benchmark {} failed: {}", name, message), line);
+		let mut iterations = 1;
+		loop {
+			let start = Instant::now();
+			run_bench(&mut heap, &program, i, iterations).map_err(failed)?;
+			if start.elapsed() >= BENCH_TARGET || iterations >= 1 << 30 {
+				break;
+			}
+			iterations *= 2;
+		}
+		let baseline = time_bench(&mut heap, &program, i, 0).map_err(failed)?;
+		let total = time_bench(&mut heap, &program, i, iterations).map_err(failed)?;
+		let per_iter = total.saturating_sub(baseline).as_nanos() / u128::from(iterations);
+		let result = format!("{:<24} {:>12} ns/iter ({} iterations)", name, per_iter, iterations);
+		if mode == OutputMode::Decorated {
+			println!("{}", result);
+		}
+		results.push(result);
+	}
+	Ok(results.join("\n"))
+}
+
+// Only the sites allocating the most bytes are listed
+const PROFILE_SITES: usize = 20;
+
+// Runs a script while counting the objects it allocates at each line
+fn profile(file: &str, style: BlockStyle, host: &HostEnv, mode: OutputMode) -> Result<String, HissyError> {
+	let code = read_to_string(file).map_err(|_| error_str("Unable to open file"))?;
+	let mut compiler = Compiler::new(true);
+	compiler.set_block_style(style);
+	compiler.set_resolver(PackageResolver::for_script(file));
+	let program = compiler.compile_program(&code)?;
+	print_warnings(&program);
+	
+	let mut heap = GCHeap::new();
+	heap.start_profiling();
+	let res = run_program_with(&mut heap, &program, host);
+	let groups = heap.stop_profiling();
+	res?;
+	
+	let mut results = vec![format!("{:<32} {:<16} {:>12} {:>14}", "Site", "Type", "Allocations", "Bytes")];
+	for group in groups.iter().take(PROFILE_SITES) {
+		let site = match group.site {
+			Some(site) => {
+				let name = program.chunk_metadata(site.chunk as usize).and_then(|meta| meta.name).unwrap_or_default();
+				format!("{}, line {}", name, site.line)
+			},
+			None => String::from("<host>"),
+		};
+		results.push(format!("{:<32} {:<16} {:>12} {:>14}", site, group.type_name, group.count, group.size));
+	}
+	if groups.len() > PROFILE_SITES {
+		results.push(format!("... and {} more sites", groups.len() - PROFILE_SITES));
+	}
+	let total: usize = groups.iter().map(|group| group.size).sum();
+	results.push(format!("{} allocations, {} bytes in total", groups.iter().map(|group| group.count).sum::<usize>(), total));
+	let results = results.join("\n");
+	if mode == OutputMode::Decorated {
+		println!("{}", results);
+	}
+	Ok(results)
+}
+
+// Finds the source files in a directory and its subdirectories, skipping hidden entries like the cache
+fn find_sources(dir: &Path, skip: &Path, sources: &mut Vec<PathBuf>) -> Result<(), HissyError> {
+	let entries = read_dir(dir).map_err(|e| error(format!("Unable to read directory {:?}: {}", dir, e)))?;
+	for entry in entries.flatten() {
+		let path = entry.path();
+		if entry.file_name().to_str().map_or(true, |name| name.starts_with('.')) || path == skip {
+			continue;
+		}
+		if path.is_dir() {
+			find_sources(&path, skip, sources)?;
+		} else if path.extension().is_some_and(|ext| ext == "hsy") {
+			sources.push(path);
+		}
+	}
 	Ok(())
 }
 
+// Writes one page per source file, mirroring the layout of the directory, and an index linking to them
+fn doc(input: &str, output: Option<String>, format: DocFormat, style: BlockStyle) -> Result<String, HissyError> {
+	let input = Path::new(input);
+	let (root, output) = if input.is_dir() {
+		(input, output.map_or_else(|| input.join("doc"), PathBuf::from))
+	} else {
+		let root = input.parent().unwrap_or(Path::new(""));
+		(root, output.map_or_else(|| root.join("doc"), PathBuf::from))
+	};
+	let mut sources = vec![];
+	if input.is_dir() {
+		find_sources(input, &output, &mut sources)?;
+		sources.sort();
+	} else {
+		sources.push(input.to_path_buf());
+	}
+	
+	let mut pages = vec![];
+	for source in &sources {
+		let relative = source.strip_prefix(root).unwrap_or(source).with_extension("");
+		let title = relative.components().map(|c| c.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/");
+		let code = read_to_string(source).map_err(|_| error(format!("Unable to open file {:?}", source)))?;
+		let ast = parser::parse_with(&code, style)
+			.map_err(|HissyError(ty, message, line)| HissyError(ty, format!("In {:?}: {}", source, message), line))?;
+		let page = relative.with_extension(format.extension());
+		let path = output.join(&page);
+		create_dir_all(path.parent().unwrap_or(&output)).map_err(|e| error(format!("Unable to create directory: {}", e)))?;
+		write(&path, to_doc(&ast, &title, format)).map_err(|e| error(format!("Unable to write file: {}", e)))?;
+		pages.push((title, page.components().map(|c| c.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/")));
+	}
+	
+	let index = output.join("index").with_extension(format.extension());
+	create_dir_all(&output).map_err(|e| error(format!("Unable to create directory: {}", e)))?;
+	write(&index, to_doc_index("Documentation", &pages, format)).map_err(|e| error(format!("Unable to write file: {}", e)))?;
+	Ok(format!("Documented {} script(s) into {:?}", pages.len(), output))
+}
+
+// The positional argument is the error code rather than a file
+fn explain(code: &str) -> Result<String, HissyError> {
+	let info = errors::lookup(code).ok_or_else(|| error(format!("Unknown error code '{}'", code)))?;
+	Ok(format!("{} ({:?} error): {}\n\n{}", info.code, info.ty, info.summary, info.explanation))
+}
+
 
 const USAGE: &str = "
 Usage:
-  hissy lex|parse <src>
-  hissy compile [--strip] [-o <bytecode>] <src>
+  hissy lex [--end-blocks] <src>
+  hissy parse [--end-blocks] [--graph] <src>
+  hissy compile [--strip|--debug] [--wide] [--compress] [--precompute] [--end-blocks] [-o <bytecode>] <src>
+  hissy aot [--strip] [--end-blocks] [-o <rust>] <src>
+  hissy build [--strip|--debug] [--end-blocks] [-o <bytecode>] <package>
   hissy list <bytecode>
-  hissy run <bytecode>
-  hissy interpret <src>
+  hissy run [--allow-env] [--allow-exit] [--allow-exec] [--allow-net] [--log-level <level>] [--stats] <bytecode>
+  hissy interpret [--no-cache] [--allow-env] [--allow-exit] [--allow-exec] [--allow-net] [--log-level <level>] [--stats] [--end-blocks] <src>
+  hissy shell [--allow-env] [--allow-exit] [--allow-exec] [--allow-net] [--log-level <level>] [--end-blocks]
+  hissy test [--end-blocks] <src>
+  hissy bench [--end-blocks] <src>
+  hissy profile [--allow-env] [--allow-exit] [--allow-exec] [--allow-net] [--log-level <level>] [--end-blocks] <src>
+  hissy doc [--html] [--end-blocks] [-o <docs>] <dir>
+  hissy isa
+  hissy explain <code>
   hissy --help|--version
 
 Arguments:
   <src>        Path to a Hissy source file (usually .hsy)
   <bytecode>   Path to a Hissy bytecode file (usually .hsyc)
+  <rust>       Path of the generated Rust source, embedding the bytecode
+  <package>    Path to the directory of a package, containing its hissy.toml manifest
+  <dir>        Path to a directory of Hissy source files, or to a single source file
+  <docs>       Path of the directory where documentation is written (default: doc, next to the sources)
+  <level>      Severity of logged messages: debug, info, warn or error
+  <code>       Code of an error, as printed in error messages (eg. E101)
 
 Options:
   --strip      Strip debug symbols from output
-  -o           Specifies the path of the resulting bytecode
+  --debug      Embed the source code in the output, alongside debug symbols
+  --wide       Pad instructions to 32-bit words, allowing longer jumps
+  --compress   Compress the bytecode (requires the 'compression' feature)
+  --precompute Run the code at the start of the program which only uses literals at compile time
+  --end-blocks Close blocks with 'end' instead of using indentation
+  --graph      Print the syntax tree in the Graphviz DOT format
+  --html       Generate HTML documentation instead of Markdown
+  --no-cache   Always recompile the source, instead of reusing the bytecode cached in .hissy-cache
+  --allow-env  Allow the script to read environment variables with env()
+  --allow-exit Allow the script to end the process with an exit code with exit()
+  --allow-exec Allow the script to run other programs with exec()
+  --allow-net  Allow the script to connect to other machines (requires the 'net' feature)
+  --log-level  Ignore the messages logged by the script below this level (default: info)
+  --stats      Print statistics about the execution (instructions, calls, collections) to the standard error
+  -o           Specifies the path of the resulting bytecode (or Rust source, or documentation)
+  --quiet      Only print errors, without colors (any command)
+  --json       Print the result or error as a JSON object (any command)
   --help       Print this help message
   --version    Print the version
+
+Exit codes:
+  0 success, 1 execution error, 2 usage error, 3 syntax error,
+  4 compilation error, 5 IO error, 6 interrupted,
+  or the code passed to exit() by the script
 ";
 
 struct CommandSpec {
@@ -122,16 +581,28 @@ impl CommandSpec {
 }
 
 static COMMANDS: &[CommandSpec] = &[
-	CommandSpec::new("lex", true, &[], &[]),
-	CommandSpec::new("parse", true, &[], &[]),
-	CommandSpec::new("compile", true, &["-o"], &["--strip"]),
+	CommandSpec::new("lex", true, &[], &["--end-blocks"]),
+	CommandSpec::new("parse", true, &[], &["--end-blocks", "--graph"]),
+	CommandSpec::new("compile", true, &["-o"], &["--strip", "--debug", "--wide", "--compress", "--precompute", "--end-blocks"]),
+	CommandSpec::new("aot", true, &["-o"], &["--strip", "--end-blocks"]),
+	CommandSpec::new("build", true, &["-o"], &["--strip", "--debug", "--end-blocks"]),
 	CommandSpec::new("list", true, &[], &[]),
-	CommandSpec::new("run", true, &[], &[]),
-	CommandSpec::new("interpret", true, &[], &[]),
+	CommandSpec::new("run", true, &["--log-level"], &["--allow-env", "--allow-exit", "--allow-exec", "--allow-net", "--stats"]),
+	CommandSpec::new("interpret", true, &["--log-level"], &["--no-cache", "--allow-env", "--allow-exit", "--allow-exec", "--allow-net", "--stats", "--end-blocks"]),
+	CommandSpec::new("shell", false, &["--log-level"], &["--allow-env", "--allow-exit", "--allow-exec", "--allow-net", "--end-blocks"]),
+	CommandSpec::new("test", true, &[], &["--end-blocks"]),
+	CommandSpec::new("bench", true, &[], &["--end-blocks"]),
+	CommandSpec::new("profile", true, &["--log-level"], &["--allow-env", "--allow-exit", "--allow-exec", "--allow-net", "--end-blocks"]),
+	CommandSpec::new("doc", true, &["-o"], &["--html", "--end-blocks"]),
+	CommandSpec::new("isa", false, &[], &[]),
+	CommandSpec::new("explain", true, &[], &[]),
 	CommandSpec::new("--version", false, &[], &[]),
 	CommandSpec::new("--help", false, &[], &[]),
 ];
 
+// Options accepted by every command
+static GLOBAL_OPTIONS: &[&str] = &["--quiet", "--json"];
+
 struct Command {
 	name: &'static str,
 	file: Option<String>,
@@ -140,6 +611,26 @@ struct Command {
 }
 
 
+fn debug_level(cmd: &Command) -> Result<DebugLevel, HissyError> {
+	match (cmd.options.contains("--strip"), cmd.options.contains("--debug")) {
+		(true, true) => Err(error_str("Options --strip and --debug are incompatible")),
+		(true, false) => Ok(DebugLevel::Strip),
+		(false, false) => Ok(DebugLevel::Default),
+		(false, true) => Ok(DebugLevel::Full),
+	}
+}
+
+fn host_env(cmd: &Command) -> Result<HostEnv, HissyError> {
+	Ok(HostEnv {
+		allow_env: cmd.options.contains("--allow-env"),
+		allow_exit: cmd.options.contains("--allow-exit"),
+		allow_exec: cmd.options.contains("--allow-exec"),
+		allow_net: cmd.options.contains("--allow-net"),
+		log_level: cmd.parameters.get("--log-level").map_or(Ok(LogLevel::default()), |level| level.parse())?,
+		log_sink: None,
+	})
+}
+
 fn parse_args(mut args: env::Args) -> Result<Command, String> {
 	let _hissy_path = args.next().unwrap();
 	
@@ -157,7 +648,7 @@ fn parse_args(mut args: env::Args) -> Result<Command, String> {
 	let mut positional = vec![];
 	while let Some(part) = args.next() {
 		if part.starts_with('-') {
-			if let Some(opt_spec) = cmd_spec.options.iter().find(|opt| *opt == &part) {
+			if let Some(opt_spec) = cmd_spec.options.iter().chain(GLOBAL_OPTIONS).find(|opt| *opt == &part) {
 				cmd.options.insert(opt_spec);
 			} else if let Some(param_spec) = cmd_spec.parameters.iter().find(|opt| *opt == &part) {
 				cmd.parameters.insert(param_spec, args.next()
@@ -181,24 +672,62 @@ fn parse_args(mut args: env::Args) -> Result<Command, String> {
 	Ok(cmd)
 }
 
-fn main() {
-	let args = env::args();
-	match parse_args(args) {
-		Ok(cmd) => {
-			match cmd.name {
-				"lex" => display_result(lex(&cmd.file.unwrap())),
-				"parse" => debug_result(parse(&cmd.file.unwrap())),
-				"compile" => display_result(compile(&cmd.file.unwrap(), cmd.parameters.get("-o").cloned(), !cmd.options.contains("--strip"))),
-				"list" => display_error(list(&cmd.file.unwrap())),
-				"interpret" => display_error(interpret(&cmd.file.unwrap())),
-				"run" => display_error(run(&cmd.file.unwrap())),
-				"--version" => println!("Hissy v{}", env!("CARGO_PKG_VERSION")),
-				"--help" => println!("{}", USAGE),
-				_ => panic!("Unimplemented command"),
-			}
+fn run_command(cmd: Command) -> i32 {
+	let mode = if cmd.options.contains("--json") {
+		OutputMode::Json
+	} else if cmd.options.contains("--quiet") {
+		OutputMode::Quiet
+	} else {
+		OutputMode::Decorated
+	};
+	let style = if cmd.options.contains("--end-blocks") { BlockStyle::End } else { BlockStyle::Indentation };
+	match cmd.name {
+		"lex" => display_result(mode, lex(&cmd.file.unwrap(), style)),
+		"parse" if cmd.options.contains("--graph") => {
+			let graph = parse(&cmd.file.unwrap(), style).map(|ast| to_dot(&ast));
+			report(mode, graph, |graph| if mode == OutputMode::Decorated { print!("{}", graph); None } else { Some(graph) })
+		},
+		"parse" => debug_result(mode, parse(&cmd.file.unwrap(), style)),
+		"compile" => {
+			let encoding = if cmd.options.contains("--wide") { Encoding::Wide } else { Encoding::Compact };
+			display_result(mode, debug_level(&cmd).and_then(|debug_level|
+				compile(cmd.file.as_ref().unwrap(), cmd.parameters.get("-o").cloned(), debug_level, encoding, style,
+					cmd.options.contains("--compress"), cmd.options.contains("--precompute"))))
+		},
+		"aot" => display_result(mode, compile_aot(&cmd.file.unwrap(), cmd.parameters.get("-o").cloned(), !cmd.options.contains("--strip"), style)),
+		"build" => display_result(mode, debug_level(&cmd).and_then(|debug_level|
+			build(cmd.file.as_ref().unwrap(), cmd.parameters.get("-o").cloned(), debug_level, style))),
+		"list" => display_error(mode, list(&cmd.file.unwrap())),
+		"interpret" => exit_status(mode, host_env(&cmd).and_then(|host|
+			interpret(cmd.file.as_ref().unwrap(), style, !cmd.options.contains("--no-cache"), &host, cmd.options.contains("--stats")))),
+		"run" => exit_status(mode, host_env(&cmd).and_then(|host| run(cmd.file.as_ref().unwrap(), &host, cmd.options.contains("--stats")))),
+		"shell" => exit_status(mode, host_env(&cmd).and_then(|host| shell::shell(style, host))),
+		"test" => display_result(mode, test(&cmd.file.unwrap(), style, mode)),
+		"doc" => {
+			let format = if cmd.options.contains("--html") { DocFormat::Html } else { DocFormat::Markdown };
+			display_result(mode, doc(&cmd.file.unwrap(), cmd.parameters.get("-o").cloned(), format, style))
 		},
+		"bench" => report(mode, bench(&cmd.file.unwrap(), style, mode), |results| if mode == OutputMode::Decorated { None } else { Some(results) }),
+		"profile" => report(mode, host_env(&cmd).and_then(|host| profile(cmd.file.as_ref().unwrap(), style, &host, mode)), |results| if mode == OutputMode::Decorated { None } else { Some(results) }),
+		"isa" => { print!("{}", instruction_set_reference()); 0 },
+		"explain" => report(mode, explain(&cmd.file.unwrap()), |text| if mode == OutputMode::Decorated { println!("{}", text); None } else { Some(text) }),
+		"--version" => { println!("Hissy v{}", env!("CARGO_PKG_VERSION")); 0 },
+		"--help" => { println!("{}", USAGE); 0 },
+		_ => panic!("Unimplemented command"),
+	}
+}
+
+fn main() {
+	let code = match parse_args(env::args()) {
+		Ok(cmd) => run_command(cmd),
 		Err(err) => {
-			eprintln!("{}{}{}\n{}", RED, err, RESET, USAGE);
+			if env::args().any(|arg| arg == "--json") {
+				println!("{}", json_error("Usage", None, &err, 0));
+			} else {
+				eprintln!("{}{}{}\n{}", RED, err, RESET, USAGE);
+			}
+			EXIT_USAGE
 		}
-	}
+	};
+	std::process::exit(code);
 }
@@ -0,0 +1,371 @@
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::{HissyError, ErrorType};
+use crate::compiler::ModuleResolver;
+
+
+fn error(s: String) -> HissyError {
+	HissyError(ErrorType::IO, s, 0)
+}
+
+/// The file name of package manifests.
+pub const MANIFEST_NAME: &str = "hissy.toml";
+
+// Git dependencies are cloned into this directory, next to the manifest of the root package
+const DEPS_DIR: &str = ".hissy-deps";
+
+/// Where the files of a dependency come from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+	/// A directory, relative to the manifest which declares the dependency
+	Path(PathBuf),
+	/// A git repository, and the branch, tag or commit to check out, if any
+	Git { url: String, rev: Option<String> },
+}
+
+/// The manifest of a package of Hissy scripts, usually read from a `hissy.toml` file such as:
+///
+/// ```text
+/// [package]
+/// name = "game"
+/// version = "0.1.0"
+/// entry = "src/main.hsy"
+///
+/// [dependencies]
+/// utils = { path = "../utils" }
+/// json = { git = "https://example.com/json.git", rev = "v1.0" }
+/// ```
+///
+/// Only this subset of TOML is supported: tables, strings, and inline tables of strings.
+#[derive(Debug, Clone)]
+pub struct Manifest {
+	pub name: String,
+	pub version: String,
+	/// Path of the main script, relative to the manifest (`main.hsy` by default)
+	pub entry: PathBuf,
+	pub dependencies: Vec<(String, Source)>,
+	/// The directory containing the manifest, which relative paths start from
+	pub dir: PathBuf,
+}
+
+enum TomlValue {
+	String(String),
+	Table(Vec<(String, String)>),
+}
+
+// Parses a key at the start of a line, and returns it with the rest of the line
+fn parse_key(s: &str) -> Option<(&str, &str)> {
+	let end = s.find(|c: char| !c.is_ascii_alphanumeric() && c != '_' && c != '-').unwrap_or(s.len());
+	if end == 0 { None } else { Some((&s[..end], s[end..].trim_start())) }
+}
+
+fn parse_string(s: &str) -> Option<(String, &str)> {
+	let mut res = String::new();
+	let mut chars = s.strip_prefix('"')?.char_indices();
+	while let Some((i, c)) = chars.next() {
+		match c {
+			'"' => return Some((res, s[i + 2..].trim_start())),
+			'\\' => res.push(match chars.next()?.1 {
+				'n' => '\n',
+				't' => '\t',
+				c @ ('"' | '\\') => c,
+				_ => return None,
+			}),
+			c => res.push(c),
+		}
+	}
+	None
+}
+
+fn parse_value(s: &str) -> Option<(TomlValue, &str)> {
+	if let Some(mut s) = s.strip_prefix('{') {
+		let mut table = vec![];
+		s = s.trim_start();
+		if let Some(rest) = s.strip_prefix('}') {
+			return Some((TomlValue::Table(table), rest.trim_start()));
+		}
+		loop {
+			let (key, rest) = parse_key(s)?;
+			let (value, rest) = parse_string(rest.strip_prefix('=')?.trim_start())?;
+			table.push((String::from(key), value));
+			if let Some(rest) = rest.strip_prefix('}') {
+				return Some((TomlValue::Table(table), rest.trim_start()));
+			}
+			s = rest.strip_prefix(',')?.trim_start();
+		}
+	} else {
+		parse_string(s).map(|(string, rest)| (TomlValue::String(string), rest))
+	}
+}
+
+impl Manifest {
+	/// Parses the contents of a manifest, which is in the directory `dir`.
+	pub fn parse(contents: &str, dir: &Path) -> Result<Manifest, HissyError> {
+		let mut manifest = Manifest {
+			name: String::new(),
+			version: String::new(),
+			entry: PathBuf::from("main.hsy"),
+			dependencies: vec![],
+			dir: dir.to_path_buf(),
+		};
+		let mut section = String::new();
+		for (i, line) in contents.lines().enumerate() {
+			let syntax_error = |s: &str| HissyError(ErrorType::Syntax, format!("Invalid manifest: {}", s), (i + 1) as u16);
+			let line = line.trim();
+			if line.is_empty() || line.starts_with('#') {
+				continue;
+			}
+			if let Some(name) = line.strip_prefix('[') {
+				let name = name.split('#').next().unwrap().trim_end().strip_suffix(']')
+					.ok_or_else(|| syntax_error("expected ']'"))?;
+				section = String::from(name.trim());
+				continue;
+			}
+
+			let (key, rest) = parse_key(line).ok_or_else(|| syntax_error("expected key"))?;
+			let value = rest.strip_prefix('=').and_then(|rest| parse_value(rest.trim_start()))
+				.filter(|(_, rest)| rest.is_empty() || rest.starts_with('#'))
+				.map(|(value, _)| value)
+				.ok_or_else(|| syntax_error("expected '=' followed by a string or an inline table"))?;
+			match (section.as_str(), key, value) {
+				("package", "name", TomlValue::String(s)) => manifest.name = s,
+				("package", "version", TomlValue::String(s)) => manifest.version = s,
+				("package", "entry", TomlValue::String(s)) => manifest.entry = PathBuf::from(s),
+				("dependencies", name, TomlValue::Table(table)) => {
+					let get = |key: &str| table.iter().find(|(k, _)| k == key).map(|(_, v)| v.clone());
+					let source = match (get("path"), get("git")) {
+						(Some(path), None) => Source::Path(PathBuf::from(path)),
+						(None, Some(url)) => Source::Git { url, rev: get("rev") },
+						_ => return Err(syntax_error(&format!("dependency '{}' needs either a path or a git URL", name))),
+					};
+					manifest.dependencies.push((String::from(name), source));
+				},
+				(section, key, _) => return Err(syntax_error(&format!("unexpected key '{}' in section [{}]", key, section))),
+			}
+		}
+		if manifest.name.is_empty() {
+			return Err(HissyError(ErrorType::Syntax, String::from("Invalid manifest: missing package name"), 0));
+		}
+		Ok(manifest)
+	}
+
+	/// Reads the manifest of the package in a directory.
+	pub fn from_dir<T: AsRef<Path>>(dir: T) -> Result<Manifest, HissyError> {
+		let path = dir.as_ref().join(MANIFEST_NAME);
+		let contents = fs::read_to_string(&path).map_err(|e| error(format!("Unable to read {:?}: {}", path, e)))?;
+		Manifest::parse(&contents, dir.as_ref())
+	}
+
+	/// The path of the main script of the package.
+	pub fn entry_path(&self) -> PathBuf {
+		self.dir.join(&self.entry)
+	}
+}
+
+// Clones a git repository, if it was not already
+fn fetch_git(url: &str, rev: Option<&str>, dest: &Path) -> Result<(), HissyError> {
+	if dest.exists() {
+		return Ok(());
+	}
+	let git = |args: &[&str]| -> Result<(), HissyError> {
+		let status = Command::new("git").args(args).status()
+			.map_err(|e| error(format!("Unable to run git: {}", e)))?;
+		if status.success() { Ok(()) } else { Err(error(format!("Unable to fetch {}: git {} failed", url, args[0]))) }
+	};
+	let dest_str = dest.to_str().ok_or_else(|| error(format!("Invalid path {:?}", dest)))?;
+	git(&["clone", "--quiet", url, dest_str])?;
+	if let Some(rev) = rev {
+		git(&["-C", dest_str, "checkout", "--quiet", rev])?;
+	}
+	Ok(())
+}
+
+struct Package {
+	modules_dir: PathBuf,
+	entry: PathBuf,
+	dependencies: HashMap<String, usize>,
+}
+
+/// A [`ModuleResolver`] for the imports of a package and of its dependencies.
+///
+/// The modules of a package are the scripts in the directory of its entry point: `import a.b` imports
+/// `a/b.hsy` from there. A dependency is imported by its name, which refers to its entry point, and
+/// its modules by names starting with the name of the dependency, eg. `import utils.strings`.
+/// Each package can import its own modules and those of its direct dependencies.
+///
+/// Files embedded with `embed("path")` are found relative to the directory of the module embedding them,
+/// and must be in the directory of the entry point of its package.
+pub struct PackageResolver {
+	packages: Vec<Package>, // The root package first
+	modules: HashMap<String, usize>, // Package of each resolved module
+}
+
+impl PackageResolver {
+	/// Finds the dependencies of a package, recursively; git dependencies are cloned into
+	/// the `.hissy-deps` directory next to the manifest, unless they already are.
+	pub fn new(manifest: &Manifest) -> Result<PackageResolver, HissyError> {
+		let mut resolver = PackageResolver { packages: vec![], modules: HashMap::new() };
+		let mut dirs = HashMap::new();
+		resolver.add_package(manifest, &manifest.dir.join(DEPS_DIR), &mut dirs)?;
+		Ok(resolver)
+	}
+
+	/// Creates a resolver for a script which is not part of a package: it can import
+	/// the scripts in its directory, and embed files relative to it.
+	pub fn for_script<T: AsRef<Path>>(path: T) -> PackageResolver {
+		let entry = path.as_ref().to_path_buf();
+		let modules_dir = entry.parent().map_or_else(PathBuf::new, Path::to_path_buf);
+		PackageResolver { packages: vec![Package { modules_dir, entry, dependencies: HashMap::new() }], modules: HashMap::new() }
+	}
+	
+	// Adds a package and its dependencies, unless a package in the same directory was already added
+	fn add_package(&mut self, manifest: &Manifest, deps_dir: &Path, dirs: &mut HashMap<PathBuf, usize>) -> Result<usize, HissyError> {
+		let dir = manifest.dir.canonicalize().map_err(|e| error(format!("Unable to find package {:?}: {}", manifest.dir, e)))?;
+		if let Some(&id) = dirs.get(&dir) {
+			return Ok(id);
+		}
+		let entry = manifest.entry_path();
+		let modules_dir = entry.parent().map_or_else(|| manifest.dir.clone(), Path::to_path_buf);
+		let id = self.packages.len();
+		self.packages.push(Package { modules_dir, entry, dependencies: HashMap::new() });
+		dirs.insert(dir, id);
+
+		for (name, source) in &manifest.dependencies {
+			let dep_dir = match source {
+				Source::Path(path) => manifest.dir.join(path),
+				Source::Git { url, rev } => {
+					let dest = deps_dir.join(name);
+					fetch_git(url, rev.as_deref(), &dest)?;
+					dest
+				},
+			};
+			// Packages without a manifest have no dependencies, and their entry point is main.hsy
+			let dep = if dep_dir.join(MANIFEST_NAME).exists() {
+				Manifest::from_dir(&dep_dir)?
+			} else {
+				Manifest { name: name.clone(), version: String::new(), entry: PathBuf::from("main.hsy"), dependencies: vec![], dir: dep_dir }
+			};
+			let dep_id = self.add_package(&dep, deps_dir, dirs)?;
+			self.packages[id].dependencies.insert(name.clone(), dep_id);
+		}
+		Ok(id)
+	}
+}
+
+impl ModuleResolver for PackageResolver {
+	fn resolve(&mut self, name: &str, importer: Option<&str>) -> Result<(String, String), String> {
+		let package = importer.and_then(|id| self.modules.get(id)).copied().unwrap_or(0);
+		let parts: Vec<&str> = name.split('.').collect();
+		let (package, parts) = match self.packages[package].dependencies.get(parts[0]) {
+			Some(&dep) => (dep, &parts[1..]),
+			None => (package, &parts[..]),
+		};
+		let path = if parts.is_empty() {
+			self.packages[package].entry.clone()
+		} else {
+			parts.iter().fold(self.packages[package].modules_dir.clone(), |path, part| path.join(part)).with_extension("hsy")
+		};
+		let source = fs::read_to_string(&path).map_err(|e| format!("unable to read {:?}: {}", path, e))?;
+		let id = path.canonicalize().map_err(|e| e.to_string())?.to_string_lossy().into_owned();
+		self.modules.insert(id.clone(), package);
+		Ok((id, source))
+	}
+	
+	fn embed(&mut self, path: &str, importer: Option<&str>) -> Result<String, String> {
+		let package = importer.and_then(|id| self.modules.get(id)).copied().unwrap_or(0);
+		let dir = match importer {
+			Some(id) => Path::new(id).parent().map_or_else(PathBuf::new, Path::to_path_buf),
+			None => self.packages[0].modules_dir.clone(),
+		};
+		// Files outside of the package (through absolute paths, `..` or links) are not embedded
+		let root = &self.packages[package].modules_dir;
+		let root = if root.as_os_str().is_empty() { Path::new(".") } else { root.as_path() };
+		let path = dir.join(path);
+		let (full_path, root) = path.canonicalize().and_then(|full_path| Ok((full_path, root.canonicalize()?)))
+			.map_err(|e| format!("unable to read {:?}: {}", path, e))?;
+		if !full_path.starts_with(&root) {
+			return Err(format!("{:?} is outside of the package directory", path));
+		}
+		fs::read_to_string(&full_path).map_err(|e| format!("unable to read {:?}: {}", path, e))
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::compiler::Compiler;
+	use crate::vm::gc::GCHeap;
+	use std::convert::TryFrom;
+
+	#[test]
+	fn test_manifest() {
+		let manifest = Manifest::parse("# Game\n[package]\nname = \"game\"\nversion = \"0.1.0\" # first\nentry = \"src/main.hsy\"\n\n\
+			[dependencies]\nutils = { path = \"../utils\" }\njson = {git = \"https://example.com/json.git\", rev=\"v1\"}\n", Path::new("dir")).unwrap();
+		assert_eq!((manifest.name.as_str(), manifest.version.as_str()), ("game", "0.1.0"));
+		assert_eq!(manifest.entry_path(), Path::new("dir/src/main.hsy"));
+		assert_eq!(manifest.dependencies, vec![
+			(String::from("utils"), Source::Path(PathBuf::from("../utils"))),
+			(String::from("json"), Source::Git { url: String::from("https://example.com/json.git"), rev: Some(String::from("v1")) }),
+		]);
+
+		let err = Manifest::parse("[package]\nname = \"a\"\nversion = 1\n", Path::new("")).err().unwrap();
+		assert_eq!(err.2, 3);
+		assert!(Manifest::parse("[package]\nname = \"a\"\n[dependencies]\nb = {}\n", Path::new("")).is_err());
+		assert!(Manifest::parse("[package]\nversion = \"1\"\n", Path::new("")).is_err());
+	}
+
+	#[test]
+	fn test_package_resolver() {
+		let dir = std::env::temp_dir().join(format!("hissy-package-test-{}", std::process::id()));
+		let write = |path: &str, contents: &str| {
+			let path = dir.join(path);
+			fs::create_dir_all(path.parent().unwrap()).unwrap();
+			fs::write(path, contents).unwrap();
+		};
+		write("game/hissy.toml", "[package]\nname = \"game\"\nentry = \"src/main.hsy\"\n[dependencies]\nutils = { path = \"../utils\" }\n");
+		write("game/src/main.hsy", "import utils\nimport utils.more\nimport consts\nlet res = double(three) + triple(1)\n");
+		write("game/src/consts.hsy", "let three = 3\n");
+		write("utils/main.hsy", "import helper\nlet double(x: Int) -> Int:\n\treturn helper(x)\n");
+		write("utils/helper.hsy", "let helper(x: Int) -> Int:\n\treturn x * 2\n");
+		write("utils/more.hsy", "import helper\nlet triple(x: Int) -> Int:\n\tassert(embed(\"data/zero.txt\") == \"0\")\n\treturn helper(x) + x\n");
+		write("utils/data/zero.txt", "0");
+
+		let manifest = Manifest::from_dir(dir.join("game")).unwrap();
+		let mut compiler = Compiler::new(false);
+		compiler.set_resolver(PackageResolver::new(&manifest).unwrap());
+		let code = fs::read_to_string(manifest.entry_path()).unwrap();
+		let func = compiler.compile_function(&(code + "res\n"), &[]).unwrap();
+		let res = func.call(&mut GCHeap::new(), vec![]).unwrap();
+		assert_eq!(i32::try_from(&res).unwrap(), 9);
+
+		let mut compiler = Compiler::new(false);
+		compiler.set_resolver(PackageResolver::new(&manifest).unwrap());
+		let err = compiler.compile_program("import missing\n").err().unwrap();
+		assert!(err.1.starts_with("Cannot import 'missing': unable to read"));
+		
+		// Scripts outside of packages embed files relative to their directory
+		let mut compiler = Compiler::new(false);
+		compiler.set_resolver(PackageResolver::for_script(dir.join("utils/main.hsy")));
+		let func = compiler.compile_function("embed(\"data/zero.txt\") == \"0\"", &[]).unwrap();
+		assert!(bool::try_from(&func.call(&mut GCHeap::new(), vec![]).unwrap()).unwrap());
+		
+		// Files outside of the package cannot be embedded
+		let outside = dir.join("game/hissy.toml");
+		for path in ["../game/hissy.toml", "data/../../game/hissy.toml", outside.to_str().unwrap()] {
+			let mut compiler = Compiler::new(false);
+			compiler.set_resolver(PackageResolver::for_script(dir.join("utils/main.hsy")));
+			let err = compiler.compile_program(&format!("let s = embed({:?})\n", path)).err().unwrap();
+			assert!(err.1.ends_with("is outside of the package directory"), "{}", err.1);
+		}
+		let mut compiler = Compiler::new(false);
+		compiler.set_resolver(PackageResolver::new(&manifest).unwrap());
+		let err = compiler.compile_program("let s = embed(\"../hissy.toml\")\n").err().unwrap();
+		assert!(err.1.ends_with("is outside of the package directory"), "{}", err.1);
+		fs::remove_dir_all(&dir).unwrap();
+	}
+}
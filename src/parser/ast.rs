@@ -2,6 +2,8 @@
 use std::fmt;
 use std::ops::Deref;
 
+pub use super::symbol::Symbol;
+
 /// A binary operator.
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub enum BinOp {
@@ -13,6 +15,30 @@ pub enum BinOp {
 	And, Or,
 }
 
+impl BinOp {
+	/// Returns the operator as written in Hissy code.
+	pub fn symbol(&self) -> &'static str {
+		match self {
+			BinOp::Plus => "+", BinOp::Minus => "-",
+			BinOp::Times => "*", BinOp::Divides => "/", BinOp::Modulo => "%",
+			BinOp::Power => "^",
+			BinOp::LEq => "<=", BinOp::GEq => ">=", BinOp::Less => "<", BinOp::Greater => ">",
+			BinOp::Equal => "==", BinOp::NEq => "!=",
+			BinOp::And => "and", BinOp::Or => "or",
+		}
+	}
+	
+	/// Returns whether the result of the operator stays the same when its operands are swapped.
+	pub fn is_commutative(&self) -> bool {
+		matches!(self, BinOp::Plus | BinOp::Times | BinOp::Equal | BinOp::NEq | BinOp::And | BinOp::Or)
+	}
+	
+	/// Returns whether the operator is a comparison, which returns a Bool.
+	pub fn is_comparison(&self) -> bool {
+		matches!(self, BinOp::LEq | BinOp::GEq | BinOp::Less | BinOp::Greater | BinOp::Equal | BinOp::NEq)
+	}
+}
+
 /// A unary operator.
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub enum UnaOp {
@@ -27,16 +53,56 @@ pub enum Expr {
 	Bool(bool),
 	Int(i32),
 	Real(f64),
+	Char(char),
 	String(String),
-	Id(String),
+	Symbol(Symbol), // symbol literal, not an identifier
+	Embed(String), // path of a file, replaced by its contents before compilation
+	Id(Symbol),
 	
 	List(Vec<Expr>),
 	BinOp(BinOp, Box<Expr>, Box<Expr>),
+	/// Chain of binary operations `a op1 b op2 c...`, evaluated from left to right, as if the operators
+	/// were left-associative; long chains are parsed into this rather than nested `BinOp`s, to keep the tree shallow
+	Chain(Box<Expr>, Vec<(BinOp, Expr)>),
 	UnaOp(UnaOp, Box<Expr>),
 	Index(Box<Expr>, Box<Expr>),
 	Call(Box<Expr>, Vec<Expr>),
-	Prop(Box<Expr>, String),
-	Function(Vec<(String, Type)>, Type, Block),
+	Prop(Box<Expr>, Symbol),
+	/// Arguments, variables captured by value, return type, and body
+	Function(Vec<(Symbol, Type)>, Vec<Symbol>, Type, Block),
+}
+
+// Shorthands for tools building syntax trees, see Compiler::compile_ast
+impl Expr {
+	/// Builds a reference to a variable.
+	pub fn id(name: &str) -> Expr {
+		Expr::Id(Symbol::intern(name))
+	}
+	
+	/// Builds a binary operation.
+	pub fn binop(op: BinOp, a: Expr, b: Expr) -> Expr {
+		Expr::BinOp(op, Box::new(a), Box::new(b))
+	}
+	
+	/// Builds a unary operation.
+	pub fn unaop(op: UnaOp, a: Expr) -> Expr {
+		Expr::UnaOp(op, Box::new(a))
+	}
+	
+	/// Builds a function call.
+	pub fn call(f: Expr, args: Vec<Expr>) -> Expr {
+		Expr::Call(Box::new(f), args)
+	}
+	
+	/// Builds an indexing operation, eg. `list[i]`.
+	pub fn index(list: Expr, idx: Expr) -> Expr {
+		Expr::Index(Box::new(list), Box::new(idx))
+	}
+	
+	/// Builds an access to a property or method, eg. `list.add`.
+	pub fn prop(e: Expr, name: &str) -> Expr {
+		Expr::Prop(Box::new(e), Symbol::intern(name))
+	}
 }
 
 /// The guard on a condition branch (else / else if).
@@ -52,14 +118,21 @@ pub type Branch = (Cond, Block);
 /// A type description.
 #[derive(Debug, PartialEq, Clone)]
 pub enum Type {
-	Named(String),
+	Named(Symbol),
 	Function(Vec<Type>, Box<Type>),
 }
 
+impl Type {
+	/// Builds a reference to a type by name, eg. `Int`.
+	pub fn named(name: &str) -> Type {
+		Type::Named(Symbol::intern(name))
+	}
+}
+
 /// The left-hand side of an assignment
 #[derive(Debug, PartialEq, Clone)]
 pub enum LExpr {
-	Id(String),
+	Id(Symbol),
 	Index(Box<Expr>, Box<Expr>),
 }
 
@@ -67,14 +140,37 @@ pub enum LExpr {
 #[derive(Debug, PartialEq, Clone)]
 pub enum Stat {
 	ExprStat(Expr),
-	Let(String, Option<Type>, Expr),
+	Let(Symbol, Option<Type>, Expr, Option<String>, Vec<Annotation>), // with doc comment
+	Const(Symbol, Expr, Option<String>, Vec<Annotation>), // function which can be evaluated at compile time
 	Set(LExpr, Expr),
 	Cond(Vec<Branch>),
 	While(Expr, Block),
-	For(String, Option<Type>, Expr, Block),
+	For(Symbol, Option<Type>, Expr, Block),
 	Return(Expr),
+	Defer(Expr), // Evaluated when leaving the enclosing block
+	Enum(Symbol, Vec<Symbol>),
+	Match(Expr, Vec<MatchArm>, Option<Block>), // with else block
+	Import(String), // dotted module name, replaced by the statements of the module before compilation
 }
 
+impl Stat {
+	/// Builds a `let` statement, without type annotation.
+	pub fn define(name: &str, e: Expr) -> Stat {
+		Stat::Let(Symbol::intern(name), None, e, None, vec![])
+	}
+	
+	/// Positions the statement at the start of a line, for error messages and debug info.
+	pub fn at(self, line: usize) -> Positioned<Stat> {
+		Positioned(self, (line, 1))
+	}
+}
+
+/// An annotation on a function declaration, eg. `@deprecated("Use g instead")`: its name and arguments
+pub type Annotation = (Symbol, Vec<String>);
+
+/// The values a `match` arm compares against, and its body
+pub type MatchArm = (Vec<Expr>, Block);
+
 /// A token with an associated positioned line number
 #[derive(PartialEq, Clone)]
 pub struct Positioned<T>(pub T, pub (usize, usize));
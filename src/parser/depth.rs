@@ -0,0 +1,147 @@
+
+use crate::{HissyError, ErrorType};
+use super::MAX_NESTING;
+use super::lexer::{Token, Tokens};
+use super::ast::*;
+
+
+fn too_deep(line: usize) -> HissyError {
+	HissyError(ErrorType::Syntax, format!("Code nested too deeply (more than {} levels)", MAX_NESTING), line as u16)
+}
+
+// Whether a token can end an operand, making a following '-' a binary operator
+fn ends_operand(token: &Token) -> bool {
+	match token {
+		Token::Symbol(s) => s == ")" || s == "]" || s == "nil" || s == "true" || s == "false",
+		Token::Newline | Token::Indent | Token::Dedent | Token::Doc(_) | Token::EOF => false,
+		_ => true,
+	}
+}
+
+// Rejects code which would overflow the stack of the recursive parser, before parsing it.
+//
+// Delimiters and blocks each add a level, as do prefix operators and '^', which nest their
+// operand ('^' counts twice, since its right operand is parsed by a full recursive descent);
+// operators are counted until the next ',' or line, which over-estimates the depth of flat
+// expressions like `a ^ 2 + b ^ 2`, but keeps the check simple.
+pub(super) fn check_tokens(tokens: &Tokens) -> Result<(), HissyError> {
+	let mut frames: Vec<usize> = vec![0]; // Number of operators counted in each open delimiter or block
+	let mut depth = 0;
+	let mut prev: Option<&Token> = None;
+	for (token, pos) in tokens.tokens.iter().zip(&tokens.token_pos) {
+		match token {
+			Token::Symbol(s) if s == "(" || s == "[" => {
+				frames.push(0);
+				depth += 1;
+			},
+			Token::Indent => {
+				frames.push(0);
+				depth += 1;
+			},
+			Token::Symbol(s) if (s == ")" || s == "]") && frames.len() > 1 => {
+				depth -= 1 + frames.pop().unwrap();
+			},
+			Token::Dedent if frames.len() > 1 => {
+				depth -= 1 + frames.pop().unwrap();
+			},
+			Token::Symbol(s) if s == "not" || (s == "-" && !prev.is_some_and(ends_operand)) => {
+				*frames.last_mut().unwrap() += 1;
+				depth += 1;
+			},
+			Token::Symbol(s) if s == "^" => {
+				*frames.last_mut().unwrap() += 2;
+				depth += 2;
+			},
+			Token::Symbol(s) if s == "," => {
+				depth -= std::mem::take(frames.last_mut().unwrap());
+			},
+			Token::Newline => {
+				depth -= std::mem::take(frames.last_mut().unwrap());
+			},
+			_ => {},
+		}
+		if depth > MAX_NESTING {
+			return Err(too_deep(pos.line));
+		}
+		prev = Some(token);
+	}
+	Ok(())
+}
+
+// Rejects syntax trees which would overflow the stack of the recursive compiler, such as long
+// chains of calls, which the parser handles without recursion.
+pub(crate) fn check_ast(ast: &ProgramAST) -> Result<(), HissyError> {
+	check_block(ast, 0)
+}
+
+fn check_block(block: &Block, depth: usize) -> Result<(), HissyError> {
+	for Positioned(stat, (line, _)) in block {
+		check_stat(stat, depth + 1, *line)?;
+	}
+	Ok(())
+}
+
+fn check_stat(stat: &Stat, depth: usize, line: usize) -> Result<(), HissyError> {
+	if depth > MAX_NESTING {
+		return Err(too_deep(line));
+	}
+	match stat {
+		Stat::ExprStat(e) | Stat::Let(_, _, e, _, _) | Stat::Const(_, e, _, _) | Stat::Return(e) | Stat::Defer(e) =>
+			check_expr(e, depth, line),
+		Stat::Set(lexpr, e) => {
+			if let LExpr::Index(list, idx) = lexpr {
+				check_expr(list, depth + 1, line)?;
+				check_expr(idx, depth + 1, line)?;
+			}
+			check_expr(e, depth, line)
+		},
+		Stat::Cond(branches) => {
+			for (cond, block) in branches {
+				if let Cond::If(e) = cond {
+					check_expr(e, depth, line)?;
+				}
+				check_block(block, depth)?;
+			}
+			Ok(())
+		},
+		Stat::While(e, block) | Stat::For(_, _, e, block) => {
+			check_expr(e, depth, line)?;
+			check_block(block, depth)
+		},
+		Stat::Match(e, arms, default) => {
+			check_expr(e, depth, line)?;
+			for (values, block) in arms {
+				for value in values {
+					check_expr(value, depth, line)?;
+				}
+				check_block(block, depth)?;
+			}
+			default.iter().try_for_each(|block| check_block(block, depth))
+		},
+		Stat::Enum(_, _) | Stat::Import(_) => Ok(()),
+	}
+}
+
+fn check_expr(e: &Expr, depth: usize, line: usize) -> Result<(), HissyError> {
+	if depth > MAX_NESTING {
+		return Err(too_deep(line));
+	}
+	match e {
+		Expr::List(values) => values.iter().try_for_each(|value| check_expr(value, depth + 1, line)),
+		Expr::BinOp(_, a, b) | Expr::Index(a, b) => {
+			check_expr(a, depth + 1, line)?;
+			check_expr(b, depth + 1, line)
+		},
+		Expr::UnaOp(_, a) | Expr::Prop(a, _) => check_expr(a, depth + 1, line),
+		Expr::Chain(first, rest) => {
+			check_expr(first, depth + 1, line)?;
+			rest.iter().try_for_each(|(_, e)| check_expr(e, depth + 1, line))
+		},
+		Expr::Call(f, args) => {
+			check_expr(f, depth + 1, line)?;
+			args.iter().try_for_each(|arg| check_expr(arg, depth + 1, line))
+		},
+		Expr::Function(_, _, _, body) => check_block(body, depth + 1),
+		_ => Ok(()),
+	}
+}
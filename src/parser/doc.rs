@@ -0,0 +1,143 @@
+
+use std::fmt::Write;
+use std::ops::Deref;
+
+use super::ast::*;
+use super::dot::type_repr;
+
+
+/// The output format of [`to_doc`] and [`to_doc_index`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DocFormat {
+	Markdown,
+	Html,
+}
+
+impl DocFormat {
+	/// The usual extension of files in this format.
+	pub fn extension(self) -> &'static str {
+		match self {
+			DocFormat::Markdown => "md",
+			DocFormat::Html => "html",
+		}
+	}
+}
+
+fn function_repr(id: Symbol, args: &[(Symbol, Type)], ret: &Type) -> String {
+	let args: Vec<String> = args.iter().map(|(arg, ty)| format!("{}: {}", arg, type_repr(ty))).collect();
+	match ret {
+		Type::Named(name) if name.deref() == "Nil" => format!("{}({})", id, args.join(", ")),
+		_ => format!("{}({}) -> {}", id, args.join(", "), type_repr(ret)),
+	}
+}
+
+fn escape_html(s: &str) -> String {
+	s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+// A documented declaration
+struct Item<'a> {
+	signature: String,
+	doc: Option<&'a str>,
+	deprecated: Option<&'a str>,
+}
+
+fn is_entry_point(id: Symbol, annotations: &[Annotation]) -> bool {
+	id.starts_with("test_") || id.starts_with("bench_")
+		|| annotations.iter().any(|(name, _)| name.deref() == "test" || name.deref() == "bench")
+}
+
+// Functions and enums are always documented, other variables only if they have a doc comment
+fn items(ast: &ProgramAST) -> Vec<Item<'_>> {
+	let mut items = vec![];
+	for Positioned(stat, _) in ast {
+		let (id, signature, doc, annotations) = match stat {
+			Stat::Let(id, _, Expr::Function(args, _, ret, _), doc, annotations)
+				=> (*id, format!("let {}", function_repr(*id, args, ret)), doc, annotations),
+			Stat::Const(id, Expr::Function(args, _, ret, _), doc, annotations)
+				=> (*id, format!("const {}", function_repr(*id, args, ret)), doc, annotations),
+			Stat::Let(id, ty, _, doc @ Some(_), annotations) => {
+				let ty = ty.as_ref().map_or_else(String::new, |ty| format!(": {}", type_repr(ty)));
+				(*id, format!("let {}{}", id, ty), doc, annotations)
+			},
+			Stat::Enum(id, variants) => {
+				let variants: Vec<&str> = variants.iter().map(|v| v.deref()).collect();
+				items.push(Item { signature: format!("enum {}: {}", id, variants.join(", ")), doc: None, deprecated: None });
+				continue;
+			},
+			_ => continue,
+		};
+		if is_entry_point(id, annotations) {
+			continue;
+		}
+		let deprecated = annotations.iter().find(|(name, _)| name.deref() == "deprecated")
+			.map(|(_, args)| args.first().map_or("", String::as_str));
+		items.push(Item { signature, doc: doc.as_deref(), deprecated });
+	}
+	items
+}
+
+/// Renders the documentation of the top-level declarations of a program: their signatures,
+/// with the types of arguments and return values, `@deprecated` annotations, and doc comments.
+///
+/// Functions and enums are always listed, and other variables only if they have a doc comment;
+/// test and benchmark functions are left out. Doc comments are copied as is in Markdown,
+/// and split into paragraphs at blank lines in HTML.
+pub fn to_doc(ast: &ProgramAST, title: &str, format: DocFormat) -> String {
+	let mut out = String::new();
+	match format {
+		DocFormat::Markdown => {
+			writeln!(out, "# {}", title).unwrap();
+			for item in items(ast) {
+				writeln!(out, "\n## `{}`", item.signature).unwrap();
+				match item.deprecated {
+					Some("") => writeln!(out, "\n**Deprecated**").unwrap(),
+					Some(msg) => writeln!(out, "\n**Deprecated:** {}", msg).unwrap(),
+					None => {},
+				}
+				if let Some(doc) = item.doc {
+					writeln!(out, "\n{}", doc).unwrap();
+				}
+			}
+		},
+		DocFormat::Html => {
+			let title = escape_html(title);
+			writeln!(out, "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{}</title></head>\n<body>\n<h1>{}</h1>", title, title).unwrap();
+			for item in items(ast) {
+				writeln!(out, "<h2><code>{}</code></h2>", escape_html(&item.signature)).unwrap();
+				match item.deprecated {
+					Some("") => writeln!(out, "<p><strong>Deprecated</strong></p>").unwrap(),
+					Some(msg) => writeln!(out, "<p><strong>Deprecated:</strong> {}</p>", escape_html(msg)).unwrap(),
+					None => {},
+				}
+				for paragraph in item.doc.iter().flat_map(|doc| doc.split("\n\n")).filter(|p| !p.trim().is_empty()) {
+					writeln!(out, "<p>{}</p>", escape_html(paragraph.trim())).unwrap();
+				}
+			}
+			out.push_str("</body>\n</html>\n");
+		},
+	}
+	out
+}
+
+/// Renders a page linking to the documentation of several scripts, given as their title and relative path.
+pub fn to_doc_index(title: &str, pages: &[(String, String)], format: DocFormat) -> String {
+	let mut out = String::new();
+	match format {
+		DocFormat::Markdown => {
+			writeln!(out, "# {}\n", title).unwrap();
+			for (name, path) in pages {
+				writeln!(out, "- [{}]({})", name, path).unwrap();
+			}
+		},
+		DocFormat::Html => {
+			let title = escape_html(title);
+			writeln!(out, "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{}</title></head>\n<body>\n<h1>{}</h1>\n<ul>", title, title).unwrap();
+			for (name, path) in pages {
+				writeln!(out, "<li><a href=\"{}\">{}</a></li>", escape_html(path), escape_html(name)).unwrap();
+			}
+			out.push_str("</ul>\n</body>\n</html>\n");
+		},
+	}
+	out
+}
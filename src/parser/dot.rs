@@ -0,0 +1,211 @@
+
+use std::fmt::Write;
+
+use super::ast::*;
+
+
+fn escape(s: &str) -> String {
+	s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+pub(super) fn type_repr(ty: &Type) -> String {
+	match ty {
+		Type::Named(name) => String::from(*name),
+		Type::Function(args, ret) => {
+			let args: Vec<String> = args.iter().map(type_repr).collect();
+			format!("({}) -> {}", args.join(", "), type_repr(ret))
+		},
+	}
+}
+
+fn binding_repr(id: &str, ty: &Option<Type>) -> String {
+	ty.as_ref().map_or_else(|| String::from(id), |ty| format!("{}: {}", id, type_repr(ty)))
+}
+
+// Accumulates the nodes and edges of the graph, numbering nodes in creation order
+struct Graph {
+	out: String,
+	nodes: usize,
+}
+
+impl Graph {
+	fn node(&mut self, label: &str, parent: Option<(usize, &str)>) -> usize {
+		let id = self.nodes;
+		self.nodes += 1;
+		writeln!(self.out, "\tn{} [label=\"{}\"];", id, escape(label)).unwrap();
+		if let Some((parent, edge)) = parent {
+			if edge.is_empty() {
+				writeln!(self.out, "\tn{} -> n{};", parent, id).unwrap();
+			} else {
+				writeln!(self.out, "\tn{} -> n{} [label=\"{}\"];", parent, id, escape(edge)).unwrap();
+			}
+		}
+		id
+	}
+
+	fn block(&mut self, block: &[Positioned<Stat>], parent: (usize, &str)) {
+		let node = self.node("Block", Some(parent));
+		for stat in block {
+			self.stat(stat, node);
+		}
+	}
+
+	fn stat(&mut self, stat: &Positioned<Stat>, parent: usize) {
+		let line = (stat.1).0;
+		let parent = Some((parent, ""));
+		match &stat.0 {
+			Stat::ExprStat(e) => {
+				let node = self.node(&format!("ExprStat\nline {}", line), parent);
+				self.expr(e, (node, ""));
+			},
+			Stat::Let(id, ty, e, _, _) => {
+				let node = self.node(&format!("Let {}\nline {}", binding_repr(id, ty), line), parent);
+				self.expr(e, (node, ""));
+			},
+			Stat::Const(id, f, _, _) => {
+				let node = self.node(&format!("Const {}\nline {}", id, line), parent);
+				self.expr(f, (node, ""));
+			},
+			Stat::Set(lexpr, e) => {
+				let node = self.node(&format!("Set\nline {}", line), parent);
+				match lexpr {
+					LExpr::Id(id) => { self.node(&format!("Id {}", id), Some((node, "target"))); },
+					LExpr::Index(list, idx) => {
+						let target = self.node("Index", Some((node, "target")));
+						self.expr(list, (target, "value"));
+						self.expr(idx, (target, "index"));
+					},
+				}
+				self.expr(e, (node, "value"));
+			},
+			Stat::Cond(branches) => {
+				let node = self.node(&format!("Cond\nline {}", line), parent);
+				for (cond, block) in branches {
+					match cond {
+						Cond::If(e) => {
+							let branch = self.node("If", Some((node, "")));
+							self.expr(e, (branch, "cond"));
+							self.block(block, (branch, "then"));
+						},
+						Cond::Else => {
+							let branch = self.node("Else", Some((node, "")));
+							self.block(block, (branch, "then"));
+						},
+					}
+				}
+			},
+			Stat::While(e, block) => {
+				let node = self.node(&format!("While\nline {}", line), parent);
+				self.expr(e, (node, "cond"));
+				self.block(block, (node, "body"));
+			},
+			Stat::For(id, ty, e, block) => {
+				let node = self.node(&format!("For {}\nline {}", binding_repr(id, ty), line), parent);
+				self.expr(e, (node, "iter"));
+				self.block(block, (node, "body"));
+			},
+			Stat::Return(e) => {
+				let node = self.node(&format!("Return\nline {}", line), parent);
+				self.expr(e, (node, ""));
+			},
+			Stat::Defer(e) => {
+				let node = self.node(&format!("Defer\nline {}", line), parent);
+				self.expr(e, (node, ""));
+			},
+			Stat::Enum(id, variants) => {
+				let variants: Vec<String> = variants.iter().map(|v| String::from(*v)).collect();
+				self.node(&format!("Enum {}: {}\nline {}", id, variants.join(", "), line), parent);
+			},
+			Stat::Import(name) => { self.node(&format!("Import {}\nline {}", name, line), parent); },
+			Stat::Match(e, arms, else_block) => {
+				let node = self.node(&format!("Match\nline {}", line), parent);
+				self.expr(e, (node, "value"));
+				for (patterns, block) in arms {
+					let arm = self.node("Arm", Some((node, "")));
+					for pattern in patterns {
+						self.expr(pattern, (arm, "pattern"));
+					}
+					self.block(block, (arm, "then"));
+				}
+				if let Some(block) = else_block {
+					let arm = self.node("Else", Some((node, "")));
+					self.block(block, (arm, "then"));
+				}
+			},
+		}
+	}
+
+	fn expr(&mut self, e: &Expr, parent: (usize, &str)) {
+		let parent_edge = Some(parent);
+		match e {
+			Expr::Nil => { self.node("nil", parent_edge); },
+			Expr::Bool(b) => { self.node(&b.to_string(), parent_edge); },
+			Expr::Int(i) => { self.node(&i.to_string(), parent_edge); },
+			Expr::Real(r) => { self.node(&format!("{:?}", r), parent_edge); },
+			Expr::Symbol(s) => { self.node(&format!(":{}", s), parent_edge); },
+			Expr::Char(c) => { self.node(&format!("{:?}", c), parent_edge); },
+			Expr::String(s) => { self.node(&format!("{:?}", s), parent_edge); },
+			Expr::Embed(path) => { self.node(&format!("Embed {:?}", path), parent_edge); },
+			Expr::Id(id) => { self.node(&format!("Id {}", id), parent_edge); },
+			Expr::List(values) => {
+				let node = self.node("List", parent_edge);
+				for (i, val) in values.iter().enumerate() {
+					self.expr(val, (node, &i.to_string()));
+				}
+			},
+			Expr::BinOp(op, a, b) => {
+				let node = self.node(&format!("{:?}", op), parent_edge);
+				self.expr(a, (node, ""));
+				self.expr(b, (node, ""));
+			},
+			Expr::UnaOp(op, a) => {
+				let node = self.node(&format!("{:?}", op), parent_edge);
+				self.expr(a, (node, ""));
+			},
+			Expr::Chain(first, rest) => {
+				let node = self.node("Chain", parent_edge);
+				self.expr(first, (node, ""));
+				for (op, e) in rest {
+					self.expr(e, (node, &format!("{:?}", op)));
+				}
+			},
+			Expr::Index(list, idx) => {
+				let node = self.node("Index", parent_edge);
+				self.expr(list, (node, "value"));
+				self.expr(idx, (node, "index"));
+			},
+			Expr::Call(func, args) => {
+				let node = self.node("Call", parent_edge);
+				self.expr(func, (node, "func"));
+				for (i, arg) in args.iter().enumerate() {
+					self.expr(arg, (node, &format!("arg {}", i)));
+				}
+			},
+			Expr::Prop(obj, prop) => {
+				let node = self.node(&format!("Prop .{}", prop), parent_edge);
+				self.expr(obj, (node, ""));
+			},
+			Expr::Function(args, captures, ret, block) => {
+				let args: Vec<String> = args.iter().map(|(id, ty)| format!("{}: {}", id, type_repr(ty))).collect();
+				let mut label = format!("Function({}) -> {}", args.join(", "), type_repr(ret));
+				if !captures.is_empty() {
+					label += &format!("\ncapture [{}]", captures.iter().map(|id| id.as_str()).collect::<Vec<&str>>().join(", "));
+				}
+				let node = self.node(&label, parent_edge);
+				self.block(block, (node, "body"));
+			},
+		}
+	}
+}
+
+/// Renders a syntax tree as a graph in the Graphviz DOT format.
+pub fn to_dot(ast: &ProgramAST) -> String {
+	let mut graph = Graph { out: String::new(), nodes: 0 };
+	graph.out.push_str("digraph ast {\n\tordering=out;\n\tnode [shape=box, fontname=\"monospace\"];\n");
+	let root = graph.node("Program", None);
+	for stat in ast {
+		graph.stat(stat, root);
+	}
+	graph.out.push_str("}\n");
+	graph.out
+}
@@ -6,6 +6,63 @@ use super::ast::*;
 
 use peg::str::LineCol;
 
+// Desugars x |> f(args) into f(x, args), and x |> f into f(x)
+fn pipe(x: Expr, f: Expr) -> Expr {
+	match f {
+		Expr::Call(f, mut args) => {
+			args.insert(0, x);
+			Expr::Call(f, args)
+		},
+		f => Expr::Call(Box::new(f), vec![x]),
+	}
+}
+
+// Length of the chains of binary operations from which they are flattened into an Expr::Chain
+const CHAIN_LENGTH: usize = 16;
+
+// Builds x op y, for left-associative operators; operations on the left of long chains (eg. in
+// generated code like `a + a + ... + a`) are flattened, so that walking the tree needs little stack
+fn binop(op: BinOp, x: Expr, y: Expr) -> Expr {
+	let mut left = &x;
+	let mut length = 0;
+	while let Expr::BinOp(_, a, _) = left {
+		if length == CHAIN_LENGTH {
+			break;
+		}
+		left = a;
+		length += 1;
+	}
+	match x {
+		Expr::Chain(first, mut rest) => {
+			rest.push((op, y));
+			Expr::Chain(first, rest)
+		},
+		x if length == CHAIN_LENGTH => {
+			let mut rest = vec![(op, y)];
+			let mut first = x;
+			while let Expr::BinOp(op, a, b) = first {
+				rest.push((op, *b));
+				first = *a;
+			}
+			rest.reverse();
+			Expr::Chain(Box::new(first), rest)
+		},
+		x => Expr::BinOp(op, Box::new(x), Box::new(y)),
+	}
+}
+
+// Attaches a doc comment and annotations to the declaration following them; doc comments are ignored
+// before other statements, but annotations can only be put on function declarations
+fn attach(s: Stat, doc: Option<String>, annotations: Vec<Annotation>) -> Result<Stat, &'static str> {
+	match s {
+		Stat::Let(id, ty, e @ Expr::Function(_, _, _, _), _, _) => Ok(Stat::Let(id, ty, e, doc, annotations)),
+		Stat::Let(id, ty, e, _, _) if annotations.is_empty() => Ok(Stat::Let(id, ty, e, doc, annotations)),
+		Stat::Const(id, f, _, _) => Ok(Stat::Const(id, f, doc, annotations)),
+		s if annotations.is_empty() => Ok(s),
+		_ => Err("function declaration after annotations"),
+	}
+}
+
 peg::parser! {
 	pub grammar peg_parser() for Tokens {
 		
@@ -26,22 +83,32 @@ peg::parser! {
 			/ sym("NaN") { Expr::Real(std::f64::NAN) }
 			/ t:token() {?
 				match t {
-					Token::Id(s) => Ok(Expr::Id(s.clone())),
+					Token::Id(s) => Ok(Expr::Id(*s)),
 					Token::Int(i) => Ok(Expr::Int(*i)),
 					Token::Real(r) => Ok(Expr::Real(*r)),
+					Token::Char(c) => Ok(Expr::Char(*c)),
+					Token::Atom(s) => Ok(Expr::Symbol(*s)),
 					Token::String(s) => Ok(Expr::String(s.clone())),
 					_ => Err("literal"),
 				}
 			}
 		
-		rule identifier() -> String = t:token() {?
+		rule identifier() -> Symbol = t:token() {?
 			if let Token::Id(s) = t {
-				Ok(s.clone())
+				Ok(*s)
 			} else {
 				Err("identifier")
 			}
 		}
 		
+		rule string() -> String = t:token() {?
+			if let Token::String(s) = t {
+				Ok(s.clone())
+			} else {
+				Err("string")
+			}
+		}
+		
 		rule list(pos: &[LineCol]) -> Expr
 			= sym("[") values:(expression(pos) ** sym(",")) sym(",")? sym("]") { Expr::List(values) }
 		
@@ -50,35 +117,44 @@ peg::parser! {
 		rule function(pos: &[LineCol]) -> Expr =
 			sym("fun") f:function_decl(pos) { f }
 		
+		rule embed() -> Expr = sym("embed") sym("(") s:string() sym(")") { Expr::Embed(s) }
+		
 		rule primary_expression(pos: &[LineCol]) -> Expr
-			= literal() / list(pos) / parenthesized(pos) / function(pos)
+			= literal() / list(pos) / parenthesized(pos) / function(pos) / embed()
 		
 		pub rule expression(pos: &[LineCol]) -> Expr = precedence!{
-			x:(@) sym("and") y:@ { Expr::BinOp(BinOp::And, Box::new(x), Box::new(y)) }
-			x:(@) sym("or") y:@  { Expr::BinOp(BinOp::Or,  Box::new(x), Box::new(y)) }
+			x:(@) sym("|>") f:@ { pipe(x, f) }
+			--
+			x:(@) sym("and") y:@ { binop(BinOp::And, x, y) }
+			x:(@) sym("or") y:@  { binop(BinOp::Or, x, y) }
 			--
 			sym("not") x:@ { Expr::UnaOp(UnaOp::Not, Box::new(x)) }
 			--
-			x:(@) sym("<=") y:@ { Expr::BinOp(BinOp::LEq, Box::new(x), Box::new(y)) }
-			x:(@) sym(">=") y:@ { Expr::BinOp(BinOp::GEq, Box::new(x), Box::new(y)) }
-			x:(@) sym("<") y:@ { Expr::BinOp(BinOp::Less,    Box::new(x), Box::new(y)) }
-			x:(@) sym(">") y:@ { Expr::BinOp(BinOp::Greater, Box::new(x), Box::new(y)) }
-			x:(@) sym("==") y:@ { Expr::BinOp(BinOp::Equal, Box::new(x), Box::new(y)) }
-			x:(@) sym("!=") y:@ { Expr::BinOp(BinOp::NEq, Box::new(x), Box::new(y)) }
+			x:(@) sym("<=") y:@ { binop(BinOp::LEq, x, y) }
+			x:(@) sym(">=") y:@ { binop(BinOp::GEq, x, y) }
+			x:(@) sym("<") y:@ { binop(BinOp::Less, x, y) }
+			x:(@) sym(">") y:@ { binop(BinOp::Greater, x, y) }
+			x:(@) sym("==") y:@ { binop(BinOp::Equal, x, y) }
+			x:(@) sym("!=") y:@ { binop(BinOp::NEq, x, y) }
 			--
-			x:(@) sym("+") y:@ { Expr::BinOp(BinOp::Plus,  Box::new(x), Box::new(y)) }
-			x:(@) sym("-") y:@ { Expr::BinOp(BinOp::Minus, Box::new(x), Box::new(y)) }
+			x:(@) sym("+") y:@ { binop(BinOp::Plus, x, y) }
+			x:(@) sym("-") y:@ { binop(BinOp::Minus, x, y) }
 			--
 			sym("-") x:@ { Expr::UnaOp(UnaOp::Minus, Box::new(x)) }
 			--
-			x:(@) sym("*") y:@ { Expr::BinOp(BinOp::Times,   Box::new(x), Box::new(y)) }
-			x:(@) sym("/") y:@ { Expr::BinOp(BinOp::Divides, Box::new(x), Box::new(y)) }
-			x:(@) sym("%") y:@ { Expr::BinOp(BinOp::Modulo,  Box::new(x), Box::new(y)) }
+			x:(@) sym("*") y:@ { binop(BinOp::Times, x, y) }
+			x:(@) sym("/") y:@ { binop(BinOp::Divides, x, y) }
+			x:(@) sym("%") y:@ { binop(BinOp::Modulo, x, y) }
 			--
 			x:@ sym("^") y:(@) { Expr::BinOp(BinOp::Power,   Box::new(x), Box::new(y)) }
 			--
 			x:@ sym("[") i:expression(pos) sym("]") { Expr::Index(Box::new(x), Box::new(i)) }
-			f:@ sym("(") args:(expression(pos) ** sym(",")) sym(",")? sym(")") { Expr::Call(Box::new(f), args) }
+			f:@ sym("(") args:(expression(pos) ** sym(",")) sym(",")? sym(")") l:function(pos)? {
+				// A function literal following the call is passed as last argument
+				let mut args = args;
+				args.extend(l);
+				Expr::Call(Box::new(f), args)
+			}
 			x:@ sym(".") p:identifier() { Expr::Prop(Box::new(x), p) }
 			--
 			e:primary_expression(pos) { e }
@@ -86,30 +162,38 @@ peg::parser! {
 		
 		rule type_desc() -> Type
 			= t:identifier() { Type::Named(t) }
-		rule typed_ident() -> (String, Option<Type>)
+		rule typed_ident() -> (Symbol, Option<Type>)
 			= i:identifier() sym(":") t:type_desc() { (i, Some(t)) }
 			/ i:identifier() { (i, None) }
+		rule captures() -> Vec<Symbol>
+			= sym("capture") sym("[") c:(identifier() ** sym(",")) sym(",")? sym("]") { c }
+			/ { vec![] }
 		rule return_type() -> Type
 			= sym("->") t:type_desc() { t }
-			/ { Type::Named(String::from("Nil")) }
+			/ { Type::Named(Symbol::intern("Nil")) }
 		
 		rule function_decl(pos: &[LineCol]) -> Expr
-			= sym("(") a:(typed_ident() ** sym(",")) sym(")") r:return_type() b:indented_block(pos) {
+			= sym("(") a:(typed_ident() ** sym(",")) sym(",")? sym(")") c:captures() r:return_type() b:indented_block(pos) {
 				let a = a.iter().map(|(i,t)|
-					(i.clone(), t.clone().unwrap_or(Type::Named(String::from("Any"))))
+					(*i, t.clone().unwrap_or(Type::Named(Symbol::intern("Any"))))
 				).collect();
-				Expr::Function(a, r, b)
+				Expr::Function(a, c, r, b)
 			}
 		
 		rule if_branch(pos: &[LineCol]) -> Branch = sym("if") c:expression(pos) b:indented_block(pos) { (Cond::If(c), b) }
 		rule else_if_branch(pos: &[LineCol]) -> Branch = [Token::Newline] sym("else") b:if_branch(pos) { b }
 		rule else_branch(pos: &[LineCol]) -> Branch = [Token::Newline] sym("else") b:indented_block(pos) { (Cond::Else, b) }
 		
+		rule match_arm(pos: &[LineCol]) -> MatchArm = p:(expression(pos) ++ sym(",")) b:indented_block(pos) { (p, b) }
+		rule match_else(pos: &[LineCol]) -> Block = [Token::Newline] sym("else") b:indented_block(pos) { b }
+		
 		rule assignment(pos: &[LineCol]) -> Expr = sym("=") e:expression(pos) { e }
 		
 		rule statement(pos: &[LineCol]) -> Stat
-			= sym("let") i:typed_ident() sym("=") e:expression(pos) { Stat::Let(i.0, i.1, e) }
-			/ sym("let") i:identifier() f:function_decl(pos) { Stat::Let(i, None, f) }
+			= sym("let") i:typed_ident() sym("=") e:expression(pos) { Stat::Let(i.0, i.1, e, None, vec![]) }
+			/ sym("let") i:identifier() f:function_decl(pos) { Stat::Let(i, None, f, None, vec![]) }
+			/ sym("const") i:identifier() f:function_decl(pos) { Stat::Const(i, f, None, vec![]) }
+			/ sym("fun") i:identifier() f:function_decl(pos) { Stat::Let(i, None, f, None, vec![]) }
 			/ i:if_branch(pos) ei:else_if_branch(pos)* e:else_branch(pos)? {
 				let mut branches = vec![i];
 				branches.extend_from_slice(&ei);
@@ -117,7 +201,15 @@ peg::parser! {
 				Stat::Cond(branches)
 			}
 			/ sym("return") e:expression(pos)? { Stat::Return(e.unwrap_or(Expr::Nil)) }
+			/ sym("defer") e:expression(pos) { Stat::Defer(e) }
 			/ sym("while") e:expression(pos) b:indented_block(pos) { Stat::While(e, b) }
+			/ sym("enum") i:identifier() sym(":") v:(identifier() ++ sym(",")) { Stat::Enum(i, v) }
+			/ sym("import") m:(identifier() ++ sym(".")) {
+				Stat::Import(m.into_iter().map(String::from).collect::<Vec<_>>().join("."))
+			}
+			/ sym("match") e:expression(pos) sym(":") [Token::Indent] a:(match_arm(pos) ++ [Token::Newline]) el:match_else(pos)? [Token::Dedent] {
+				Stat::Match(e, a, el)
+			}
 			/ e:expression(pos) a:assignment(pos)? {?
 				if let Some(assigned) = a {
 					let lexpr = match e {
@@ -136,8 +228,23 @@ peg::parser! {
 				Stat::For(i.0, i.1, e, b)
 			}
 		
+		rule doc_comment() -> String = t:token() {?
+			if let Token::Doc(s) = t {
+				Ok(s.clone())
+			} else {
+				Err("doc comment")
+			}
+		}
+		
+		rule annotation() -> Annotation
+			= sym("@") n:identifier() a:(sym("(") a:(string() ** sym(",")) sym(")") { a })? [Token::Newline] {
+				(n, a.unwrap_or_default())
+			}
+		
 		rule positioned_statement(pos: &[LineCol]) -> Positioned<Stat>
-			= p:position!() s:statement(pos) { Positioned(s, (pos[p].line, pos[p].column)) }
+			= d:doc_comment()? a:annotation()* p:position!() s:statement(pos) {?
+				attach(s, d, a).map(|s| Positioned(s, (pos[p].line, pos[p].column)))
+			}
 		
 		rule block(pos: &[LineCol]) -> Block
 			= s:(positioned_statement(pos) ** [Token::Newline]) { s }
@@ -1,13 +1,14 @@
 
 use std::str::CharIndices;
 use std::iter::{Peekable, FromIterator};
-use std::ops::Deref;
+use std::ops::{Deref, Range};
 use std::fmt;
 use unicode_xid::UnicodeXID;
 use peg::{Parse, ParseElem, ParseLiteral, ParseSlice, RuleResult, str::LineCol};
 use smallstr::SmallString;
 
 use crate::{HissyError, ErrorType};
+use super::symbol::Symbol;
 
 
 fn error(s: String, pos: LineCol) -> HissyError {
@@ -23,20 +24,23 @@ type SymbolStr = SmallString<[u8;6]>;
 #[derive(Debug, PartialEq, Clone)]
 pub enum Token {
 	Symbol(SymbolStr),
-	Id(String),
+	Id(Symbol),
 	Int(i32),
 	Real(f64),
+	Char(char),
 	String(String),
+	Atom(Symbol), // symbol literal, eg. :name
+	Doc(String),
 	Newline, Indent, Dedent,
 	EOF,
 }
 
-static KEYWORDS: [&str; 15] = [
-	"let", "if", "else", "while", "for", "in",
+static KEYWORDS: [&str; 22] = [
+	"let", "const", "enum", "import", "embed", "if", "else", "match", "while", "for", "in",
 	"not", "and", "or",
 	"nil", "true", "false",
-	"return",
-	"fun",
+	"return", "defer",
+	"fun", "capture",
 	"pass",
 ];
 
@@ -53,26 +57,26 @@ fn parse_number(input: &str, is_integer: bool) -> Token {
 	Token::Real(input.parse::<f64>().expect("Error while parsing real literal"))
 }
 
-static SIMPLE_SYMBOLS: [char; 17] = [
+static SIMPLE_SYMBOLS: [char; 18] = [
 	'+', '-', '*', '/', '^', '%',
 	'=', '<', '>',
 	',', '(', ')', ':',
 	'[', ']',
-	'.',
+	'.', '@',
 	'\n',
 ];
 
-static SYMBOL_START: [char; 11] = [
+static SYMBOL_START: [char; 12] = [
 	'+', '-', '*', '/', '^', '%',
 	'=', '<', '>',
-	'!',
+	'!', '|',
 	'\r',
 ];
 
-static COMPLEX_SYMBOLS: [&str; 21] = [
+static COMPLEX_SYMBOLS: [&str; 22] = [
 	"=", "+", "-", "*", "/", "^", "%", "<", ">",
 	"==", "!=", "+=", "-=", "*=", "/=", "^=", "%=", "<=", ">=",
-	"->",
+	"->", "|>",
 	"\r\n",
 ];
 
@@ -95,6 +99,13 @@ fn parse_symbol(it: &mut Peekable<CharIndices>, c: char) -> Option<SymbolStr> {
 	Some(SmallString::from(c))
 }
 
+// A ':' directly followed by an identifier starts a symbol literal, unless it directly follows
+// an operand, as in type annotations without spaces (x:Int)
+fn is_atom_start(input: &str, i: usize) -> bool {
+	input[i+1..].chars().next().is_some_and(|c| c.is_xid_start())
+		&& !input[..i].chars().next_back().is_some_and(|c| c.is_xid_continue() || ")]'\"".contains(c))
+}
+
 fn test_next_char<P>(it: &mut Peekable<CharIndices>, pred: &P) -> bool where P: Fn(char) -> bool {
 	it.peek().map_or(false, |(_,c)| pred(*c))
 }
@@ -109,12 +120,109 @@ fn get_next_index(it: &mut Peekable<CharIndices>, end: usize) -> usize {
 	it.peek().map_or(end, |(i,_)| *i)
 }
 
+// Skips a comment starting at index i, if there is one, and returns whether one was skipped.
+// The contents of doc comments are appended to doc, and the final newline is never consumed.
+fn skip_comment(input: &str, it: &mut Peekable<CharIndices>, i: usize, cur_line: &mut usize, line_start: &mut usize,
+		doc: Option<&mut Option<String>>) -> Result<bool, HissyError> {
+	let rest = &input[i..];
+	if rest.starts_with("/*") {
+		let len = rest.find("*/").ok_or_else(|| error_str("Unfinished block comment",
+			LineCol { line: *cur_line, column: i - *line_start + 1, offset: i }))?;
+		for (j, c) in rest[..len].char_indices() {
+			if c == '\n' {
+				*cur_line += 1;
+				*line_start = i + j + 1;
+			}
+		}
+		while get_next_index(it, input.len()) < i + len + 2 {
+			it.next();
+		}
+	} else if rest.starts_with('#') || rest.starts_with("//") {
+		let line = &rest[..rest.find('\n').unwrap_or(rest.len())];
+		if let (Some(text), Some(doc)) = (line.strip_prefix("##"), doc) {
+			let text = text.strip_prefix(' ').unwrap_or(text).trim_end();
+			let doc = doc.get_or_insert_with(String::new);
+			if !doc.is_empty() { doc.push('\n'); }
+			doc.push_str(text);
+		}
+		skip_chars(it, &|c| c != '\n');
+	} else {
+		return Ok(false);
+	}
+	Ok(true)
+}
+
+/// How blocks are delimited in source code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockStyle {
+	/// Blocks start with a `:` at the end of a line, and contain the following more indented lines.
+	Indentation,
+	/// Blocks start with a `:` at the end of a line, and end with `end` (or `else`, which opens another).
+	/// Indentation is ignored.
+	End,
+}
+
+// Is the identifier at index i the given word?
+fn is_word(input: &str, i: usize, word: &str) -> bool {
+	input[i..].starts_with(word) && !input[i + word.len()..].chars().next().is_some_and(|c| c.is_xid_continue())
+}
+
+// In the End block style, replaces the Newline preceding an 'end' or 'else' with the Dedent closing the block
+fn close_block(tokens: &mut Vec<Token>, token_pos: &mut Vec<LineCol>, open_blocks: &mut usize, word: &str, pos: LineCol) -> Result<(), HissyError> {
+	if *open_blocks == 0 {
+		return Err(error(format!("Unexpected '{}' outside of a block", word), pos));
+	}
+	*open_blocks -= 1;
+	if tokens.last() == Some(&Token::Newline) {
+		tokens.pop();
+		token_pos.pop();
+	}
+	token_pos.push(pos);
+	tokens.push(Token::Dedent);
+	Ok(())
+}
+
+/// A range of byte offsets in source code.
+pub type Span = Range<usize>;
+
+/// The syntactic class of a piece of source code, for syntax highlighting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenClass {
+	Keyword,
+	/// `nil`, `true`, `false` and symbol literals
+	Constant,
+	Number,
+	String,
+	Identifier,
+	/// Operators and punctuation
+	Operator,
+	Comment,
+	DocComment,
+}
+
+fn comment_class(input: &str, i: usize) -> TokenClass {
+	if input[i..].starts_with("##") { TokenClass::DocComment } else { TokenClass::Comment }
+}
+
+fn token_class(token: &Token) -> TokenClass {
+	match token {
+		Token::Symbol(s) if s == "nil" || s == "true" || s == "false" => TokenClass::Constant,
+		Token::Symbol(s) if is_keyword(s) => TokenClass::Keyword,
+		Token::Atom(_) => TokenClass::Constant,
+		Token::Id(_) => TokenClass::Identifier,
+		Token::Int(_) | Token::Real(_) => TokenClass::Number,
+		Token::Char(_) | Token::String(_) => TokenClass::String,
+		_ => TokenClass::Operator,
+	}
+}
+
 /// A [`Token`] sequence, suitable for use with peg.rs parsers.
 /// 
 /// Can be Displayed to inspect contents.
 pub struct Tokens {
 	pub tokens: Vec<Token>,
 	pub(super) token_pos: Vec<LineCol>,
+	spans: Vec<(Span, TokenClass)>, // Source code spans, including comments
 }
 
 impl fmt::Display for Tokens {
@@ -128,30 +236,72 @@ impl fmt::Display for Tokens {
 	}
 }
 
-/// Lexes a string slice into a `Tokens` container.
+/// Lexes a string slice with indentation-based blocks into a `Tokens` container.
 pub fn read_tokens(input: &str) -> Result<Tokens, HissyError> {
-	let mut tokens = vec![];
-	let mut token_pos = vec![];
+	read_tokens_with(input, BlockStyle::Indentation)
+}
+
+/// Lexes a string slice into a `Tokens` container.
+/// 
+/// Whatever the block style, blocks are delimited by [`Token::Indent`] and [`Token::Dedent`].
+/// 
+/// A `;` produces a [`Token::Newline`], separating statements on the same line,
+/// and a `\` at the end of a line continues the current line on the next one.
+/// 
+/// Comments (`#`, `//` and `/* */`) are skipped. Doc comments (`##`) outside of delimiters
+/// are collected into a single [`Token::Doc`], emitted just before the next token.
+pub fn read_tokens_with(input: &str, style: BlockStyle) -> Result<Tokens, HissyError> {
+	let mut tokens = Tokens { tokens: vec![], token_pos: vec![], spans: vec![] };
+	lex(input, style, &mut tokens)?;
+	Ok(tokens)
+}
+
+/// Splits source code with indentation-based blocks into classified spans, for syntax highlighting.
+/// 
+/// Spans are in order, and whitespace is not included. Since code being edited is often invalid,
+/// errors are ignored, and the spans up to the error are returned.
+pub fn highlight(input: &str) -> Vec<(Span, TokenClass)> {
+	highlight_with(input, BlockStyle::Indentation)
+}
+
+/// Splits source code into classified spans, for syntax highlighting. See [`highlight`].
+pub fn highlight_with(input: &str, style: BlockStyle) -> Vec<(Span, TokenClass)> {
+	let mut tokens = Tokens { tokens: vec![], token_pos: vec![], spans: vec![] };
+	let _ = lex(input, style, &mut tokens);
+	tokens.spans
+}
+
+fn lex(input: &str, style: BlockStyle, out: &mut Tokens) -> Result<(), HissyError> {
+	let Tokens { tokens, token_pos, spans } = out;
 	let mut it = input.char_indices().peekable();
 	let mut indent_levels = vec![""];
 	let mut cur_line = 1;
 	let mut line_start = 0;
 	let mut delimiter_levels = 0; // How many ()/[] pairs are we inside of
+	let mut doc = None;
+	let mut open_blocks = 0; // In the End block style, how many blocks are waiting for an 'end'
 	
 	'outer: while let Some((i,c)) = it.peek().copied() {
 		if c.is_ascii_whitespace() { // Get indent
 			let mut start = i;
+			let mut comment_start = None; // Start of a comment preceding code on the same line
 			let end;
 			loop {
-				if let Some((i, c)) = it.peek() {
+				if let Some((i, c)) = it.peek().copied() {
 					if !c.is_ascii_whitespace() {
-						end = *i;
+						if skip_comment(input, &mut it, i, &mut cur_line, &mut line_start, Some(&mut doc))? {
+							spans.push((i..get_next_index(&mut it, input.len()), comment_class(input, i)));
+							comment_start.get_or_insert(i);
+							continue;
+						}
+						end = comment_start.unwrap_or(i);
 						break;
 					}
-					if *c == '\n' {
+					if c == '\n' {
 						cur_line += 1;
 						line_start = i + 1; // Assuming '\n' is always 1 byte
 						start = line_start;
+						comment_start = None;
 					}
 					it.next();
 				} else { // If at end of file, ignore whitespace
@@ -159,10 +309,23 @@ pub fn read_tokens(input: &str) -> Result<Tokens, HissyError> {
 				}
 			}
 			
+			if tokens.last() == Some(&Token::Newline) { // A ';' at the end of a line is redundant
+				tokens.pop();
+				token_pos.pop();
+			}
+			
 			let new_indent = &input[start..end];
 			let pos = LineCol { line: cur_line, column: 1, offset: start };
 			let last_indent = *indent_levels.last().unwrap();
-			if last_indent == new_indent {
+			if style == BlockStyle::End {
+				token_pos.push(pos);
+				if tokens.last().is_some_and(|t| matches!(t, Token::Symbol(s) if s == ":")) {
+					open_blocks += 1;
+					tokens.push(Token::Indent);
+				} else {
+					tokens.push(Token::Newline);
+				}
+			} else if last_indent == new_indent {
 				token_pos.push(pos);
 				tokens.push(Token::Newline);
 			} else if new_indent.starts_with(last_indent) {
@@ -182,8 +345,26 @@ pub fn read_tokens(input: &str) -> Result<Tokens, HissyError> {
 				return Err(error(format!("Invalid indentation {:?}", new_indent), pos));
 			}
 			
+		} else if skip_comment(input, &mut it, i, &mut cur_line, &mut line_start,
+				if delimiter_levels == 0 { Some(&mut doc) } else { None })? {
+			// Nothing to emit, but following whitespace is skipped as after a token
+			spans.push((i..get_next_index(&mut it, input.len()), comment_class(input, i)));
+		} else if style == BlockStyle::End && is_word(input, i, "end") {
+			let pos = LineCol { line: cur_line, column: i - line_start + 1, offset: i };
+			close_block(tokens, token_pos, &mut open_blocks, "end", pos)?;
+			it.nth(2);
+			spans.push((i..i + 3, TokenClass::Keyword));
 		} else {
 			let pos = LineCol { line: cur_line, column: i - line_start + 1, offset: i };
+			if style == BlockStyle::End && is_word(input, i, "else") {
+				close_block(tokens, token_pos, &mut open_blocks, "else", pos.clone())?;
+				token_pos.push(pos.clone());
+				tokens.push(Token::Newline);
+			}
+			if let Some(doc) = doc.take() {
+				token_pos.push(pos.clone());
+				tokens.push(Token::Doc(doc));
+			}
 			token_pos.push(pos.clone());
 			
 			if c.is_xid_start() {
@@ -194,7 +375,7 @@ pub fn read_tokens(input: &str) -> Result<Tokens, HissyError> {
 				if is_keyword(id) {
 					tokens.push(Token::Symbol(SmallString::from(id)));
 				} else {
-					tokens.push(Token::Id(String::from(id)));
+					tokens.push(Token::Id(Symbol::intern(id)));
 				}
 			} else if c.is_ascii_digit() {
 				let start = i;
@@ -245,6 +426,33 @@ pub fn read_tokens(input: &str) -> Result<Tokens, HissyError> {
 					}
 				}
 				tokens.push(Token::String(contents));
+			} else if c == '\'' {
+				it.next();
+				let c = match it.next() {
+					Some((_, '\\')) => match it.next().map(|(_,c)| c) {
+						Some(c @ '\\') | Some(c @ '\'') | Some(c @ '"') => c,
+						Some('t') => '\t',
+						Some('r') => '\r',
+						Some('n') => '\n',
+						Some(c) => return Err(error(format!("Invalid escape sequence '\\{}' in character", c.escape_default()), pos)),
+						None => return Err(error_str("Unfinished character literal", pos)),
+					},
+					Some((_, '\'')) => return Err(error_str("Empty character literal", pos)),
+					Some((_, '\n')) | None => return Err(error_str("Unfinished character literal", pos)),
+					Some((_, c)) => c,
+				};
+				if it.next().map(|(_,c)| c) != Some('\'') {
+					return Err(error_str("Character literal must contain a single character", pos));
+				}
+				tokens.push(Token::Char(c));
+			} else if c == ':' && is_atom_start(input, i) {
+				it.next();
+				skip_chars(&mut it, &|c| c.is_xid_continue());
+				let end = get_next_index(&mut it, input.len());
+				tokens.push(Token::Atom(Symbol::intern(&input[i+1..end])));
+			} else if c == ';' {
+				it.next();
+				tokens.push(Token::Newline);
 			} else if let Some(s) = parse_symbol(&mut it, c) {
 				if s == "(" || s == "[" {
 					delimiter_levels += 1;
@@ -258,6 +466,7 @@ pub fn read_tokens(input: &str) -> Result<Tokens, HissyError> {
 			} else {
 				return Err(error(format!("Unexpected character {:?}", c), pos))
 			}
+			spans.push((i..get_next_index(&mut it, input.len()), token_class(tokens.last().unwrap())));
 		}
 		
 		while let Some((i,c)) = it.peek().copied() {
@@ -267,15 +476,28 @@ pub fn read_tokens(input: &str) -> Result<Tokens, HissyError> {
 					line_start = i + 1;
 				}
 				it.next();
+			} else if c == '\\' && (input[i+1..].starts_with('\n') || input[i+1..].starts_with("\r\n")) { // Line continuation
+				skip_chars(&mut it, &|c| c != '\n');
+				it.next();
+				cur_line += 1;
+				line_start = get_next_index(&mut it, input.len());
 			} else {
 				break;
 			}
 		}
 	}
 	
+	if tokens.last() == Some(&Token::Newline) {
+		tokens.pop();
+		token_pos.pop();
+	}
+	
 	let i = input.len();
 	let pos = LineCol { line: cur_line, column: i - line_start + 1, offset: i };
 	
+	if open_blocks > 0 {
+		return Err(error_str("Missing 'end' at end of file", pos));
+	}
 	while indent_levels.len() > 1 {
 		indent_levels.pop();
 		token_pos.push(pos.clone());
@@ -285,7 +507,7 @@ pub fn read_tokens(input: &str) -> Result<Tokens, HissyError> {
 	token_pos.push(pos);
 	tokens.push(Token::EOF);
 	
-	Ok(Tokens { tokens, token_pos })
+	Ok(())
 }
 
 impl Tokens {
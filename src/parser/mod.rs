@@ -3,18 +3,201 @@
 pub mod lexer;
 /// Data structures representing Hissy code.
 pub mod ast;
+/// Interned identifiers.
+pub mod symbol;
+/// Rendering syntax trees as Graphviz graphs.
+pub mod dot;
+/// Generating documentation for scripts from their doc comments.
+pub mod doc;
+/// Traversing and transforming syntax trees.
+pub mod visit;
 mod grammar;
+mod depth;
 
 
 use crate::{HissyError, ErrorType};
 use grammar::peg_parser;
+use lexer::BlockStyle;
+pub(crate) use depth::check_ast as check_depth;
 
-/// Parses a string slice containing Hissy code into an Abstract Syntax Tree.
+/// Maximum nesting depth of expressions and blocks: deeper code is rejected with a syntax error,
+/// instead of overflowing the stack of the recursive parser and compiler.
+pub const MAX_NESTING: usize = 128;
+
+/// Parses a string slice containing Hissy code with indentation-based blocks into an Abstract Syntax Tree.
 pub fn parse(input: &str) -> Result<ast::ProgramAST, HissyError> {
-	let tokens = lexer::read_tokens(input)?;
-	peg_parser::program(&tokens, &tokens.token_pos).map_err(|err| {
+	parse_with(input, BlockStyle::Indentation)
+}
+
+/// Parses a string slice containing Hissy code into an Abstract Syntax Tree,
+/// with the given block style.
+pub fn parse_with(input: &str, style: BlockStyle) -> Result<ast::ProgramAST, HissyError> {
+	let tokens = lexer::read_tokens_with(input, style)?;
+	depth::check_tokens(&tokens)?;
+	let ast = peg_parser::program(&tokens, &tokens.token_pos).map_err(|err| {
 		let err_str = format!("Near {:?}, expected {}", err.location.near, err.expected);
 		HissyError(ErrorType::Syntax, err_str, err.location.line)
-	})
+	})?;
+	depth::check_ast(&ast)?;
+	Ok(ast)
 }
 
+
+
+#[cfg(test)]
+mod tests {
+	use super::{parse, parse_with};
+	use super::lexer::{read_tokens, read_tokens_with, BlockStyle};
+	use super::ast::{Stat, Expr, Symbol};
+	
+	#[test]
+	fn test_nesting_limit() {
+		let n = 3000;
+		let deep = [
+			format!("log({}1{})\n", "(".repeat(n), ")".repeat(n)),
+			format!("log({}]\n", "[".repeat(n)),
+			format!("log({}1)\n", "- ".repeat(n)),
+			format!("log({}true)\n", "not ".repeat(n)),
+			format!("log(2{})\n", " ^ 2".repeat(n)),
+			format!("log(f{})\n", "()".repeat(n)),
+		];
+		for src in &deep {
+			let err = parse(src).unwrap_err();
+			assert_eq!(err.1, format!("Code nested too deeply (more than {} levels)", super::MAX_NESTING));
+		}
+		// Code just below the limit compiles without overflowing the stack ('^' counts twice)
+		let n = super::MAX_NESTING - 10;
+		let src = format!("let x = 2\nlog({}x{}, -{}x, x{})\n", "(".repeat(n), ")".repeat(n), "- ".repeat(n), " ^ x".repeat(n / 2));
+		assert!(crate::compiler::Compiler::new(true).compile_program(&src).is_ok());
+	}
+	
+	#[test]
+	fn test_pipeline() {
+		assert_eq!(parse("x + 1 |> f(y) |> g\n").unwrap(), parse("g(f(x + 1, y))\n").unwrap());
+		assert_eq!(parse("x |> l.add()\n").unwrap(), parse("l.add(x)\n").unwrap());
+	}
+	
+	#[test]
+	fn test_trailing_function() {
+		let ast = parse("each(l) fun(x):\n\tlog(x)\nnext()\n").unwrap();
+		assert_eq!(ast.len(), 2);
+		if let Stat::ExprStat(Expr::Call(_, args)) = &*ast[0] {
+			assert_eq!(args[0], Expr::Id(Symbol::intern("l")));
+			assert!(matches!(&args[1], Expr::Function(fun_args, _, _, body) if fun_args.len() == 1 && body.len() == 1));
+		} else {
+			panic!("Expected call statement, got {:?}", ast[0]);
+		}
+	}
+	
+	#[test]
+	fn test_comments() {
+		let src = "let x = 1 // comment\nif x /* 1 */ == 1: /* multi\nline */\n\t# comment\n\tlog(x)\n# end\n";
+		assert_eq!(parse(src).unwrap(), parse("let x = 1\nif x == 1:\n\n\n\tlog(x)\n").unwrap());
+		assert!(parse("x = 1 /* unfinished\n").is_err());
+		
+		let ast = parse("## Adds one.\n##\n## Only works on ints.\nlet f(x: Int):\n\treturn x + 1\nlet y = f(1)\n").unwrap();
+		assert!(matches!(&*ast[0], Stat::Let(_, _, _, Some(doc), _) if doc == "Adds one.\n\nOnly works on ints."));
+		assert!(matches!(&*ast[1], Stat::Let(_, _, _, None, _)));
+	}
+	
+	#[test]
+	fn test_separators() {
+		let tokens = |src| read_tokens(src).unwrap().tokens;
+		let expected = tokens("let x = 1\nif x == 1:\n\tlog(x)\n\tlog(2)\nelse:\n\tpass\n");
+		assert_eq!(tokens("let x = 1; if x == 1:\n\tlog(x); log(2);\nelse:\n\tpass;"), expected);
+		assert_eq!(tokens("let x = \\\n\t1\nif x \\\r\n== 1:\n\tlog(x)\n\tlog(2)\nelse:\n\tpass\n"), expected);
+		assert!(parse("let x = 1;; log(x)\n").is_err());
+	}
+	
+	#[test]
+	fn test_multiline_brackets() {
+		let tokens = |src| read_tokens(src).unwrap().tokens;
+		assert_eq!(tokens("f(\n\t[1,\n\t\t2],\n\n\t3, # three\n)\n"), tokens("f([1, 2], 3,)\n"));
+		let ast = parse("let add(\n\ta: Int,\n\tb: Int,\n) -> Int:\n\treturn a + b\nlog(add(1,\n\t2))\n").unwrap();
+		assert_eq!(ast.len(), 2);
+		assert_eq!(ast[1].1, (6, 1));
+	}
+	
+	#[test]
+	fn test_end_blocks() {
+		let indented = "let f(x: Int):\n\tif x > 0:\n\t\tlog(x)\n\telse if x < 0:\n\t\tpass\n\telse:\n\t\tlog(0)\n\tlog(x) # done\nf(1)\n";
+		let end = "let f(x: Int):\nif x > 0:\n  log(x)\nelse if x < 0: # nothing\n  pass\n    else:\nlog(0)\nend; log(x)\nend\nf(1)\n";
+		let tokens = |src, style| read_tokens_with(src, style).unwrap().tokens;
+		assert_eq!(tokens(end, BlockStyle::End), tokens(indented, BlockStyle::Indentation));
+		assert!(parse_with(end, BlockStyle::End).is_ok());
+		assert!(parse_with("let end = 1\n", BlockStyle::Indentation).is_ok());
+		assert!(read_tokens_with("if x:\n\tlog(x)\n", BlockStyle::End).is_err());
+		assert!(read_tokens_with("log(x)\nend\n", BlockStyle::End).is_err());
+	}
+	
+	#[test]
+	fn test_dot_output() {
+		let dot = super::dot::to_dot(&parse("let f(x: Int) -> Int:\n\treturn x * 2\nlog(\"a \\\"b\\\"\")\n").unwrap());
+		assert!(dot.starts_with("digraph ast {\n"));
+		assert!(dot.contains("[label=\"Let f\\nline 1\"]"));
+		assert!(dot.contains("[label=\"Function(x: Int) -> Int\"]"));
+		assert!(dot.contains("[label=\"\\\"a \\\\\\\"b\\\\\\\"\\\"\"]"));
+		let edges = dot.lines().filter(|l| l.contains(" -> n")).count();
+		let nodes = dot.lines().filter(|l| l.contains("[label=") && !l.contains(" -> n")).count();
+		assert_eq!(nodes, 12);
+		assert_eq!(edges, nodes - 1);
+	}
+	
+	#[test]
+	fn test_doc_output() {
+		use super::doc::{to_doc, DocFormat};
+		let src = "## Adds one.\n##\n## Only <ints>.\n@deprecated(\"Use g\")\nlet f(x: Int) -> Int:\n\treturn x + 1\n## Scale\nlet k: Real = 2\nlet y = f(1)\n\
+			const g(x):\n\tlog(x)\nenum Color: Red, Green\nlet test_f():\n\tpass\n";
+		let md = to_doc(&parse(src).unwrap(), "lib", DocFormat::Markdown);
+		assert_eq!(md, "# lib\n\n## `let f(x: Int) -> Int`\n\n**Deprecated:** Use g\n\nAdds one.\n\nOnly <ints>.\n\n## `let k: Real`\n\nScale\n\
+			\n## `const g(x: Any)`\n\n## `enum Color: Red, Green`\n");
+		let html = to_doc(&parse(src).unwrap(), "lib", DocFormat::Html);
+		assert!(html.contains("<h2><code>let f(x: Int) -&gt; Int</code></h2>\n<p><strong>Deprecated:</strong> Use g</p>\n<p>Adds one.</p>\n<p>Only &lt;ints&gt;.</p>\n"));
+	}
+	
+	#[test]
+	fn test_highlight() {
+		use super::lexer::{highlight, TokenClass::*};
+		let src = "## Doc\nlet x = 1.5 # note\nlog(\"s\", nil)\nlog(\"unfinished";
+		let spans: Vec<(&str, _)> = highlight(src).into_iter().map(|(span, class)| (&src[span], class)).collect();
+		assert_eq!(spans, vec![
+			("## Doc", DocComment),
+			("let", Keyword), ("x", Identifier), ("=", Operator), ("1.5", Number), ("# note", Comment),
+			("log", Identifier), ("(", Operator), ("\"s\"", String), (",", Operator), ("nil", Constant), (")", Operator),
+			("log", Identifier), ("(", Operator),
+		]);
+	}
+	
+	#[test]
+	fn test_visitors() {
+		use super::visit::*;
+		struct Calls(Vec<Symbol>);
+		impl Visitor for Calls {
+			fn visit_expr(&mut self, expr: &Expr) {
+				if let Expr::Call(f, _) = expr {
+					if let Expr::Id(id) = **f {
+						self.0.push(id);
+					}
+				}
+				walk_expr(self, expr);
+			}
+		}
+		struct Double;
+		impl MutVisitor for Double {
+			fn visit_expr(&mut self, expr: &mut Expr) {
+				match expr {
+					Expr::Int(i) => *i *= 2,
+					_ => walk_expr_mut(self, expr),
+				}
+			}
+		}
+		
+		let mut ast = parse("let f(x: Int) -> Int:\n\treturn g(x + 1)\nfor i in range(0, 2):\n\tlog(f(i))\n").unwrap();
+		let mut calls = Calls(vec![]);
+		calls.visit_block(&ast);
+		let names: Vec<&str> = calls.0.iter().map(|id| id.as_str()).collect();
+		assert_eq!(names, ["g", "range", "log", "f"]);
+		Double.visit_block(&mut ast);
+		assert_eq!(ast, parse("let f(x: Int) -> Int:\n\treturn g(x + 2)\nfor i in range(0, 4):\n\tlog(f(i))\n").unwrap());
+	}
+}
@@ -0,0 +1,117 @@
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::cmp::Ordering;
+use std::fmt;
+use std::ops::Deref;
+use std::sync::{RwLock, OnceLock};
+
+
+/// An interned identifier.
+///
+/// Symbols are indices into a global table of names, so they are cheap to copy, compare and hash.
+/// Interned names are never freed, which is fine for identifiers, since programs only use a limited number of them.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Symbol(u32);
+
+#[derive(Default)]
+struct Interner {
+	names: Vec<&'static str>,
+	ids: HashMap<&'static str, Symbol>,
+}
+
+fn interner() -> &'static RwLock<Interner> {
+	static INTERNER: OnceLock<RwLock<Interner>> = OnceLock::new();
+	INTERNER.get_or_init(|| RwLock::new(Interner::default()))
+}
+
+impl Symbol {
+	/// Returns the symbol for a name, adding it to the table if necessary.
+	pub fn intern(name: &str) -> Symbol {
+		if let Some(sym) = interner().read().unwrap().ids.get(name) {
+			return *sym;
+		}
+		let mut table = interner().write().unwrap();
+		if let Some(sym) = table.ids.get(name) { // interned by another thread in the meantime
+			return *sym;
+		}
+		let sym = Symbol(u32::try_from(table.names.len()).expect("Too many interned symbols"));
+		let name: &'static str = Box::leak(Box::from(name));
+		table.names.push(name);
+		table.ids.insert(name, sym);
+		sym
+	}
+
+	// Values store symbols by index
+	pub(crate) fn index(self) -> u32 {
+		self.0
+	}
+	
+	pub(crate) fn from_index(index: u32) -> Symbol {
+		assert!((index as usize) < interner().read().unwrap().names.len(), "Invalid symbol index");
+		Symbol(index)
+	}
+
+	/// Returns the name of the symbol.
+	pub fn as_str(self) -> &'static str {
+		interner().read().unwrap().names[self.0 as usize]
+	}
+}
+
+impl Deref for Symbol {
+	type Target = str;
+	fn deref(&self) -> &str { self.as_str() }
+}
+
+impl From<&str> for Symbol {
+	fn from(name: &str) -> Symbol { Symbol::intern(name) }
+}
+
+impl From<Symbol> for String {
+	fn from(sym: Symbol) -> String { String::from(sym.as_str()) }
+}
+
+impl PartialEq<str> for Symbol {
+	fn eq(&self, other: &str) -> bool { self.as_str() == other }
+}
+impl PartialEq<&str> for Symbol {
+	fn eq(&self, other: &&str) -> bool { self.as_str() == *other }
+}
+
+// Symbols are ordered by name, not by interning order, so that sorting them is deterministic
+impl PartialOrd for Symbol {
+	fn partial_cmp(&self, other: &Symbol) -> Option<Ordering> { Some(self.cmp(other)) }
+}
+impl Ord for Symbol {
+	fn cmp(&self, other: &Symbol) -> Ordering {
+		if self == other { Ordering::Equal } else { self.as_str().cmp(other.as_str()) }
+	}
+}
+
+impl fmt::Debug for Symbol {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		fmt::Debug::fmt(self.as_str(), f)
+	}
+}
+impl fmt::Display for Symbol {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(self.as_str())
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_interning() {
+		let a = Symbol::intern("alpha");
+		assert_eq!(a, Symbol::from("alpha"));
+		assert_ne!(a, Symbol::intern("beta"));
+		assert_eq!(a.as_str(), "alpha");
+		assert_eq!(a, "alpha");
+		assert_eq!(format!("{} {:?}", a, a), "alpha \"alpha\"");
+		assert!(Symbol::intern("zeta") > Symbol::intern("beta"));
+	}
+}
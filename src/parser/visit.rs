@@ -0,0 +1,186 @@
+
+use super::ast::*;
+
+
+/// Visits the nodes of a syntax tree, eg. to analyze a program.
+///
+/// By default, each method visits the children of its node with the corresponding `walk_*` function;
+/// implementations override the methods of the nodes they are interested in, and call the `walk_*`
+/// function themselves to keep visiting their children.
+pub trait Visitor {
+	/// Visits a block of statements.
+	fn visit_block(&mut self, block: &Block) {
+		walk_block(self, block);
+	}
+	
+	/// Visits a statement, along with its position.
+	fn visit_stat(&mut self, stat: &Positioned<Stat>) {
+		walk_stat(self, stat);
+	}
+	
+	/// Visits an expression.
+	fn visit_expr(&mut self, expr: &Expr) {
+		walk_expr(self, expr);
+	}
+}
+
+/// Visits the statements of a block.
+pub fn walk_block<V: Visitor + ?Sized>(v: &mut V, block: &Block) {
+	for stat in block {
+		v.visit_stat(stat);
+	}
+}
+
+/// Visits the expressions and blocks of a statement.
+pub fn walk_stat<V: Visitor + ?Sized>(v: &mut V, stat: &Positioned<Stat>) {
+	match &stat.0 {
+		Stat::ExprStat(e) | Stat::Let(_, _, e, _, _) | Stat::Const(_, e, _, _) | Stat::Return(e) | Stat::Defer(e) =>
+			v.visit_expr(e),
+		Stat::Set(lexpr, e) => {
+			if let LExpr::Index(list, idx) = lexpr {
+				v.visit_expr(list);
+				v.visit_expr(idx);
+			}
+			v.visit_expr(e);
+		},
+		Stat::Cond(branches) => {
+			for (cond, block) in branches {
+				if let Cond::If(e) = cond {
+					v.visit_expr(e);
+				}
+				v.visit_block(block);
+			}
+		},
+		Stat::While(e, block) | Stat::For(_, _, e, block) => {
+			v.visit_expr(e);
+			v.visit_block(block);
+		},
+		Stat::Match(e, arms, default) => {
+			v.visit_expr(e);
+			for (values, block) in arms {
+				for value in values {
+					v.visit_expr(value);
+				}
+				v.visit_block(block);
+			}
+			if let Some(block) = default {
+				v.visit_block(block);
+			}
+		},
+		Stat::Enum(_, _) | Stat::Import(_) => {},
+	}
+}
+
+/// Visits the operands of an expression, and the body of functions.
+pub fn walk_expr<V: Visitor + ?Sized>(v: &mut V, expr: &Expr) {
+	match expr {
+		Expr::List(values) => values.iter().for_each(|value| v.visit_expr(value)),
+		Expr::BinOp(_, a, b) | Expr::Index(a, b) => {
+			v.visit_expr(a);
+			v.visit_expr(b);
+		},
+		Expr::Chain(first, rest) => {
+			v.visit_expr(first);
+			rest.iter().for_each(|(_, e)| v.visit_expr(e));
+		},
+		Expr::UnaOp(_, a) | Expr::Prop(a, _) => v.visit_expr(a),
+		Expr::Call(f, args) => {
+			v.visit_expr(f);
+			args.iter().for_each(|arg| v.visit_expr(arg));
+		},
+		Expr::Function(_, _, _, body) => v.visit_block(body),
+		Expr::Nil | Expr::Bool(_) | Expr::Int(_) | Expr::Real(_) | Expr::Char(_) | Expr::String(_)
+			| Expr::Symbol(_) | Expr::Embed(_) | Expr::Id(_) => {},
+	}
+}
+
+
+/// Visits the nodes of a syntax tree mutably, eg. to transform a program before compiling it.
+///
+/// Works like [`Visitor`], with the `walk_*_mut` functions.
+pub trait MutVisitor {
+	/// Visits a block of statements, which can be added or removed.
+	fn visit_block(&mut self, block: &mut Block) {
+		walk_block_mut(self, block);
+	}
+	
+	/// Visits a statement, along with its position.
+	fn visit_stat(&mut self, stat: &mut Positioned<Stat>) {
+		walk_stat_mut(self, stat);
+	}
+	
+	/// Visits an expression, which can be replaced.
+	fn visit_expr(&mut self, expr: &mut Expr) {
+		walk_expr_mut(self, expr);
+	}
+}
+
+/// Visits the statements of a block mutably.
+pub fn walk_block_mut<V: MutVisitor + ?Sized>(v: &mut V, block: &mut Block) {
+	for stat in block {
+		v.visit_stat(stat);
+	}
+}
+
+/// Visits the expressions and blocks of a statement mutably.
+pub fn walk_stat_mut<V: MutVisitor + ?Sized>(v: &mut V, stat: &mut Positioned<Stat>) {
+	match &mut stat.0 {
+		Stat::ExprStat(e) | Stat::Let(_, _, e, _, _) | Stat::Const(_, e, _, _) | Stat::Return(e) | Stat::Defer(e) =>
+			v.visit_expr(e),
+		Stat::Set(lexpr, e) => {
+			if let LExpr::Index(list, idx) = lexpr {
+				v.visit_expr(list);
+				v.visit_expr(idx);
+			}
+			v.visit_expr(e);
+		},
+		Stat::Cond(branches) => {
+			for (cond, block) in branches {
+				if let Cond::If(e) = cond {
+					v.visit_expr(e);
+				}
+				v.visit_block(block);
+			}
+		},
+		Stat::While(e, block) | Stat::For(_, _, e, block) => {
+			v.visit_expr(e);
+			v.visit_block(block);
+		},
+		Stat::Match(e, arms, default) => {
+			v.visit_expr(e);
+			for (values, block) in arms {
+				for value in values {
+					v.visit_expr(value);
+				}
+				v.visit_block(block);
+			}
+			if let Some(block) = default {
+				v.visit_block(block);
+			}
+		},
+		Stat::Enum(_, _) | Stat::Import(_) => {},
+	}
+}
+
+/// Visits the operands of an expression, and the body of functions, mutably.
+pub fn walk_expr_mut<V: MutVisitor + ?Sized>(v: &mut V, expr: &mut Expr) {
+	match expr {
+		Expr::List(values) => values.iter_mut().for_each(|value| v.visit_expr(value)),
+		Expr::BinOp(_, a, b) | Expr::Index(a, b) => {
+			v.visit_expr(a);
+			v.visit_expr(b);
+		},
+		Expr::Chain(first, rest) => {
+			v.visit_expr(first);
+			rest.iter_mut().for_each(|(_, e)| v.visit_expr(e));
+		},
+		Expr::UnaOp(_, a) | Expr::Prop(a, _) => v.visit_expr(a),
+		Expr::Call(f, args) => {
+			v.visit_expr(f);
+			args.iter_mut().for_each(|arg| v.visit_expr(arg));
+		},
+		Expr::Function(_, _, _, body) => v.visit_block(body),
+		Expr::Nil | Expr::Bool(_) | Expr::Int(_) | Expr::Real(_) | Expr::Char(_) | Expr::String(_)
+			| Expr::Symbol(_) | Expr::Embed(_) | Expr::Id(_) => {},
+	}
+}
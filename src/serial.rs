@@ -1,4 +1,7 @@
 
+// Helpers for the bytecode format. Numbers are always little-endian, whatever the host,
+// and values are read byte by byte, so there are no alignment requirements.
+
 use std::fmt::Debug;
 use std::convert::{TryFrom, TryInto};
 
@@ -47,6 +50,7 @@ macro_rules! serialize_numeric {
 }
 
 serialize_numeric!(read_i8, write_i8, write_into_i8, i8);
+serialize_numeric!(read_i16, write_i16, write_into_i16, i16);
 serialize_numeric!(read_u16, write_u16, write_into_u16, u16);
 serialize_numeric!(read_u32, write_u32, write_into_u32, u32);
 serialize_numeric!(read_i32, write_i32, write_into_i32, i32);
@@ -58,8 +62,10 @@ pub fn read_small_str<'a>(it: &mut impl Iterator<Item = &'a u8>) -> Result<Strin
 	String::from_utf8(read_u8s(it, length)?).map_err(|_| error_str("Invalid UTF8 in string"))
 }
 
+// Longer strings are truncated, which is only meant for names in debug info
 pub fn write_small_str(out: &mut Vec<u8>, s: &str) {
-	let s = if s.len() >= 256 { &s[..255] } else { s };
+	let end = (0..=s.len().min(255)).rev().find(|i| s.is_char_boundary(*i)).unwrap();
+	let s = &s[..end];
 	write_u8(out, u8::try_from(s.len()).unwrap());
 	out.extend(s.as_bytes());
 }
@@ -75,3 +81,15 @@ pub fn write_str(out: &mut Vec<u8>, s: &str) -> Result<(), HissyError> {
 	out.extend(s.as_bytes());
 	Ok(())
 }
+
+
+pub fn read_long_str<'a>(it: &mut impl Iterator<Item = &'a u8>) -> Result<String, HissyError> {
+	let length = read_u32(it)? as usize;
+	String::from_utf8(read_u8s(it, length)?).map_err(|_| error_str("Invalid UTF8 in string"))
+}
+
+pub fn write_long_str(out: &mut Vec<u8>, s: &str) -> Result<(), HissyError> {
+	write_into_u32(out, s.len(), error_str("Cannot serialise string: string too long"))?;
+	out.extend(s.as_bytes());
+	Ok(())
+}
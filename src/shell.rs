@@ -0,0 +1,391 @@
+// Interactive shell, running each input in a session.
+//
+// When the standard input is a terminal, lines are read with a small line editor: the terminal is put in raw mode
+// with stty while a line is being edited, and is back in its normal mode while inputs run. Otherwise, lines are
+// read as is, so that the shell can also be fed a script.
+
+use std::env;
+use std::fs::{read_to_string, write, File};
+use std::io::{self, Read, Write, BufRead, IsTerminal};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use hissy_lib::HissyError;
+use hissy_lib::parser::lexer::BlockStyle;
+use hissy_lib::package::PackageResolver;
+use hissy_lib::vm::{host::HostEnv, session::{Session, Evaluation}, value::ReprOptions};
+
+use crate::{error, print_warnings, RED, RESET};
+
+
+// History is kept in this file, in the home directory
+const HISTORY_FILE: &str = ".hissy_history";
+const HISTORY_SIZE: usize = 1000;
+
+// Values are printed with limits, so that large lists do not flood the terminal
+const REPR_OPTIONS: ReprOptions = ReprOptions { max_depth: 8, max_items: 100 };
+
+const HELP: &str = "\
+Inputs are run as they are entered, and the variables, functions and enums they declare
+are kept for the next ones. Lines ending with ':' open a block, which ends at the next empty line.
+
+Commands:
+  :vars             List the variables of the session, with their types and values
+  :type <expr>      Show the type of an expression, without running it
+  :disasm <expr>    Disassemble the bytecode of an expression, without running it
+  :dis              Disassemble the bytecode of the last input
+  :heap             Show the memory usage of the session
+  :heap-dump <file> Write a JSON snapshot of the objects in the heap and their references
+  :reset            Forget all variables
+  :help             Print this help message
+  :quit             Exit the shell (or press Ctrl-D)
+
+Keys:
+  Tab completes names in scope, Up and Down go through the history of inputs,
+  Ctrl-C cancels the current input.
+";
+
+const COMMANDS: &[&str] = &[":vars", ":type", ":disasm", ":dis", ":heap", ":heap-dump", ":reset", ":help", ":quit"];
+
+
+fn stty(args: &[&str]) -> Option<String> {
+	let output = Command::new("stty").args(args).stdin(Stdio::inherit()).output().ok()?;
+	if output.status.success() { String::from_utf8(output.stdout).ok() } else { None }
+}
+
+// Keeps the terminal in raw mode until it is dropped, which restores the previous settings
+struct RawMode(String);
+
+impl RawMode {
+	fn enable() -> Option<RawMode> {
+		let saved = stty(&["-g"])?;
+		stty(&["raw", "-echo"])?;
+		Some(RawMode(String::from(saved.trim())))
+	}
+}
+
+impl Drop for RawMode {
+	fn drop(&mut self) {
+		stty(&[&self.0]);
+	}
+}
+
+
+enum Key {
+	Char(char),
+	Enter, Tab, Backspace, Delete,
+	Left, Right, Up, Down, Home, End,
+	Interrupt, // Ctrl-C
+	Eof, // Ctrl-D, or the end of the input
+	ClearBefore, // Ctrl-U
+	Unknown,
+}
+
+fn read_byte(input: &mut impl Read) -> io::Result<Option<u8>> {
+	let mut byte = [0];
+	Ok(if input.read(&mut byte)? == 0 { None } else { Some(byte[0]) })
+}
+
+fn read_key(input: &mut impl Read) -> io::Result<Key> {
+	let first = match read_byte(input)? {
+		Some(b) => b,
+		None => return Ok(Key::Eof),
+	};
+	Ok(match first {
+		b'\r' | b'\n' => Key::Enter,
+		b'\t' => Key::Tab,
+		0x7f | 0x08 => Key::Backspace,
+		0x01 => Key::Home, // Ctrl-A
+		0x05 => Key::End, // Ctrl-E
+		0x03 => Key::Interrupt,
+		0x04 => Key::Eof,
+		0x15 => Key::ClearBefore,
+		0x1b => {
+			// Escape sequences: ESC [ <letter>, ESC [ <digits> ~, or ESC O <letter>
+			match read_byte(input)? {
+				Some(b'[') | Some(b'O') => {},
+				_ => return Ok(Key::Unknown),
+			}
+			let mut param = vec![];
+			loop {
+				match read_byte(input)? {
+					Some(b) if b.is_ascii_digit() || b == b';' => param.push(b),
+					Some(b'A') => return Ok(Key::Up),
+					Some(b'B') => return Ok(Key::Down),
+					Some(b'C') => return Ok(Key::Right),
+					Some(b'D') => return Ok(Key::Left),
+					Some(b'H') => return Ok(Key::Home),
+					Some(b'F') => return Ok(Key::End),
+					Some(b'~') => return Ok(match param.as_slice() {
+						b"1" | b"7" => Key::Home,
+						b"4" | b"8" => Key::End,
+						b"3" => Key::Delete,
+						_ => Key::Unknown,
+					}),
+					_ => return Ok(Key::Unknown),
+				}
+			}
+		},
+		b if b < 0x20 => Key::Unknown,
+		b => {
+			// UTF-8 sequences are as long as the number of leading ones in their first byte
+			let len = (b.leading_ones() as usize).max(1);
+			let mut bytes = vec![b];
+			for _ in 1..len {
+				bytes.extend(read_byte(input)?);
+			}
+			std::str::from_utf8(&bytes).ok().and_then(|s| s.chars().next()).map_or(Key::Unknown, Key::Char)
+		},
+	})
+}
+
+
+enum ReadResult {
+	Line(String),
+	Interrupted,
+	Eof,
+}
+
+struct Editor {
+	history: Vec<String>,
+	history_path: Option<PathBuf>,
+}
+
+impl Editor {
+	fn new() -> Editor {
+		let history_path = env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE));
+		let history = history_path.as_ref().and_then(|path| read_to_string(path).ok())
+			.map_or_else(Vec::new, |contents| contents.lines().map(String::from).collect());
+		Editor { history, history_path }
+	}
+
+	// Failing to save the history is not worth interrupting the session
+	fn add_history(&mut self, line: &str) {
+		if line.trim().is_empty() || self.history.last().map(String::as_str) == Some(line) {
+			return;
+		}
+		self.history.push(String::from(line));
+		if self.history.len() > HISTORY_SIZE {
+			self.history.drain(..self.history.len() - HISTORY_SIZE);
+		}
+		if let Some(path) = &self.history_path {
+			let _ = write(path, self.history.join("\n") + "\n");
+		}
+	}
+
+	// Completion gets the text before the cursor, and returns the names which can complete its last word
+	fn read_line(&mut self, prompt: &str, complete: impl Fn(&str) -> Vec<String>) -> io::Result<ReadResult> {
+		let stdin = io::stdin();
+		let raw = if stdin.is_terminal() { RawMode::enable() } else { None };
+		if raw.is_none() {
+			let mut line = String::new();
+			return Ok(if stdin.lock().read_line(&mut line)? == 0 {
+				ReadResult::Eof
+			} else {
+				ReadResult::Line(String::from(line.trim_end_matches(['\n', '\r'])))
+			});
+		}
+
+		let mut input = stdin.lock();
+		let mut out = io::stdout();
+		let mut buf: Vec<char> = vec![];
+		let mut cursor = 0;
+		let mut history_pos = self.history.len();
+		let mut edited = String::new(); // The line being edited, while going through the history
+		let refresh = |out: &mut io::Stdout, buf: &[char], cursor: usize| -> io::Result<()> {
+			write!(out, "\r{}{}\x1b[K", prompt, buf.iter().collect::<String>())?;
+			if cursor < buf.len() {
+				write!(out, "\x1b[{}D", buf.len() - cursor)?;
+			}
+			out.flush()
+		};
+		refresh(&mut out, &buf, cursor)?;
+		loop {
+			match read_key(&mut input)? {
+				Key::Char(c) => {
+					buf.insert(cursor, c);
+					cursor += 1;
+				},
+				Key::Enter => break,
+				Key::Backspace if cursor > 0 => {
+					cursor -= 1;
+					buf.remove(cursor);
+				},
+				Key::Delete if cursor < buf.len() => {
+					buf.remove(cursor);
+				},
+				Key::Left if cursor > 0 => cursor -= 1,
+				Key::Right if cursor < buf.len() => cursor += 1,
+				Key::Home => cursor = 0,
+				Key::End => cursor = buf.len(),
+				Key::ClearBefore => {
+					buf.drain(..cursor);
+					cursor = 0;
+				},
+				Key::Up if history_pos > 0 => {
+					if history_pos == self.history.len() {
+						edited = buf.iter().collect();
+					}
+					history_pos -= 1;
+					buf = self.history[history_pos].chars().collect();
+					cursor = buf.len();
+				},
+				Key::Down if history_pos < self.history.len() => {
+					history_pos += 1;
+					let line = if history_pos == self.history.len() { &edited } else { &self.history[history_pos] };
+					buf = line.chars().collect();
+					cursor = buf.len();
+				},
+				Key::Tab => {
+					let line: String = buf[..cursor].iter().collect();
+					let start = line.rfind(|c: char| !(c.is_alphanumeric() || c == '_' || c == ':')).map_or(0, |i| i + 1);
+					let prefix = &line[start..];
+					let candidates = complete(&line);
+					let candidates: Vec<&String> = candidates.iter().filter(|c| c.starts_with(prefix)).collect();
+					if let Some(first) = candidates.first() {
+						// Insert the longest common prefix of the candidates, or list them if there is nothing to insert
+						let common = candidates.iter().fold(first.len(), |len, c| {
+							first.chars().zip(c.chars()).take_while(|(a, b)| a == b).map(|(a, _)| a.len_utf8()).sum::<usize>().min(len)
+						});
+						if common > prefix.len() {
+							let added: Vec<char> = first[prefix.len()..common].chars().collect();
+							buf.splice(cursor..cursor, added.iter().copied());
+							cursor += added.len();
+						} else if candidates.len() > 1 {
+							let list: Vec<&str> = candidates.iter().map(|c| c.as_str()).collect();
+							write!(out, "\r\n{}\r\n", list.join("  "))?;
+						}
+					}
+				},
+				Key::Interrupt => {
+					write!(out, "^C\r\n")?;
+					out.flush()?;
+					return Ok(ReadResult::Interrupted);
+				},
+				Key::Eof if buf.is_empty() => {
+					write!(out, "\r\n")?;
+					out.flush()?;
+					return Ok(ReadResult::Eof);
+				},
+				Key::Eof if cursor < buf.len() => {
+					buf.remove(cursor);
+				},
+				_ => {},
+			}
+			refresh(&mut out, &buf, cursor)?;
+		}
+		write!(out, "\r\n")?;
+		out.flush()?;
+		Ok(ReadResult::Line(buf.into_iter().collect()))
+	}
+}
+
+
+// Names which can complete the last word of a line, given the previous lines of the input
+fn completions(session: &Session, input: &str, line: &str) -> Vec<String> {
+	let line = match line.split_once(' ') {
+		Some((cmd, expr)) if input.is_empty() && cmd.starts_with(':') => expr, // Argument of a command
+		_ if input.is_empty() && line.starts_with(':') => return COMMANDS.iter().map(|cmd| String::from(*cmd)).collect(),
+		_ => line,
+	};
+	if line.trim_end_matches(|c: char| c.is_alphanumeric() || c == '_').ends_with('.') {
+		return vec![]; // Properties are not completed
+	}
+	let text = format!("{}{}", input, line);
+	match session.completions(&text, text.len()) {
+		Ok(completions) => completions.into_iter().map(|c| c.name).collect(),
+		Err(_) => session.bindings().map(|(name, _, _)| String::from(name)).collect(),
+	}
+}
+
+// Runs a shell command; returns false to quit
+fn command(session: &mut Session, line: &str) -> bool {
+	let (cmd, arg) = line.split_once(char::is_whitespace).map_or((line, ""), |(cmd, arg)| (cmd, arg.trim()));
+	match cmd {
+		":vars" => for (name, ty, val) in session.bindings() {
+			println!("{}: {:?} = {}", name, ty, val.repr_with(REPR_OPTIONS));
+		},
+		":dis" => match session.last_program() {
+			Some(program) => if let Err(err) = program.disassemble() {
+				eprintln!("{}", err);
+			},
+			None => println!("Nothing was run yet"),
+		},
+		":type" | ":disasm" if arg.is_empty() => eprintln!("{}Usage: {} <expr>{}", RED, cmd, RESET),
+		":heap-dump" if arg.is_empty() => eprintln!("{}Usage: :heap-dump <file>{}", RED, RESET),
+		":type" => match session.compile_snippet(arg) {
+			Ok(snippet) => println!("{:?}", snippet.ret_type()),
+			Err(err) => eprintln!("{}", err),
+		},
+		":disasm" => if let Err(err) = session.compile_snippet(arg).and_then(|snippet| snippet.program().disassemble()) {
+			eprintln!("{}", err);
+		},
+		":heap" => {
+			let heap = session.heap();
+			println!("{} objects, {} bytes used, next collection at {} bytes", heap.object_count(), heap.used_memory(), heap.threshold());
+		},
+		":heap-dump" => {
+			let res = File::create(arg).and_then(|mut file| session.heap().dump_snapshot(&mut io::BufWriter::new(&mut file)));
+			if let Err(err) = res {
+				eprintln!("{}Could not write heap snapshot to '{}': {}{}", RED, arg, err, RESET);
+			}
+		},
+		":reset" => session.reset(),
+		":help" => print!("{}", HELP),
+		":quit" => return false,
+		_ => eprintln!("{}Unknown command '{}'{}, type :help for the list of commands", RED, cmd, RESET),
+	}
+	true
+}
+
+/// Runs the interactive shell until the end of the input, or until a script calls exit(), returning its exit code.
+pub fn shell(style: BlockStyle, host: HostEnv) -> Result<Option<i32>, HissyError> {
+	let mut session = Session::new();
+	session.set_block_style(style);
+	session.set_resolver(PackageResolver::for_script("<shell>"));
+	session.set_host(host);
+	let mut editor = Editor::new();
+	if io::stdin().is_terminal() {
+		println!("Hissy v{} shell, type :help for help", env!("CARGO_PKG_VERSION"));
+	}
+
+	let mut input = String::new();
+	loop {
+		let prompt = if input.is_empty() { ">>> " } else { "... " };
+		let line = match editor.read_line(prompt, |line| completions(&session, &input, line)) {
+			Ok(ReadResult::Line(line)) => line,
+			Ok(ReadResult::Interrupted) => {
+				input.clear();
+				continue;
+			},
+			Ok(ReadResult::Eof) => break,
+			Err(e) => return Err(error(format!("Unable to read input: {}", e))),
+		};
+		editor.add_history(&line);
+		if input.is_empty() && line.trim_start().starts_with(':') {
+			if !command(&mut session, line.trim()) {
+				break;
+			}
+			continue;
+		}
+
+		input.push_str(&line);
+		input.push('\n');
+		let in_block = input.lines().count() > 1 || line.trim_end().ends_with(':');
+		if in_block && !line.trim().is_empty() {
+			continue;
+		}
+		match session.eval(&input) {
+			Ok(Evaluation::Value(val)) => if !val.is_nil() {
+				println!("{}", val.pretty_with(2, REPR_OPTIONS));
+			},
+			Ok(Evaluation::Exit(code)) => return Ok(Some(code)),
+			Err(err) => eprintln!("{}", err),
+		}
+		if let Some(program) = session.last_program() {
+			print_warnings(program);
+		}
+		input.clear();
+	}
+	Ok(None)
+}
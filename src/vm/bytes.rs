@@ -0,0 +1,115 @@
+
+use std::cell::{Cell, Ref, RefCell, RefMut};
+use std::fmt;
+
+use crate::{HissyError, ErrorType};
+use super::gc::Traceable;
+
+
+/// A mutable buffer of bytes, made with `bytes(size)` in scripts, which hosts can read and write in place.
+///
+/// GC objects never move, and a rooted [`GCRef<Bytes>`](super::gc::GCRef) keeps its buffer alive across
+/// collections, so the slices borrowed from it stay valid, eg. to read from a file or a socket
+/// directly into a buffer used by a script. The size of a buffer is fixed.
+pub struct Bytes {
+	data: RefCell<Box<[u8]>>,
+	frozen: Cell<bool>,
+}
+
+impl Bytes {
+	pub fn new(data: Vec<u8>) -> Bytes {
+		Bytes { data: RefCell::new(data.into_boxed_slice()), frozen: Cell::new(false) }
+	}
+
+	/// Makes a buffer of the given size, filled with zeroes.
+	pub fn zeroed(size: usize) -> Bytes {
+		Bytes::new(vec![0; size])
+	}
+
+	pub fn len(&self) -> usize {
+		self.data.borrow().len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	/// Makes the buffer immutable. This cannot be undone.
+	pub fn freeze(&self) {
+		self.frozen.set(true);
+	}
+
+	pub fn is_frozen(&self) -> bool {
+		self.frozen.get()
+	}
+
+	/// Returns an error if the buffer is frozen.
+	pub fn check_mutable(&self) -> Result<(), HissyError> {
+		if self.is_frozen() {
+			Err(HissyError(ErrorType::Execution, String::from("Cannot modify frozen bytes"), 0))
+		} else {
+			Ok(())
+		}
+	}
+
+	/// Borrows the contents of the buffer. Panics if they are mutably borrowed.
+	pub fn as_slice(&self) -> Ref<'_, [u8]> {
+		Ref::map(self.data.borrow(), |data| &**data)
+	}
+
+	/// Mutably borrows the contents of the buffer. Panics if they are already borrowed, or if the buffer
+	/// is frozen; use [`check_mutable`](Bytes::check_mutable) first when that can happen.
+	pub fn as_mut_slice(&self) -> RefMut<'_, [u8]> {
+		assert!(!self.is_frozen(), "Cannot modify frozen bytes");
+		RefMut::map(self.data.borrow_mut(), |data| &mut **data)
+	}
+}
+
+impl Traceable for Bytes {
+	fn external_size(&self) -> usize {
+		self.len()
+	}
+}
+
+impl fmt::Debug for Bytes {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "<{} bytes>", self.len())
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::convert::TryFrom;
+	use crate::compiler::Compiler;
+	use crate::vm::VM;
+	use crate::vm::gc::{GCHeap, GCRef};
+
+	#[test]
+	fn test_bytes() {
+		let mut heap = GCHeap::new();
+		let src = "let buf = bytes(4)\nbuf.set(0, 104)\nbuf.set(3, 255)\nassert(buf.size() == 4 and buf.get(1) == 0)\nreturn buf\n";
+		let f = Compiler::new(true).compile_function(src, &[]).unwrap();
+		let mut vm = VM::new(&mut heap, f.program().clone());
+		vm.run(&mut heap).unwrap();
+		let buf = GCRef::<Bytes>::try_from(vm.result().clone()).unwrap();
+		drop(vm);
+		heap.collect();
+
+		// The host writes into the buffer the script sees
+		assert_eq!(&*buf.as_slice(), &[104, 0, 0, 255]);
+		buf.as_mut_slice()[1..3].copy_from_slice(b"is");
+		let check = Compiler::new(true).compile_function("assert(buf.get(1) == 105 and buf.get(2) == 115)\n", &[("buf", crate::compiler::Type::Bytes)]).unwrap();
+		check.call(&mut heap, vec![buf.clone().into()]).unwrap();
+
+		for src in &["bytes(-1)\n", "bytes(2).get(2)\n", "bytes(2).set(0, 256)\n"] {
+			assert!(Compiler::new(true).compile_function(src, &[]).unwrap().call(&mut heap, vec![]).is_err(), "{}", src);
+		}
+
+		let freeze = Compiler::new(true).compile_function("freeze(buf)\nbuf.set(0, 1)\n", &[("buf", crate::compiler::Type::Bytes)]).unwrap();
+		let err = freeze.call(&mut heap, vec![buf.clone().into()]).unwrap_err();
+		assert_eq!(err.1, "Cannot modify frozen bytes");
+		assert!(buf.is_frozen() && buf.check_mutable().is_err() && buf.as_slice()[0] == 104);
+	}
+}
@@ -0,0 +1,250 @@
+
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex, Condvar, OnceLock};
+use std::fmt;
+
+use crate::{HissyError, ErrorType};
+use crate::parser::symbol::Symbol;
+use super::value::{Value, NIL};
+use super::gc::{GCHeap, GCRef, Traceable};
+use super::object::List;
+use super::vector::{Vec2, Vec3};
+
+
+fn error(s: String) -> HissyError {
+	HissyError(ErrorType::Execution, s, 0)
+}
+
+
+/// A plain data value, deep-copied out of a GC heap so that it can be sent to another isolate.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+	Nil,
+	Bool(bool),
+	Int(i32),
+	Real(f64),
+	String(String),
+	Symbol(Symbol),
+	Vec2([f64; 2]),
+	Vec3([f64; 3]),
+	List(Arc<Vec<Message>>),
+}
+
+impl Message {
+	/// Copies a value out of the heap. Fails if the value contains functions, channels,
+	/// or other objects which cannot be shared between isolates, or if a list contains itself.
+	///
+	/// A list referenced several times is only copied once, so the copy has the same structure as the original.
+	pub fn from_value(val: &Value) -> Result<Message, HissyError> {
+		Message::from_value_rec(val, &mut vec![], &mut HashMap::new())
+	}
+
+	// `ancestors` holds the lists which are being copied, outermost first, and `copies` those already copied
+	fn from_value_rec(val: &Value, ancestors: &mut Vec<*const ()>, copies: &mut HashMap<*const (), Arc<Vec<Message>>>)
+			-> Result<Message, HissyError> {
+		if val.is_nil() {
+			Ok(Message::Nil)
+		} else if let Ok(b) = bool::try_from(val) {
+			Ok(Message::Bool(b))
+		} else if let Ok(i) = i32::try_from(val) {
+			Ok(Message::Int(i))
+		} else if let Ok(r) = f64::try_from(val) {
+			Ok(Message::Real(r))
+		} else if let Some(s) = val.as_str() {
+			Ok(Message::String(String::from(&*s)))
+		} else if let Ok(s) = Symbol::try_from(val) {
+			Ok(Message::Symbol(s))
+		} else if let Ok(v) = GCRef::<Vec2>::try_from(val.clone()) {
+			Ok(Message::Vec2(v.0))
+		} else if let Ok(v) = GCRef::<Vec3>::try_from(val.clone()) {
+			Ok(Message::Vec3(v.0))
+		} else if let Ok(list) = GCRef::<List>::try_from(val.clone()) {
+			let key = list.pointer as *const ();
+			if let Some(copy) = copies.get(&key) {
+				return Ok(Message::List(copy.clone()));
+			}
+			if ancestors.contains(&key) {
+				return Err(error(String::from("Cannot send a list containing itself through a channel")));
+			}
+			ancestors.push(key);
+			let values: Result<Vec<Message>, HissyError> = list.get_copy().iter()
+				.map(|val| Message::from_value_rec(val, ancestors, copies)).collect();
+			ancestors.pop();
+			let values = Arc::new(values?);
+			copies.insert(key, values.clone());
+			Ok(Message::List(values))
+		} else {
+			Err(error(format!("Cannot send {} through a channel", val.repr())))
+		}
+	}
+
+	/// Rebuilds the value in a (possibly different) heap.
+	pub fn to_value(&self, heap: &mut GCHeap) -> Value {
+		self.to_value_rec(heap, &mut HashMap::new())
+	}
+
+	// `copies` holds the lists already rebuilt, so that shared lists stay shared
+	fn to_value_rec(&self, heap: &mut GCHeap, copies: &mut HashMap<*const Vec<Message>, Value>) -> Value {
+		match self {
+			Message::Nil => NIL,
+			Message::Bool(b) => Value::from(*b),
+			Message::Int(i) => Value::from(*i),
+			Message::Real(r) => Value::from(*r),
+			Message::String(s) => heap.make_string(s),
+			Message::Symbol(s) => Value::from(*s),
+			Message::Vec2(v) => heap.make_value(Vec2(*v)),
+			Message::Vec3(v) => heap.make_value(Vec3(*v)),
+			Message::List(values) => {
+				if let Some(copy) = copies.get(&Arc::as_ptr(values)) {
+					return copy.clone();
+				}
+				let items: Vec<Value> = values.iter().map(|msg| msg.to_value_rec(heap, copies)).collect();
+				let list = List::new();
+				list.extend(&items);
+				let list = heap.make_value(list);
+				copies.insert(Arc::as_ptr(values), list.clone());
+				list
+			},
+		}
+	}
+}
+
+
+#[derive(Default)]
+struct Queue {
+	messages: Mutex<VecDeque<Message>>,
+	available: Condvar,
+}
+
+/// A FIFO message queue shared between isolates (VMs with their own heap, usually on different threads).
+///
+/// Cloning a `Channel` returns a new handle to the same queue.
+#[derive(Clone, Default)]
+pub struct Channel(Arc<Queue>);
+
+fn registry() -> &'static Mutex<HashMap<String, Channel>> {
+	static CHANNELS: OnceLock<Mutex<HashMap<String, Channel>>> = OnceLock::new();
+	CHANNELS.get_or_init(Default::default)
+}
+
+impl Channel {
+	/// Creates a new, anonymous channel.
+	pub fn new() -> Channel {
+		Channel::default()
+	}
+
+	/// Returns the channel with the given name, creating it if needed.
+	///
+	/// This is how scripts obtain channels, through the `channel` native function.
+	pub fn open(name: &str) -> Channel {
+		registry().lock().unwrap().entry(String::from(name)).or_default().clone()
+	}
+
+	pub fn send(&self, msg: Message) {
+		self.0.messages.lock().unwrap().push_back(msg);
+		self.0.available.notify_one();
+	}
+
+	/// Returns the oldest message, if there is one.
+	pub fn try_recv(&self) -> Option<Message> {
+		self.0.messages.lock().unwrap().pop_front()
+	}
+
+	/// Waits for a message to be available, and returns it.
+	pub fn recv(&self) -> Message {
+		let mut messages = self.0.messages.lock().unwrap();
+		loop {
+			if let Some(msg) = messages.pop_front() {
+				return msg;
+			}
+			messages = self.0.available.wait(messages).unwrap();
+		}
+	}
+}
+
+impl Traceable for Channel {}
+
+impl fmt::Debug for Channel {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "<channel>")
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::thread;
+
+	#[test]
+	fn test_channel_between_heaps() {
+		let ch = Channel::new();
+		let ch2 = ch.clone();
+		let worker = thread::spawn(move || {
+			let mut heap = GCHeap::new();
+			let msg = ch2.recv();
+			let val = msg.to_value(&mut heap);
+			let reply = Message::from_value(&val).unwrap();
+			ch2.send(reply);
+			drop(val);
+			heap.collect();
+		});
+
+		let mut heap = GCHeap::new();
+		let list = List::new();
+		list.extend(&[Value::from(1), heap.make_value(String::from("two")), heap.make_value(Vec2([3.0, 4.0]))]);
+		let list = heap.make_value(list);
+		let msg = Message::from_value(&list).unwrap();
+		ch.send(msg.clone());
+		worker.join().unwrap();
+		assert_eq!(ch.recv(), msg);
+		assert_eq!(ch.try_recv(), None);
+
+		let chan = heap.make_value(Channel::new());
+		assert!(Message::from_value(&chan).is_err());
+		drop((list, chan));
+		heap.collect();
+	}
+
+	#[test]
+	fn test_cyclic_messages() {
+		let mut heap = GCHeap::new();
+		let inner = heap.make_ref(List::new());
+		let outer = heap.make_ref(List::new());
+		outer.extend(&[Value::from(inner.clone()), Value::from(inner.clone())]);
+		// A list referenced twice is copied once
+		let msg = Message::from_value(&Value::from(outer.clone())).unwrap();
+		let empty = Message::List(Arc::new(vec![]));
+		assert_eq!(msg, Message::List(Arc::new(vec![empty.clone(), empty])));
+
+		// Without sharing, the copy would double in size at every level
+		let mut shared = heap.make_ref(List::new());
+		for _ in 0..64 {
+			let list = heap.make_ref(List::new());
+			list.extend(&[Value::from(shared.clone()), Value::from(shared)]);
+			shared = list;
+		}
+		let msg = Message::from_value(&Value::from(shared)).unwrap();
+		let mut copy = GCRef::<List>::try_from(msg.to_value(&mut heap)).unwrap();
+		for _ in 0..64 {
+			let first = GCRef::<List>::try_from(copy.get(0).unwrap()).unwrap();
+			let second = GCRef::<List>::try_from(copy.get(1).unwrap()).unwrap();
+			assert_eq!(first.pointer, second.pointer);
+			copy = first;
+		}
+		drop(copy);
+
+		inner.extend(&[Value::from(outer.clone())]);
+		let err = Message::from_value(&Value::from(outer.clone())).unwrap_err();
+		assert_eq!(err.1, "Cannot send a list containing itself through a channel");
+		drop((inner, outer));
+		heap.collect();
+	}
+
+	#[test]
+	fn test_named_channels() {
+		Channel::open("test").send(Message::Int(3));
+		assert_eq!(Channel::open("test").try_recv(), Some(Message::Int(3)));
+	}
+}
@@ -1,18 +1,33 @@
 
 use std::pin::Pin;
-use std::cell::Cell;
-use std::{ptr, mem, raw, fmt};
+use std::cell::{Cell, RefCell};
+use std::{ptr, mem, fmt};
+#[repr(C)] struct ShimTraitObject { data: *mut (), vtable: *mut () }
+mod raw { pub(super) use super::ShimTraitObject as TraitObject; }
 use std::fmt::Debug;
 use std::marker::PhantomData;
 use std::any::Any;
 use std::ops::Deref;
+use std::collections::HashMap;
+use std::cmp::Reverse;
+use std::io;
 
-use super::value::Value;
+use super::value::{self, Value};
+use super::registry::{self, TypeInfo};
 
 
 const RED: &str = "\u{001b}[31;1m";
 const RESET: &str = "\u{001b}[0m";
 
+// Maximum number of characters of the representation of an object in heap snapshots
+const SNAPSHOT_REPR_LEN: usize = 80;
+
+thread_local! {
+	// While a heap snapshot is being taken, marking an object only records it here,
+	// so that touching an object lists its direct children without going through the rest of the graph.
+	static EDGES: RefCell<Option<Vec<*const ()>>> = const { RefCell::new(None) };
+}
+
 
 /// An auto-implemented trait to allow easier access to Any methods.
 pub trait AsAny {
@@ -30,6 +45,10 @@ pub trait Traceable {
 	/// Should call .touch(initial) on all direct GCRef/Value children of self.
 	/// This is used for garbage collection.
 	fn touch(&self, _initial: bool) {}
+	
+	/// Should return the number of bytes owned by self outside of the GC heap (eg. string buffers).
+	/// This is counted in the heap's memory usage, which determines when to collect.
+	fn external_size(&self) -> usize { 0 }
 }
 
 /// An auto-implemented trait with all the supertraits required for GC values.
@@ -44,6 +63,9 @@ pub(super) struct GCWrapper_<T: ?Sized> {
 	vtable: *mut (),
 	marked: Cell<bool>,
 	roots: Cell<u32>,
+	serial: u64, // Number of objects allocated in the heap before this one
+	#[cfg(feature = "alloc-sites")]
+	site: Option<AllocSite>,
 	data: T,
 }
 pub(super) type GCWrapper = GCWrapper_<dyn GC>;
@@ -51,7 +73,7 @@ pub(super) type GCWrapper = GCWrapper_<dyn GC>;
 // because of Rust's still partial support for custom DSTs.
 
 impl GCWrapper {
-	fn new_pinned<T: GC>(mut value: T) -> Pin<Box<GCWrapper>> {
+	fn new_pinned<T: GC>(mut value: T, serial: u64, #[cfg(feature = "alloc-sites")] site: Option<AllocSite>) -> Pin<Box<GCWrapper>> {
 		let trait_object: &mut dyn GC = &mut value;
 		// Safety: raw::TraitObject layout should correspond to actual trait object layout
 		let raw_object: raw::TraitObject = unsafe { mem::transmute(trait_object) };
@@ -59,6 +81,9 @@ impl GCWrapper {
 			vtable: raw_object.vtable,
 			marked: Cell::new(false),
 			roots: Cell::new(0),
+			serial,
+			#[cfg(feature = "alloc-sites")]
+			site,
 			data: value
 		})
 	}
@@ -84,12 +109,16 @@ impl GCWrapper {
 		self.data.as_any().downcast_ref::<T>()
 	}
 	
+	pub fn type_info(&self) -> Option<TypeInfo> {
+		registry::type_info(self.data.as_any().type_id())
+	}
+	
 	pub fn debug(&self) -> String {
 		format!("{:?}", self)
 	}
 	
 	fn size(&self) -> usize {
-		mem::size_of_val(&self) + mem::size_of_val(&self.data)
+		mem::size_of_val(&self) + mem::size_of_val(&self.data) + self.data.external_size()
 	}
 	
 	pub fn signal_root(&self) {
@@ -100,7 +129,10 @@ impl GCWrapper {
 	}
 	
 	pub fn mark(&self) {
-		if !self.marked.get() {
+		let recorded = EDGES.with(|edges| {
+			edges.borrow_mut().as_mut().map(|edges| edges.push(self as *const GCWrapper as *const ())).is_some()
+		});
+		if !recorded && !self.marked.get() {
 			self.marked.set(true);
 			self.data.touch(false);
 		}
@@ -205,6 +237,9 @@ impl<T: GC> Debug for GCRef<T> {
 
 const INIT_THRESHOLD: usize = 64;
 
+// Number of objects allocated and their size, by allocation site and type
+type AllocProfile = HashMap<(Option<AllocSite>, &'static str), (usize, usize)>;
+
 /// Object maintaining all GC state.
 /// 
 /// Usually, only one should be created.
@@ -213,6 +248,11 @@ pub struct GCHeap {
 	objects: Vec<Pin<Box<GCWrapper>>>,
 	threshold: usize,
 	used: usize,
+	external: usize, // Bytes tracked with track_external
+	disabled: bool,
+	allocated: u64, // Number of objects allocated since the creation of the heap
+	site: Option<AllocSite>, // Site of the instruction being run, if tracked
+	profile: Option<AllocProfile>,
 }
 
 impl GCHeap {
@@ -222,12 +262,24 @@ impl GCHeap {
 			objects: vec![],
 			threshold: INIT_THRESHOLD,
 			used: 0,
+			external: 0,
+			disabled: false,
+			allocated: 0,
+			site: None,
+			profile: None,
 		}
 	}
 	
 	fn add<T: GC>(&mut self, v: T) -> &GCWrapper {
-		let wrapper = GCWrapper::new_pinned(v);
+		let wrapper = GCWrapper::new_pinned(v, self.allocated, #[cfg(feature = "alloc-sites")] self.site);
+		self.allocated += 1;
 		self.used += wrapper.size();
+		if let Some(profile) = &mut self.profile {
+			let type_name = wrapper.type_info().map_or("Object", |info| info.name);
+			let (count, size) = profile.entry((self.site, type_name)).or_default();
+			*count += 1;
+			*size += wrapper.size();
+		}
 		wrapper.unroot_children(); // Unroot children
 		self.objects.push(wrapper);
 		self.objects.last_mut().unwrap()
@@ -242,6 +294,17 @@ impl GCHeap {
 		Value::from_pointer(self.add(v), true) // Root new object
 	}
 	
+	/// Creates a string value, stored inline if it is short enough, or as a `String` object otherwise.
+	pub fn make_string(&mut self, s: &str) -> Value {
+		Value::short_str(s).unwrap_or_else(|| self.make_value(String::from(s)))
+	}
+	
+	/// Creates a string value from an owned `String`, which is moved into the heap without copying its contents
+	/// (unless it is short enough to be stored inline), eg. to pass large text blobs to scripts.
+	pub fn alloc_str_from(&mut self, s: String) -> Value {
+		Value::short_str(&s).unwrap_or_else(|| self.make_value(s))
+	}
+	
 	/// Delete dead objects from heap.
 	/// 
 	/// This uses [`Traceable.touch`] to determine all live objects.
@@ -252,9 +315,14 @@ impl GCHeap {
 			}
 		}
 		
-		self.objects.retain(|wrapper| wrapper.marked.get());
+		self.objects.retain(|wrapper| {
+			if !wrapper.marked.get() {
+				value::pointers::release(&**wrapper as *const GCWrapper as *mut ());
+			}
+			wrapper.marked.get()
+		});
 		
-		self.used = 0;
+		self.used = self.external;
 		for wrapper in self.objects.iter_mut() {
 			wrapper.reset();
 			self.used += wrapper.size();
@@ -265,13 +333,45 @@ impl GCHeap {
 	/// 
 	/// The threshold is set to some initial value, and will be set to double
 	/// the current usage at the end of any collection initiated by this function.
+	/// 
+	/// Does nothing while automatic collection is disabled.
 	pub fn step(&mut self) {
-		if self.used >= self.threshold {
+		if self.needs_collection() {
 			self.collect();
 			self.threshold = self.used * 2;
 		}
 	}
 	
+	/// Returns whether the next call to [`GCHeap::step`] will collect.
+	pub fn needs_collection(&self) -> bool {
+		!self.disabled && self.used >= self.threshold
+	}
+	
+	/// Disables automatic collection by [`GCHeap::step`], eg. during time-critical sections.
+	/// Explicit calls to [`GCHeap::collect`] still collect.
+	pub fn disable(&mut self) {
+		self.disabled = true;
+	}
+	
+	/// Re-enables automatic collection.
+	pub fn enable(&mut self) {
+		self.disabled = false;
+	}
+	
+	pub fn is_enabled(&self) -> bool {
+		!self.disabled
+	}
+	
+	/// Returns the memory usage at which the next automatic collection will happen.
+	pub fn threshold(&self) -> usize {
+		self.threshold
+	}
+	
+	/// Returns the number of objects in the heap, including dead objects not yet collected.
+	pub fn object_count(&self) -> usize {
+		self.objects.len()
+	}
+	
 	/// Inspect current heap contents. Prints to standard output.
 	pub fn inspect(&self) {
 		println!("[GC inspect] ({}B used, collect at {}B)", self.used, self.threshold);
@@ -280,16 +380,180 @@ impl GCHeap {
 		}
 	}
 	
-	/// Returns the total number of bytes stored in the GC heap.
+	/// Writes a snapshot of the heap as JSON, for finding memory leaks.
+	/// 
+	/// Every object is listed with a numeric id, its type name, its size in bytes (including external allocations),
+	/// its number of root references, a truncated representation, and the ids of the objects it references:
+	/// `{"used": 120, "objects": [{"id": 0, "type": "List", "size": 72, "roots": 1, "repr": "[\"hiss\"]", "refs": [1]}, ...]}`.
+	/// Dead objects which were not collected yet are included.
+	pub fn dump_snapshot<W: io::Write>(&self, out: &mut W) -> io::Result<()> {
+		let ids: HashMap<*const (), usize> = self.objects.iter().enumerate()
+			.map(|(id, wrapper)| (&**wrapper as *const GCWrapper as *const (), id))
+			.collect();
+		write!(out, "{{\"used\": {}, \"objects\": [", self.used)?;
+		for (id, wrapper) in self.objects.iter().enumerate() {
+			EDGES.with(|edges| *edges.borrow_mut() = Some(vec![]));
+			wrapper.data.touch(false);
+			let refs = EDGES.with(|edges| edges.borrow_mut().take()).unwrap();
+			let refs: Vec<String> = refs.iter().filter_map(|child| ids.get(child)).map(|id| id.to_string()).collect();
+			let type_name = wrapper.type_info().map_or("Object", |info| info.name);
+			let repr: String = wrapper.debug().chars().take(SNAPSHOT_REPR_LEN).collect();
+			write!(out, "{}\n\t{{\"id\": {}, \"type\": {}, \"size\": {}, \"roots\": {}, \"repr\": {}, \"refs\": [{}]}}",
+				if id == 0 { "" } else { "," }, id, json_string(type_name), wrapper.size(), wrapper.roots.get(),
+				json_string(&repr), refs.join(", "))?;
+		}
+		writeln!(out, "\n]}}")
+	}
+	
+	/// Takes a summary of the objects currently in the heap, which can be compared to a later one
+	/// with [`HeapSnapshot::diff`] to find the objects allocated in between which were not collected.
+	pub fn snapshot(&self) -> HeapSnapshot {
+		let objects = self.objects.iter().map(|wrapper| ObjectInfo {
+			serial: wrapper.serial,
+			type_name: wrapper.type_info().map_or("Object", |info| info.name),
+			size: wrapper.size(),
+			#[cfg(feature = "alloc-sites")]
+			site: wrapper.site,
+			#[cfg(not(feature = "alloc-sites"))]
+			site: None,
+		}).collect();
+		HeapSnapshot { objects, allocated: self.allocated }
+	}
+	
+	/// Starts counting the objects allocated from now on and their size, by allocation site and type,
+	/// to find the places where scripts allocate the most. Sites are only known in programs with debug info.
+	pub fn start_profiling(&mut self) {
+		self.profile = Some(HashMap::new());
+	}
+	
+	/// Stops profiling allocations, and returns the counts since [`GCHeap::start_profiling`], the most bytes first.
+	pub fn stop_profiling(&mut self) -> Vec<AllocGroup> {
+		let mut groups: Vec<AllocGroup> = self.profile.take().unwrap_or_default().into_iter()
+			.map(|((site, type_name), (count, size))| AllocGroup { site, type_name, count, size })
+			.collect();
+		groups.sort_by_key(|group| Reverse(group.size));
+		groups
+	}
+	
+	/// Returns whether the site of allocations should be recorded, with the `alloc-sites` feature or while profiling.
+	pub fn tracks_sites(&self) -> bool {
+		cfg!(feature = "alloc-sites") || self.profile.is_some()
+	}
+	
+	/// Sets the site recorded for the objects allocated from now on. When [`GCHeap::tracks_sites`] is true,
+	/// the VM updates it for every instruction of programs with debug info, and resets it afterwards.
+	pub fn set_alloc_site(&mut self, site: Option<AllocSite>) {
+		self.site = site;
+	}
+	
+	/// Returns the total number of bytes stored in the GC heap, including external allocations.
 	pub fn used_memory(&self) -> usize {
 		self.used
 	}
 	
+	/// Counts memory allocated by the host outside of the heap (eg. buffers owned by userdata)
+	/// towards the heap's memory usage, until it is released with [`GCHeap::untrack_external`].
+	/// 
+	/// Objects whose external allocations are fixed should implement [`Traceable::external_size`] instead.
+	pub fn track_external(&mut self, bytes: usize) {
+		self.external += bytes;
+		self.used += bytes;
+	}
+	
+	/// Runs `f`, which may grow the external allocations of an object in the heap (eg. by adding values to a list),
+	/// and counts the growth in the heap's memory usage, which is otherwise only measured again when collecting.
+	pub fn grow<T: Traceable + ?Sized, R>(&mut self, obj: &T, f: impl FnOnce(&T) -> R) -> R {
+		let before = obj.external_size();
+		let res = f(obj);
+		self.used += obj.external_size().saturating_sub(before);
+		res
+	}
+	
+	/// Stops counting memory previously passed to [`GCHeap::track_external`].
+	pub fn untrack_external(&mut self, bytes: usize) {
+		assert!(bytes <= self.external, "Untracking more external memory than was tracked");
+		self.external -= bytes;
+		self.used = self.used.saturating_sub(bytes);
+	}
+	
 	pub fn is_empty(&self) -> bool {
 		self.objects.is_empty()
 	}
 }
 
+/// The instruction which allocated an object, recorded with the `alloc-sites` feature or while profiling.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct AllocSite {
+	/// Index of the chunk in the running program (0 is the main chunk).
+	pub chunk: u32,
+	pub line: u16,
+}
+
+/// Information about an object in a [`HeapSnapshot`].
+#[derive(Clone, Debug)]
+pub struct ObjectInfo {
+	pub serial: u64,
+	pub type_name: &'static str,
+	pub size: usize,
+	/// Only recorded with the `alloc-sites` feature, for objects allocated by programs with debug info.
+	pub site: Option<AllocSite>,
+}
+
+/// Objects of the same type allocated at the same site, as listed by [`HeapSnapshot::diff`] and [`GCHeap::stop_profiling`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct AllocGroup {
+	pub site: Option<AllocSite>,
+	pub type_name: &'static str,
+	pub count: usize,
+	pub size: usize,
+}
+
+/// A summary of the objects in a heap at some point, taken with [`GCHeap::snapshot`].
+#[derive(Clone, Debug)]
+pub struct HeapSnapshot {
+	objects: Vec<ObjectInfo>,
+	allocated: u64,
+}
+
+impl HeapSnapshot {
+	pub fn objects(&self) -> &[ObjectInfo] {
+		&self.objects
+	}
+	
+	/// Returns the objects of a later snapshot of the same heap which were allocated after this one was taken,
+	/// grouped by allocation site and type, the largest groups first.
+	/// 
+	/// Objects which keep showing up between snapshots while a program runs in a loop are likely leaking.
+	pub fn diff(&self, later: &HeapSnapshot) -> Vec<AllocGroup> {
+		let mut groups: Vec<AllocGroup> = vec![];
+		for obj in later.objects.iter().filter(|obj| obj.serial >= self.allocated) {
+			match groups.iter_mut().find(|group| group.site == obj.site && group.type_name == obj.type_name) {
+				Some(group) => {
+					group.count += 1;
+					group.size += obj.size;
+				},
+				None => groups.push(AllocGroup { site: obj.site, type_name: obj.type_name, count: 1, size: obj.size }),
+			}
+		}
+		groups.sort_by_key(|group| Reverse(group.size));
+		groups
+	}
+}
+
+fn json_string(s: &str) -> String {
+	let mut res = String::from("\"");
+	for c in s.chars() {
+		match c {
+			'"' => res.push_str("\\\""),
+			'\\' => res.push_str("\\\\"),
+			c if (c as u32) < 0x20 => res.push_str(&format!("\\u{:04x}", c as u32)),
+			c => res.push(c),
+		}
+	}
+	res.push('"');
+	res
+}
+
 /// The `Drop` implementation for `GCHeap` does not collect all remaining objects;
 /// it simply prints a warning if the heap is not empty.
 /// 
@@ -303,3 +567,79 @@ impl Drop for GCHeap {
 		}
 	}
 }
+
+
+/// A guard which roots the objects allocated through it, until it is dropped.
+/// 
+/// This makes it easier for native functions to allocate several objects: the [`Handle`]s
+/// it returns are `Copy`, and do not need to be rooted individually, but the borrow checker
+/// ensures they cannot outlive the scope.
+pub struct HandleScope<'h> {
+	heap: RefCell<&'h mut GCHeap>,
+	roots: RefCell<Vec<Value>>,
+}
+
+impl<'h> HandleScope<'h> {
+	pub fn new(heap: &'h mut GCHeap) -> HandleScope<'h> {
+		HandleScope { heap: RefCell::new(heap), roots: RefCell::new(vec![]) }
+	}
+	
+	/// Places an object into the heap, returning a handle rooted by this scope.
+	pub fn make<T: GC>(&self, v: T) -> Handle<'_, T> {
+		let gc_ref = self.heap.borrow_mut().make_ref(v);
+		self.root(gc_ref)
+	}
+	
+	/// Roots an existing reference for the lifetime of this scope.
+	pub fn root<T: GC>(&self, gc_ref: GCRef<T>) -> Handle<'_, T> {
+		let pointer = gc_ref.pointer;
+		self.roots.borrow_mut().push(Value::from(gc_ref));
+		Handle { pointer, phantom: PhantomData }
+	}
+	
+	/// Gives access to the heap, eg. to collect garbage. Handles from this scope remain valid.
+	pub fn heap(&self) -> std::cell::RefMut<'_, &'h mut GCHeap> {
+		self.heap.borrow_mut()
+	}
+}
+
+
+/// A reference to a GC object, kept alive by a [`HandleScope`].
+pub struct Handle<'s, T: GC> {
+	pointer: *const GCWrapper,
+	phantom: PhantomData<(&'s (), T)>,
+}
+
+impl<'s, T: GC> Handle<'s, T> {
+	/// Returns a root reference to the object, which can outlive the scope.
+	pub fn to_ref(self) -> GCRef<T> {
+		GCRef::from_pointer(self.pointer, true)
+	}
+	
+	/// Returns a root value containing the object, which can outlive the scope.
+	pub fn to_value(self) -> Value {
+		Value::from_pointer(self.pointer, true)
+	}
+}
+
+impl<'s, T: GC> Clone for Handle<'s, T> {
+	fn clone(&self) -> Self {
+		*self
+	}
+}
+impl<'s, T: GC> Copy for Handle<'s, T> {}
+
+impl<'s, T: GC> Deref for Handle<'s, T> {
+	type Target = T;
+	
+	fn deref(&self) -> &T {
+		// Safety: the object is rooted by the scope, which outlives 's
+		unsafe { &*self.pointer }.get::<T>().unwrap()
+	}
+}
+
+impl<'s, T: GC> Debug for Handle<'s, T> {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+		write!(f, "Handle({:?})", **self)
+	}
+}
@@ -0,0 +1,160 @@
+
+use std::convert::TryFrom;
+use std::env;
+use std::fmt;
+use std::process::Command;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::{HissyError, ErrorType};
+use crate::vm::PendingOperation;
+use crate::vm::gc::{GCHeap, GCRef};
+use crate::vm::value::{Value, NIL};
+use crate::vm::object::List;
+
+
+fn error(s: String) -> HissyError {
+	HissyError(ErrorType::Execution, s, 0)
+}
+
+/// The severity of a message logged by a script with `log_debug`, `log_info`, `log_warn` or `log_error`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum LogLevel {
+	Debug,
+	#[default]
+	Info,
+	Warn,
+	Error,
+}
+
+impl LogLevel {
+	const ALL: [LogLevel; 4] = [LogLevel::Debug, LogLevel::Info, LogLevel::Warn, LogLevel::Error];
+	
+	/// The lowercase name of the level, as accepted by `FromStr`.
+	pub fn name(self) -> &'static str {
+		match self {
+			LogLevel::Debug => "debug",
+			LogLevel::Info => "info",
+			LogLevel::Warn => "warn",
+			LogLevel::Error => "error",
+		}
+	}
+}
+
+impl FromStr for LogLevel {
+	type Err = HissyError;
+	
+	fn from_str(s: &str) -> Result<LogLevel, HissyError> {
+		LogLevel::ALL.iter().copied().find(|level| level.name() == s)
+			.ok_or_else(|| HissyError(ErrorType::IO, format!("Unknown log level '{}', expected debug, info, warn or error", s), 0))
+	}
+}
+
+type LogFn = dyn Fn(LogLevel, &str) + Send + Sync;
+
+/// Receives the messages logged by scripts, eg. to forward them to the logging of the application.
+#[derive(Clone)]
+pub struct LogSink(Arc<LogFn>);
+
+impl LogSink {
+	pub fn new(sink: impl Fn(LogLevel, &str) + Send + Sync + 'static) -> LogSink {
+		LogSink(Arc::new(sink))
+	}
+}
+
+impl fmt::Debug for LogSink {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "LogSink")
+	}
+}
+
+/// What a script run with [`run_program_with`](super::run_program_with) is allowed to do on the host process,
+/// through the `env`, `exit` and `exec` natives and the network natives, which fail with an execution error unless allowed.
+///
+/// The default environment allows nothing.
+#[derive(Debug, Clone, Default)]
+pub struct HostEnv {
+	/// Allows reading environment variables with `env(name)`, which returns nil for undefined variables
+	pub allow_env: bool,
+	/// Allows ending the script with an exit code with `exit(code)`
+	pub allow_exit: bool,
+	/// Allows running programs with `exec(cmd, args)`, which returns their standard output,
+	/// and fails if they exit with a non-zero code
+	pub allow_exec: bool,
+	/// Allows connecting to other machines with `tcp_connect(host, port)` and `http_get(url)`
+	/// (requires the `net` feature)
+	pub allow_net: bool,
+	/// Messages logged by scripts below this level are ignored; logging is always allowed
+	pub log_level: LogLevel,
+	/// Receives the messages logged by scripts; they are printed to the standard error if there is none
+	pub log_sink: Option<LogSink>,
+}
+
+// What to do after an operation performed by the host
+pub(super) enum HostAction {
+	Resume(Value),
+	Exit(i32),
+}
+
+impl HostEnv {
+	/// An environment allowing everything, for trusted scripts such as small automation scripts.
+	pub fn trusted() -> HostEnv {
+		HostEnv { allow_env: true, allow_exit: true, allow_exec: true, allow_net: true, ..HostEnv::default() }
+	}
+
+	// Performs an operation started by one of the host natives, which have already checked their arguments;
+	// returns None for other operations
+	pub(super) fn perform(&self, heap: &mut GCHeap, op: &PendingOperation) -> Result<Option<HostAction>, HissyError> {
+		let allowed = match op.name.as_str() {
+			"env" => self.allow_env,
+			"exit" => self.allow_exit,
+			"exec" => self.allow_exec,
+			"log" => true,
+			#[cfg(feature = "net")]
+			"tcp_connect" | "tcp_read" | "tcp_write" | "http_get" => self.allow_net,
+			_ => return Ok(None),
+		};
+		if !allowed {
+			return Err(error(format!("Native '{}' is not allowed by the host", op.name)));
+		}
+		let action = match op.name.as_str() {
+			"env" => {
+				let name = op.args[0].as_str().unwrap();
+				HostAction::Resume(env::var(&*name).map_or(NIL, |value| heap.alloc_str_from(value)))
+			},
+			"exit" => HostAction::Exit(i32::try_from(&op.args[0]).unwrap()),
+			"log" => {
+				let level = LogLevel::ALL[usize::try_from(i32::try_from(&op.args[0]).unwrap()).unwrap()];
+				if level >= self.log_level {
+					let message = op.args[1].as_str().unwrap();
+					match &self.log_sink {
+						Some(LogSink(sink)) => sink(level, &message),
+						None => eprintln!("[{}] {}", level.name(), &*message),
+					}
+				}
+				HostAction::Resume(NIL)
+			},
+			"exec" => {
+				let cmd = op.args[0].as_str().unwrap();
+				let args: Vec<String> = GCRef::<List>::try_from(op.args[1].clone()).unwrap().get_copy().iter()
+					.map(|arg| String::from(&*arg.as_str().unwrap()))
+					.collect();
+				let output = Command::new(&*cmd).args(&args).output()
+					.map_err(|e| error(format!("Unable to run '{}': {}", &*cmd, e)))?;
+				if !output.status.success() {
+					let code = output.status.code().map_or_else(|| String::from("none"), |code| code.to_string());
+					return Err(error(format!("Command '{}' failed with exit code {}: {}",
+						&*cmd, code, String::from_utf8_lossy(&output.stderr).trim_end())));
+				}
+				let stdout = String::from_utf8(output.stdout)
+					.unwrap_or_else(|err| String::from_utf8_lossy(err.as_bytes()).into_owned());
+				HostAction::Resume(heap.alloc_str_from(stdout))
+			},
+			#[cfg(feature = "net")]
+			_ => HostAction::Resume(super::net::perform(heap, op)?),
+			#[cfg(not(feature = "net"))]
+			_ => unreachable!(),
+		};
+		Ok(Some(action))
+	}
+}
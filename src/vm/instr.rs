@@ -0,0 +1,355 @@
+
+use std::convert::TryFrom;
+use std::fmt::Write;
+
+use crate::{HissyError, ErrorType};
+use crate::serial::*;
+use OperandType::*;
+
+
+fn error_str(s: &str) -> HissyError {
+	HissyError(ErrorType::IO, String::from(s), 0)
+}
+
+
+/// The kind of a bytecode instruction operand, which determines its size and interpretation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperandType {
+	/// One-byte register index (`r`)
+	Reg,
+	/// One-byte register or constant index (`rc`): registers below `MAX_REGISTERS`, constants above
+	RegOrCst,
+	/// Signed address, relative to the first byte containing it (`a`);
+	/// one byte in the compact encoding, two in the wide encoding
+	RelAdd,
+	/// One-byte upvalue index (`u`)
+	Upvalue,
+	/// One-byte chunk index (`c`)
+	Chunk,
+	/// Two-byte index into the external values (`e`)
+	External,
+	/// One-byte index of a property in a namespace (`p`)
+	Prop,
+	/// One-byte count (`n`)
+	Count,
+}
+
+impl OperandType {
+	/// The size of the operand in bytes.
+	pub fn size(self, encoding: Encoding) -> usize {
+		match (self, encoding) {
+			(External, _) | (RelAdd, Encoding::Wide) => 2,
+			_ => 1,
+		}
+	}
+
+	/// The notation used for this operand in the instruction reference.
+	pub fn notation(self) -> &'static str {
+		match self {
+			Reg => "r",
+			RegOrCst => "rc",
+			RelAdd => "a",
+			Upvalue => "u",
+			Chunk => "c",
+			External => "e",
+			Prop => "p",
+			Count => "n",
+		}
+	}
+}
+
+
+/// The layout of instructions in the bytecode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+	/// Opcodes directly followed by their operands, with one-byte relative addresses.
+	Compact,
+	/// Instructions padded to a whole number of 32-bit words, the first of which holds
+	/// the opcode and up to 3 bytes of operands, with two-byte relative addresses.
+	Wide,
+}
+
+impl Encoding {
+	/// The range of relative addresses which can be encoded.
+	pub fn max_jump(self) -> (i16, i16) {
+		match self {
+			Encoding::Compact => (i16::from(i8::MIN), i16::from(i8::MAX)),
+			Encoding::Wide => (i16::MIN, i16::MAX),
+		}
+	}
+	
+	fn padding(self, size: usize) -> usize {
+		match self {
+			Encoding::Compact => 0,
+			Encoding::Wide => (4 - size % 4) % 4,
+		}
+	}
+}
+
+/// A value which can be encoded as an instruction operand.
+trait Operand: Sized + Copy + Into<i32> {
+	fn read<'a>(it: &mut impl Iterator<Item = &'a u8>, encoding: Encoding) -> Result<Self, HissyError>;
+	fn write(self, out: &mut Vec<u8>, encoding: Encoding);
+}
+
+impl Operand for u8 {
+	fn read<'a>(it: &mut impl Iterator<Item = &'a u8>, _: Encoding) -> Result<Self, HissyError> { read_u8(it) }
+	fn write(self, out: &mut Vec<u8>, _: Encoding) { write_u8(out, self) }
+}
+
+impl Operand for u16 {
+	fn read<'a>(it: &mut impl Iterator<Item = &'a u8>, _: Encoding) -> Result<Self, HissyError> { read_u16(it) }
+	fn write(self, out: &mut Vec<u8>, _: Encoding) { write_u16(out, self) }
+}
+
+// Relative addresses; the compiler ensures they fit in the encoding's range
+impl Operand for i16 {
+	fn read<'a>(it: &mut impl Iterator<Item = &'a u8>, encoding: Encoding) -> Result<Self, HissyError> {
+		match encoding {
+			Encoding::Compact => Ok(i16::from(read_i8(it)?)),
+			Encoding::Wide => read_i16(it),
+		}
+	}
+	fn write(self, out: &mut Vec<u8>, encoding: Encoding) {
+		match encoding {
+			Encoding::Compact => write_i8(out, self as i8),
+			Encoding::Wide => write_i16(out, self),
+		}
+	}
+}
+
+macro_rules! operand_type {
+	(RelAdd) => { i16 };
+	(External) => { u16 };
+	($other:ident) => { u8 };
+}
+
+// Single source of truth for the instruction set: opcodes, operand layouts,
+// typed instructions, their encoding and decoding, and their descriptions.
+macro_rules! instructions {
+	($($name:ident { $($field:ident: $op:ident),* } => $desc:literal,)*) => {
+		#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
+		#[repr(u8)]
+		pub(crate) enum InstrType {
+			$($name,)*
+		}
+		
+		/// A decoded instruction, with typed operands.
+		#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+		pub(crate) enum Instr {
+			$($name { $($field: operand_type!($op)),* },)*
+		}
+		
+		impl InstrType {
+			/// The operands following the opcode, in order.
+			pub fn operands(self) -> &'static [OperandType] {
+				match self {
+					$(InstrType::$name => &[$($op),*],)*
+				}
+			}
+			
+			/// A short description of the instruction's semantics, referring to operands by position.
+			pub fn description(self) -> &'static str {
+				match self {
+					$(InstrType::$name => $desc,)*
+				}
+			}
+		}
+		
+		impl Instr {
+			pub fn instr_type(&self) -> InstrType {
+				match self {
+					$(Instr::$name { .. } => InstrType::$name,)*
+				}
+			}
+			
+			/// Appends the encoded instruction to `out`.
+			pub fn encode(&self, out: &mut Vec<u8>, encoding: Encoding) {
+				let start = out.len();
+				match *self {
+					$(Instr::$name { $($field),* } => {
+						write_u8(out, InstrType::$name as u8);
+						$(Operand::write($field, out, encoding);)*
+					},)*
+				}
+				let padding = encoding.padding(out.len() - start);
+				out.extend(std::iter::repeat(0).take(padding));
+			}
+			
+			/// Reads an instruction from a bytecode stream.
+			pub fn decode<'a>(it: &mut impl Iterator<Item = &'a u8>, encoding: Encoding) -> Result<Instr, HissyError> {
+				let instr_type = InstrType::try_from(read_u8(it)?)
+					.map_err(|_| error_str("Invalid instruction in bytecode"))?;
+				let instr = match instr_type {
+					$(InstrType::$name => Instr::$name { $($field: Operand::read(it, encoding)?),* },)*
+				};
+				for _ in 0..encoding.padding(instr_type.size(encoding)) {
+					read_u8(it)?;
+				}
+				Ok(instr)
+			}
+			
+			/// The operands of the instruction, in order, along with their types.
+			pub fn operand_values(&self) -> Vec<(OperandType, i32)> {
+				match *self {
+					$(Instr::$name { $($field),* } => vec![$(($op, $field.into())),*],)*
+				}
+			}
+		}
+	};
+}
+
+instructions! {
+	Nop {} => "No effect",
+	Cpy { src: RegOrCst, dst: Reg } => "Copies $1 into $2",
+	GetUp { upv: Upvalue, dst: Reg } => "Copies upvalue $1 into $2",
+	SetUp { upv: Upvalue, src: RegOrCst } => "Sets upvalue $1 to $2",
+	CloseUp { reg: Reg } => "Moves the value of $1 to the heap, if it was captured as an upvalue",
+	GetExt { ext: External, dst: Reg } => "Copies external value $1 into $2",
+	Neg { a: RegOrCst, dst: Reg } => "Stores -$1 into $2",
+	Add { a: RegOrCst, b: RegOrCst, dst: Reg } => "Stores $1 + $2 into $3",
+	Sub { a: RegOrCst, b: RegOrCst, dst: Reg } => "Stores $1 - $2 into $3",
+	Mul { a: RegOrCst, b: RegOrCst, dst: Reg } => "Stores $1 * $2 into $3",
+	Div { a: RegOrCst, b: RegOrCst, dst: Reg } => "Stores $1 / $2 into $3",
+	Mod { a: RegOrCst, b: RegOrCst, dst: Reg } => "Stores $1 % $2 into $3",
+	Pow { a: RegOrCst, b: RegOrCst, dst: Reg } => "Stores $1 ^ $2 into $3",
+	Not { a: RegOrCst, dst: Reg } => "Stores not $1 into $2",
+	Or { a: RegOrCst, b: RegOrCst, dst: Reg } => "Stores $1 or $2 into $3",
+	And { a: RegOrCst, b: RegOrCst, dst: Reg } => "Stores $1 and $2 into $3",
+	Eq { a: RegOrCst, b: RegOrCst, dst: Reg } => "Stores $1 == $2 into $3",
+	Neq { a: RegOrCst, b: RegOrCst, dst: Reg } => "Stores $1 != $2 into $3",
+	Lth { a: RegOrCst, b: RegOrCst, dst: Reg } => "Stores $1 < $2 into $3",
+	Leq { a: RegOrCst, b: RegOrCst, dst: Reg } => "Stores $1 <= $2 into $3",
+	Gth { a: RegOrCst, b: RegOrCst, dst: Reg } => "Stores $1 > $2 into $3",
+	Geq { a: RegOrCst, b: RegOrCst, dst: Reg } => "Stores $1 >= $2 into $3",
+	Func { chunk: Chunk, dst: Reg } => "Creates a closure from chunk $1, storing it in $2",
+	Call { func: RegOrCst, args: Reg, n: Count, dst: Reg } => "Calls $1 with the $3 arguments starting at $2, storing the result in $4",
+	Ret { src: RegOrCst } => "Returns $1 from the current function",
+	ListNew { dst: Reg } => "Stores a new empty list into $1",
+	ListExtend { list: RegOrCst, vals: Reg, n: Count } => "Appends the $3 values starting at $2 to the list $1",
+	ListGet { list: RegOrCst, idx: RegOrCst, dst: Reg } => "Stores element $2 of list $1 into $3",
+	ListSet { list: RegOrCst, idx: RegOrCst, src: RegOrCst } => "Sets element $2 of list $1 to $3",
+	MakeMethod { ns: External, prop: Prop, this: RegOrCst, dst: Reg } => "Binds method $2 of namespace $1 to $3, storing it in $4",
+	CallMethod { ns: External, prop: Prop, this: RegOrCst, args: Reg, n: Count, dst: Reg } => "Calls method $2 of namespace $1 on $3, with the $5 arguments starting at $4, storing the result in $6",
+	Jmp { rel: RelAdd } => "Jumps to $1",
+	Jit { rel: RelAdd, cond: RegOrCst } => "Jumps to $1 if $2 is true",
+	Jif { rel: RelAdd, cond: RegOrCst } => "Jumps to $1 if $2 is false",
+	Jin { rel: RelAdd, val: RegOrCst } => "Jumps to $1 if $2 is nil",
+	ForPrep { rel: RelAdd, base: Reg } => "Starts an integer loop with counter $2, limit $2+1 and step $2+2: jumps to $1 if the counter is past the limit, else copies it into $2+3",
+	ForLoop { rel: RelAdd, base: Reg } => "Adds step $2+2 to counter $2; if it is not past limit $2+1, copies it into $2+3 and jumps to $1",
+}
+
+impl InstrType {
+	/// Iterates over all instruction types, in opcode order.
+	pub fn all() -> impl Iterator<Item = InstrType> {
+		(0..=u8::MAX).map(InstrType::try_from).take_while(Result::is_ok).map(Result::unwrap)
+	}
+	
+	/// The size of the opcode and operands in bytes, excluding padding.
+	fn size(self, encoding: Encoding) -> usize {
+		1 + self.operands().iter().map(|op| op.size(encoding)).sum::<usize>()
+	}
+}
+
+impl Instr {
+	/// Replaces the relative address of a jump instruction.
+	pub fn set_rel_add(&mut self, new_rel: i16) {
+		match self {
+			Instr::Jmp { rel } | Instr::Jit { rel, .. } | Instr::Jif { rel, .. } | Instr::Jin { rel, .. }
+				| Instr::ForPrep { rel, .. } | Instr::ForLoop { rel, .. } => *rel = new_rel,
+			_ => panic!("{:?} is not a jump instruction", self),
+		}
+	}
+	
+	/// Returns whether the VM should check for interruptions before executing this instruction,
+	/// ie. if it is a function call or a backward jump.
+	pub fn is_safepoint(&self) -> bool {
+		match self {
+			Instr::Call { .. } | Instr::CallMethod { .. } => true,
+			Instr::Jmp { rel } | Instr::Jit { rel, .. } | Instr::Jif { rel, .. } | Instr::Jin { rel, .. }
+				| Instr::ForLoop { rel, .. } => *rel <= 0,
+			_ => false,
+		}
+	}
+}
+
+/// Generates a reference of the instruction set, listing every instruction
+/// with its opcode, operand layout, and semantics.
+pub fn instruction_set_reference() -> String {
+	let mut res = String::new();
+	writeln!(res, "Operands:").unwrap();
+	for op in &[Reg, RegOrCst, RelAdd, Upvalue, Chunk, External, Prop, Count] {
+		let size = op.size(Encoding::Compact);
+		writeln!(res, "  {:<4}{:?} ({} byte{})", op.notation(), op, size, if size > 1 { "s" } else { "" }).unwrap();
+	}
+	writeln!(res, "\nIn the wide encoding, relative addresses take 2 bytes, and each instruction").unwrap();
+	writeln!(res, "is padded to a multiple of 4 bytes.").unwrap();
+	writeln!(res, "\nInstructions:").unwrap();
+	for instr in InstrType::all() {
+		let operands: Vec<&str> = instr.operands().iter().map(|op| op.notation()).collect();
+		let signature = format!("{:?}({})", instr, operands.join(", "));
+		writeln!(res, "  {:<3}{:<32}{}", instr as u8, signature, instr.description()).unwrap();
+	}
+	res
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_descriptions_match_operands() {
+		for instr in InstrType::all() {
+			let n = instr.operands().len();
+			let desc = instr.description();
+			for i in 1..=n {
+				assert!(desc.contains(&format!("${}", i)), "{:?} does not describe operand {}", instr, i);
+			}
+			assert!(!desc.contains(&format!("${}", n + 1)), "{:?} describes nonexistent operand {}", instr, n + 1);
+		}
+	}
+
+	#[test]
+	fn test_opcodes_are_contiguous() {
+		let all: Vec<InstrType> = InstrType::all().collect();
+		assert_eq!(all.first(), Some(&InstrType::Nop));
+		assert_eq!(all.last(), Some(&InstrType::ForLoop));
+		assert_eq!(all.len(), InstrType::ForLoop as usize + 1);
+	}
+
+	#[test]
+	fn test_encode_decode() {
+		let instrs = [
+			Instr::Nop {},
+			Instr::Add { a: 1, b: 200, dst: 3 },
+			Instr::GetExt { ext: 300, dst: 2 },
+			Instr::CallMethod { ns: 1, prop: 2, this: 3, args: 4, n: 5, dst: 6 },
+			Instr::Jif { rel: -5, cond: 7 },
+		];
+		for &encoding in &[Encoding::Compact, Encoding::Wide] {
+			let mut code = vec![];
+			for instr in &instrs {
+				let before = code.len();
+				instr.encode(&mut code, encoding);
+				let size = instr.instr_type().size(encoding);
+				assert_eq!(code.len() - before, size + encoding.padding(size));
+				assert_eq!(instr.operand_values().len(), instr.instr_type().operands().len());
+				if encoding == Encoding::Wide {
+					assert_eq!(code.len() % 4, 0);
+				}
+			}
+			let mut it = code.iter();
+			for instr in &instrs {
+				assert_eq!(&Instr::decode(&mut it, encoding).unwrap(), instr);
+			}
+			assert!(it.next().is_none());
+			assert!(Instr::decode(&mut [255u8].iter(), encoding).is_err());
+			assert!(Instr::decode(&mut [InstrType::Add as u8, 1].iter(), encoding).is_err());
+		}
+		
+		let mut code = vec![];
+		Instr::Jmp { rel: 1000 }.encode(&mut code, Encoding::Wide);
+		assert_eq!(Instr::decode(&mut code.iter(), Encoding::Wide).unwrap(), Instr::Jmp { rel: 1000 });
+	}
+}
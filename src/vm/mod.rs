@@ -8,46 +8,72 @@
 //! - `rc` represents a one-byte (signed) register or constant index (non-negative → register, negative → constant)
 //! - `r` represents a one-byte (unsigned) register index
 //! - `a` represents a one-byte (signed) relative address within the bytecode, based on the byte containing the address
+//!   (two bytes in the wide encoding, where instructions are also padded to a multiple of 4 bytes)
 //! - `u` represents a one-byte (unsigned) upvalue index
 //! - `c` represents a one-byte (unsigned) chunk index
 //! 
+//! - `e` represents a two-byte (unsigned) external value index
+//! - `p` represents a one-byte (unsigned) property index in a namespace
+//! - `n` represents a one-byte (unsigned) count
+//! 
 //! ## Instructions
-//! - `Nop`: No effect
-//! - `Cpy(rc, r)`: Copies `rc` into `r`
-//! - `GetUp(u, r)`, `SetUp(u, rc)`: Gets or sets an upvalue with a register
-//! - `Neg/Not(rc, r)`: Computes `-rc`/`not rc` and storing the result in `r`
-//! - `Or/And/Eq/Neq/Lth/Leq/Gth/Geq/Add/Sub/Mul/Div/Mod/Pow(rc1, rc2, r)`:
-//!    
-//!    Applies the corresponding binary operation to `rc1` and `rc2`, storing the result in `r`
-//! - `Func(c, r)`: Creates a closure from the chunk with index `c`, storing the result in `r`
-//! - `Call(r1, r2, r3)`: Calls the function in `r1`, using arguments starting at `r2`, storing the result in `r3`
-//! - `Ret(rc)`: Returns `rc` from the current function
-//! - `Jmp(a)`: Unconditional jump to `a`
-//! - `Jit/Jif(a, rc)`: Jumps to `a` if `rc` is true/false (panics if not a boolean)
+//! The full list of instructions, with their operands and semantics, is generated from
+//! the metadata attached to each instruction type; see [`instruction_set_reference`],
+//! or run `hissy isa`.
 //! 
 
 /// Garbage collector and tools for manipulating values in the GC heap.
 pub mod gc;
 /// Type-erased Hissy value type and constants.
 pub mod value;
+/// Registry of GC object types, with identifiers which are stable across versions.
+pub mod registry;
 mod op;
-mod object;
+pub(crate) mod object;
+mod instr;
+pub(crate) mod vector;
+/// Message-passing channels for communication between isolates.
+pub mod channel;
+/// Byte buffers shared between scripts and the host.
+pub mod bytes;
+mod parallel;
+/// Dense numeric arrays exposed to scripts (requires the `tensor` feature).
+#[cfg(feature = "tensor")]
+pub mod tensor;
+/// TCP and HTTP client natives (requires the `net` feature).
+#[cfg(feature = "net")]
+pub mod net;
 pub(crate) mod prelude;
+/// Restrictions for running untrusted scripts.
+pub mod sandbox;
+/// Counters about the execution of scripts.
+pub mod stats;
+/// Capabilities of scripts on the host process.
+pub mod host;
+/// Interactive sessions, running Hissy code one input at a time.
+pub mod session;
 
 
 use std::collections::HashMap;
-use num_enum::TryFromPrimitive;
 use std::ops::Deref;
 use std::convert::TryFrom;
-use std::{slice, iter};
+use std::iter;
+use std::mem;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use crate::{HissyError, ErrorType};
-use crate::serial::*;
-use crate::compiler::chunk::{Chunk, Program};
+use crate::compiler::Type;
+use crate::compiler::chunk::{Chunk, LazyChunk, Program};
 
 use gc::{GCHeap, GCRef};
 use value::{Value, NIL};
 use object::*;
+use sandbox::Sandbox;
+use stats::VmStats;
+use host::{HostEnv, HostAction};
 
 
 pub(crate) const MAX_REGISTERS: u8 = 128;
@@ -60,19 +86,8 @@ fn error_str(s: &str) -> HissyError {
 	error(String::from(s))
 }
 
-#[derive(Debug, TryFromPrimitive)]
-#[repr(u8)]
-pub(crate) enum InstrType {
-	Nop,
-	Cpy, GetUp, SetUp, CloseUp, GetExt,
-	Neg, Add, Sub, Mul, Div, Mod, Pow,
-	Not, Or, And,
-	Eq, Neq, Lth, Leq, Gth, Geq,
-	Func, Call, Ret,
-	ListNew, ListExtend, ListGet, ListSet,
-	MakeMethod, CallMethod,
-	Jmp, Jit, Jif, Jin,
-}
+pub(crate) use instr::Instr;
+pub use instr::{OperandType, Encoding, instruction_set_reference};
 
 
 struct ReturnParams {
@@ -80,8 +95,18 @@ struct ReturnParams {
 	reg: u8,
 }
 
+// A call to a native function: its arguments are in a range of registers, after `this` for methods
+struct NativeCall {
+	func: Value,
+	this: Option<Value>,
+	args_start: u8,
+	args_cnt: u8,
+	rout: u8,
+}
+
 struct ExecRecord {
 	closure: GCRef<Closure>,
+	chunk_id: usize,
 	upvalues: HashMap<u8, GCRef<Upvalue>>,
 	return_params: Option<ReturnParams>,
 	reg_win: (usize, usize),
@@ -154,6 +179,14 @@ impl Registers {
 		&self.registers[start_abs .. start_abs + (cnt as usize)]
 	}
 	
+	// Reads the counter, limit and step of an integer for loop
+	pub fn for_loop_state(&self, base: u8) -> Result<(i32, i32, i32), HissyError> {
+		let start = self.window_start + usize::from(base);
+		let state = self.registers.get(start .. start + 4).ok_or_else(|| error_str("Invalid register"))?;
+		let int = |val: &Value| i32::try_from(val).map_err(|_| error_str("Bounds of for loop must be integers"));
+		Ok((int(&state[0])?, int(&state[1])?, int(&state[2])?))
+	}
+	
 	pub fn get_upvalue(&self, upv: GCRef<Upvalue>) -> Value {
 		match upv.get() {
 			UpvalueData::OnStack(idx) => self.registers[idx].clone(),
@@ -170,56 +203,128 @@ impl Registers {
 }
 
 
-fn read_rel_add<'a>(it: &mut slice::Iter<'a, u8>, code: &'a [u8]) -> Result<usize, HissyError> {
-	let pos = isize::try_from(code.len() - it.len()).unwrap();
-	let rel_add = isize::from(read_i8(it)?);
-	usize::try_from(pos + rel_add).map_err(|_| error_str("Jumped back too far"))
+// Relative addresses are based on the address byte, which directly follows the opcode
+fn for_loop_continues(counter: i32, limit: i32, step: i32) -> bool {
+	if step > 0 { counter < limit } else { counter > limit }
+}
+
+fn jump_target(chunk: &Chunk, instr_pos: usize, rel_add: i16) -> Result<usize, HissyError> {
+	let pos = isize::try_from(instr_pos).unwrap() + 1;
+	let target = usize::try_from(pos + isize::from(rel_add)).map_err(|_| error_str("Jumped back too far"))?;
+	if target > chunk.code.len() {
+		return Err(error_str("Jumped forward too far"));
+	}
+	Ok(target)
+}
+
+
+// Finds the line of the instruction at a position in a chunk with debug info
+fn line_at(chunk: &Chunk, pos: usize) -> u16 {
+	let line_numbers = &chunk.debug_info.line_numbers;
+	let line_idx = line_numbers.iter().position(|(pos2, _)| pos < usize::from(*pos2))
+		.unwrap_or(line_numbers.len()) - 1;
+	line_numbers.get(line_idx)
+		.expect("Could not get line number of instruction").1
+}
+
+// Values in stack traces are cut to this number of characters, and only the most recent calls are listed
+const TRACE_VALUE_LEN: usize = 40;
+const TRACE_CALLS: usize = 16;
+
+fn trace_repr(val: &Value) -> String {
+	let repr = val.repr();
+	if repr.chars().count() > TRACE_VALUE_LEN {
+		format!("{}...", repr.chars().take(TRACE_VALUE_LEN).collect::<String>())
+	} else {
+		repr
+	}
+}
+
+
+// The chunks loaded into a VM; hot reloading appends new versions of chunks
+struct LoadedCode {
+	chunks: Vec<LazyChunk>, // Chunks are decoded when closures are created
+	bases: Vec<usize>, // Index of the main chunk of the program each chunk was loaded with
+	forward: Vec<usize>, // Index of the latest version of each chunk
+	debug_info: bool,
+}
+
+impl LoadedCode {
+	fn new(program: Program) -> LoadedCode {
+		let n = program.chunks.len();
+		LoadedCode { chunks: program.chunks, bases: vec![0; n], forward: (0..n).collect(), debug_info: program.debug_info }
+	}
+	
+	// Calls through values of unknown type are not checked by the compiler
+	fn check_arity(&self, func: &Closure, args_cnt: u8) -> Result<(), HissyError> {
+		let nb_params = self.chunks[self.forward[func.chunk_id]].decoded().nb_params;
+		if nb_params != args_cnt {
+			return Err(error(format!("Expected {} arguments, got {}", nb_params, args_cnt)));
+		}
+		Ok(())
+	}
 }
 
-fn iter_from(code: &[u8], pos: usize) -> slice::Iter<u8> {
-	code.get(pos..).expect("Jumped forward too far").iter()
+
+/// An operation started by a native function, which a suspended script is waiting on.
+#[derive(Debug)]
+pub struct PendingOperation {
+	/// The name of the operation (e.g. "sleep")
+	pub name: String,
+	/// The arguments passed to the native function
+	pub args: Vec<Value>,
 }
 
 
-struct VMState<'a> {
+/// A callback invoked before each call to a native function, with its name and arguments
+/// (including the object for methods); returning false denies the call. See [`VM::set_native_hook`].
+pub type NativeHook = dyn FnMut(&str, &[Value]) -> bool;
+
+
+struct VMState {
 	regs: Registers,
 	chunk_id: usize,
-	chunk: &'a Chunk,
-	it: slice::Iter<'a, u8>,
+	pos: usize,
 	calls: Vec<ExecRecord>,
 	external: Vec<Value>,
+	pending: Option<(PendingOperation, u8)>,
+	result: Value, // Value returned by the outermost function
+	interrupt: Arc<AtomicBool>,
+	native_hook: Option<Box<NativeHook>>,
+	native_names: Vec<(Value, String)>, // Natives from the prelude, with their names, when a hook is set
+	sandboxed: bool, // Whether a restrictive sandbox was ever set
 }
 
-impl<'a> VMState<'a> {
-	pub fn new(program: &Program) -> VMState {
-		let mut vm = VMState {
+impl VMState {
+	pub fn new() -> VMState {
+		VMState {
 			regs: Registers::new(),
 			chunk_id: 0,
-			chunk: program.chunks.get(0).expect("Program contains no chunks"),
-			it: [].iter(),
+			pos: 0,
 			calls: vec![],
 			external: vec![],
-		};
-		vm.regs.allocate(vm.chunk.nb_registers);
-		vm
-	}
-	
-	pub fn pos(&self) -> usize {
-		usize::try_from(self.chunk.code.len() - self.it.len()).unwrap()
+			pending: None,
+			result: NIL,
+			interrupt: Arc::new(AtomicBool::new(false)),
+			native_hook: None,
+			native_names: vec![],
+			sandboxed: false,
+		}
 	}
 	
-	pub fn call(&mut self, program: &'a Program, func: GCRef<Closure>, args_start: u8, ret_reg: Option<u8>) {
-		let ret_add = self.pos();
+	pub fn call(&mut self, code: &LoadedCode, func: GCRef<Closure>, args_start: u8, ret_reg: Option<u8>) {
+		let ret_add = self.pos;
 		
-		self.chunk_id = usize::from(func.chunk_id);
-		self.chunk = &program.chunks[self.chunk_id];
-		self.it = self.chunk.code.iter();
+		self.chunk_id = code.forward[func.chunk_id];
+		self.pos = 0;
+		let chunk = code.chunks[self.chunk_id].decoded();
 		
 		self.regs.shift_window(u16::from(args_start));
-		self.regs.registers.resize(self.regs.window_start + usize::from(self.chunk.nb_registers), NIL);
+		self.regs.registers.resize(self.regs.window_start + usize::from(chunk.nb_registers), NIL);
 		
 		self.calls.push(ExecRecord {
 			closure: func,
+			chunk_id: self.chunk_id,
 			upvalues: HashMap::new(),
 			return_params: ret_reg.map(|ret_reg| ReturnParams {
 				add: ret_add,
@@ -229,11 +334,47 @@ impl<'a> VMState<'a> {
 		});
 	}
 	
-	fn call_native(&mut self, heap: &mut GCHeap, func: Value, this: Option<Value>, args_start: u8, args_cnt: u8, rout: u8) -> Result<bool, HissyError> {
+	// Lists the natives in the external values with their names, namespace methods being named like "List.add"
+	fn name_natives(&mut self) {
+		self.native_names.clear();
+		for ((name, ty), val) in prelude::list().into_iter().zip(&self.external) {
+			if let (Type::Namespace(methods), Ok(ns)) = (ty, GCRef::<Namespace>::try_from(val.clone())) {
+				for ((method, _), func) in methods.iter().zip(&ns.0) {
+					self.native_names.push((func.clone(), format!("{}.{}", name, method)));
+				}
+			} else {
+				self.native_names.push((val.clone(), name));
+			}
+		}
+	}
+	
+	fn call_native(&mut self, heap: &mut GCHeap, code: &LoadedCode, call: NativeCall) -> Result<bool, HissyError> {
+		let NativeCall { func, this, args_start, args_cnt, rout } = call;
 		let mut args = self.regs.reg_range(args_start, args_cnt).to_vec();
 		if let Some(this) = this { args.insert(0, this); }
-		if let Ok(func) = GCRef::<NativeFunction>::try_from(func) {
-			let res = func.call(heap, args.to_vec())?;
+		if let Ok(native) = GCRef::<NativeFunction>::try_from(func.clone()) {
+			if let Some(hook) = &mut self.native_hook {
+				let name = self.native_names.iter().find(|(f, _)| *f == func)
+					.map_or("<native>", |(_, name)| name.as_str());
+				if !hook(name, &args) {
+					return Err(error(format!("Call to native '{}' was denied", name)));
+				}
+			}
+			let mut res = native.call(heap, args)?;
+			if let Ok(op) = GCRef::<Pending>::try_from(res.clone()) {
+				if op.name == "par_map" { // Needs access to the code, so is performed by the VM itself
+					if self.sandboxed {
+						return Err(error_str("Native 'par_map' is not allowed in this sandbox"));
+					}
+					// Workers run on other threads, where they cannot call the hook
+					if self.native_hook.is_some() {
+						return Err(error_str("Native 'par_map' cannot run while a native hook is set"));
+					}
+					res = parallel::map(heap, code, &self.interrupt, &op.args[0], &op.args[1])?;
+				} else {
+					self.pending = Some((PendingOperation { name: op.name.clone(), args: op.args.clone() }, rout));
+				}
+			}
 			*self.regs.mut_reg(rout) = res;
 			Ok(true)
 		} else {
@@ -241,300 +382,1659 @@ impl<'a> VMState<'a> {
 		}
 	}
 	
-	pub fn ret(&mut self, program: &'a Program, ret_val: Value) -> Result<bool, HissyError> {
+	pub fn ret(&mut self, ret_val: Value) -> Result<bool, HissyError> {
 		let cur_call = self.calls.pop().unwrap();
 		
+		// Returning from inside a block skips the CloseUp instructions at its end
+		for (reg, upv) in cur_call.upvalues {
+			upv.set_inside(self.regs.registers[cur_call.reg_win.0 + usize::from(reg)].clone());
+		}
+		
 		if let Some(prev_call) = self.calls.last() {
-			assert!(cur_call.upvalues.is_empty(), "Upvalues where not all closed over");
-			
 			self.regs.reset_window(prev_call.reg_win.0, prev_call.reg_win.1);
 			
-			self.chunk_id = prev_call.closure.chunk_id as usize;
-			self.chunk = &program.chunks[self.chunk_id];
+			self.chunk_id = prev_call.chunk_id;
 			let ret = cur_call.return_params.expect("No return address/register set");
-			self.it = iter_from(&self.chunk.code, ret.add);
+			self.pos = ret.add;
 			*self.regs.mut_reg(ret.reg) = ret_val;
 			
 			Ok(false)
 			
 		} else { // Return from main chunk
 			self.chunk_id = 0;
-			self.chunk = &program.chunks[0];
-			self.it = [].iter();
+			self.pos = 0;
+			self.result = ret_val;
 			
 			Ok(true)
 		}
 	}
-}
-
-/// Runs a compiled Hissy program, using an existing GC heap.
-pub fn run_program(heap: &mut GCHeap, program: &Program) -> Result<(), HissyError> {
-	let mut vm = VMState::new(program);
-	
-	vm.external.extend(prelude::create(heap));
-	
-	
 	
-	let main = heap.make_ref(Closure::new(0, vec![]));
-	vm.call(program, main, 0, None);
-	
-	macro_rules! bin_op {
-		($method:ident) => {{
-			let (a, b, c) = (read_u8(&mut vm.it)?, read_u8(&mut vm.it)?, read_u8(&mut vm.it)?);
-			let a = vm.regs.reg_or_cst(vm.chunk, heap, a)?;
-			let b = vm.regs.reg_or_cst(vm.chunk, heap, b)?;
-			*vm.regs.mut_reg(c) = a.$method(&b)
-				.ok_or_else(|| error_str(concat!("Cannot ", stringify!($method), " these values")))?;
-		}};
+	// Describes the calls in progress, the last one being at the given position; requires debug info
+	fn stack_trace(&self, code: &LoadedCode, chunk_id: usize, pos: usize) -> String {
+		let mut trace = String::from("Stack trace (most recent call first):");
+		for (i, call) in self.calls.iter().enumerate().rev().take(TRACE_CALLS) {
+			let (chunk_id, pos) = match self.calls.get(i + 1) {
+				// The caller is at the call instruction, which ends at the return address
+				Some(callee) => (call.chunk_id, callee.return_params.as_ref().map_or(0, |ret| ret.add - 1)),
+				None => (chunk_id, pos),
+			};
+			let chunk = code.chunks[chunk_id].decoded();
+			let mut values: Vec<String> = chunk.debug_info.locals_at(pos)
+				.map(|local| format!("{} = {}", local.name, trace_repr(&self.regs.registers[call.reg_win.0 + usize::from(local.reg)])))
+				.collect();
+			values.extend(chunk.debug_info.upvalue_names.iter().zip(&call.closure.upvalues)
+				.map(|(name, upv)| format!("{} = {}", name, trace_repr(&self.regs.get_upvalue(upv.clone())))));
+			trace.push_str(&format!("\n  in {}, line {}", chunk.debug_info.name, line_at(chunk, pos)));
+			if !values.is_empty() {
+				trace.push_str(&format!(": {}", values.join(", ")));
+			}
+		}
+		if self.calls.len() > TRACE_CALLS {
+			trace.push_str(&format!("\n  ... and {} more calls", self.calls.len() - TRACE_CALLS));
+		}
+		trace
 	}
 	
-	loop {
-		// println!("({}) {}@{}", vm.calls.len(), vm.chunk_id, vm.pos());
-		
-		let instr_pos = vm.pos() as u16;
-		
-		let mut run_instr = || -> Result<bool, HissyError> {
-			if let Some(b) = vm.it.next() {
-				match InstrType::try_from(*b).unwrap() {
-					InstrType::Nop => (),
-					InstrType::Cpy => {
-						let (rin, rout) = (read_u8(&mut vm.it)?, read_u8(&mut vm.it)?);
-						let rin = vm.regs.reg_or_cst(vm.chunk, heap, rin)?;
-						*vm.regs.mut_reg(rout) = rin.clone();
-					},
-					InstrType::Neg => {
-						let (rin, rout) = (read_u8(&mut vm.it)?, read_u8(&mut vm.it)?);
-						let rin = vm.regs.reg_or_cst(vm.chunk, heap, rin)?;
-						*vm.regs.mut_reg(rout) = rin.neg().ok_or_else(|| error_str("Cannot negate value!"))?;
-					},
-					InstrType::Add => bin_op!(add),
-					InstrType::Sub => bin_op!(sub),
-					InstrType::Mul => bin_op!(mul),
-					InstrType::Div => bin_op!(div),
-					InstrType::Pow => bin_op!(pow),
-					InstrType::Mod => bin_op!(modulo),
-					InstrType::Not => {
-						let (rin, rout) = (read_u8(&mut vm.it)?, read_u8(&mut vm.it)?);
-						let rin = vm.regs.reg_or_cst(vm.chunk, heap, rin)?;
-						*vm.regs.mut_reg(rout) = rin.not().ok_or_else(|| error_str("Cannot apply logical NOT to value"))?;
-					},
-					InstrType::Or => bin_op!(or),
-					InstrType::And => bin_op!(and),
-					InstrType::Eq => {
-						let (a, b, c) = (read_u8(&mut vm.it)?, read_u8(&mut vm.it)?, read_u8(&mut vm.it)?);
-						let a = vm.regs.reg_or_cst(vm.chunk, heap, a)?;
-						let b = vm.regs.reg_or_cst(vm.chunk, heap, b)?;
-						*vm.regs.mut_reg(c) = Value::from(a.eq(&b));
-					},
-					InstrType::Neq => {
-						let (a, b, c) = (read_u8(&mut vm.it)?, read_u8(&mut vm.it)?, read_u8(&mut vm.it)?);
-						let a = vm.regs.reg_or_cst(vm.chunk, heap, a)?;
-						let b = vm.regs.reg_or_cst(vm.chunk, heap, b)?;
-						*vm.regs.mut_reg(c) = Value::from(!a.eq(&b));
-					},
-					InstrType::Lth => bin_op!(lth),
-					InstrType::Leq => bin_op!(leq),
-					InstrType::Gth => bin_op!(gth),
-					InstrType::Geq => bin_op!(geq),
-					InstrType::Func => {
-						let chunk_id = read_u8(&mut vm.it)?;
-						let rout = read_u8(&mut vm.it)?;
-						let chunk = program.chunks.get(chunk_id as usize)
-							.ok_or_else(|| error_str("Invalid chunk id"))?;
-						let cur_call = vm.calls.last_mut().unwrap();
-						let upvalues = chunk.upvalues.iter().copied().map(|reg| {
-							if reg < MAX_REGISTERS { // Upvalue points to register 
-								if let Some(upv) = cur_call.upvalues.get(&reg) {
-									upv.clone()
-								} else {
-									let idx = cur_call.reg_win.0 + (reg as usize);
-									let upv = heap.make_ref(Upvalue::new(idx));
-									cur_call.upvalues.insert(reg, upv.clone());
-									upv
-								}
-							} else { // Upvalue points to upvalue
-								cur_call.closure.upvalues[(reg - MAX_REGISTERS) as usize].clone()
+	// Executes the instruction at the current position; returns true if the program has ended
+	fn execute(&mut self, heap: &mut GCHeap, code: &LoadedCode) -> Result<bool, HissyError> {
+		let vm = self;
+		let chunk = code.chunks[vm.chunk_id].decoded();
+		let instr_pos = vm.pos;
+		
+		macro_rules! bin_op {
+			($method:ident, $a:expr, $b:expr, $c:expr) => {{
+				let (a, b, c) = ($a, $b, $c);
+				let a = vm.regs.reg_or_cst(chunk, heap, a)?;
+				let b = vm.regs.reg_or_cst(chunk, heap, b)?;
+				*vm.regs.mut_reg(c) = a.$method(&b)
+					.ok_or_else(|| error_str(concat!("Cannot ", stringify!($method), " these values")))?;
+			}};
+		}
+		
+		macro_rules! checked_bin_op {
+			($method:ident, $a:expr, $b:expr, $c:expr) => {{
+				let (a, b, c) = ($a, $b, $c);
+				let a = vm.regs.reg_or_cst(chunk, heap, a)?;
+				let b = vm.regs.reg_or_cst(chunk, heap, b)?;
+				*vm.regs.mut_reg(c) = a.$method(&b)?
+					.ok_or_else(|| error_str(concat!("Cannot ", stringify!($method), " these values")))?;
+			}};
+		}
+		
+		// Falls back to vector arithmetic if the operands are not numeric
+		macro_rules! arith_op {
+			($method:ident, $a:expr, $b:expr, $c:expr) => {{
+				let (a, b, c) = ($a, $b, $c);
+				let a = vm.regs.reg_or_cst(chunk, heap, a)?.clone();
+				let b = vm.regs.reg_or_cst(chunk, heap, b)?.clone();
+				*vm.regs.mut_reg(c) = a.$method(&b).or_else(|| vector::$method(heap, &a, &b))
+					.ok_or_else(|| error_str(concat!("Cannot ", stringify!($method), " these values")))?;
+			}};
+		}
+		
+		if vm.pos < chunk.code.len() {
+			let mut it = chunk.code[vm.pos..].iter();
+			let instr = Instr::decode(&mut it, chunk.encoding)?;
+			// Interrupt before executing anything, so that execution can be resumed afterwards
+			if instr.is_safepoint() && vm.interrupt.swap(false, Ordering::Relaxed) {
+				return Err(HissyError(ErrorType::Interrupt, String::from("Script was interrupted"), 0));
+			}
+			vm.pos = chunk.code.len() - it.len();
+			
+			match instr {
+				Instr::Nop {} => (),
+				Instr::Cpy { src, dst } => {
+					let src = vm.regs.reg_or_cst(chunk, heap, src)?;
+					*vm.regs.mut_reg(dst) = src.clone();
+				},
+				Instr::Neg { a, dst } => {
+					let a = vm.regs.reg_or_cst(chunk, heap, a)?.clone();
+					*vm.regs.mut_reg(dst) = a.neg().or_else(|| vector::neg(heap, &a))
+						.ok_or_else(|| error_str("Cannot negate value!"))?;
+				},
+				Instr::Add { a, b, dst } => arith_op!(add, a, b, dst),
+				Instr::Sub { a, b, dst } => arith_op!(sub, a, b, dst),
+				Instr::Mul { a, b, dst } => arith_op!(mul, a, b, dst),
+				Instr::Div { a, b, dst } => {
+					let a = vm.regs.reg_or_cst(chunk, heap, a)?.clone();
+					let b = vm.regs.reg_or_cst(chunk, heap, b)?.clone();
+					*vm.regs.mut_reg(dst) = a.div(&b)?.or_else(|| vector::div(heap, &a, &b))
+						.ok_or_else(|| error_str("Cannot div these values"))?;
+				},
+				Instr::Pow { a, b, dst } => bin_op!(pow, a, b, dst),
+				Instr::Mod { a, b, dst } => checked_bin_op!(modulo, a, b, dst),
+				Instr::Not { a, dst } => {
+					let a = vm.regs.reg_or_cst(chunk, heap, a)?;
+					*vm.regs.mut_reg(dst) = a.not().ok_or_else(|| error_str("Cannot apply logical NOT to value"))?;
+				},
+				Instr::Or { a, b, dst } => bin_op!(or, a, b, dst),
+				Instr::And { a, b, dst } => bin_op!(and, a, b, dst),
+				Instr::Eq { a, b, dst } => {
+					let a = vm.regs.reg_or_cst(chunk, heap, a)?;
+					let b = vm.regs.reg_or_cst(chunk, heap, b)?;
+					*vm.regs.mut_reg(dst) = Value::from(a.eq(&b));
+				},
+				Instr::Neq { a, b, dst } => {
+					let a = vm.regs.reg_or_cst(chunk, heap, a)?;
+					let b = vm.regs.reg_or_cst(chunk, heap, b)?;
+					*vm.regs.mut_reg(dst) = Value::from(!a.eq(&b));
+				},
+				Instr::Lth { a, b, dst } => bin_op!(lth, a, b, dst),
+				Instr::Leq { a, b, dst } => bin_op!(leq, a, b, dst),
+				Instr::Gth { a, b, dst } => bin_op!(gth, a, b, dst),
+				Instr::Geq { a, b, dst } => bin_op!(geq, a, b, dst),
+				Instr::Func { chunk: chunk_id, dst } => {
+					let chunk_id = code.bases[vm.chunk_id] + usize::from(chunk_id);
+					let parent = chunk;
+					let chunk = code.chunks.get(chunk_id)
+						.ok_or_else(|| error_str("Invalid chunk id"))?.get()?;
+					chunk.verify_upvalues(parent)?;
+					let cur_call = vm.calls.last_mut().unwrap();
+					let upvalues = chunk.upvalues.iter().copied().map(|reg| {
+						if reg < MAX_REGISTERS { // Upvalue points to register 
+							if let Some(upv) = cur_call.upvalues.get(&reg) {
+								upv.clone()
+							} else {
+								let idx = cur_call.reg_win.0 + (reg as usize);
+								let upv = heap.make_ref(Upvalue::new(idx));
+								cur_call.upvalues.insert(reg, upv.clone());
+								upv
 							}
-						}).collect();
-						*vm.regs.mut_reg(rout) = heap.make_value(Closure::new(chunk_id, upvalues));
-					},
-					InstrType::Call => {
-						let func = vm.regs.reg_or_cst(vm.chunk, heap, read_u8(&mut vm.it)?)?.clone();
-						let args_start = read_u8(&mut vm.it)?;
-						let args_cnt = read_u8(&mut vm.it)?;
-						let rout = read_u8(&mut vm.it)?;
-						
-						if let Ok(method) = GCRef::<Method>::try_from(func.clone()) {
-							vm.call_native(heap, method.func.clone(), Some(method.this.clone()), args_start, args_cnt, rout)
-								.map_err(|_| error(format!("{} is not a method", func.repr())))?;
-						} else if let Ok(func) = GCRef::<Closure>::try_from(func.clone()) {
-							vm.call(program, func, args_start, Some(rout));
-						} else {
-							vm.call_native(heap, func.clone(), None, args_start, args_cnt, rout)
-								.map_err(|_| error(format!("Cannot call value {}", func.repr())))?;
+						} else { // Upvalue points to upvalue
+							cur_call.closure.upvalues[(reg - MAX_REGISTERS) as usize].clone()
 						}
-					},
-					InstrType::CallMethod => {
-						let ext_idx = read_u16(&mut vm.it)?;
-						let prop = read_u8(&mut vm.it)?;
-						let val = read_u8(&mut vm.it)?;
-						let args_start = read_u8(&mut vm.it)?;
-						let args_cnt = read_u8(&mut vm.it)?;
-						let rout = read_u8(&mut vm.it)?;
-						
-						let this = vm.regs.reg_or_cst(vm.chunk, heap, val)?.clone();
-						let ns = GCRef::<Namespace>::try_from(vm.external.get(ext_idx as usize)
-							.ok_or_else(|| error_str("Invalid external value"))?.clone())
-							.map_err(|_| error_str("Invalid namespace"))?;
-						let func = ns.get(prop)?.clone();
-						vm.call_native(heap, func.clone(), Some(this), args_start, args_cnt, rout)
-							.map_err(|_| error(format!("Cannot call method {}", func.repr())))?;
-					},
-					InstrType::Ret => {
-						let rin = read_u8(&mut vm.it)?;
-						let temp = vm.regs.reg_or_cst(vm.chunk, heap, rin)?.clone();
-						
-						if vm.ret(program, temp)? {
-							return Ok(true);
+					}).collect();
+					*vm.regs.mut_reg(dst) = heap.make_value(Closure::new(chunk_id, upvalues));
+				},
+				Instr::Call { func, args: args_start, n: args_cnt, dst: rout } => {
+					let func = vm.regs.reg_or_cst(chunk, heap, func)?.clone();
+					
+					if let Ok(method) = GCRef::<Method>::try_from(func.clone()) {
+						if !vm.call_native(heap, code, NativeCall { func: method.func.clone(), this: Some(method.this.clone()), args_start, args_cnt, rout })? {
+							return Err(error(format!("{} is not a method", func.repr())));
 						}
+					} else if let Ok(func) = GCRef::<Closure>::try_from(func.clone()) {
+						code.check_arity(&func, args_cnt)?;
+						vm.call(code, func, args_start, Some(rout));
+					} else if !vm.call_native(heap, code, NativeCall { func: func.clone(), this: None, args_start, args_cnt, rout })? {
+						return Err(error(format!("Cannot call value {}", func.repr())));
 					}
-					InstrType::Jmp => {
-						let final_add = read_rel_add(&mut vm.it, &vm.chunk.code)?;
-						vm.it = iter_from(&vm.chunk.code, final_add);
-					},
-					InstrType::Jit => {
-						let final_add = read_rel_add(&mut vm.it, &vm.chunk.code)?;
-						let cond_val = vm.regs.reg_or_cst(vm.chunk, heap, read_u8(&mut vm.it)?)?;
-						let cond = bool::try_from(cond_val.deref())
-							.map_err(|_| error_str("Non-bool used in condition"))?;
-						if cond {
-							vm.it = iter_from(&vm.chunk.code, final_add);
-						}
-					},
-					InstrType::Jif => {
-						let final_add = read_rel_add(&mut vm.it, &vm.chunk.code)?;
-						let cond_val = vm.regs.reg_or_cst(vm.chunk, heap, read_u8(&mut vm.it)?)?;
-						let cond = bool::try_from(cond_val.deref())
-							.map_err(|_| error_str("Non-bool used in condition"))?;
-						if !cond {
-							vm.it = iter_from(&vm.chunk.code, final_add);
-						}
-					},
-					InstrType::Jin => {
-						let final_add = read_rel_add(&mut vm.it, &vm.chunk.code)?;
-						let val = vm.regs.reg_or_cst(vm.chunk, heap, read_u8(&mut vm.it)?)?;
-						if val.is_nil() {
-							vm.it = iter_from(&vm.chunk.code, final_add);
-						}
-					},
-					InstrType::GetUp => {
-						let upv_idx = read_u8(&mut vm.it)?;
-						let rout = read_u8(&mut vm.it)?;
-						let upv = vm.calls.last().unwrap().closure.upvalues[upv_idx as usize].clone();
-						*vm.regs.mut_reg(rout) = vm.regs.get_upvalue(upv);
-					},
-					InstrType::SetUp => {
-						let upv_idx = read_u8(&mut vm.it)?;
-						let rin = read_u8(&mut vm.it)?;
-						let upv = vm.calls.last().unwrap().closure.upvalues[upv_idx as usize].clone();
-						vm.regs.set_upvalue(upv, vm.regs.reg_or_cst(vm.chunk, heap, rin)?.clone());
-					},
-					InstrType::CloseUp => {
-						let reg = read_u8(&mut vm.it)?;
-						if let Some(upv) = vm.calls.last_mut().unwrap().upvalues.remove(&reg) { // If there is an upvalue at reg
-							let val = vm.regs.reg_or_cst(vm.chunk, heap, reg)?.clone();
-							upv.set_inside(val);
-						}
-					},
-					InstrType::GetExt => {
-						let ext_idx = read_u16(&mut vm.it)?;
-						let rout = read_u8(&mut vm.it)?;
-						*vm.regs.mut_reg(rout) = vm.external.get(ext_idx as usize)
-							.ok_or_else(|| error_str("Invalid external value"))?.clone();
-					},
-					InstrType::ListNew => {
-						let rout = read_u8(&mut vm.it)?;
-						*vm.regs.mut_reg(rout) = heap.make_value(List::new());
-					},
-					InstrType::ListExtend => {
-						let list = read_u8(&mut vm.it)?;
-						let vals_start = read_u8(&mut vm.it)?;
-						let vals_cnt = read_u8(&mut vm.it)?;
-						let list = GCRef::<List>::try_from(vm.regs.reg_or_cst(vm.chunk, heap, list)?.deref().clone())
-							.map_err(|_| error_str("Cannot use ListExtend on non-List value"))?;
-						let vals = vm.regs.reg_range(vals_start, vals_cnt);
-						list.extend(vals);
-					},
-					InstrType::ListGet => {
-						let list = read_u8(&mut vm.it)?;
-						let index = read_u8(&mut vm.it)?;
-						let rout = read_u8(&mut vm.it)?;
-						let list = GCRef::<List>::try_from(vm.regs.reg_or_cst(vm.chunk, heap, list)?.deref().clone())
-							.map_err(|_| error_str("Cannot index non-list value"))?;
-						let index = i32::try_from(vm.regs.reg_or_cst(vm.chunk, heap, index)?.deref())
-							.map_err(|_| error_str("Cannot index list with non-integer"))?;
-						let index = usize::try_from(index)
-							.map_err(|_| error_str("Cannot index list with negative integer"))?;
-						*vm.regs.mut_reg(rout) = list.get(index)?;
-					},
-					InstrType::ListSet => {
-						let list = read_u8(&mut vm.it)?;
-						let index = read_u8(&mut vm.it)?;
-						let rin = read_u8(&mut vm.it)?;
-						let list = GCRef::<List>::try_from(vm.regs.reg_or_cst(vm.chunk, heap, list)?.deref().clone())
-							.map_err(|_| error_str("Cannot index non-list value"))?;
-						let index = i32::try_from(vm.regs.reg_or_cst(vm.chunk, heap, index)?.deref())
-							.map_err(|_| error_str("Cannot index list with non-integer"))?;
-						let index = usize::try_from(index)
-							.map_err(|_| error_str("Cannot index list with negative integer"))?;
-						list.set(index, vm.regs.reg_or_cst(vm.chunk, heap, rin)?.clone())?;
-					},
-					InstrType::MakeMethod => {
-						let ext_idx = read_u16(&mut vm.it)?;
-						let prop = read_u8(&mut vm.it)?;
-						let val = read_u8(&mut vm.it)?;
-						let rout = read_u8(&mut vm.it)?;
-						
-						let this = vm.regs.reg_or_cst(vm.chunk, heap, val)?.clone();
-						let ns = GCRef::<Namespace>::try_from(vm.external.get(ext_idx as usize)
-							.ok_or_else(|| error_str("Invalid external value"))?.clone())
-							.map_err(|_| error_str("Invalid namespace"))?;
-						let func = ns.get(prop)?;
-						*vm.regs.mut_reg(rout) = heap.make_value(Method { this, func });
+				},
+				Instr::CallMethod { ns: ext_idx, prop, this, args: args_start, n: args_cnt, dst: rout } => {
+					let this = vm.regs.reg_or_cst(chunk, heap, this)?.clone();
+					let ns = GCRef::<Namespace>::try_from(vm.external.get(ext_idx as usize)
+						.ok_or_else(|| error_str("Invalid external value"))?.clone())
+						.map_err(|_| error_str("Invalid namespace"))?;
+					let func = ns.get(prop)?.clone();
+					if !vm.call_native(heap, code, NativeCall { func: func.clone(), this: Some(this), args_start, args_cnt, rout })? {
+						return Err(error(format!("Cannot call method {}", func.repr())));
+					}
+				},
+				Instr::Ret { src } => {
+					let temp = vm.regs.reg_or_cst(chunk, heap, src)?.clone();
+					
+					if vm.ret(temp)? {
+						return Ok(true);
 					}
-					#[allow(unreachable_patterns)]
-					i => unimplemented!("Unimplemented instruction: {:?}", i)
 				}
-			} else { // implicit return
-				if vm.ret(program, NIL)? {
-					return Ok(true);
+				Instr::Jmp { rel } => {
+					let final_add = jump_target(chunk, instr_pos, rel)?;
+					vm.pos = final_add;
+				},
+				Instr::Jit { rel, cond } => {
+					let final_add = jump_target(chunk, instr_pos, rel)?;
+					let cond_val = vm.regs.reg_or_cst(chunk, heap, cond)?;
+					let cond = bool::try_from(cond_val.deref())
+						.map_err(|_| error_str("Non-bool used in condition"))?;
+					if cond {
+						vm.pos = final_add;
+					}
+				},
+				Instr::Jif { rel, cond } => {
+					let final_add = jump_target(chunk, instr_pos, rel)?;
+					let cond_val = vm.regs.reg_or_cst(chunk, heap, cond)?;
+					let cond = bool::try_from(cond_val.deref())
+						.map_err(|_| error_str("Non-bool used in condition"))?;
+					if !cond {
+						vm.pos = final_add;
+					}
+				},
+				Instr::Jin { rel, val } => {
+					let final_add = jump_target(chunk, instr_pos, rel)?;
+					let val = vm.regs.reg_or_cst(chunk, heap, val)?;
+					if val.is_nil() {
+						vm.pos = final_add;
+					}
+				},
+				Instr::ForPrep { rel, base } => {
+					let final_add = jump_target(chunk, instr_pos, rel)?;
+					let (counter, limit, step) = vm.regs.for_loop_state(base)?;
+					if step == 0 {
+						return Err(error_str("Step of for loop cannot be zero"));
+					}
+					if for_loop_continues(counter, limit, step) {
+						*vm.regs.mut_reg(base + 3) = Value::from(counter);
+					} else {
+						vm.pos = final_add;
+					}
+				},
+				Instr::ForLoop { rel, base } => {
+					let final_add = jump_target(chunk, instr_pos, rel)?;
+					let (counter, limit, step) = vm.regs.for_loop_state(base)?;
+					// The loop also ends if the counter would overflow
+					if let Some(counter) = counter.checked_add(step).filter(|&c| for_loop_continues(c, limit, step)) {
+						*vm.regs.mut_reg(base) = Value::from(counter);
+						*vm.regs.mut_reg(base + 3) = Value::from(counter);
+						vm.pos = final_add;
+					}
+				},
+				Instr::GetUp { upv, dst } => {
+					let upv = vm.calls.last().unwrap().closure.upvalues[upv as usize].clone();
+					*vm.regs.mut_reg(dst) = vm.regs.get_upvalue(upv);
+				},
+				Instr::SetUp { upv, src } => {
+					let upv = vm.calls.last().unwrap().closure.upvalues[upv as usize].clone();
+					vm.regs.set_upvalue(upv, vm.regs.reg_or_cst(chunk, heap, src)?.clone());
+				},
+				Instr::CloseUp { reg } => {
+					if let Some(upv) = vm.calls.last_mut().unwrap().upvalues.remove(&reg) { // If there is an upvalue at reg
+						let val = vm.regs.reg_or_cst(chunk, heap, reg)?.clone();
+						upv.set_inside(val);
+					}
+				},
+				Instr::GetExt { ext, dst } => {
+					*vm.regs.mut_reg(dst) = vm.external.get(ext as usize)
+						.ok_or_else(|| error_str("Invalid external value"))?.clone();
+				},
+				Instr::ListNew { dst } => {
+					*vm.regs.mut_reg(dst) = heap.make_value(List::new());
+				},
+				Instr::ListExtend { list, vals, n } => {
+					let list = GCRef::<List>::try_from(vm.regs.reg_or_cst(chunk, heap, list)?.deref().clone())
+						.map_err(|_| error_str("Cannot use ListExtend on non-List value"))?;
+					list.check_mutable()?;
+					let vals = vm.regs.reg_range(vals, n);
+					heap.grow(&*list, |list| list.extend(vals));
+				},
+				Instr::ListGet { list, idx, dst } => {
+					let list = GCRef::<List>::try_from(vm.regs.reg_or_cst(chunk, heap, list)?.deref().clone())
+						.map_err(|_| error_str("Cannot index non-list value"))?;
+					let index = i32::try_from(vm.regs.reg_or_cst(chunk, heap, idx)?.deref())
+						.map_err(|_| error_str("Cannot index list with non-integer"))?;
+					let index = usize::try_from(index)
+						.map_err(|_| error_str("Cannot index list with negative integer"))?;
+					*vm.regs.mut_reg(dst) = list.get(index)?;
+				},
+				Instr::ListSet { list, idx, src } => {
+					let list = GCRef::<List>::try_from(vm.regs.reg_or_cst(chunk, heap, list)?.deref().clone())
+						.map_err(|_| error_str("Cannot index non-list value"))?;
+					let index = i32::try_from(vm.regs.reg_or_cst(chunk, heap, idx)?.deref())
+						.map_err(|_| error_str("Cannot index list with non-integer"))?;
+					let index = usize::try_from(index)
+						.map_err(|_| error_str("Cannot index list with negative integer"))?;
+					list.set(index, vm.regs.reg_or_cst(chunk, heap, src)?.clone())?;
+				},
+				Instr::MakeMethod { ns: ext_idx, prop, this, dst } => {
+					let this = vm.regs.reg_or_cst(chunk, heap, this)?.clone();
+					let ns = GCRef::<Namespace>::try_from(vm.external.get(ext_idx as usize)
+						.ok_or_else(|| error_str("Invalid external value"))?.clone())
+						.map_err(|_| error_str("Invalid namespace"))?;
+					let func = ns.get(prop)?;
+					*vm.regs.mut_reg(dst) = heap.make_value(Method { this, func });
 				}
 			}
-			Ok(false)
-		};
+		} else { // implicit return
+			if vm.ret(NIL)? {
+				return Ok(true);
+			}
+		}
+		Ok(false)
+	}
+}
+
+
+/// The reason why [`VM::run_for`] returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunStatus {
+	/// The program has finished executing.
+	Done,
+	/// The instruction budget was used up; execution can be continued by running the VM again.
+	OutOfFuel,
+	/// The script is waiting on a pending operation.
+	Suspended,
+}
+
+
+/// A handle which can be used to interrupt a [`VM`], possibly from another thread.
+#[derive(Clone)]
+pub struct InterruptHandle(Arc<AtomicBool>);
+
+impl InterruptHandle {
+	/// Makes the script raise an interruption error at the next function call or loop iteration.
+	pub fn interrupt(&self) {
+		self.0.store(true, Ordering::Relaxed);
+	}
+}
+
+
+/// A Hissy virtual machine executing a program, which can be run step by step,
+/// and whose code can be reloaded while it is running.
+pub struct VM {
+	code: LoadedCode,
+	state: VMState,
+	sandbox: Sandbox,
+	fuel_used: usize,
+	trace: Option<String>,
+	stats: Option<Box<VmStats>>,
+}
+
+impl VM {
+	/// Prepares the execution of a program, using an existing GC heap.
+	pub fn new(heap: &mut GCHeap, program: Program) -> VM {
+		VM::with_args(heap, program, vec![])
+	}
+	
+	/// Prepares the execution of a program whose main chunk takes arguments,
+	/// such as the program of a [`CompiledFunction`](crate::compiler::CompiledFunction).
+	pub fn with_args(heap: &mut GCHeap, program: Program, args: Vec<Value>) -> VM {
+		assert!(!program.chunks.is_empty(), "Program contains no chunks");
+		let mut vm = VM { code: LoadedCode::new(program), state: VMState::new(), sandbox: Sandbox::default(), fuel_used: 0, trace: None, stats: None };
+		vm.state.external.extend(prelude::create(heap));
+		vm.state.regs.allocate(vm.code.chunks[0].decoded().nb_registers);
+		assert!(args.len() <= vm.state.regs.registers.len(), "Too many arguments for main chunk");
+		for (reg, arg) in args.into_iter().enumerate() {
+			vm.state.regs.registers[reg] = arg;
+		}
+		let main = heap.make_ref(Closure::new(0, vec![]));
+		vm.state.call(&vm.code, main, 0, None);
+		vm
+	}
+	
+	/// Makes the script raise an interruption error at the next function call or loop iteration.
+	///
+	/// The error is returned by [`VM::step`] or [`VM::run`]; the script can then be resumed as if
+	/// it had not been interrupted.
+	pub fn interrupt(&self) {
+		self.state.interrupt.store(true, Ordering::Relaxed);
+	}
+	
+	/// Returns a handle to interrupt the script from another thread.
+	pub fn interrupt_handle(&self) -> InterruptHandle {
+		InterruptHandle(self.state.interrupt.clone())
+	}
+	
+	/// Restricts what the script is allowed to do from now on, eg. with [`Sandbox::strict`]
+	/// before running untrusted code.
+	///
+	/// The fuel limit counts instructions executed since the sandbox was set.
+	/// Natives denied by a previous sandbox stay denied, and once any restriction was set,
+	/// `par_map` is denied too, since its worker threads would escape the sandbox.
+	pub fn set_sandbox(&mut self, heap: &mut GCHeap, sandbox: Sandbox) {
+		sandbox.apply(heap, &mut self.state.external);
+		if self.state.native_hook.is_some() {
+			self.state.name_natives();
+		}
+		self.state.sandboxed |= sandbox.is_restrictive();
+		self.sandbox = sandbox;
+		self.fuel_used = 0;
+	}
+	
+	/// Sets a callback invoked before every call to a native function, eg. to log calls
+	/// or enforce a policy; calls it denies make the script fail with an execution error.
+	///
+	/// Natives are named as in scripts, with methods prefixed by their namespace (eg. `List.add`).
+	/// Calls to `par_map` fail while a hook is set, since its worker threads could not call it.
+	pub fn set_native_hook(&mut self, hook: impl FnMut(&str, &[Value]) -> bool + 'static) {
+		self.state.native_hook = Some(Box::new(hook));
+		self.state.name_natives();
+	}
+	
+	/// Removes the callback set with [`VM::set_native_hook`].
+	pub fn clear_native_hook(&mut self) {
+		self.state.native_hook = None;
+		self.state.native_names.clear();
+	}
+	
+	/// Returns whether the program has finished executing.
+	pub fn is_finished(&self) -> bool {
+		self.state.calls.is_empty()
+	}
+	
+	/// Returns the value returned by the main chunk once the program has finished,
+	/// which is always nil for programs compiled with [`Compiler::compile_program`](crate::compiler::Compiler::compile_program).
+	pub fn result(&self) -> &Value {
+		&self.state.result
+	}
+	
+	/// Returns the operation the script is waiting on, if it is suspended.
+	pub fn pending(&self) -> Option<&PendingOperation> {
+		self.state.pending.as_ref().map(|(op, _)| op)
+	}
+	
+	/// Returns whether the script is suspended, waiting on a pending operation.
+	pub fn is_suspended(&self) -> bool {
+		self.state.pending.is_some()
+	}
+	
+	/// Resumes a suspended script, providing the result of the pending operation.
+	pub fn resume(&mut self, result: Value) -> Result<(), HissyError> {
+		let (_, reg) = self.state.pending.take().ok_or_else(|| error_str("Script is not suspended"))?;
+		*self.state.regs.mut_reg(reg) = result;
+		Ok(())
+	}
+	
+	/// Executes a single instruction. Returns true once the program has finished.
+	pub fn step(&mut self, heap: &mut GCHeap) -> Result<bool, HissyError> {
+		if self.is_finished() {
+			return Ok(true);
+		}
+		if self.is_suspended() {
+			return Err(error_str("Script is waiting on a pending operation"));
+		}
 		
-		let mut stop = run_instr();
+		let chunk_id = self.state.chunk_id;
+		let instr_pos = self.state.pos;
+		let depth = self.state.calls.len();
+		let mut is_call = false;
+		if let Some(stats) = &mut self.stats {
+			let chunk = self.code.chunks[chunk_id].decoded();
+			if let Some(Ok(instr)) = chunk.code.get(instr_pos..).map(|code| Instr::decode(&mut code.iter(), chunk.encoding)) {
+				stats.count_instruction(instr.instr_type());
+				is_call = matches!(instr, Instr::Call { .. } | Instr::CallMethod { .. });
+			}
+		}
+		let track_sites = self.code.debug_info && heap.tracks_sites();
+		if track_sites {
+			let line = line_at(self.code.chunks[chunk_id].decoded(), instr_pos);
+			heap.set_alloc_site(Some(gc::AllocSite { chunk: chunk_id as u32, line }));
+		}
+		let mut stop = self.state.execute(heap, &self.code);
+		if track_sites {
+			heap.set_alloc_site(None);
+		}
+		if let (Some(stats), Ok(false)) = (&mut self.stats, &stop) {
+			if self.state.calls.len() > depth {
+				stats.count_call(self.state.chunk_id);
+			} else if is_call {
+				stats.native_calls += 1;
+			}
+		}
+		self.fuel_used += 1;
+		if let Ok(false) = stop {
+			stop = self.sandbox.check(heap, self.fuel_used, self.state.calls.len()).map(|_| false);
+		}
 		
-		if program.debug_info {
-			if let Err(HissyError(ErrorType::Execution, err, 0)) = stop {
-				let line_numbers = &vm.chunk.debug_info.line_numbers;
-				let line_idx = line_numbers.iter().position(|(pos2, _)| instr_pos < *pos2)
-					.unwrap_or_else(|| line_numbers.len()) - 1;
-				let line = line_numbers.get(line_idx)
-					.expect("Could not get line number of instruction").1;
-				stop = Err(HissyError(ErrorType::Execution, err, line));
+		if self.code.debug_info {
+			if let Err(HissyError(ty @ (ErrorType::Execution | ErrorType::Interrupt), err, 0)) = stop {
+				let line = line_at(self.code.chunks[chunk_id].decoded(), instr_pos);
+				stop = Err(HissyError(ty, err, line));
+			}
+			if let Err(HissyError(ErrorType::Execution, _, _)) = stop {
+				self.trace = Some(self.state.stack_trace(&self.code, chunk_id, instr_pos));
 			}
 		}
 		
 		if stop? {
-			break;
+			self.state.regs.free_all();
+			Ok(true)
+		} else {
+			let start = (self.stats.is_some() && heap.needs_collection()).then(Instant::now);
+			heap.step();
+			if let (Some(stats), Some(start)) = (&mut self.stats, start) {
+				stats.count_gc_pause(start.elapsed());
+			}
+			Ok(false)
+		}
+	}
+	
+	/// Starts collecting execution statistics, which slows down execution.
+	/// The calls in progress are counted as if they just started.
+	pub fn enable_stats(&mut self) {
+		let mut stats = VmStats::default();
+		for call in &self.state.calls {
+			stats.count_call(call.chunk_id);
+		}
+		self.stats = Some(Box::new(stats));
+	}
+	
+	/// Returns the statistics collected since [`VM::enable_stats`] was called, if it was.
+	pub fn stats(&self) -> Option<&VmStats> {
+		self.stats.as_deref()
+	}
+	
+	/// Returns the stack trace of the last execution error, listing the calls in progress, most recent first,
+	/// with the values of their named locals and upvalues. Requires debug info.
+	pub fn stack_trace(&self) -> Option<&str> {
+		self.trace.as_deref()
+	}
+	
+	/// Runs the program until it finishes, or is suspended by a native function.
+	pub fn run(&mut self, heap: &mut GCHeap) -> Result<(), HissyError> {
+		while !self.is_suspended() && !self.step(heap)? {}
+		Ok(())
+	}
+	
+	/// Runs the program for at most `budget` instructions, eg. to advance a script
+	/// by a bounded amount every frame of a game.
+	pub fn run_for(&mut self, heap: &mut GCHeap, budget: usize) -> Result<RunStatus, HissyError> {
+		let mut fuel = budget;
+		loop {
+			if self.is_finished() {
+				return Ok(RunStatus::Done);
+			} else if self.is_suspended() {
+				return Ok(RunStatus::Suspended);
+			} else if fuel == 0 {
+				return Ok(RunStatus::OutOfFuel);
+			}
+			self.step(heap)?;
+			fuel -= 1;
 		}
+	}
+	
+	/// Hot-reloads a new version of the running program.
+	///
+	/// Functions are matched with their new version by name, and later calls to existing
+	/// closures execute the new function bodies, while calls in progress finish with the old code.
+	/// The main chunk is not re-run: existing bindings and objects are preserved,
+	/// and new top-level code is ignored.
+	///
+	/// Both versions need debug info, and a reloaded function must capture the same
+	/// variables as before. Functions whose name is not unique are not reloaded.
+	pub fn reload(&mut self, program: Program) -> Result<(), HissyError> {
+		if !self.code.debug_info || !program.debug_info {
+			return Err(error_str("Hot reloading requires debug info"));
+		}
+		
+		// Maps function names to chunks, or None if the name is ambiguous
+		fn unique_names<'a>(chunks: impl Iterator<Item = (usize, &'a LazyChunk)>) -> Result<HashMap<&'a str, Option<usize>>, HissyError> {
+			let mut names = HashMap::new();
+			for (i, chunk) in chunks {
+				names.entry(chunk.get()?.debug_info.name.as_str())
+					.and_modify(|e| *e = None)
+					.or_insert(Some(i));
+			}
+			Ok(names)
+		}
+		let code = &self.code;
+		let old_names = unique_names(code.chunks.iter().enumerate()
+			.filter(|(i, _)| code.forward[*i] == *i && code.bases[*i] != *i))?;
+		let new_names = unique_names(program.chunks.iter().enumerate().skip(1))?;
 		
-		heap.step();
+		let base = self.code.chunks.len();
+		let mut matches = vec![];
+		for (name, new_id) in &new_names {
+			if let (Some(new_id), Some(Some(old_id))) = (new_id, old_names.get(name)) {
+				let (old, new) = (self.code.chunks[*old_id].decoded(), program.chunks[*new_id].decoded());
+				if old.debug_info.upvalue_names != new.debug_info.upvalue_names {
+					return Err(error(format!("Cannot reload function {}: captured variables changed", name)));
+				}
+				matches.push((*old_id, base + new_id));
+			}
+		}
+		
+		for (i, chunk) in program.chunks.into_iter().enumerate() {
+			self.code.chunks.push(chunk);
+			self.code.bases.push(base);
+			self.code.forward.push(base + i);
+		}
+		for (old_id, new_id) in matches {
+			for fwd in self.code.forward.iter_mut().filter(|fwd| **fwd == old_id) {
+				*fwd = new_id;
+			}
+		}
+		Ok(())
 	}
 	
-	vm.regs.free_all();
+	// Loads another program after the current one, and prepares the execution of its main chunk, discarding the
+	// state of the current one; closures created by previous programs can still be called (see session)
+	pub(super) fn start_next(&mut self, heap: &mut GCHeap, program: Program, args: Vec<Value>) {
+		assert!(!program.chunks.is_empty(), "Program contains no chunks");
+		let base = self.code.chunks.len();
+		let n = program.chunks.len();
+		self.code.chunks.extend(program.chunks);
+		self.code.bases.resize(base + n, base);
+		self.code.forward.extend(base..base + n);
+		self.code.debug_info &= program.debug_info;
+		
+		let external = mem::take(&mut self.state.external);
+		self.state = VMState::new();
+		self.state.external = external;
+		self.trace = None;
+		self.state.regs.allocate(self.code.chunks[base].decoded().nb_registers);
+		assert!(args.len() <= self.state.regs.registers.len(), "Too many arguments for main chunk");
+		for (reg, arg) in args.into_iter().enumerate() {
+			self.state.regs.registers[reg] = arg;
+		}
+		let main = heap.make_ref(Closure::new(base, vec![]));
+		self.state.call(&self.code, main, 0, None);
+	}
+}
+
+/// Runs a compiled Hissy program, using an existing GC heap.
+///
+/// If the program has debug info, the message of execution errors ends with a stack trace,
+/// with the values of the named locals and upvalues of each call.
+pub fn run_program(heap: &mut GCHeap, program: &Program) -> Result<(), HissyError> {
+	run_program_with(heap, program, &HostEnv::default()).map(|_| ())
+}
+
+/// Runs a compiled Hissy program, using an existing GC heap, with the given capabilities on the host process.
+///
+/// Returns the exit code passed to `exit`, if the script called it. Errors include a stack trace, as with [`run_program`].
+pub fn run_program_with(heap: &mut GCHeap, program: &Program, host: &HostEnv) -> Result<Option<i32>, HissyError> {
+	let mut vm = VM::new(heap, program.clone());
+	let exit_code = run_until_exit(heap, &mut vm, host);
+	let exit_code = with_trace(&vm, exit_code)?;
+	drop(vm);
+	heap.collect();
+	Ok(exit_code)
+}
+
+/// Runs a compiled Hissy program like [`run_program_with`], collecting execution statistics into `stats`,
+/// which are also filled if the program fails.
+pub fn run_program_with_stats(heap: &mut GCHeap, program: &Program, host: &HostEnv, stats: &mut VmStats) -> Result<Option<i32>, HissyError> {
+	let mut vm = VM::new(heap, program.clone());
+	vm.enable_stats();
+	let exit_code = run_until_exit(heap, &mut vm, host);
+	let exit_code = with_trace(&vm, exit_code);
+	*stats = vm.stats().unwrap().clone();
+	drop(vm);
+	heap.collect();
+	exit_code
+}
+
+/// Runs the test of the given index in a program compiled with
+/// [`Compiler::compile_tests`](crate::compiler::Compiler::compile_tests), using an existing GC heap.
+///
+/// The top-level code of the program is run first, so that each test starts from a fresh state.
+pub fn run_test(heap: &mut GCHeap, program: &Program, index: usize) -> Result<(), HissyError> {
+	if index >= program.tests().len() {
+		return Err(error(format!("No test of index {}", index)));
+	}
+	let vm = VM::with_args(heap, program.clone(), vec![Value::from(index as i32)]);
+	run_to_end(heap, vm, &HostEnv::default()).map(|_| ())
+}
+
+/// Runs the benchmark of the given index in a program compiled with
+/// [`Compiler::compile_benches`](crate::compiler::Compiler::compile_benches) for a number of iterations,
+/// using an existing GC heap.
+///
+/// The top-level code of the program is run first, so running with 0 iterations gives the time
+/// to subtract from the total.
+pub fn run_bench(heap: &mut GCHeap, program: &Program, index: usize, iterations: u32) -> Result<(), HissyError> {
+	if index >= program.benches().len() {
+		return Err(error(format!("No benchmark of index {}", index)));
+	}
+	let iterations = i32::try_from(iterations).map_err(|_| error_str("Too many benchmark iterations"))?;
+	let vm = VM::with_args(heap, program.clone(), vec![Value::from(index as i32), Value::from(iterations)]);
+	run_to_end(heap, vm, &HostEnv::default()).map(|_| ())
+}
+
+// Runs a VM until the end of its program, and collects its garbage
+fn run_to_end(heap: &mut GCHeap, mut vm: VM, host: &HostEnv) -> Result<Option<i32>, HissyError> {
+	let exit_code = run_until_exit(heap, &mut vm, host)?;
+	drop(vm);
 	heap.collect();
+	Ok(exit_code)
+}
+
+// Adds the stack trace of the last execution error of a VM to the message of an error it returned
+fn with_trace<T>(vm: &VM, res: Result<T, HissyError>) -> Result<T, HissyError> {
+	res.map_err(|HissyError(ty, err, line)| match vm.stack_trace() {
+		Some(trace) => HissyError(ty, format!("{}\n{}", err, trace), line),
+		None => HissyError(ty, err, line),
+	})
+}
+
+// Runs a VM until the end of its program or a call to exit(), handling the operations it waits on
+fn run_until_exit(heap: &mut GCHeap, vm: &mut VM, host: &HostEnv) -> Result<Option<i32>, HissyError> {
+	loop {
+		vm.run(heap)?;
+		match vm.pending() {
+			Some(op) if op.name == "sleep" => {
+				let dur = Duration::try_from_secs_f64(op.args[0].cast_real())
+					.map_err(|_| error(format!("Invalid sleep duration {}", op.args[0].repr())))?;
+				thread::sleep(dur);
+				vm.resume(NIL)?;
+			},
+			Some(op) if op.name == "recv" => {
+				let chan = GCRef::<channel::Channel>::try_from(op.args[0].clone())
+					.map_err(|_| error_str("Invalid channel"))?;
+				let msg = chan.recv();
+				drop(chan);
+				vm.resume(msg.to_value(heap))?;
+			},
+			Some(op) => match host.perform(heap, op)? {
+				Some(HostAction::Resume(res)) => vm.resume(res)?,
+				Some(HostAction::Exit(code)) => return Ok(Some(code)),
+				None => return Err(error(format!("Unsupported pending operation: {}", op.name))),
+			},
+			None => return Ok(None),
+		}
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::compiler::Compiler;
+
+	const SCRIPT: &str = "let res = []\nlet k = 10\nlet f = fun() -> Int:\n\treturn k + 1\nwhile res.size() < 3:\n\tres.add(f())\n";
+
+	fn results(vm: &VM) -> GCRef<List> {
+		GCRef::<List>::try_from(vm.state.regs.registers[0].clone()).unwrap()
+	}
+
+	#[test]
+	fn test_hot_reload() {
+		let mut heap = GCHeap::new();
+		let program = Compiler::new(true).compile_program(SCRIPT).unwrap();
+		let mut vm = VM::new(&mut heap, program);
+		let res = loop {
+			assert!(!vm.step(&mut heap).unwrap());
+			if vm.state.calls.len() == 1 && results(&vm).len() == 1 {
+				break results(&vm);
+			}
+		};
+
+		let new_version = SCRIPT.replace("k + 1", "k * 2");
+		vm.reload(Compiler::new(true).compile_program(&new_version).unwrap()).unwrap();
+		vm.run(&mut heap).unwrap();
+		let res: Vec<i32> = (0..3).map(|i| i32::try_from(&res.get(i).unwrap()).unwrap()).collect();
+		assert_eq!(res, vec![11, 20, 20]);
+
+		let mut vm = VM::new(&mut heap, Compiler::new(true).compile_program(SCRIPT).unwrap());
+		let captures_changed = SCRIPT.replace("k + 1", "1");
+		assert!(vm.reload(Compiler::new(true).compile_program(&captures_changed).unwrap()).is_err());
+		assert!(vm.reload(Compiler::new(false).compile_program(SCRIPT).unwrap()).is_err());
+	}
+
+	#[test]
+	fn test_pending_operation() {
+		let mut heap = GCHeap::new();
+		let program = Compiler::new(true).compile_program("let x = sleep(2)
+let y = x
+").unwrap();
+		let mut vm = VM::new(&mut heap, program);
+		vm.run(&mut heap).unwrap();
+		assert!(vm.is_suspended() && !vm.is_finished());
+		let op = vm.pending().unwrap();
+		assert_eq!(op.name, "sleep");
+		assert_eq!(op.args[0].cast_real(), 2.0);
+		assert!(vm.step(&mut heap).is_err());
+
+		vm.resume(Value::from(42)).unwrap();
+		assert!(vm.resume(NIL).is_err());
+		while vm.state.pos != vm.code.chunks[0].decoded().code.len() {
+			vm.step(&mut heap).unwrap();
+		}
+		assert_eq!(i32::try_from(&vm.state.regs.registers[1]).unwrap(), 42);
+		vm.run(&mut heap).unwrap();
+		assert!(vm.is_finished());
+	}
+
+	#[test]
+	fn test_sleep_duration() {
+		let run = |src: &str| run_program(&mut GCHeap::new(), &Compiler::new(false).compile_program(src).unwrap());
+		run("sleep(0)\nsleep(0.001)\n").unwrap();
+		// Durations which cannot be waited for are refused, instead of making the host panic
+		for (src, dur) in [("sleep(1.0 / 0.0)", "inf"), ("sleep(0.0 / 0.0)", "NaN"), ("sleep(10.0 ^ 300)", "1e300"), ("sleep(-1)", "-1")] {
+			let err = run(src).err().unwrap();
+			assert_eq!(err.1, format!("Invalid sleep duration {}", dur), "{}", src);
+		}
+	}
+
+	#[test]
+	fn test_parallel_map() {
+		let mut heap = GCHeap::new();
+		let inputs: Vec<i32> = (0..100).collect();
+		let script = format!("let sq = fun(x: Int) -> Int:\n\treturn x * x\nlet res = par_map({:?}, sq)\n", inputs);
+		let mut vm = VM::new(&mut heap, Compiler::new(true).compile_program(&script).unwrap());
+		while vm.state.pos != vm.code.chunks[0].decoded().code.len() {
+			vm.step(&mut heap).unwrap();
+		}
+		let res = GCRef::<List>::try_from(vm.state.regs.registers[1].clone()).unwrap();
+		let res: Vec<i32> = (0..100).map(|i| i32::try_from(&res.get(i).unwrap()).unwrap()).collect();
+		assert_eq!(res, inputs.iter().map(|i| i * i).collect::<Vec<i32>>());
+		
+		let captures = "let k = 2\nlet f = fun(x: Int) -> Int:\n\treturn x * k\nlet res = par_map([1], f)\n";
+		let mut vm = VM::new(&mut heap, Compiler::new(true).compile_program(captures).unwrap());
+		assert!(vm.run(&mut heap).is_err());
+
+		for send in ["channel(\"cycle\").send(l)", "let f = fun(x) -> Int:\n\treturn 0\npar_map([l], f)"] {
+			let src = format!("let l = []\nl.add(l)\n{}\n", send);
+			let mut vm = VM::new(&mut heap, Compiler::new(true).compile_program(&src).unwrap());
+			let err = vm.run(&mut heap).unwrap_err();
+			assert_eq!(err.1, "Cannot send a list containing itself through a channel");
+		}
+	}
+
+	#[test]
+	fn test_interrupt() {
+		let mut heap = GCHeap::new();
+		let program = Compiler::new(true).compile_program("let res = [0]\nwhile res[0] < 10:\n\tres[0] = res[0] + 1\n").unwrap();
+		let mut vm = VM::new(&mut heap, program);
+		vm.interrupt_handle().interrupt();
+		match vm.run(&mut heap) {
+			Err(HissyError(ErrorType::Interrupt, _, _)) => (),
+			res => panic!("Script was not interrupted: {:?}", res),
+		}
+		let res = results(&vm);
+		assert_eq!(i32::try_from(&res.get(0).unwrap()).unwrap(), 1);
+		vm.run(&mut heap).unwrap();
+		assert!(vm.is_finished());
+		assert_eq!(i32::try_from(&res.get(0).unwrap()).unwrap(), 10);
+		
+		// par_map workers are interrupted along with the VM
+		let src = "let spin = fun(x: Int) -> Int:\n\twhile true:\n\t\tx = x + 1\n\treturn x\npar_map([1, 2, 3], spin)\n";
+		let mut vm = VM::new(&mut heap, Compiler::new(true).compile_program(src).unwrap());
+		let handle = vm.interrupt_handle();
+		let interrupter = thread::spawn(move || {
+			thread::sleep(Duration::from_millis(50));
+			handle.interrupt();
+		});
+		let err = vm.run(&mut heap).unwrap_err();
+		interrupter.join().unwrap();
+		assert_eq!((err.0, err.2), (ErrorType::Interrupt, 5));
+		assert!(!vm.state.interrupt.load(Ordering::Relaxed));
+	}
+
+	#[test]
+	fn test_capture_by_value() {
+		let mut heap = GCHeap::new();
+		let script = "let res = []\nlet i = 0\nlet f = fun() -> Int:\n\treturn i\nlet g = fun() capture [i] -> Int:\n\ti = i + 10\n\treturn i\ni = 5\nres.add(f())\nres.add(g())\nres.add(g())\nres.add(i)\n";
+		let mut vm = VM::new(&mut heap, Compiler::new(true).compile_program(script).unwrap());
+		let res = loop {
+			vm.step(&mut heap).unwrap();
+			if vm.state.calls.len() == 1 && results(&vm).len() == 4 {
+				break results(&vm);
+			}
+		};
+		let res: Vec<i32> = (0..4).map(|i| i32::try_from(&res.get(i).unwrap()).unwrap()).collect();
+		assert_eq!(res, vec![5, 10, 20, 5]);
+		
+		assert!(Compiler::new(true).compile_program("let i = 0\nlet f = fun() capture [i, i]:\n\tpass\n").is_err());
+	}
+
+	#[test]
+	fn test_for_over_variable() {
+		let src = "let r = range(0, 3)\nlet sum = 0\nfor i in r:\n\tsum = sum + i\nlet l = [1, 2, 3]\nlet it = l.iter()\nlet seen = []\nfor x in it:\n\tlet y = [x]\n\tseen.add(y)\nlet after = 100\nlet f = fun(k: Int) -> Int:\n\tlet n = 0\n\tlet it = range(0, k)\n\tfor x in it:\n\t\tn = n + x\n\treturn n\nreturn [sum, seen, after, f(4), it.next()]\n";
+		let mut heap = GCHeap::new();
+		let res = Compiler::new(true).compile_function(src, &[]).unwrap().call(&mut heap, vec![]).unwrap();
+		// The loop advances the iterator of the variable, which is then exhausted
+		assert_eq!(res.repr(), "[3, [[1], [2], [3]], 100, 6, nil]");
+	}
+
+	#[test]
+	fn test_loop_bindings() {
+		let mut heap = GCHeap::new();
+		let script = "let res = []
+let f0 = fun() -> Int:
+	return -1
+let f1 = f0
+let i = 0
+while i < 2:
+	let j = i
+	let f = fun() -> Int:
+		return j
+	if i == 0:
+		f0 = f
+	else:
+		f1 = f
+	i = i + 1
+res.add(f0())
+res.add(f1())
+for x in range(0, 2):
+	let g = fun() -> Int:
+		return x
+	if x == 0:
+		f0 = g
+	else:
+		f1 = g
+res.add(f0())
+res.add(f1())
+let early = fun() -> Int:
+	for x in range(0, 3):
+		let g = fun() -> Int:
+			return x
+		if x == 1:
+			f0 = g
+			return x
+	return -1
+early()
+res.add(f0())
+";
+		let mut vm = VM::new(&mut heap, Compiler::new(true).compile_program(script).unwrap());
+		let res = loop {
+			vm.step(&mut heap).unwrap();
+			if vm.state.calls.len() == 1 && results(&vm).len() == 5 {
+				break results(&vm);
+			}
+		};
+		let res: Vec<i32> = (0..5).map(|i| i32::try_from(&res.get(i).unwrap()).unwrap()).collect();
+		assert_eq!(res, vec![0, 1, 0, 1, 1]);
+	}
+
+	#[test]
+	fn test_nested_scopes() {
+		let mut script = String::from("let res = []
+let x = 1
+let f = fun() -> Int:
+	let g = fun() -> Int:
+		return x
+	let x = 2
+	let h = fun() -> Int:
+		return x
+	return g() * 10 + h()
+res.add(f())
+if true:
+	let x = 3
+	res.add(x)
+res.add(x)
+let r = fun() capture [range] -> Int:
+	let n = 0
+	for i in range(0, 4):
+		n = n + i
+	return n
+res.add(r())
+");
+		// Deeply nested blocks and closures, using bindings from every level
+		let depth = 40;
+		for d in 0..depth {
+			let indent = "\t".repeat(d);
+			script += &format!("{}let v{} = {}\n{}let f{} = fun():\n", indent, d, d, indent, d);
+		}
+		let sum: Vec<String> = (0..depth).map(|d| format!("v{}", d)).collect();
+		script += &format!("{}res.add({})\n", "\t".repeat(depth), sum.join(" + "));
+		for d in (0..depth).rev() {
+			script += &format!("{}f{}()\n", "\t".repeat(d), d);
+		}
+		
+		let mut heap = GCHeap::new();
+		let mut vm = VM::new(&mut heap, Compiler::new(true).compile_program(&script).unwrap());
+		vm.step(&mut heap).unwrap();
+		let res = results(&vm);
+		vm.run(&mut heap).unwrap();
+		let res: Vec<i32> = (0..res.len()).map(|i| i32::try_from(&res.get(i).unwrap()).unwrap()).collect();
+		assert_eq!(res, vec![12, 3, 1, 6, (0..depth as i32).sum()]);
+	}
+
+	#[test]
+	fn test_compiled_function() {
+		use crate::compiler::{Type, PrimitiveType};
+		let mut heap = GCHeap::new();
+		let params = [("price", Type::Primitive(PrimitiveType::Real)), ("n", Type::Primitive(PrimitiveType::Int))];
+		let total = Compiler::new(true).compile_function("price * n * (1 + 0.25)", &params).unwrap();
+		let res = total.call(&mut heap, vec![Value::from(2.0), Value::from(3)]).unwrap();
+		assert_eq!(res.cast_real(), 7.5);
+		assert!(total.call(&mut heap, vec![Value::from(2.0)]).is_err());
+		
+		let sum = Compiler::new(false).compile_function("let s = 0\nfor i in range(0, n):\n\ts = s + i\nreturn s\n", &params[1..]).unwrap();
+		assert_eq!(i32::try_from(&sum.call(&mut heap, vec![Value::from(5)]).unwrap()).unwrap(), 10);
+		assert_eq!(format!("{:?}", (total.ret_type(), sum.ret_type())), "(Real, Int)");
+		assert!(sum.call(&mut heap, vec![Value::from(2.0)]).is_err());
+		
+		assert!(Compiler::new(true).compile_function("price * 2", &[]).is_err());
+		let wait = Compiler::new(true).compile_function("sleep(1)", &[]).unwrap();
+		assert!(wait.call(&mut heap, vec![]).is_err());
+	}
+
+	#[test]
+	fn test_eval() {
+		let mut heap = GCHeap::new();
+		let mut vars = HashMap::new();
+		vars.insert(String::from("width"), Value::from(3));
+		vars.insert(String::from("scale"), Value::from(1.5));
+		let sizes = List::new();
+		sizes.extend(&[Value::from(1), Value::from(2)]);
+		vars.insert(String::from("sizes"), heap.make_value(sizes));
+		let res = crate::eval("width * scale + sizes[1]", &vars, &mut heap).unwrap();
+		assert_eq!(res.cast_real(), 6.5);
+		assert_eq!(i32::try_from(&crate::eval("sizes.size() + width", &vars, &mut heap).unwrap()).unwrap(), 5);
+		assert!(crate::eval("width + height", &vars, &mut heap).is_err());
+		assert!(crate::eval("sizes + 1", &vars, &mut heap).is_err());
+	}
+
+	#[test]
+	fn test_int_pow_type() {
+		// Int ^ Int is typed as a Real, and must behave as one in later operations
+		let src = "let x: Real = 2 ^ 3\nassert(x / 3 > 2.6)\nlet y = 3 ^ 2\nassert(y / 2 == 4.5)\n";
+		let program = Compiler::new(true).compile_program(src).unwrap();
+		run_program(&mut GCHeap::new(), &program).unwrap();
+		let res = crate::eval("2 ^ 10", &HashMap::new(), &mut GCHeap::new()).unwrap();
+		assert_eq!(f64::try_from(&res).unwrap(), 1024.0);
+	}
+
+	#[test]
+	fn test_numeric_intrinsics() {
+		let mut heap = GCHeap::new();
+		let vars = HashMap::new();
+		let mut eval_real = |expr: &str| crate::eval(expr, &vars, &mut heap).map(|res| f64::try_from(&res).unwrap());
+		// Ints and Reals can be mixed, and the result is always a Real
+		assert_eq!(eval_real("fma(2, 3.5, 1)").unwrap(), 8.0);
+		assert_eq!(eval_real("hypot(3, 4)").unwrap(), 5.0);
+		assert_eq!(eval_real("clamp(5, 0, 2.5)").unwrap(), 2.5);
+		assert_eq!(eval_real("clamp(-1, 0.5, 3)").unwrap(), 0.5);
+		assert_eq!(eval_real("clamp(1.5, 1, 1)").unwrap(), 1.0);
+		assert_eq!(eval_real("lerp(0, 10, 0.25)").unwrap(), 2.5);
+		assert_eq!(eval_real("lerp(2.5, 4, 2)").unwrap(), 5.5);
+		assert_eq!(eval_real("lerp(1, 2, 0)").unwrap(), 1.0);
+		
+		assert_eq!(eval_real("clamp(1, 2, 0)").err().unwrap().1, "Invalid clamping interval [2, 0]");
+		assert!(eval_real("clamp(1, 0, 0 / 0)").is_err());
+		assert!(eval_real("hypot(3, \"4\")").is_err());
+		
+		let src = "let r: Real = clamp(7, 0, 5) + lerp(0, 1, 0.5)\nassert(r == 5.5)\nclamp(r, 6, 5.9)\n";
+		let err = run_program(&mut GCHeap::new(), &Compiler::new(true).compile_program(src).unwrap()).err().unwrap();
+		assert_eq!((err.1.lines().next().unwrap(), err.2), ("Invalid clamping interval [6, 5.9]", 3));
+	}
+
+	#[test]
+	fn test_run_for() {
+		let mut heap = GCHeap::new();
+		let mut vm = VM::new(&mut heap, Compiler::new(true).compile_program(SCRIPT).unwrap());
+		let mut frames = 1;
+		while vm.run_for(&mut heap, 10).unwrap() == RunStatus::OutOfFuel {
+			frames += 1;
+		}
+		assert!(frames > 1 && vm.is_finished());
+		assert_eq!(vm.run_for(&mut heap, 10).unwrap(), RunStatus::Done);
+
+		let mut vm = VM::new(&mut heap, Compiler::new(true).compile_program("sleep(1)\n").unwrap());
+		assert_eq!(vm.run_for(&mut heap, 100).unwrap(), RunStatus::Suspended);
+		vm.resume(NIL).unwrap();
+		assert_eq!(vm.run_for(&mut heap, 0).unwrap(), RunStatus::OutOfFuel);
+		assert_eq!(vm.run_for(&mut heap, 100).unwrap(), RunStatus::Done);
+	}
+
+	#[test]
+	fn test_chars() {
+		use crate::compiler::{Type, PrimitiveType};
+		let mut heap = GCHeap::new();
+		let params = [("s", Type::Primitive(PrimitiveType::String))];
+		let src = "let codes = []\nfor c in s.iter():\n\tif c != ' ':\n\t\tcodes.add(int(c))\nlet last: Char = char(int(codes[2]) + 1)\nreturn [codes, last]\n";
+		let f = Compiler::new(true).compile_function(src, &params).unwrap();
+		let arg = heap.make_string("hé y");
+		let res = GCRef::<List>::try_from(f.call(&mut heap, vec![arg]).unwrap()).unwrap();
+		assert_eq!(res.get(0).unwrap().repr(), "[104, 233, 121]");
+		assert_eq!(char::try_from(&res.get(1).unwrap()), Ok('z'));
+		
+		assert!(Compiler::new(true).compile_function("char(-1)", &[]).unwrap().call(&mut heap, vec![]).is_err());
+		assert!(Compiler::new(true).compile_program("let c: Char = 'ab'\n").is_err());
+		assert!(Compiler::new(true).compile_program("let c: Char = \"a\"\n").is_err());
+	}
+
+	#[test]
+	fn test_symbols() {
+		use crate::parser::symbol::Symbol;
+		let mut heap = GCHeap::new();
+		let src = "let next(state: Symbol) -> Symbol:\n\tif state == :idle:\n\t\treturn :running\n\treturn :idle\nlet n:Int = 0\nreturn [next(:idle), next(next(:idle)), :idle == :running]\n";
+		let f = Compiler::new(true).compile_function(src, &[]).unwrap();
+		let program = Program::from_bytes(&f.program().to_bytes().unwrap()).unwrap();
+		let mut vm = VM::new(&mut heap, program);
+		vm.run(&mut heap).unwrap();
+		assert_eq!(vm.result().repr(), "[:running, :idle, false]");
+		let res = GCRef::<List>::try_from(vm.result().clone()).unwrap();
+		assert_eq!(Symbol::try_from(&res.get(0).unwrap()), Ok(Symbol::intern("running")));
+		assert!(Compiler::new(true).compile_program("let s: Symbol = \"idle\"\n").is_err());
+	}
+
+	#[test]
+	fn test_enums() {
+		let src = "enum Color: Red, Green, Blue\nlet name(c: Color) -> String:\n\tmatch c:\n\t\tColor.Red:\n\t\t\treturn \"red\"\n\t\tColor.Green, :Blue:\n\t\t\treturn \"other\"\n\t\telse:\n\t\t\treturn \"?\"\nlet c: Color = Color.Blue\nreturn [name(Color.Red), name(c), c, c == :Blue]\n";
+		let mut heap = GCHeap::new();
+		let f = Compiler::new(true).compile_function(src, &[]).unwrap();
+		assert!(f.program().warnings().is_empty());
+		assert_eq!(f.call(&mut heap, vec![]).unwrap().repr(), "[\"red\", \"other\", :Blue, true]");
+		
+		let partial = "enum Color: Red, Green, Blue\nlet c = Color.Red\nmatch c:\n\tColor.Green:\n\t\tlog(1)\nlog(2)\n";
+		let program = Compiler::new(true).compile_program(partial).unwrap();
+		assert_eq!(program.warnings(), [crate::compiler::Warning(String::from("Match on Color does not handle Red, Blue"), 3)]);
+		
+		let errors = [
+			"enum Color: Red\nlet c = Color.Yellow\n",
+			"enum Color: Red\nenum Color: Blue\n",
+			"enum Color: Red, Red\n",
+			"enum Color: Red\nenum Fruit: Apple\nlet c: Color = Fruit.Apple\n",
+			"enum Color: Red\nmatch Color.Red:\n\t1:\n\t\tpass\n",
+		];
+		for src in &errors {
+			assert!(Compiler::new(true).compile_program(src).is_err(), "{}", src);
+		}
+	}
+
+	#[test]
+	fn test_long_chains() {
+		// Generated code can have chains of operations far longer than the nesting limit
+		let terms = 100_000;
+		let src = format!("let a = 1\nreturn a{} - a * 2\n", " + a".repeat(terms - 1));
+		let mut heap = GCHeap::new();
+		let f = Compiler::new(true).compile_function(&src, &[]).unwrap();
+		assert_eq!(f.call(&mut heap, vec![]).unwrap().repr(), (terms as i32 - 2).to_string());
+		// Flattened chains mixing operators of different precedences keep their meaning
+		let sum = format!("a - a + a * a{}", " - 1".repeat(20));
+		let f = Compiler::new(true).compile_function(&format!("let a = 2\nreturn [{}, {} == -16]\n", sum, sum), &[]).unwrap();
+		assert_eq!(f.call(&mut heap, vec![]).unwrap().repr(), "[-16, true]");
+	}
+	
+	#[test]
+	fn test_operand_order() {
+		// Operands of commutative operators needing more registers are computed first
+		let registers = |op: &str| {
+			let src = format!("let a = 3\nlet b = 2\nreturn a * b {0} (a * b {0} (a * b {0} a * b))\n", op);
+			let program = Compiler::new(true).compile_program(&src.replace("return", "let c =")).unwrap();
+			let required = program.chunks[0].get().unwrap().nb_registers;
+			let mut heap = GCHeap::new();
+			let res = Compiler::new(true).compile_function(&src, &[]).unwrap().call(&mut heap, vec![]).unwrap();
+			(required, res.repr())
+		};
+		let (plus, plus_res) = registers("+");
+		let (minus, minus_res) = registers("-");
+		assert_eq!((plus_res.as_str(), minus_res.as_str()), ("24", "0"));
+		assert_eq!(minus - plus, 2);
+	}
+	
+	#[test]
+	fn test_arity_check() {
+		let src = "let f = fun(a: Int, b: Int) -> Int:\n\treturn a + b\nlog(f(1, 2))\n";
+		let program = Program::from_bytes(&Compiler::new(true).compile_program(src).unwrap().to_bytes().unwrap()).unwrap();
+		assert_eq!((program.chunk_metadata(0).unwrap().params, program.chunk_metadata(1).unwrap().params), (0, 2));
+		assert!(run_program(&mut GCHeap::new(), &program).is_ok());
+		
+		// Calls which the compiler could not check, such as those of bytecode edited after compilation
+		let mut edited = program.clone();
+		edited.chunks[1].get_mut().unwrap().nb_params = 1;
+		let err = run_program(&mut GCHeap::new(), &edited).err().unwrap();
+		assert!(err.1.starts_with("Expected 1 arguments, got 2"), "{}", err.1);
+		
+		let src = "let f = fun(a: Int, b: Int) -> Int:\n\treturn a + b\nlog(par_map([1, 2], f))\n";
+		let err = run_program(&mut GCHeap::new(), &Compiler::new(true).compile_program(src).unwrap()).err().unwrap();
+		assert!(err.1.starts_with("Expected 2 arguments, got 1"), "{}", err.1);
+	}
+	
+	#[test]
+	fn test_function_statement() {
+		let src = "fun fact(n: Int) -> Int:\nif n <= 1:\nreturn 1\nend\nreturn n * fact(n - 1)\nend\nlet twice = fun(f: Int) -> Int:\n\treturn 2 * f\nend\nreturn twice(fact(5))\n";
+		let mut compiler = Compiler::new(true);
+		compiler.set_block_style(crate::parser::lexer::BlockStyle::End);
+		let program = compiler.compile_program(&src.replace("return twice(fact(5))", "log(twice(fact(5)))")).unwrap();
+		let names: Vec<_> = (1..3).map(|i| program.chunk_metadata(i).unwrap().name.unwrap()).collect();
+		assert_eq!(names, ["fact", "twice"]);
+		
+		let mut compiler = Compiler::new(true);
+		compiler.set_block_style(crate::parser::lexer::BlockStyle::End);
+		let f = compiler.compile_function(src, &[]).unwrap();
+		assert_eq!(f.call(&mut GCHeap::new(), vec![]).unwrap().repr(), "240");
+	}
+	
+	#[test]
+	fn test_nested_upvalues() {
+		// Variables captured through several levels of closures, with shadowing and mutation at each level,
+		// including after the functions declaring them have returned
+		let src = "let res = []
+let x = 1
+let f = fun() -> Int:
+	let g = fun() -> Int:
+		let h = fun() -> Int:
+			return x
+		x = x + 1
+		return h()
+	return g()
+res.add(f())
+res.add(x)
+let keep = fun() -> Int:
+	return 0
+let peek = keep
+fun make():
+	let n = 0
+	let mid = fun():
+		let inc = fun() -> Int:
+			n = n + 1
+			return n
+		keep = inc
+		inc()
+	mid()
+	mid()
+	peek = fun() -> Int:
+		return n * 100
+make()
+res.add(keep())
+res.add(keep())
+res.add(peek())
+let a = fun() -> Int:
+	let y = 10
+	let b = fun() -> Int:
+		let y = 20
+		let c = fun() -> Int:
+			let d = fun() -> Int:
+				y = y + 1
+				return y
+			return d() + d()
+		return c() * 100 + y
+	return b() * 1000 + y
+res.add(a())
+let fs = [keep, keep, keep]
+for i in range(0, 3):
+	let mid = fun():
+		let inner = fun() -> Int:
+			return i * 10
+		fs[i] = inner
+	mid()
+res.add(fs[0]() + fs[1]() + fs[2]())
+let p = 1
+let q = 2
+let r = 3
+let t1 = fun() -> Int:
+	let t2 = fun() -> Int:
+		let u = p
+		let t3 = fun() capture [q] -> Int:
+			let t4 = fun() -> Int:
+				return r * 100 + q * 10 + u
+			return t4()
+		q = 5
+		return t3()
+	return t2()
+res.add(t1())
+return res
+";
+		let mut heap = GCHeap::new();
+		let res = Compiler::new(true).compile_function(src, &[]).unwrap().call(&mut heap, vec![]).unwrap();
+		assert_eq!(res.repr(), "[2, 2, 3, 4, 400, 4322010, 30, 321]");
+		
+		// Upvalues of closures are checked against the chunk creating them
+		let src = "let x = 1\nlet f = fun() -> Int:\n\tlet g = fun() -> Int:\n\t\treturn x\n\treturn g()\nlog(f())\n";
+		let mut program = Compiler::new(true).compile_program(src).unwrap();
+		assert_eq!(program.chunks[2].get().unwrap().upvalues, [MAX_REGISTERS]);
+		program.chunks[2].get_mut().unwrap().upvalues[0] = MAX_REGISTERS + 1;
+		let err = run_program(&mut GCHeap::new(), &program).err().unwrap();
+		assert!(err.1.starts_with("Invalid upvalue 129 of closure"), "{}", err.1);
+	}
+	
+	#[test]
+	fn test_compile_ast() {
+		use crate::parser::ast::*;
+		// let x = 6 * 7; assert(x == 42)
+		let check = |value: i32| vec![
+			Stat::define("x", Expr::binop(BinOp::Times, Expr::Int(6), Expr::Int(value))).at(1),
+			Stat::ExprStat(Expr::call(Expr::id("assert"), vec![Expr::binop(BinOp::Equal, Expr::id("x"), Expr::Int(42))])).at(2),
+		];
+		let program = Compiler::new(true).compile_ast(check(7)).unwrap();
+		assert!(run_program(&mut GCHeap::new(), &program).is_ok());
+		let program = Compiler::new(true).compile_ast(check(8)).unwrap();
+		assert_eq!(run_program(&mut GCHeap::new(), &program).err().unwrap().2, 2);
+		
+		let err = Compiler::new(true).compile_ast(vec![Stat::ExprStat(Expr::id("y")).at(3)]).err().unwrap();
+		assert_eq!((err.0, err.2), (ErrorType::Compilation, 3));
+		let deep = (0..1000).fold(Expr::Int(1), |e, _| Expr::unaop(UnaOp::Minus, e));
+		assert!(Compiler::new(true).compile_ast(vec![Stat::ExprStat(deep).at(1)]).is_err());
+	}
+	
+	#[test]
+	fn test_precedence_warnings() {
+		let src = "let a = true\nlet x = 2\nlog(not a == false)\nlog(-x ^ 2)\nlog(x == 2 == true)\nlog(not (x > 1), (-x) ^ 2, a == (x < 3))\n";
+		let program = Compiler::new(true).compile_program(src).unwrap();
+		let warnings: Vec<_> = program.warnings().iter().map(|crate::compiler::Warning(msg, line)| (&msg[..msg.find(';').unwrap()], *line)).collect();
+		assert_eq!(warnings, [
+			("'not a == b' is parsed as 'not (a == b)'", 3),
+			("'-a ^ b' is parsed as '-(a ^ b)'", 4),
+			("'a == b == c' is parsed as '(a == b) == c', which compares a Bool", 5),
+		]);
+	}
+	
+	#[test]
+	fn test_sandbox() {
+		let run = |src: &str, sandbox: Sandbox| -> Result<(), HissyError> {
+			let mut heap = GCHeap::new();
+			let mut vm = VM::new(&mut heap, Compiler::new(true).compile_program(src).unwrap());
+			vm.set_sandbox(&mut heap, sandbox);
+			let res = vm.run(&mut heap);
+			drop(vm);
+			heap.collect();
+			res
+		};
+		let recurse = "let f = fun(n: Int) -> Int:\n\treturn f(n + 1)\nf(0)\n";
+		let allocate = "let l = []\nwhile true:\n\tl.add([1, 2, 3])\n";
+		
+		assert!(run("let a = 1 + 2\nlog(a)\n", Sandbox::strict()).is_ok());
+		let err = run("sleep(1)\n", Sandbox::strict()).unwrap_err();
+		assert_eq!((err.1.as_str(), err.2), ("Native 'sleep' is not allowed in this sandbox", 1));
+		assert!(run(recurse, Sandbox::strict()).unwrap_err().1.starts_with("Recursion limit"));
+		let small = Sandbox { memory_limit: Some(100_000), fuel_limit: Some(100_000), ..Sandbox::strict() };
+		assert!(run("while true:\n\tlet a = 1\n", small.clone()).unwrap_err().1.starts_with("Fuel limit"));
+		assert!(run(allocate, small).unwrap_err().1.starts_with("Memory limit"));
+		// A single list growing, without allocating other objects
+		let sandbox = Sandbox { memory_limit: Some(1 << 20), ..Sandbox::strict() };
+		let grow = "let l = []\nlet i = 0\nwhile i < 3000000:\n\tl.add(i)\n\ti = i + 1\n";
+		assert_eq!(run(grow, sandbox).unwrap_err().1, "Memory limit of 1048576B exceeded");
+		
+		let sandbox = Sandbox { fuel_limit: Some(1000), ..Sandbox::default() };
+		assert!(run("let i = 0\nwhile i < 100:\n\ti = i + 1\n", sandbox.clone()).is_ok());
+		assert!(run("let i = 0\nwhile i < 1000:\n\ti = i + 1\n", sandbox.clone()).is_err());
+		// par_map workers would escape the limits
+		let err = run("let f = fun(x: Int) -> Int:\n\treturn x\npar_map([1], f)\n", sandbox).unwrap_err();
+		assert_eq!((err.1.as_str(), err.2), ("Native 'par_map' is not allowed in this sandbox", 3));
+	}
+
+	#[test]
+	fn test_native_hook() {
+		use std::rc::Rc;
+		use std::cell::RefCell;
+		let mut heap = GCHeap::new();
+		let calls = Rc::new(RefCell::new(vec![]));
+		let src = "let l = []\nl.add(1)\nlet s = int(l.size() + 1)\nlog(s)\n";
+		let mut vm = VM::new(&mut heap, Compiler::new(true).compile_program(src).unwrap());
+		let log = calls.clone();
+		vm.set_native_hook(move |name, args| {
+			log.borrow_mut().push((String::from(name), args.len()));
+			name != "log"
+		});
+		let err = vm.run(&mut heap).unwrap_err();
+		assert_eq!((err.1.as_str(), err.2), ("Call to native 'log' was denied", 4));
+		assert_eq!(*calls.borrow(), vec![
+			(String::from("List.add"), 2), (String::from("List.size"), 1), (String::from("int"), 1), (String::from("log"), 1),
+		]);
+		drop(vm);
+		
+		// Natives called by par_map workers would not go through the hook
+		calls.borrow_mut().clear();
+		let src = "let f = fun(x: Int) -> Int:\n\treturn int(x * 2.0)\nlog(par_map([1, 2], f))\n";
+		let mut vm = VM::new(&mut heap, Compiler::new(true).compile_program(src).unwrap());
+		let log = calls.clone();
+		vm.set_native_hook(move |name, args| {
+			log.borrow_mut().push((String::from(name), args.len()));
+			true
+		});
+		let err = vm.run(&mut heap).unwrap_err();
+		assert_eq!((err.1.as_str(), err.2), ("Native 'par_map' cannot run while a native hook is set", 3));
+		assert_eq!(*calls.borrow(), vec![(String::from("par_map"), 2)]);
+		drop(vm);
+		heap.collect();
+	}
+
+	#[test]
+	fn test_defer() {
+		let mut heap = GCHeap::new();
+		let src = "let res = []\nlet f = fun(n: Int) -> Int:\n\tdefer res.add(n * 10)\n\tif n > 1:\n\t\treturn n\n\tdefer res.add(n)\n\tres.add(0)\n\treturn n + 1\n\
+			let i = 0\nwhile i < 2:\n\tdefer res.add(100 + i)\n\ti = i + 1\nres.add(f(1))\nres.add(f(5))\nres\n";
+		let func = Compiler::new(true).compile_function(src, &[]).unwrap();
+		let list = GCRef::<List>::try_from(func.call(&mut heap, vec![]).unwrap()).unwrap();
+		let res: Vec<i32> = list.get_copy().iter().map(|v| i32::try_from(v).unwrap()).collect();
+		drop(list);
+		heap.collect();
+		assert_eq!(res, vec![101, 102, 0, 1, 10, 2, 50, 5]);
+	}
+
+	fn main_instrs(program: &Program) -> Vec<Instr> {
+		let chunk = program.chunks[0].decoded();
+		let mut it = chunk.code.iter();
+		let mut instrs = vec![];
+		while it.len() > 0 {
+			instrs.push(Instr::decode(&mut it, chunk.encoding).unwrap());
+		}
+		instrs
+	}
+	
+	#[test]
+	fn test_int_loop() {
+		let mut heap = GCHeap::new();
+		let mut run = |src: &str| -> Vec<i32> {
+			let func = Compiler::new(true).compile_function(src, &[]).unwrap();
+			let list = GCRef::<List>::try_from(func.call(&mut heap, vec![]).unwrap()).unwrap();
+			list.get_copy().iter().map(|v| i32::try_from(v).unwrap()).collect()
+		};
+		let src = "let res = []\nlet g = fun() -> Int:\n\treturn 0\nfor i in range(1, 4):\n\tlet f = fun() -> Int:\n\t\treturn i\n\tif i == 2:\n\t\tg = f\n\ti = i * 10\n\tres.add(i)\n\
+			for i in range(5, 5):\n\tres.add(i)\nfor i in range(2147483645, 2147483647):\n\tres.add(i - 2147483640)\nres.add(g())\nres\n";
+		assert_eq!(run(src), vec![10, 20, 30, 5, 6, 20]);
+		heap.collect();
+		
+		// A shadowed range() is called normally
+		let shadowed = "let range = fun(a: Int, b: Int) -> Any:\n\treturn nil\nfor i in range(0, 3):\n\tlog(i)\n";
+		let err = Compiler::new(false).compile_program(shadowed).err().unwrap();
+		assert!(err.1.ends_with("is not an iterable type"));
+		
+		let program = Compiler::new(false).compile_program("for i in range(0, 3):\n\tlog(i)\n").unwrap();
+		let types: Vec<instr::InstrType> = main_instrs(&program).iter().map(Instr::instr_type).collect();
+		assert!(types.contains(&instr::InstrType::ForLoop) && !types.contains(&instr::InstrType::CallMethod));
+	}
+
+	#[test]
+	fn test_constant_operands() {
+		// Constants are encoded directly in rc operands, without being loaded into a register first
+		let program = Compiler::new(false).compile_program("let i = 0\ni = i + 1\ni = 2 * i\n").unwrap();
+		let instrs = main_instrs(&program);
+		assert_eq!(program.chunks[0].decoded().nb_registers, 1);
+		assert!(matches!(instrs[1], Instr::Add { a: 0, b, dst: 0 } if b >= MAX_REGISTERS));
+		assert!(matches!(instrs[2], Instr::Mul { a, b: 0, dst: 0 } if a >= MAX_REGISTERS));
+		assert_eq!(instrs.len(), 3);
+	}
+
+	#[test]
+	fn test_unit_tests() {
+		let src = "let k = 2\n@test\nlet doubles():\n\tassert(k * 2 == 4)\nlet test_fails():\n\tk = 3\n\tassert(k == 2)\n\
+			let test_with_arg(x: Int):\n\tpass\nlet helper():\n\tpass\nlet test_state():\n\tassert(k == 2)\n";
+		let program = Compiler::new(true).compile_tests(src).unwrap();
+		let names: Vec<&str> = program.tests().iter().map(|(name, _)| name.as_str()).collect();
+		assert_eq!(names, vec!["doubles", "test_fails", "test_state"]);
+		assert_eq!(program.tests()[1].1, 5);
+		
+		// Each test starts from the state left by the top-level code
+		let mut heap = GCHeap::new();
+		assert!(run_test(&mut heap, &program, 0).is_ok());
+		let err = run_test(&mut heap, &program, 1).err().unwrap();
+		assert_eq!((err.1.as_str(), err.2), ("Assertion failed", 7));
+		assert!(run_test(&mut heap, &program, 2).is_ok());
+		assert!(run_test(&mut heap, &program, 3).is_err());
+		
+		let err = Compiler::new(false).compile_tests("@test\nlet f(x: Int):\n\tpass\n").err().unwrap();
+		assert_eq!(err.1, "Test function 'f' cannot take arguments");
+		let err = Compiler::new(false).compile_tests("let f():\n\t@test\n\tlet g():\n\t\tpass\n").err().unwrap();
+		assert_eq!(err.1, "Test functions must be declared at the top level");
+	}
+
+	#[test]
+	fn test_benches() {
+		let src = "let calls = []\n@bench\nlet add():\n\tcalls.add(1)\n\tassert(calls.size() <= 3)\nlet bench_check():\n\tassert(calls.size() == 0)\nlet test_other():\n\tpass\n";
+		let program = Compiler::new(true).compile_benches(src).unwrap();
+		let names: Vec<&str> = program.benches().iter().map(|(name, _)| name.as_str()).collect();
+		assert_eq!(names, vec!["add", "bench_check"]);
+		assert!(program.tests().is_empty());
+		
+		// Each run starts from the state left by the top-level code, and calls the benchmark the given number of times
+		let mut heap = GCHeap::new();
+		assert!(run_bench(&mut heap, &program, 0, 3).is_ok());
+		assert!(run_bench(&mut heap, &program, 0, 4).is_err());
+		assert!(run_bench(&mut heap, &program, 1, 0).is_ok());
+		assert!(run_bench(&mut heap, &program, 1, 1).is_ok());
+		assert!(run_bench(&mut heap, &program, 2, 1).is_err());
+	}
+
+	#[test]
+	fn test_imports() {
+		use crate::compiler::ModuleResolver;
+		struct Modules(Vec<(&'static str, &'static str)>);
+		impl ModuleResolver for Modules {
+			fn resolve(&mut self, name: &str, _importer: Option<&str>) -> Result<(String, String), String> {
+				let (_, source) = self.0.iter().find(|(n, _)| *n == name).ok_or_else(|| String::from("not found"))?;
+				Ok((String::from(name), String::from(*source)))
+			}
+		}
+		let compile = |src: &str| {
+			let mut compiler = Compiler::new(false);
+			compiler.set_resolver(Modules(vec![
+				("a", "import b\nlet x = y + 1\n"),
+				("b", "let y = 1\n"),
+				("c.d", "import c.e\n"),
+				("c.e", "import c.d\n"),
+			]));
+			compiler.compile_function(src, &[])
+		};
+		let res = compile("import a\nimport b\nimport a\nx + y\n").unwrap().call(&mut GCHeap::new(), vec![]).unwrap();
+		assert_eq!(i32::try_from(&res).unwrap(), 3);
+		
+		assert_eq!(compile("import c.d\n").err().unwrap().1, "Circular import of 'c.d'");
+		assert_eq!(compile("\nimport z\n").err().unwrap().2, 2);
+		assert_eq!(compile("if true:\n\timport b\n").err().unwrap().1, "Module 'b' must be imported at the top level");
+		assert!(Compiler::new(false).compile_program("import b\n").is_err());
+		assert_eq!(compile("let s = embed(\"b\")\n").err().unwrap().1, "Cannot embed 'b': embedding files is not supported by this module resolver");
+		
+		// Untrusted code cannot read files, even with a resolver
+		let compile_untrusted = |src: &str| {
+			let mut compiler = Compiler::new(false);
+			compiler.set_resolver(Modules(vec![("b", "let y = 1\n")]));
+			compiler.set_untrusted(true);
+			compiler.compile_program(src).err().unwrap().1
+		};
+		assert_eq!(compile_untrusted("import b\n"), "Cannot import 'b': imports are not allowed in untrusted code");
+		assert_eq!(compile_untrusted("let s = embed(\"b\")\n"), "Cannot embed 'b': embedding files is not allowed in untrusted code");
+	}
+
+	#[test]
+	fn test_host_env() {
+		let run = |src: &str, host: &HostEnv| {
+			let program = Compiler::new(false).compile_program(src).unwrap();
+			run_program_with(&mut GCHeap::new(), &program, host)
+		};
+		let err = run("env(\"PATH\")\n", &HostEnv::default()).err().unwrap();
+		assert_eq!(err.1, "Native 'env' is not allowed by the host");
+		assert_eq!(run("assert(env(\"HISSY_UNDEFINED_VARIABLE\") == nil)\n", &HostEnv::trusted()).unwrap(), None);
+		
+		// exit() stops the script right away
+		let host = HostEnv { allow_exit: true, ..HostEnv::default() };
+		assert_eq!(run("exit(3)\nassert(false)\n", &host).unwrap(), Some(3));
+		assert!(run("exec(\"echo\", [])\n", &host).is_err());
+		
+		let host = HostEnv { allow_exec: true, ..HostEnv::default() };
+		assert_eq!(run("assert(exec(\"echo\", [\"a\", \"b\"]) == \"a b\\n\")\n", &host).unwrap(), None);
+		let err = run("exec(\"false\", [])\n", &host).err().unwrap();
+		assert!(err.1.starts_with("Command 'false' failed with exit code 1"));
+		assert!(run("exec(\"echo\", [1])\n", &host).is_err());
+	}
+
+	#[test]
+	fn test_log_levels() {
+		use std::sync::Mutex;
+		use host::{LogLevel, LogSink};
+		let logged = Arc::new(Mutex::new(vec![]));
+		let sink = logged.clone();
+		let host = HostEnv {
+			log_level: LogLevel::Warn,
+			log_sink: Some(LogSink::new(move |level, msg| sink.lock().unwrap().push((level, String::from(msg))))),
+			..HostEnv::default()
+		};
+		let src = "log_debug(\"a\")\nlog_info(\"b\")\nlog_warn(\"c\", 1, [\"d\"])\nlog_error(\"e\")\n";
+		let program = Compiler::new(false).compile_program(src).unwrap();
+		run_program_with(&mut GCHeap::new(), &program, &host).unwrap();
+		assert_eq!(*logged.lock().unwrap(), vec![(LogLevel::Warn, String::from("c 1 [\"d\"]")), (LogLevel::Error, String::from("e"))]);
+		assert_eq!("debug".parse::<LogLevel>().unwrap(), LogLevel::Debug);
+		assert!("verbose".parse::<LogLevel>().is_err());
+	}
+
+	#[test]
+	fn test_dump() {
+		use std::sync::Mutex;
+		use host::{LogLevel, LogSink};
+		let logged = Arc::new(Mutex::new(vec![]));
+		let sink = logged.clone();
+		let host = HostEnv {
+			log_sink: Some(LogSink::new(move |level, msg| sink.lock().unwrap().push((level, String::from(msg))))),
+			..HostEnv::default()
+		};
+		let run = |src: &str| run_program_with(&mut GCHeap::new(), &Compiler::new(false).compile_program(src).unwrap(), &host);
+		run("dump([1, \"a\"])\n").unwrap();
+		assert_eq!(*logged.lock().unwrap(), vec![(LogLevel::Info, String::from("[1, \"a\"]"))]);
+		// The compiler rejects dump() in scripts, but the native can still be called without arguments
+		let mut heap = GCHeap::new();
+		let dump_idx = prelude::list().iter().position(|(name, _)| name == "dump").unwrap();
+		let dump = GCRef::<NativeFunction>::try_from(prelude::create(&mut heap)[dump_idx].clone()).unwrap();
+		assert_eq!(dump.call(&mut heap, vec![]).err().unwrap().1, "Expected 1 argument, got 0");
+	}
 	
-	Ok(())
+	#[test]
+	fn test_stack_trace() {
+		let src = "let big = []\nfor i in range(0, 100):\n\tbig.add(i)\nlet div(a: Int, b: Int) -> Real:\n\tlet q = a / b\n\treturn q\nlet f(n: Int) -> Real:\n\tif n > 0:\n\t\treturn f(n - 1)\n\treturn div(n, n) + big.size()\nf(2)\n";
+		let program = Compiler::new(true).compile_program(src).unwrap();
+		// Traces survive serialization, and name the locals in scope at each call, most recent first
+		let program = Program::from_bytes(&program.to_bytes().unwrap()).unwrap();
+		let err = run_program(&mut GCHeap::new(), &program).err().unwrap();
+		let lines: Vec<&str> = err.1.lines().collect();
+		assert_eq!(lines[..4], ["Integer division by zero", "Stack trace (most recent call first):",
+			"  in div, line 5: a = 0, b = 0", "  in f, line 10: n = 0, f = <function>, div = <function>, big = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 1..."]);
+		assert_eq!(lines[6], "  in <main>, line 11: big = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 1..., div = <function>, f = <function>");
+		assert_eq!(err.2, 5);
+		
+		let program = Compiler::new(false).compile_program(src).unwrap();
+		assert_eq!(run_program(&mut GCHeap::new(), &program).err().unwrap().1, "Integer division by zero");
+	}
+
+	#[test]
+	fn test_snapshot_diff() {
+		let mut heap = GCHeap::new();
+		let src = "let kept = []\nlet make(n: Int):\n\tlet temp = [n, n]\n\tkept.add([n])\nfor i in range(0, 10):\n\tmake(i)\nreturn kept\n";
+		let f = Compiler::new(true).compile_function(src, &[]).unwrap();
+		let mut vm = VM::new(&mut heap, f.program().clone());
+		let before = heap.snapshot();
+		vm.run(&mut heap).unwrap();
+		heap.collect();
+		let groups = before.diff(&heap.snapshot());
+		// Only the lists still referenced by the result are left, the temporary ones were collected
+		let lists: Vec<&gc::AllocGroup> = groups.iter().filter(|group| group.type_name == "List").collect();
+		if cfg!(feature = "alloc-sites") {
+			assert_eq!(lists.len(), 2);
+			assert_eq!((lists[0].site, lists[0].count), (Some(gc::AllocSite { chunk: 1, line: 4 }), 10));
+			assert_eq!((lists[1].site, lists[1].count), (Some(gc::AllocSite { chunk: 0, line: 1 }), 1));
+		} else {
+			assert_eq!((lists[0].site, lists[0].count), (None, 11));
+		}
+		assert!(heap.snapshot().diff(&heap.snapshot()).is_empty());
+		drop(vm);
+		heap.collect();
+	}
+
+	#[test]
+	fn test_alloc_profile() {
+		let mut heap = GCHeap::new();
+		let src = "let make(n: Int) -> Int:\n\tlet temp = [n, n]\n\treturn temp.size()\nfor i in range(0, 10):\n\tmake(i)\n";
+		let mut vm = VM::new(&mut heap, Compiler::new(true).compile_program(src).unwrap());
+		heap.start_profiling();
+		vm.run(&mut heap).unwrap();
+		let groups = heap.stop_profiling();
+		// Allocations are counted even if the objects were collected since
+		let lists = groups.iter().find(|group| group.type_name == "List").unwrap();
+		assert_eq!((lists.site, lists.count), (Some(gc::AllocSite { chunk: 1, line: 2 }), 10));
+		assert!(!heap.tracks_sites() || cfg!(feature = "alloc-sites"));
+		drop(vm);
+		heap.collect();
+	}
+
+	#[test]
+	fn test_vm_stats() {
+		let mut heap = GCHeap::new();
+		let src = "let fib(n: Int) -> Int:\n\tif n < 2:\n\t\treturn n\n\treturn fib(n - 1) + fib(n - 2)\nlet l = []\nl.add(fib(10))\nlog(l)\n";
+		let program = Compiler::new(false).compile_program(src).unwrap();
+		let mut stats = VmStats::default();
+		run_program_with_stats(&mut heap, &program, &HostEnv::default(), &mut stats).unwrap();
+		assert_eq!(stats.calls, vec![1, 177]);
+		assert_eq!(stats.native_calls, 2);
+		let mix = stats.instruction_mix();
+		assert_eq!(mix.iter().find(|(name, _)| name == "Call").unwrap().1, 177 + 1);
+		assert_eq!(mix.iter().map(|(_, count)| count).sum::<u64>(), stats.total_instructions());
+		assert_eq!(stats.gc_pauses.iter().sum::<u64>() > 0, stats.gc_time > Duration::default());
+	}
+
+	#[test]
+	fn test_native_panic() {
+		let mut heap = GCHeap::new();
+		let log_idx = prelude::list().iter().position(|(name, _)| name == "log").unwrap();
+		let native = heap.make_value(NativeFunction::new(|_heap, args| panic!("Cannot log {}", args[0].repr())));
+		let program = Compiler::new(true).compile_program("let l = [1]\nlog(l)\n").unwrap();
+		for _ in 0..2 {
+			// The native can still be called after panicking
+			let mut vm = VM::new(&mut heap, program.clone());
+			vm.state.external[log_idx] = native.clone();
+			let err = vm.run(&mut heap).err().unwrap();
+			assert_eq!((err.1.as_str(), err.2), ("Native function panicked: Cannot log [1]", 2));
+		}
+		heap.collect();
+	}
 }
@@ -0,0 +1,223 @@
+
+use std::cell::RefCell;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use crate::{prim_ty, HissyError, ErrorType};
+use crate::compiler::{Type, PrimitiveType};
+use super::PendingOperation;
+use super::value::{Value, NIL};
+use super::gc::{GCHeap, GCRef, Traceable};
+use super::object::{NativeFunction, Namespace, Pending};
+
+
+fn error(s: String) -> HissyError {
+	HissyError(ErrorType::Execution, s, 0)
+}
+
+// Limits on what a script can make the host read, whatever the server sends
+const MAX_READ: usize = 64 << 10;
+const MAX_RESPONSE: usize = 16 << 20;
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+
+/// An open TCP connection, made with `tcp_connect(host, port)`.
+pub struct Connection(RefCell<Option<TcpStream>>);
+
+impl Connection {
+	fn with_stream<T>(&self, f: impl FnOnce(&mut TcpStream) -> std::io::Result<T>) -> Result<T, HissyError> {
+		let mut stream = self.0.borrow_mut();
+		let stream = stream.as_mut().ok_or_else(|| error(String::from("Connection is closed")))?;
+		f(stream).map_err(|e| error(format!("Connection error: {}", e)))
+	}
+}
+
+impl Traceable for Connection {}
+
+impl fmt::Debug for Connection {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self.0.borrow().as_ref().and_then(|stream| stream.peer_addr().ok()) {
+			Some(addr) => write!(f, "<connection to {}>", addr),
+			None => write!(f, "<closed connection>"),
+		}
+	}
+}
+
+
+// Only plain HTTP is supported; the request uses HTTP/1.0 so that the body is never chunked.
+// Responses longer than max_len bytes (including headers) are refused
+fn http_get(url: &str, max_len: usize) -> Result<String, HissyError> {
+	let rest = url.strip_prefix("http://")
+		.ok_or_else(|| error(format!("Unsupported URL '{}', only http:// URLs are supported", url)))?;
+	let (authority, path) = rest.find('/').map_or((rest, "/"), |i| (&rest[..i], &rest[i..]));
+	let (host, port) = match authority.rsplit_once(':') {
+		Some((host, port)) => (host, port.parse::<u16>().map_err(|_| error(format!("Invalid port in URL '{}'", url)))?),
+		None => (authority, 80),
+	};
+	let io_error = |e: std::io::Error| error(format!("Request to '{}' failed: {}", url, e));
+	let mut stream = TcpStream::connect((host, port)).map_err(io_error)?;
+	stream.set_read_timeout(Some(HTTP_TIMEOUT)).and_then(|_| stream.set_write_timeout(Some(HTTP_TIMEOUT))).map_err(io_error)?;
+	let request = format!("GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: hissy/{}\r\nConnection: close\r\n\r\n",
+		path, authority, env!("CARGO_PKG_VERSION"));
+	stream.write_all(request.as_bytes()).map_err(io_error)?;
+	let mut response = vec![];
+	stream.take(max_len as u64 + 1).read_to_end(&mut response).map_err(io_error)?;
+	if response.len() > max_len {
+		return Err(error(format!("Request to '{}' failed: response is larger than {} bytes", url, max_len)));
+	}
+
+	let malformed = || error(format!("Malformed HTTP response from '{}'", url));
+	let head_end = response.windows(4).position(|w| w == b"\r\n\r\n").ok_or_else(malformed)?;
+	let head = String::from_utf8_lossy(&response[..head_end]);
+	let status: u16 = head.lines().next().and_then(|line| line.split(' ').nth(1))
+		.and_then(|status| status.parse().ok()).ok_or_else(malformed)?;
+	if !(200..300).contains(&status) {
+		return Err(error(format!("Request to '{}' failed with HTTP status {}", url, status)));
+	}
+	Ok(String::from_utf8_lossy(&response[head_end+4..]).into_owned())
+}
+
+// Performs an operation started by one of the network natives, once the host has allowed it
+pub(super) fn perform(heap: &mut GCHeap, op: &PendingOperation) -> Result<Value, HissyError> {
+	match op.name.as_str() {
+		"tcp_connect" => {
+			let host = op.args[0].as_str().unwrap();
+			let port = i32::try_from(&op.args[1]).unwrap();
+			let stream = TcpStream::connect((&*host, port as u16))
+				.map_err(|e| error(format!("Unable to connect to {}:{}: {}", &*host, port, e)))?;
+			Ok(heap.make_value(Connection(RefCell::new(Some(stream)))))
+		},
+		"tcp_read" => {
+			let conn = GCRef::<Connection>::try_from(op.args[0].clone()).unwrap();
+			let mut buf = vec![0; (i32::try_from(&op.args[1]).unwrap() as usize).min(MAX_READ)];
+			let n = conn.with_stream(|stream| stream.read(&mut buf))?;
+			Ok(heap.make_string(&String::from_utf8_lossy(&buf[..n])))
+		},
+		"tcp_write" => {
+			let conn = GCRef::<Connection>::try_from(op.args[0].clone()).unwrap();
+			let data = op.args[1].as_str().unwrap();
+			conn.with_stream(|stream| stream.write_all(data.as_bytes()))?;
+			Ok(NIL)
+		},
+		"http_get" => {
+			let url = op.args[0].as_str().unwrap();
+			Ok(heap.alloc_str_from(http_get(&url, MAX_RESPONSE)?))
+		},
+		_ => unreachable!(),
+	}
+}
+
+pub(crate) fn list() -> Vec<(String, Type)> {
+	vec![
+		(String::from("Connection"), Type::Namespace(vec![
+			(String::from("read"), Type::TypedFunction(vec![prim_ty!(Int)], Box::new(prim_ty!(String)))),
+			(String::from("write"), Type::TypedFunction(vec![prim_ty!(String)], Box::new(prim_ty!(Nil)))),
+			(String::from("close"), Type::TypedFunction(vec![], Box::new(prim_ty!(Nil)))),
+		])),
+		(String::from("tcp_connect"), Type::TypedFunction(vec![prim_ty!(String), prim_ty!(Int)], Box::new(Type::Connection))),
+		(String::from("http_get"), Type::TypedFunction(vec![prim_ty!(String)], Box::new(prim_ty!(String)))),
+	]
+}
+
+// All operations except closing connections are performed by the host, which can deny them (see HostEnv)
+pub(crate) fn create(heap: &mut GCHeap) -> Vec<Value> {
+	let mut res = vec![];
+
+	// Returns at most the given number of bytes (and at most 64 KiB), as soon as some are available,
+	// or an empty string at the end of the stream; invalid UTF-8, including characters split between reads, is replaced
+	let conn_read = heap.make_value(NativeFunction::new(|heap, args| {
+		if i32::try_from(&args[1]).map_or(true, |max| max <= 0) {
+			return Err(error(format!("Expected positive byte count, got {}", args[1].repr())));
+		}
+		Ok(heap.make_value(Pending { name: String::from("tcp_read"), args }))
+	}));
+	let conn_write = heap.make_value(NativeFunction::new(|heap, args| {
+		if !args[1].is_string() {
+			return Err(error(format!("Expected string value, got {}", args[1].repr())));
+		}
+		Ok(heap.make_value(Pending { name: String::from("tcp_write"), args }))
+	}));
+	let conn_close = heap.make_value(NativeFunction::new(|_heap, args| {
+		let this = GCRef::<Connection>::try_from(args[0].clone()).unwrap();
+		this.0.borrow_mut().take();
+		Ok(NIL)
+	}));
+	res.push(heap.make_value(Namespace(vec![ conn_read, conn_write, conn_close ])));
+
+	res.push(heap.make_value(NativeFunction::new(|heap, args| {
+		if args.len() != 2 || !args[0].is_string() || i32::try_from(&args[1]).map_or(true, |port| !(0..=65535).contains(&port)) {
+			return Err(error(String::from("Expected a host name and a port number")));
+		}
+		Ok(heap.make_value(Pending { name: String::from("tcp_connect"), args }))
+	})));
+	res.push(heap.make_value(NativeFunction::new(|heap, args| {
+		if args.len() != 1 || !args[0].is_string() {
+			return Err(error(String::from("Expected a URL")));
+		}
+		Ok(heap.make_value(Pending { name: String::from("http_get"), args }))
+	})));
+
+	res
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::net::TcpListener;
+	use std::thread;
+	use crate::compiler::Compiler;
+	use crate::vm::{run_program_with, host::HostEnv};
+
+	#[test]
+	fn test_http_get() {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let port = listener.local_addr().unwrap().port();
+		let server = thread::spawn(move || {
+			for status in &["200 OK", "404 Not Found", "200 OK"] {
+				let (mut stream, _) = listener.accept().unwrap();
+				let mut request = vec![];
+				while !request.ends_with(b"\r\n\r\n") {
+					let mut buf = [0; 256];
+					let n = stream.read(&mut buf).unwrap();
+					request.extend_from_slice(&buf[..n]);
+				}
+				assert!(request.starts_with(b"GET /hello HTTP/1.0\r\n"));
+				write!(stream, "HTTP/1.0 {}\r\nContent-Type: text/plain\r\n\r\nHello!", status).unwrap();
+			}
+		});
+		let url = format!("http://127.0.0.1:{}/hello", port);
+		assert_eq!(http_get(&url, MAX_RESPONSE).unwrap(), "Hello!");
+		assert!(http_get(&url, MAX_RESPONSE).err().unwrap().1.ends_with("failed with HTTP status 404"));
+		assert!(http_get(&url, 20).err().unwrap().1.ends_with("failed: response is larger than 20 bytes"));
+		server.join().unwrap();
+
+		assert!(http_get("https://example.com/", MAX_RESPONSE).is_err());
+	}
+
+	#[test]
+	fn test_tcp_script() {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let port = listener.local_addr().unwrap().port();
+		let server = thread::spawn(move || {
+			let (mut stream, _) = listener.accept().unwrap();
+			let mut buf = [0; 5];
+			stream.read_exact(&mut buf).unwrap();
+			stream.write_all(&buf.to_ascii_uppercase()).unwrap();
+		});
+		// The reply may be split between several reads
+		let src = format!("let conn = tcp_connect(\"127.0.0.1\", {})\nconn.write(\"hello\")\n\
+			let expected = \"HELLO\".iter()\nlet count = 0\nwhile count < 5:\n\tfor c in conn.read(2000000000).iter():\n\
+			\t\tassert(c == expected.next())\n\t\tcount = count + 1\nconn.close()\n", port);
+		let program = Compiler::new(false).compile_program(&src).unwrap();
+		let host = HostEnv { allow_net: true, ..HostEnv::default() };
+		assert_eq!(run_program_with(&mut GCHeap::new(), &program, &host).unwrap(), None);
+		server.join().unwrap();
+
+		let err = run_program_with(&mut GCHeap::new(), &program, &HostEnv::default()).err().unwrap();
+		assert_eq!(err.1, "Native 'tcp_connect' is not allowed by the host");
+	}
+}
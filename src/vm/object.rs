@@ -1,10 +1,13 @@
 
 use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::ops::{Deref, DerefMut};
 use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
 
 use crate::{HissyError, ErrorType};
-use super::value::Value;
+use super::value::{self, Value};
 use super::gc::{GCHeap, Traceable, GC, GCRef};
 
 
@@ -13,7 +16,11 @@ fn error(s: String) -> HissyError {
 }
 
 
-impl Traceable for String {}
+impl Traceable for String {
+	fn external_size(&self) -> usize {
+		self.capacity()
+	}
+}
 
 impl Traceable for Vec<Value> {
 	fn touch(&self, initial: bool) {
@@ -21,6 +28,10 @@ impl Traceable for Vec<Value> {
 			el.touch(initial);
 		}
 	}
+	
+	fn external_size(&self) -> usize {
+		self.capacity() * std::mem::size_of::<Value>()
+	}
 }
 
 impl<T: GC> Traceable for Vec<GCRef<T>> {
@@ -74,12 +85,12 @@ impl fmt::Debug for Upvalue {
 
 
 pub(super) struct Closure {
-	pub chunk_id: u8,
+	pub chunk_id: usize,
 	pub upvalues: Vec<GCRef<Upvalue>>,
 }
 
 impl Closure {
-	pub fn new(chunk_id: u8, upvalues: Vec<GCRef<Upvalue>>) -> Closure {
+	pub fn new(chunk_id: usize, upvalues: Vec<GCRef<Upvalue>>) -> Closure {
 		Closure { chunk_id, upvalues }
 	}
 }
@@ -110,8 +121,16 @@ impl NativeFunction {
 		}
 	}
 	
+	// Panics are turned into execution errors, so that they do not unwind through the interpreter loop;
+	// the borrow of the function and the values it held are released while unwinding, so the VM stays usable
 	pub fn call(&self, heap: &mut GCHeap, args: Vec<Value>) -> Result<Value, HissyError> {
-		self.fun.borrow_mut().deref_mut()(heap, args)
+		panic::catch_unwind(AssertUnwindSafe(|| self.fun.borrow_mut().deref_mut()(heap, args)))
+			.unwrap_or_else(|payload| {
+				let msg = payload.downcast_ref::<&str>().copied()
+					.or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+					.unwrap_or("unknown cause");
+				Err(error(format!("Native function panicked: {}", msg)))
+			})
 	}
 }
 
@@ -124,9 +143,25 @@ impl fmt::Debug for NativeFunction {
 }
 
 
+/// Returned by a native function to suspend the calling script,
+/// until the host performs the operation and resumes it with the result.
+#[derive(Debug)]
+pub struct Pending {
+	pub name: String,
+	pub args: Vec<Value>,
+}
+
+impl Traceable for Pending {
+	fn touch(&self, initial: bool) {
+		self.args.touch(initial);
+	}
+}
+
+
 #[derive(Default)]
 pub struct List {
-	data: RefCell<Vec<Value>>
+	data: RefCell<Vec<Value>>,
+	frozen: Cell<bool>,
 }
 
 impl List {
@@ -138,6 +173,24 @@ impl List {
 		self.data.borrow().len()
 	}
 	
+	/// Makes the list immutable. This cannot be undone.
+	pub fn freeze(&self) {
+		self.frozen.set(true);
+	}
+	
+	pub fn is_frozen(&self) -> bool {
+		self.frozen.get()
+	}
+	
+	/// Returns an error if the list is frozen.
+	pub fn check_mutable(&self) -> Result<(), HissyError> {
+		if self.is_frozen() {
+			Err(error(String::from("Cannot modify frozen list")))
+		} else {
+			Ok(())
+		}
+	}
+	
 	pub fn extend(&self, values: &[Value]) {
 		let mut data = self.data.borrow_mut();
 		let start = data.len();
@@ -153,6 +206,7 @@ impl List {
 	}
 	
 	pub fn set(&self, idx: usize, val: Value) -> Result<(), HissyError> {
+		self.check_mutable()?;
 		let mut data = self.data.borrow_mut();
 		let val2 = data.get_mut(idx)
 			.ok_or_else(|| error(format!("Can't set value at index {} in list of length {}", idx, self.len())))?;
@@ -163,18 +217,58 @@ impl List {
 	pub fn get_copy(&self) -> Vec<Value> {
 		self.data.borrow().clone()
 	}
+	
+	/// Returns a new, unfrozen list containing the same elements.
+	pub fn shallow_clone(&self) -> List {
+		List { data: RefCell::new(self.get_copy()), frozen: Cell::new(false) }
+	}
+	
+	/// Copies a value, recursively copying the lists it contains into new, unfrozen lists.
+	/// 
+	/// A list referenced several times (including through a cycle) is only copied once,
+	/// so the copy has the same structure as the original.
+	pub fn deep_clone(heap: &mut GCHeap, val: &Value) -> Value {
+		fn clone_rec(heap: &mut GCHeap, val: &Value, copies: &mut HashMap<*const (), Value>) -> Value {
+			if let Ok(list) = GCRef::<List>::try_from(val.clone()) {
+				let key = list.pointer as *const ();
+				if let Some(copy) = copies.get(&key) {
+					return copy.clone();
+				}
+				let copy = heap.make_ref(List::new());
+				copies.insert(key, Value::from(copy.clone()));
+				let values: Vec<Value> = list.get_copy().iter().map(|val| clone_rec(heap, val, copies)).collect();
+				heap.grow(&*copy, |copy| copy.extend(&values));
+				Value::from(copy)
+			} else {
+				val.clone()
+			}
+		}
+		clone_rec(heap, val, &mut HashMap::new())
+	}
 }
 
 impl Traceable for List {
 	fn touch(&self, initial: bool) {
 		self.data.borrow().touch(initial);
 	}
+	
+	fn external_size(&self) -> usize {
+		self.data.borrow().external_size()
+	}
 }
 
 impl fmt::Debug for List {
 	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> fmt::Result {
+		let (options, depth) = value::repr_context();
+		if depth > options.max_depth && !self.data.borrow().is_empty() {
+			return write!(f, "[...]");
+		}
 		write!(f, "[")?;
 		for (i, val) in self.data.borrow().iter().enumerate() {
+			if i == options.max_items {
+				write!(f, "...")?;
+				break;
+			}
 			write!(f, "{}", val.repr())?;
 			if i != self.len()-1 {
 				write!(f, ", ")?;
@@ -295,7 +389,11 @@ impl GCIterator for VecIterator {
 mod tests {
 	#![allow(clippy::blacklisted_name)]
 	
-	use super::super::gc::GCHeap;
+	use super::super::gc::{GCHeap, HandleScope};
+	use std::convert::TryFrom;
+	use super::List;
+	use super::super::gc::GCRef;
+	use super::super::value::Value;
 	
 	#[test]
 	fn test_vec_ref() {
@@ -364,4 +462,98 @@ mod tests {
 		heap.inspect();
 		assert!(heap.is_empty());
 	}
+	
+	#[test]
+	fn test_handle_scope() {
+		let mut heap = GCHeap::new();
+		let list = {
+			let scope = HandleScope::new(&mut heap);
+			let foo = scope.make(String::from("foo"));
+			let list = scope.make(List::new());
+			scope.heap().collect();
+			list.extend(&[foo.to_value()]);
+			scope.heap().collect();
+			assert_eq!(foo.as_str(), "foo");
+			list.to_ref()
+		};
+		heap.collect();
+		assert_eq!(list.get(0).unwrap().repr(), "\"foo\"");
+		drop(list);
+		heap.collect();
+		assert!(heap.is_empty());
+	}
+	
+	#[test]
+	fn test_frozen_list() {
+		let list = List::new();
+		list.extend(&[Value::from(1)]);
+		list.freeze();
+		assert!(list.check_mutable().is_err());
+		assert!(list.set(0, Value::from(2)).is_err());
+		assert_eq!(i32::try_from(&list.get(0).unwrap()), Ok(1));
+	}
+	
+	#[test]
+	fn test_deep_clone() {
+		let mut heap = GCHeap::new();
+		let inner = heap.make_value(List::new());
+		let outer = heap.make_ref(List::new());
+		outer.extend(&[inner.clone(), inner.clone()]);
+		outer.extend(&[Value::from(outer.clone())]);
+		outer.freeze();
+		
+		let copy = GCRef::<List>::try_from(List::deep_clone(&mut heap, &Value::from(outer.clone()))).unwrap();
+		assert!(!copy.is_frozen() && copy.len() == 3);
+		assert!(copy.get(0).unwrap() == copy.get(1).unwrap() && copy.get(0).unwrap() != inner);
+		assert!(copy.get(2).unwrap() == Value::from(copy.clone()));
+		
+		let shallow = outer.shallow_clone();
+		assert!(shallow.get(0).unwrap() == inner && !shallow.is_frozen());
+		
+		drop((inner, outer, copy, shallow));
+		heap.collect();
+		assert!(heap.is_empty());
+	}
+	
+	#[test]
+	fn test_disable_collection() {
+		let mut heap = GCHeap::new();
+		heap.disable();
+		for _ in 0..100 {
+			heap.make_value(String::from("foo"));
+			heap.step();
+		}
+		assert_eq!(heap.object_count(), 100);
+		heap.enable();
+		heap.step();
+		assert!(heap.is_empty());
+	}
+	
+	#[test]
+	fn test_external_memory() {
+		let mut heap = GCHeap::new();
+		let before = heap.used_memory();
+		let s = heap.make_value(String::from("x").repeat(10_000));
+		assert!(heap.used_memory() >= before + 10_000);
+		drop(s);
+		heap.collect();
+		assert!(heap.used_memory() < 10_000);
+		
+		heap.track_external(50_000);
+		heap.collect();
+		assert!(heap.used_memory() >= 50_000);
+		heap.untrack_external(50_000);
+		assert!(heap.used_memory() < 10_000);
+		
+		// Lists growing after their allocation
+		let list = heap.make_ref(List::new());
+		let before = heap.used_memory();
+		for i in 0..10_000 {
+			heap.grow(&*list, |list| list.extend(&[Value::from(i)]));
+		}
+		assert!(heap.used_memory() >= before + 10_000 * std::mem::size_of::<Value>());
+		drop(list);
+		heap.collect();
+		assert!(heap.used_memory() < 10_000);
+	}
 }
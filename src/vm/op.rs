@@ -1,7 +1,15 @@
 use std::convert::TryFrom;
 
+use crate::{HissyError, ErrorType};
+use crate::parser::symbol;
 use super::value::{Value, ValueType::*};
 use super::gc::GCWrapper;
+use super::vector;
+
+
+fn error_str(s: &str) -> HissyError {
+	HissyError(ErrorType::Execution, String::from(s), 0)
+}
 
 enum NumPair {
 	Ints(i32, i32),
@@ -30,8 +38,11 @@ macro_rules! basic_num_op {
 ///
 /// If the internal types of the `Value`s aren't compatible, `None` will be returned.
 /// 
+/// Integer division and modulo by zero are runtime errors, while the real versions follow IEEE 754
+/// (e.g. `1.0 / 0.0` is infinity, and `1.0 % 0.0` is NaN).
+/// 
 /// `Value`s of any type can be compared for equality. Two values of different types will always be unequal,
-/// except for examples such as 2 == 2.0.
+/// except for examples such as 2 == 2.0. Vectors are compared component-wise, other objects by identity.
 impl Value {
 	
 	/// Returns whether the `Value` is numeric, ie. contains an integer or real.
@@ -73,27 +84,44 @@ impl Value {
 	basic_num_op!(sub, |a,b| a - b);
 	basic_num_op!(mul, |a,b| a * b);
 	
-	pub fn div(&self, other: &Value) -> Option<Value> {
-		if !self.is_numeric() || !other.is_numeric() { return None; }
-		Some(Value::from(self.cast_real() / other.cast_real()))
+	/// Divides `self` by `other`. The result is always a real, even for two integers.
+	/// 
+	/// Dividing an integer by zero is an error.
+	pub fn div(&self, other: &Value) -> Result<Option<Value>, HissyError> {
+		match self.get_num_pair(other) {
+			NumPair::Ints(_, 0) => Err(error_str("Integer division by zero")),
+			NumPair::Ints(i1, i2) => Ok(Some(Value::from(f64::from(i1) / f64::from(i2)))),
+			NumPair::Reals(r1, r2) => Ok(Some(Value::from(r1 / r2))),
+			NumPair::NaN => Ok(None),
+		}
 	}
 	
+	/// Raises `self` to the power `other`.
+	/// 
+	/// The result is always a real, as the compiler expects. An integer raised to a non-negative
+	/// integer power is still computed exactly when the result fits in an integer, instead of going
+	/// through `powf`; a negative integer exponent or an overflowing result use `powf`.
 	pub fn pow(&self, other: &Value) -> Option<Value> {
-		if !self.is_numeric() || !other.is_numeric() { return None; }
-		Some(Value::from(self.cast_real().powf(other.cast_real())))
+		match self.get_num_pair(other) {
+			NumPair::Ints(i1, i2) => Some(Value::from(
+				u32::try_from(i2).ok().and_then(|e| i1.checked_pow(e))
+					.map_or_else(|| f64::from(i1).powf(f64::from(i2)), f64::from)
+			)),
+			NumPair::Reals(r1, r2) => Some(Value::from(r1.powf(r2))),
+			NumPair::NaN => None,
+		}
 	}
 	
-	pub fn modulo(&self, other: &Value) -> Option<Value> {
+	/// Computes the Euclidean remainder of `self` divided by `other`.
+	/// 
+	/// The result is always non-negative, whatever the signs of the operands:
+	/// `-7 % 3 == 2`, and `7 % -3 == 1`. Taking an integer modulo zero is an error.
+	pub fn modulo(&self, other: &Value) -> Result<Option<Value>, HissyError> {
 		match self.get_num_pair(other) {
-			NumPair::Ints(i1, i2) => Some(Value::from({
-				let r = i1 % i2;
-				if r < 0 { r + i2.abs() } else { r }
-			})),
-			NumPair::Reals(r1, r2) => Some(Value::from({
-				let r = r1 % r2;
-				if r < 0.0 { r + r2.abs() } else { r }
-			})),
-			NumPair::NaN => None,
+			NumPair::Ints(_, 0) => Err(error_str("Integer modulo by zero")),
+			NumPair::Ints(i1, i2) => Ok(Some(Value::from(i1.wrapping_rem_euclid(i2)))),
+			NumPair::Reals(r1, r2) => Ok(Some(Value::from(r1.rem_euclid(r2)))),
+			NumPair::NaN => Ok(None),
 		}
 	}
 	
@@ -134,8 +162,14 @@ impl PartialEq for Value {
 			(Bool, Bool) => bool::try_from(self).unwrap() == bool::try_from(other).unwrap(),
 			(Int, Int) => i32::try_from(self).unwrap() == i32::try_from(other).unwrap(),
 			(Real, Real) => f64::try_from(self).unwrap() == f64::try_from(other).unwrap(),
+			(Char, Char) => char::try_from(self).unwrap() == char::try_from(other).unwrap(),
+			(Symbol, Symbol) => symbol::Symbol::try_from(self).unwrap() == symbol::Symbol::try_from(other).unwrap(),
 			_ =>
-				if let (Some(p1), Some(p2)) = (self.get_pointer(), other.get_pointer()) {
+				if let Some(eq) = vector::eq(self, other) {
+					eq
+				} else if let (Some(a), Some(b)) = (self.as_str(), other.as_str()) { // Inline or object strings
+					*a == *b
+				} else if let (Some(p1), Some(p2)) = (self.get_pointer(), other.get_pointer()) {
 					p1 as *const GCWrapper == p2 as *const GCWrapper
 				} else {
 					false
@@ -143,3 +177,99 @@ impl PartialEq for Value {
 		}
 	}
 }
+
+
+#[cfg(test)]
+mod tests {
+	use std::convert::TryFrom;
+	use super::*;
+	
+	fn unwrap_checked(v: Result<Option<Value>, HissyError>) -> Option<Value> {
+		v.expect("Operation raised an error")
+	}
+	
+	fn int(v: Option<Value>) -> i32 {
+		i32::try_from(&v.expect("Operation failed")).expect("Result is not an integer")
+	}
+	
+	fn real(v: Option<Value>) -> f64 {
+		f64::try_from(&v.expect("Operation failed")).expect("Result is not a real")
+	}
+	
+	#[test]
+	fn test_int_pow() {
+		let pow = |a: i32, b: i32| real(Value::from(a).pow(&Value::from(b)));
+		assert_eq!(pow(2, 10), 1024.0);
+		assert_eq!(pow(-3, 3), -27.0);
+		assert_eq!(pow(7, 0), 1.0);
+		assert_eq!(pow(0, 0), 1.0);
+		assert_eq!(pow(-1, i32::MAX), -1.0);
+		assert_eq!(pow(3, 19), 1_162_261_467.0);
+		assert_eq!(pow(-2, 31), f64::from(i32::MIN));
+	}
+	
+	#[test]
+	fn test_int_pow_promotion() {
+		let pow = |a: i32, b: i32| real(Value::from(a).pow(&Value::from(b)));
+		assert_eq!(pow(2, 31), 2_147_483_648.0);
+		assert_eq!(pow(10, 12), 1e12);
+		assert_eq!(pow(2, -1), 0.5);
+		assert_eq!(pow(0, -1), f64::INFINITY);
+	}
+	
+	#[test]
+	fn test_real_pow() {
+		assert_eq!(real(Value::from(2.0).pow(&Value::from(0.5))), 2f64.sqrt());
+		assert_eq!(real(Value::from(4).pow(&Value::from(0.5))), 2.0);
+		assert_eq!(real(Value::from(1.5).pow(&Value::from(2))), 2.25);
+		assert!(real(Value::from(-8.0).pow(&Value::from(1.0 / 3.0))).is_nan());
+	}
+	
+	#[test]
+	fn test_int_modulo() {
+		let modulo = |a: i32, b: i32| int(unwrap_checked(Value::from(a).modulo(&Value::from(b))));
+		assert_eq!(modulo(7, 3), 1);
+		assert_eq!(modulo(-7, 3), 2);
+		assert_eq!(modulo(7, -3), 1);
+		assert_eq!(modulo(-7, -3), 2);
+		assert_eq!(modulo(6, 3), 0);
+		assert_eq!(modulo(-6, 3), 0);
+		assert_eq!(modulo(i32::MIN, -1), 0);
+		assert_eq!(modulo(i32::MIN, i32::MAX), i32::MAX - 1);
+	}
+	
+	#[test]
+	fn test_real_modulo() {
+		let modulo = |a: f64, b: f64| real(unwrap_checked(Value::from(a).modulo(&Value::from(b))));
+		assert_eq!(modulo(7.5, 2.0), 1.5);
+		assert_eq!(modulo(-7.5, 2.0), 0.5);
+		assert_eq!(modulo(7.5, -2.0), 1.5);
+		assert_eq!(modulo(-7.5, -2.0), 0.5);
+		assert_eq!(real(unwrap_checked(Value::from(-7).modulo(&Value::from(2.0)))), 1.0);
+	}
+	
+	#[test]
+	fn test_int_div() {
+		let div = |a: i32, b: i32| real(unwrap_checked(Value::from(a).div(&Value::from(b))));
+		assert_eq!(div(7, 2), 3.5);
+		assert_eq!(div(-7, 2), -3.5);
+		assert_eq!(div(6, -3), -2.0);
+		assert_eq!(div(i32::MIN, -1), 2_147_483_648.0);
+	}
+	
+	#[test]
+	fn test_division_by_zero() {
+		assert!(Value::from(1).div(&Value::from(0)).is_err());
+		assert!(Value::from(1).modulo(&Value::from(0)).is_err());
+		assert_eq!(real(unwrap_checked(Value::from(1.0).div(&Value::from(0.0)))), f64::INFINITY);
+		assert_eq!(real(unwrap_checked(Value::from(-1).div(&Value::from(0.0)))), -f64::INFINITY);
+		assert!(real(unwrap_checked(Value::from(0.0).div(&Value::from(0.0)))).is_nan());
+		assert!(real(unwrap_checked(Value::from(1.0).modulo(&Value::from(0.0)))).is_nan());
+	}
+	
+	#[test]
+	fn test_non_numeric() {
+		assert!(Value::from(true).pow(&Value::from(2)).is_none());
+		assert!(unwrap_checked(Value::from(2).modulo(&Value::from(false))).is_none());
+	}
+}
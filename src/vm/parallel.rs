@@ -0,0 +1,85 @@
+
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+use crate::{HissyError, ErrorType};
+use super::{VMState, LoadedCode, prelude};
+use super::gc::{GCHeap, GCRef};
+use super::value::Value;
+use super::object::{Closure, List};
+use super::channel::Message;
+
+
+fn error_str(s: &str) -> HissyError {
+	HissyError(ErrorType::Execution, String::from(s), 0)
+}
+
+
+/// Maps a function over a list on a pool of worker threads, each with its own heap.
+///
+/// The list is split into one contiguous shard per worker, and the results are merged in order.
+/// Since workers do not share the caller's heap, the function cannot capture variables,
+/// and its arguments and results must be plain data, as with channels.
+///
+/// Workers stop when the calling VM is interrupted; the interruption is then consumed,
+/// as if the VM had raised it itself.
+pub(super) fn map(heap: &mut GCHeap, code: &LoadedCode, interrupt: &AtomicBool, list: &Value, func: &Value) -> Result<Value, HissyError> {
+	let list = GCRef::<List>::try_from(list.clone())
+		.map_err(|_| error_str("par_map expects a list"))?;
+	let func = GCRef::<Closure>::try_from(func.clone())
+		.map_err(|_| error_str("par_map expects a Hissy function"))?;
+	if !func.upvalues.is_empty() {
+		return Err(error_str("par_map function cannot capture variables"));
+	}
+	code.check_arity(&func, 1)?;
+	let chunk_id = func.chunk_id;
+
+	let inputs: Result<Vec<Message>, HissyError> = list.get_copy().iter().map(Message::from_value).collect();
+	let inputs = inputs?;
+	let workers = thread::available_parallelism().map_or(1, |n| n.get());
+	let shard_size = inputs.len().div_ceil(workers).max(1);
+
+	let outputs: Result<Vec<Vec<Message>>, HissyError> = thread::scope(|scope| {
+		let shards: Vec<_> = inputs.chunks(shard_size)
+			.map(|shard| scope.spawn(move || run_shard(code, interrupt, chunk_id, shard)))
+			.collect();
+		shards.into_iter().map(|shard| shard.join().expect("par_map worker panicked")).collect()
+	});
+	if let Err(HissyError(ErrorType::Interrupt, _, _)) = outputs {
+		interrupt.store(false, Ordering::Relaxed);
+	}
+
+	let results: Vec<Value> = outputs?.iter().flatten().map(|msg| msg.to_value(heap)).collect();
+	let res = List::new();
+	res.extend(&results);
+	Ok(heap.make_value(res))
+}
+
+fn run_shard(code: &LoadedCode, interrupt: &AtomicBool, chunk_id: usize, shard: &[Message]) -> Result<Vec<Message>, HissyError> {
+	let mut heap = GCHeap::new();
+	let external = prelude::create(&mut heap);
+	let func = heap.make_ref(Closure::new(chunk_id, vec![]));
+
+	let res = shard.iter().map(|arg| {
+		let mut state = VMState::new();
+		state.external = external.clone();
+		state.regs.registers.push(arg.to_value(&mut heap));
+		state.call(code, func.clone(), 0, None);
+		while !state.execute(&mut heap, code)? {
+			// Only the calling VM clears the flag, so that all workers see it
+			if interrupt.load(Ordering::Relaxed) {
+				return Err(HissyError(ErrorType::Interrupt, String::from("Script was interrupted"), 0));
+			}
+			if state.pending.is_some() {
+				return Err(error_str("par_map function cannot wait on pending operations"));
+			}
+			heap.step();
+		}
+		Message::from_value(&state.result)
+	}).collect();
+
+	drop((external, func));
+	heap.collect();
+	res
+}
@@ -2,32 +2,140 @@
 use std::convert::TryFrom;
 use std::cell::RefCell;
 use std::iter::Iterator;
+use std::time::Duration;
 
 use crate::{prim_ty, HissyError, ErrorType};
 use crate::compiler::{Type, PrimitiveType};
 use crate::vm::gc::{GCHeap, GCRef};
 use crate::vm::value::{Value, NIL};
-use crate::vm::object::{NativeFunction, List, Namespace, IteratorWrapper, VecIterator};
+use crate::vm::object::{NativeFunction, List, Namespace, IteratorWrapper, VecIterator, Pending};
+use crate::vm::vector::{Vec2, Vec3};
+use crate::vm::channel::{Channel, Message};
+use crate::vm::bytes::Bytes;
 
 fn error(s: String) -> HissyError {
 	HissyError(ErrorType::Execution, s, 0)
 }
 
+// Checks the argument count, and casts all arguments to reals
+fn real_args(args: &[Value], n: usize) -> Result<Vec<f64>, HissyError> {
+	if args.len() != n {
+		return Err(error(format!("Expected {} arguments, got {}", n, args.len())));
+	}
+	args.iter().map(|arg| {
+		if arg.is_numeric() {
+			Ok(arg.cast_real())
+		} else {
+			Err(error(format!("Expected numeric value, got {:?}", arg)))
+		}
+	}).collect()
+}
+
+// Optional sets of natives, listed after the others in this order, so that their external indices
+// depend on which are enabled
+const OPTIONAL_NATIVES: &[(&str, bool)] = &[
+	("tensor", cfg!(feature = "tensor")),
+	("net", cfg!(feature = "net")),
+	("gc-control", cfg!(feature = "gc-control")),
+];
+
+/// Returns the optional sets of natives included in the prelude, as a bit set which is stored
+/// in bytecode files, since compiled code refers to natives by index.
+pub fn optional_natives() -> u8 {
+	OPTIONAL_NATIVES.iter().enumerate()
+		.filter(|(_, (_, enabled))| *enabled)
+		.fold(0, |bits, (i, _)| bits | 1 << i)
+}
+
+/// Lists the features providing a bit set of optional natives.
+pub fn describe_optional_natives(bits: u8) -> String {
+	let names: Vec<&str> = OPTIONAL_NATIVES.iter().enumerate()
+		.filter(|(i, _)| bits & 1 << i != 0)
+		.map(|(_, (name, _))| *name)
+		.collect();
+	if names.is_empty() { String::from("none") } else { names.join(", ") }
+}
+
 pub fn list() -> Vec<(String, Type)> {
-	vec![
+	#[allow(unused_mut)]
+	let mut list = vec![
 		(String::from("List"), Type::Namespace(vec![
 			(String::from("size"), Type::TypedFunction(vec![], Box::new(prim_ty!(Int)))),
 			(String::from("add"), Type::TypedFunction(vec![Type::Any], Box::new(prim_ty!(Nil)))),
 			(String::from("iter"), Type::TypedFunction(vec![], Box::new(Type::Iterator(Box::new(Type::Any))))),
+			(String::from("clone"), Type::TypedFunction(vec![], Box::new(Type::List(Box::new(Type::Any))))),
+			(String::from("deep_clone"), Type::TypedFunction(vec![], Box::new(Type::List(Box::new(Type::Any))))),
 		])),
 		(String::from("Iterator"), Type::Namespace(vec![
 			(String::from("next"), Type::TypedFunction(vec![], Box::new(Type::Any))),
 		])),
+		(String::from("String"), Type::Namespace(vec![
+			(String::from("iter"), Type::TypedFunction(vec![], Box::new(Type::Iterator(Box::new(prim_ty!(Char)))))),
+		])),
 		(String::from("log"), Type::UntypedFunction(Box::new(prim_ty!(Nil)))),
+		(String::from("dump"), Type::TypedFunction(vec![Type::Any], Box::new(prim_ty!(Nil)))),
 		(String::from("range"), Type::TypedFunction(vec![prim_ty!(Int), prim_ty!(Int)], Box::new(Type::Iterator(Box::new(prim_ty!(Int)))))),
 		(String::from("int"), Type::TypedFunction(vec![Type::Any], Box::new(prim_ty!(Int)))),
 		(String::from("string"), Type::TypedFunction(vec![Type::Any], Box::new(prim_ty!(String)))),
-	]
+		(String::from("char"), Type::TypedFunction(vec![Type::Any], Box::new(prim_ty!(Char)))),
+		(String::from("assert"), Type::TypedFunction(vec![prim_ty!(Bool)], Box::new(prim_ty!(Nil)))),
+		(String::from("fma"), Type::TypedFunction(vec![Type::Any, Type::Any, Type::Any], Box::new(prim_ty!(Real)))),
+		(String::from("hypot"), Type::TypedFunction(vec![Type::Any, Type::Any], Box::new(prim_ty!(Real)))),
+		(String::from("clamp"), Type::TypedFunction(vec![Type::Any, Type::Any, Type::Any], Box::new(prim_ty!(Real)))),
+		(String::from("lerp"), Type::TypedFunction(vec![Type::Any, Type::Any, Type::Any], Box::new(prim_ty!(Real)))),
+		(String::from("Vec2"), Type::Namespace(vec![
+			(String::from("x"), Type::TypedFunction(vec![], Box::new(prim_ty!(Real)))),
+			(String::from("y"), Type::TypedFunction(vec![], Box::new(prim_ty!(Real)))),
+			(String::from("length"), Type::TypedFunction(vec![], Box::new(prim_ty!(Real)))),
+			(String::from("dot"), Type::TypedFunction(vec![Type::Vector(2)], Box::new(prim_ty!(Real)))),
+			(String::from("normalized"), Type::TypedFunction(vec![], Box::new(Type::Vector(2)))),
+		])),
+		(String::from("Vec3"), Type::Namespace(vec![
+			(String::from("x"), Type::TypedFunction(vec![], Box::new(prim_ty!(Real)))),
+			(String::from("y"), Type::TypedFunction(vec![], Box::new(prim_ty!(Real)))),
+			(String::from("z"), Type::TypedFunction(vec![], Box::new(prim_ty!(Real)))),
+			(String::from("length"), Type::TypedFunction(vec![], Box::new(prim_ty!(Real)))),
+			(String::from("dot"), Type::TypedFunction(vec![Type::Vector(3)], Box::new(prim_ty!(Real)))),
+			(String::from("normalized"), Type::TypedFunction(vec![], Box::new(Type::Vector(3)))),
+			(String::from("cross"), Type::TypedFunction(vec![Type::Vector(3)], Box::new(Type::Vector(3)))),
+		])),
+		(String::from("vec2"), Type::TypedFunction(vec![Type::Any, Type::Any], Box::new(Type::Vector(2)))),
+		(String::from("vec3"), Type::TypedFunction(vec![Type::Any, Type::Any, Type::Any], Box::new(Type::Vector(3)))),
+		(String::from("sleep"), Type::TypedFunction(vec![Type::Any], Box::new(prim_ty!(Nil)))),
+		(String::from("env"), Type::TypedFunction(vec![prim_ty!(String)], Box::new(Type::Any))),
+		(String::from("exit"), Type::TypedFunction(vec![prim_ty!(Int)], Box::new(prim_ty!(Nil)))),
+		(String::from("exec"), Type::TypedFunction(vec![prim_ty!(String), Type::List(Box::new(Type::Any))], Box::new(prim_ty!(String)))),
+		(String::from("log_debug"), Type::UntypedFunction(Box::new(prim_ty!(Nil)))),
+		(String::from("log_info"), Type::UntypedFunction(Box::new(prim_ty!(Nil)))),
+		(String::from("log_warn"), Type::UntypedFunction(Box::new(prim_ty!(Nil)))),
+		(String::from("log_error"), Type::UntypedFunction(Box::new(prim_ty!(Nil)))),
+		(String::from("Channel"), Type::Namespace(vec![
+			(String::from("send"), Type::TypedFunction(vec![Type::Any], Box::new(prim_ty!(Nil)))),
+			(String::from("recv"), Type::TypedFunction(vec![], Box::new(Type::Any))),
+			(String::from("try_recv"), Type::TypedFunction(vec![], Box::new(Type::Any))),
+		])),
+		(String::from("channel"), Type::TypedFunction(vec![prim_ty!(String)], Box::new(Type::Channel))),
+		(String::from("Bytes"), Type::Namespace(vec![
+			(String::from("size"), Type::TypedFunction(vec![], Box::new(prim_ty!(Int)))),
+			(String::from("get"), Type::TypedFunction(vec![prim_ty!(Int)], Box::new(prim_ty!(Int)))),
+			(String::from("set"), Type::TypedFunction(vec![prim_ty!(Int), prim_ty!(Int)], Box::new(prim_ty!(Nil)))),
+		])),
+		(String::from("bytes"), Type::TypedFunction(vec![prim_ty!(Int)], Box::new(Type::Bytes))),
+		(String::from("freeze"), Type::TypedFunction(vec![Type::Any], Box::new(prim_ty!(Nil)))),
+		(String::from("par_map"), Type::TypedFunction(vec![Type::Any, Type::Any], Box::new(Type::List(Box::new(Type::Any))))),
+	];
+	#[cfg(feature = "tensor")]
+	list.extend(crate::vm::tensor::list());
+	#[cfg(feature = "net")]
+	list.extend(crate::vm::net::list());
+	#[cfg(feature = "gc-control")]
+	list.extend(vec![
+		(String::from("gc_collect"), Type::TypedFunction(vec![], Box::new(prim_ty!(Nil)))),
+		(String::from("gc_stats"), Type::TypedFunction(vec![], Box::new(Type::List(Box::new(prim_ty!(Int)))))),
+		(String::from("gc_disable"), Type::TypedFunction(vec![], Box::new(prim_ty!(Nil)))),
+		(String::from("gc_enable"), Type::TypedFunction(vec![], Box::new(prim_ty!(Nil)))),
+	]);
+	list
 }
 
 pub fn create(heap: &mut GCHeap) -> Vec<Value> {
@@ -37,9 +145,10 @@ pub fn create(heap: &mut GCHeap) -> Vec<Value> {
 		let this = GCRef::<List>::try_from(args[0].clone()).unwrap();
 		Ok(Value::from(this.len() as i32))
 	}));
-	let list_add = heap.make_value(NativeFunction::new(|_heap, args| {
+	let list_add = heap.make_value(NativeFunction::new(|heap, args| {
 		let this = GCRef::<List>::try_from(args[0].clone()).unwrap();
-		this.extend(&[ args[1].clone() ]);
+		this.check_mutable()?;
+		heap.grow(&*this, |list| list.extend(&[ args[1].clone() ]));
 		Ok(NIL)
 	}));
 	let list_iter = heap.make_value(NativeFunction::new(|heap, args| {
@@ -50,8 +159,15 @@ pub fn create(heap: &mut GCHeap) -> Vec<Value> {
 			))
 		}))
 	}));
+	let list_clone = heap.make_value(NativeFunction::new(|heap, args| {
+		let this = GCRef::<List>::try_from(args[0].clone()).unwrap();
+		Ok(heap.make_value(this.shallow_clone()))
+	}));
+	let list_deep_clone = heap.make_value(NativeFunction::new(|heap, args| {
+		Ok(List::deep_clone(heap, &args[0]))
+	}));
 	res.push(heap.make_value(
-		Namespace(vec![ list_size, list_add, list_iter ])
+		Namespace(vec![ list_size, list_add, list_iter, list_clone, list_deep_clone ])
 	));
 	
 	let iter_next = heap.make_value(NativeFunction::new(|heap, args| {
@@ -62,6 +178,16 @@ pub fn create(heap: &mut GCHeap) -> Vec<Value> {
 		Namespace(vec![ iter_next ])
 	));
 	
+	let string_iter = heap.make_value(NativeFunction::new(|heap, args| {
+		let chars: Vec<char> = args[0].as_str().unwrap().chars().collect();
+		Ok(heap.make_value(IteratorWrapper {
+			iter: Box::new(RefCell::new(chars.into_iter()))
+		}))
+	}));
+	res.push(heap.make_value(
+		Namespace(vec![ string_iter ])
+	));
+	
 	res.push(heap.make_value(
 		NativeFunction::new(|_heap, args| {
 			let mut it = args.iter();
@@ -76,6 +202,18 @@ pub fn create(heap: &mut GCHeap) -> Vec<Value> {
 		})
 	));
 	
+	// Prints one value, with nested lists indented over several lines;
+	// it is logged at the info level, so the host decides where it goes like for log_info()
+	res.push(heap.make_value(
+		NativeFunction::new(|heap, args| {
+			if args.len() != 1 {
+				return Err(error(format!("Expected 1 argument, got {}", args.len())));
+			}
+			let text = heap.alloc_str_from(args[0].pretty(2));
+			Ok(heap.make_value(Pending { name: String::from("log"), args: vec![Value::from(1), text] }))
+		})
+	));
+	
 	res.push(heap.make_value(
 		NativeFunction::new(|heap, args| {
 			if args.len() != 2 {
@@ -99,6 +237,8 @@ pub fn create(heap: &mut GCHeap) -> Vec<Value> {
 			}
 			if i32::try_from(&args[0]).is_ok() {
 				Ok(args[0].clone())
+			} else if let Ok(c) = char::try_from(&args[0]) {
+				Ok(Value::from(c as i32))
 			} else {
 				Err(error(format!("Expected integer value, got {:?}", &args[0])))
 			}
@@ -109,13 +249,305 @@ pub fn create(heap: &mut GCHeap) -> Vec<Value> {
 			if args.len() != 1 {
 				return Err(error(format!("Expected 1 argument, got {}", args.len())));
 			}
-			if GCRef::<String>::try_from(args[0].clone()).is_ok() {
+			if args[0].is_string() {
 				Ok(args[0].clone())
 			} else {
 				Err(error(format!("Expected string value, got {:?}", &args[0])))
 			}
 		})
 	));
+	res.push(heap.make_value(
+		NativeFunction::new(|_heap, args| {
+			if args.len() != 1 {
+				return Err(error(format!("Expected 1 argument, got {}", args.len())));
+			}
+			if let Ok(c) = char::try_from(&args[0]) {
+				Ok(Value::from(c))
+			} else if let Ok(i) = i32::try_from(&args[0]) {
+				let c = u32::try_from(i).ok().and_then(char::from_u32)
+					.ok_or_else(|| error(format!("Invalid character code point {}", i)))?;
+				Ok(Value::from(c))
+			} else {
+				Err(error(format!("Expected integer or character value, got {:?}", &args[0])))
+			}
+		})
+	));
+	res.push(heap.make_value(
+		NativeFunction::new(|_heap, args| {
+			if args.len() != 1 {
+				return Err(error(format!("Expected 1 argument, got {}", args.len())));
+			}
+			match bool::try_from(&args[0]) {
+				Ok(true) => Ok(NIL),
+				Ok(false) => Err(error(String::from("Assertion failed"))),
+				Err(_) => Err(error(format!("Expected boolean value, got {:?}", &args[0]))),
+			}
+		})
+	));
+	
+	// Numeric intrinsics
+	res.push(heap.make_value(
+		NativeFunction::new(|_heap, args| {
+			let args = real_args(&args, 3)?;
+			Ok(Value::from(args[0].mul_add(args[1], args[2])))
+		})
+	));
+	res.push(heap.make_value(
+		NativeFunction::new(|_heap, args| {
+			let args = real_args(&args, 2)?;
+			Ok(Value::from(args[0].hypot(args[1])))
+		})
+	));
+	res.push(heap.make_value(
+		NativeFunction::new(|_heap, args| {
+			let args = real_args(&args, 3)?;
+			let (x, min, max) = (args[0], args[1], args[2]);
+			if min.is_nan() || max.is_nan() || min > max {
+				return Err(error(format!("Invalid clamping interval [{}, {}]", min, max)));
+			}
+			Ok(Value::from(if x < min { min } else if x > max { max } else { x }))
+		})
+	));
+	res.push(heap.make_value(
+		NativeFunction::new(|_heap, args| {
+			let args = real_args(&args, 3)?;
+			let (a, b, t) = (args[0], args[1], args[2]);
+			Ok(Value::from(t.mul_add(b - a, a)))
+		})
+	));
+	
+	// Vectors
+	macro_rules! vector_methods {
+		($ty:ident, $($idx:literal),*) => {{
+			let mut methods = vec![];
+			$(
+				methods.push(heap.make_value(NativeFunction::new(|_heap, args| {
+					let this = GCRef::<$ty>::try_from(args[0].clone()).unwrap();
+					Ok(Value::from(this.0[$idx]))
+				})));
+			)*
+			methods.push(heap.make_value(NativeFunction::new(|_heap, args| {
+				let this = GCRef::<$ty>::try_from(args[0].clone()).unwrap();
+				Ok(Value::from(this.length()))
+			})));
+			methods.push(heap.make_value(NativeFunction::new(|_heap, args| {
+				let this = GCRef::<$ty>::try_from(args[0].clone()).unwrap();
+				let other = GCRef::<$ty>::try_from(args[1].clone())
+					.map_err(|_| error(format!("Expected {} value, got {:?}", stringify!($ty), &args[1])))?;
+				Ok(Value::from(this.dot(&other)))
+			})));
+			methods.push(heap.make_value(NativeFunction::new(|heap, args| {
+				let this = GCRef::<$ty>::try_from(args[0].clone()).unwrap();
+				let length = this.length();
+				let mut res = this.0;
+				for x in res.iter_mut() {
+					*x /= length;
+				}
+				Ok(heap.make_value($ty(res)))
+			})));
+			methods
+		}};
+	}
+	
+	let vec2_methods = vector_methods!(Vec2, 0, 1);
+	res.push(heap.make_value(Namespace(vec2_methods)));
+	let mut vec3_methods = vector_methods!(Vec3, 0, 1, 2);
+	vec3_methods.push(heap.make_value(NativeFunction::new(|heap, args| {
+		let this = GCRef::<Vec3>::try_from(args[0].clone()).unwrap();
+		let other = GCRef::<Vec3>::try_from(args[1].clone())
+			.map_err(|_| error(format!("Expected Vec3 value, got {:?}", &args[1])))?;
+		Ok(heap.make_value(this.cross(&other)))
+	})));
+	res.push(heap.make_value(Namespace(vec3_methods)));
+	
+	res.push(heap.make_value(
+		NativeFunction::new(|heap, args| {
+			let args = real_args(&args, 2)?;
+			Ok(heap.make_value(Vec2([args[0], args[1]])))
+		})
+	));
+	res.push(heap.make_value(
+		NativeFunction::new(|heap, args| {
+			let args = real_args(&args, 3)?;
+			Ok(heap.make_value(Vec3([args[0], args[1], args[2]])))
+		})
+	));
+	
+	// Suspends the script; the host is responsible for waiting
+	res.push(heap.make_value(
+		NativeFunction::new(|heap, args| {
+			let secs = real_args(&args, 1)?[0];
+			if Duration::try_from_secs_f64(secs).is_err() {
+				return Err(error(format!("Invalid sleep duration {}", args[0].repr())));
+			}
+			Ok(heap.make_value(Pending { name: String::from("sleep"), args }))
+		})
+	));
+	
+	// Natives acting on the host process, which performs them if it allows them (see HostEnv)
+	res.push(heap.make_value(
+		NativeFunction::new(|heap, args| {
+			if args.len() != 1 || !args[0].is_string() {
+				return Err(error(String::from("Expected a variable name")));
+			}
+			Ok(heap.make_value(Pending { name: String::from("env"), args }))
+		})
+	));
+	res.push(heap.make_value(
+		NativeFunction::new(|heap, args| {
+			if args.len() != 1 || i32::try_from(&args[0]).is_err() {
+				return Err(error(String::from("Expected an integer exit code")));
+			}
+			Ok(heap.make_value(Pending { name: String::from("exit"), args }))
+		})
+	));
+	res.push(heap.make_value(
+		NativeFunction::new(|heap, args| {
+			let cmd_args = args.get(1).and_then(|list| GCRef::<List>::try_from(list.clone()).ok());
+			let valid = args.len() == 2 && args[0].is_string()
+				&& cmd_args.is_some_and(|list| list.get_copy().iter().all(Value::is_string));
+			if !valid {
+				return Err(error(String::from("Expected a command and a list of string arguments")));
+			}
+			Ok(heap.make_value(Pending { name: String::from("exec"), args }))
+		})
+	));
+	
+	// Logging with a level (debug, info, warn, error); the host filters the messages and decides where they go.
+	// Unlike log(), strings are written without quotes
+	for level in 0..4 {
+		res.push(heap.make_value(
+			NativeFunction::new(move |heap, args| {
+				let parts: Vec<String> = args.iter()
+					.map(|arg| arg.as_str().map_or_else(|| arg.repr(), |s| String::from(&*s)))
+					.collect();
+				let message = heap.alloc_str_from(parts.join(" "));
+				Ok(heap.make_value(Pending { name: String::from("log"), args: vec![Value::from(level), message] }))
+			})
+		));
+	}
+	
+	// Channels; recv() suspends the script if no message is available yet
+	let channel_send = heap.make_value(NativeFunction::new(|_heap, args| {
+		let this = GCRef::<Channel>::try_from(args[0].clone()).unwrap();
+		this.send(Message::from_value(&args[1])?);
+		Ok(NIL)
+	}));
+	let channel_recv = heap.make_value(NativeFunction::new(|heap, args| {
+		let this = GCRef::<Channel>::try_from(args[0].clone()).unwrap();
+		match this.try_recv() {
+			Some(msg) => Ok(msg.to_value(heap)),
+			None => Ok(heap.make_value(Pending { name: String::from("recv"), args })),
+		}
+	}));
+	let channel_try_recv = heap.make_value(NativeFunction::new(|heap, args| {
+		let this = GCRef::<Channel>::try_from(args[0].clone()).unwrap();
+		Ok(this.try_recv().map_or(NIL, |msg| msg.to_value(heap)))
+	}));
+	res.push(heap.make_value(
+		Namespace(vec![ channel_send, channel_recv, channel_try_recv ])
+	));
+	res.push(heap.make_value(
+		NativeFunction::new(|heap, args| {
+			if args.len() != 1 {
+				return Err(error(format!("Expected 1 argument, got {}", args.len())));
+			}
+			let name = args[0].as_str()
+				.ok_or_else(|| error(format!("Expected string value, got {:?}", &args[0])))?;
+			Ok(heap.make_value(Channel::open(&name)))
+		})
+	));
+	
+	// Byte buffers, whose bytes are read and written as integers between 0 and 255
+	fn byte_index(this: &Bytes, idx: &Value) -> Result<usize, HissyError> {
+		let idx = i32::try_from(idx).map_err(|_| error(format!("Expected integer index, got {}", idx.repr())))?;
+		usize::try_from(idx).ok().filter(|idx| *idx < this.len())
+			.ok_or_else(|| error(format!("Index {} out of bounds for {} bytes", idx, this.len())))
+	}
+	let bytes_size = heap.make_value(NativeFunction::new(|_heap, args| {
+		let this = GCRef::<Bytes>::try_from(args[0].clone()).unwrap();
+		Ok(Value::from(this.len() as i32))
+	}));
+	let bytes_get = heap.make_value(NativeFunction::new(|_heap, args| {
+		let this = GCRef::<Bytes>::try_from(args[0].clone()).unwrap();
+		let idx = byte_index(&this, &args[1])?;
+		let byte = this.as_slice()[idx];
+		Ok(Value::from(i32::from(byte)))
+	}));
+	let bytes_set = heap.make_value(NativeFunction::new(|_heap, args| {
+		let this = GCRef::<Bytes>::try_from(args[0].clone()).unwrap();
+		this.check_mutable()?;
+		let idx = byte_index(&this, &args[1])?;
+		let byte = i32::try_from(&args[2]).ok().and_then(|b| u8::try_from(b).ok())
+			.ok_or_else(|| error(format!("Expected byte value between 0 and 255, got {}", args[2].repr())))?;
+		this.as_mut_slice()[idx] = byte;
+		Ok(NIL)
+	}));
+	res.push(heap.make_value(
+		Namespace(vec![ bytes_size, bytes_get, bytes_set ])
+	));
+	res.push(heap.make_value(
+		NativeFunction::new(|heap, args| {
+			let size = args.first().and_then(|size| i32::try_from(size).ok()).and_then(|size| usize::try_from(size).ok())
+				.ok_or_else(|| error(String::from("Expected a non-negative size")))?;
+			Ok(heap.make_value(Bytes::zeroed(size)))
+		})
+	));
+	
+	// Other values are either immutable, or cannot be frozen yet
+	res.push(heap.make_value(
+		NativeFunction::new(|_heap, args| {
+			if args.len() != 1 {
+				return Err(error(format!("Expected 1 argument, got {}", args.len())));
+			}
+			if let Ok(list) = GCRef::<List>::try_from(args[0].clone()) {
+				list.freeze();
+			} else if let Ok(bytes) = GCRef::<Bytes>::try_from(args[0].clone()) {
+				bytes.freeze();
+			}
+			Ok(NIL)
+		})
+	));
+	
+	// Performed by the VM on worker threads, since it needs to run the function
+	res.push(heap.make_value(
+		NativeFunction::new(|heap, args| {
+			if args.len() != 2 {
+				return Err(error(format!("Expected 2 arguments, got {}", args.len())));
+			}
+			Ok(heap.make_value(Pending { name: String::from("par_map"), args }))
+		})
+	));
+	
+	#[cfg(feature = "tensor")]
+	res.extend(crate::vm::tensor::create(heap));
+	#[cfg(feature = "net")]
+	res.extend(crate::vm::net::create(heap));
+	
+	// Garbage collection controls; all live values are rooted during native calls, so collecting is safe
+	#[cfg(feature = "gc-control")]
+	{
+		res.push(heap.make_value(NativeFunction::new(|heap, _args| {
+			heap.collect();
+			Ok(NIL)
+		})));
+		// Returns [used memory in bytes, collection threshold in bytes, object count]
+		res.push(heap.make_value(NativeFunction::new(|heap, _args| {
+			let stats = [heap.used_memory(), heap.threshold(), heap.object_count()];
+			let stats: Vec<Value> = stats.iter().map(|x| Value::from(i32::try_from(*x).unwrap_or(i32::MAX))).collect();
+			let list = List::new();
+			list.extend(&stats);
+			Ok(heap.make_value(list))
+		})));
+		res.push(heap.make_value(NativeFunction::new(|heap, _args| {
+			heap.disable();
+			Ok(NIL)
+		})));
+		res.push(heap.make_value(NativeFunction::new(|heap, _args| {
+			heap.enable();
+			Ok(NIL)
+		})));
+	}
 	
 	res
 }
@@ -0,0 +1,125 @@
+
+use std::any::TypeId;
+use std::collections::HashMap;
+use std::sync::{RwLock, OnceLock};
+
+use super::gc::GC;
+use super::object::*;
+use super::vector::{Vec2, Vec3};
+use super::channel::Channel;
+use super::bytes::Bytes;
+
+
+/// The name and stable identifier of a GC object type.
+///
+/// Unlike `TypeId`s, these stay the same across program versions and platforms,
+/// so they can be used to identify objects in serialized heaps or host userdata.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TypeInfo {
+	pub name: &'static str,
+	pub id: u32,
+}
+
+// FNV-1a, which is simple and guaranteed to stay the same
+fn stable_id(name: &str) -> u32 {
+	name.bytes().fold(0x811c_9dc5, |hash, b| (hash ^ u32::from(b)).wrapping_mul(0x0100_0193))
+}
+
+#[derive(Default)]
+struct Registry {
+	types: HashMap<TypeId, TypeInfo>,
+	ids: HashMap<u32, TypeId>,
+}
+
+impl Registry {
+	fn add(&mut self, type_id: TypeId, name: &'static str) -> Result<TypeInfo, TypeInfo> {
+		let info = TypeInfo { name, id: stable_id(name) };
+		if let Some(other) = self.ids.get(&info.id) {
+			return if *other == type_id { Ok(info) } else { Err(self.types[other]) };
+		}
+		if let Some(prev) = self.types.get(&type_id) {
+			return Err(*prev);
+		}
+		self.types.insert(type_id, info);
+		self.ids.insert(info.id, type_id);
+		Ok(info)
+	}
+}
+
+fn registry() -> &'static RwLock<Registry> {
+	static REGISTRY: OnceLock<RwLock<Registry>> = OnceLock::new();
+	REGISTRY.get_or_init(|| {
+		let mut reg = Registry::default();
+		let builtins = [
+			(TypeId::of::<String>(), "String"),
+			(TypeId::of::<List>(), "List"),
+			(TypeId::of::<Closure>(), "Function"),
+			(TypeId::of::<NativeFunction>(), "NativeFunction"),
+			(TypeId::of::<Method>(), "Method"),
+			(TypeId::of::<Namespace>(), "Namespace"),
+			(TypeId::of::<IteratorWrapper>(), "Iterator"),
+			(TypeId::of::<Upvalue>(), "Upvalue"),
+			(TypeId::of::<Pending>(), "Pending"),
+			(TypeId::of::<Vec2>(), "Vec2"),
+			(TypeId::of::<Vec3>(), "Vec3"),
+			(TypeId::of::<Channel>(), "Channel"),
+			(TypeId::of::<Bytes>(), "Bytes"),
+			#[cfg(feature = "tensor")]
+			(TypeId::of::<super::tensor::Tensor>(), "Tensor"),
+			#[cfg(feature = "net")]
+			(TypeId::of::<super::net::Connection>(), "Connection"),
+		];
+		for (type_id, name) in builtins.iter() {
+			reg.add(*type_id, name).expect("Conflicting built-in type names");
+		}
+		RwLock::new(reg)
+	})
+}
+
+/// Registers a GC object type under a stable name.
+///
+/// Fails, returning the conflicting registration, if the name (or its identifier)
+/// is already used by another type, or if the type was registered under another name.
+pub fn register_type<T: GC>(name: &'static str) -> Result<TypeInfo, TypeInfo> {
+	registry().write().unwrap().add(TypeId::of::<T>(), name)
+}
+
+/// Returns the registration of a type, if it has been registered.
+pub fn type_info(type_id: TypeId) -> Option<TypeInfo> {
+	registry().read().unwrap().types.get(&type_id).copied()
+}
+
+/// Finds the type registered with a stable identifier.
+pub fn type_by_id(id: u32) -> Option<(TypeId, TypeInfo)> {
+	let reg = registry().read().unwrap();
+	reg.ids.get(&id).map(|type_id| (*type_id, reg.types[type_id]))
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::vm::gc::GCHeap;
+	use crate::vm::value::{Value, NIL};
+
+	#[derive(Debug)]
+	struct Userdata;
+	impl crate::vm::gc::Traceable for Userdata {}
+
+	#[test]
+	fn test_type_registry() {
+		let list = type_info(TypeId::of::<List>()).unwrap();
+		assert_eq!(list, TypeInfo { name: "List", id: stable_id("List") });
+		assert_eq!(type_by_id(list.id).unwrap().0, TypeId::of::<List>());
+
+		assert!(register_type::<Userdata>("List").is_err());
+		let info = register_type::<Userdata>("Userdata").unwrap();
+		assert_eq!(register_type::<Userdata>("Userdata"), Ok(info));
+		assert!(register_type::<Userdata>("Userdata2").is_err());
+
+		let mut heap = GCHeap::new();
+		let vals = vec![NIL, Value::from(1), heap.make_value(List::new()), heap.make_value(Userdata)];
+		let names: Vec<&str> = vals.iter().map(Value::type_name).collect();
+		assert_eq!(names, vec!["Nil", "Int", "List", "Userdata"]);
+	}
+}
@@ -0,0 +1,82 @@
+
+use crate::{HissyError, ErrorType};
+use crate::vm::gc::GCHeap;
+use crate::vm::value::Value;
+use crate::vm::object::NativeFunction;
+use crate::vm::prelude;
+
+
+fn error(s: String) -> HissyError {
+	HissyError(ErrorType::Execution, s, 0)
+}
+
+// Natives which interact with the world outside of the script: time, threads and other isolates, the GC,
+// the host process, and the network
+const STRICT_DENIED: &[&str] = &["sleep", "channel", "par_map", "gc_collect", "gc_stats", "gc_disable", "gc_enable", "env", "exit", "exec",
+	"tcp_connect", "http_get"];
+
+/// Restrictions on what a script running in a [`VM`](super::VM) is allowed to do,
+/// set with [`VM::set_sandbox`](super::VM::set_sandbox).
+///
+/// The default sandbox imposes no restrictions. Limits which are exceeded make the script
+/// fail with an execution error.
+#[derive(Debug, Clone, Default)]
+pub struct Sandbox {
+	/// Names of the natives which raise an error when called
+	pub denied_natives: Vec<String>,
+	/// Maximum memory usage of the GC heap, in bytes, checked after a collection
+	pub memory_limit: Option<usize>,
+	/// Maximum number of instructions executed in total
+	pub fuel_limit: Option<usize>,
+	/// Maximum depth of nested function calls
+	pub recursion_limit: Option<usize>,
+}
+
+impl Sandbox {
+	/// A sandbox for running untrusted scripts: natives giving access to time, threads, channels,
+	/// the garbage collector, the host process and the network are denied, and memory usage, execution time and recursion are limited.
+	///
+	/// Hissy has no file or random facilities, but `import` statements and `embed()` expressions read files
+	/// when compiling: untrusted scripts should be compiled with [`Compiler::set_untrusted`](crate::compiler::Compiler::set_untrusted).
+	pub fn strict() -> Sandbox {
+		Sandbox {
+			denied_natives: STRICT_DENIED.iter().map(|name| String::from(*name)).collect(),
+			memory_limit: Some(64 << 20),
+			fuel_limit: Some(100_000_000),
+			recursion_limit: Some(200),
+		}
+	}
+
+	// Whether the sandbox restricts anything; par_map workers cannot enforce restrictions, so it is refused then
+	pub(super) fn is_restrictive(&self) -> bool {
+		!self.denied_natives.is_empty() || self.memory_limit.is_some() || self.fuel_limit.is_some() || self.recursion_limit.is_some()
+	}
+
+	// Replaces denied natives in the list of external values
+	pub(super) fn apply(&self, heap: &mut GCHeap, external: &mut [Value]) {
+		for (i, (name, _)) in prelude::list().into_iter().enumerate() {
+			if self.denied_natives.contains(&name) {
+				external[i] = heap.make_value(NativeFunction::new(move |_heap, _args| {
+					Err(error(format!("Native '{}' is not allowed in this sandbox", name)))
+				}));
+			}
+		}
+	}
+
+	// Checks the limits after executing an instruction
+	pub(super) fn check(&self, heap: &mut GCHeap, fuel_used: usize, call_depth: usize) -> Result<(), HissyError> {
+		if let Some(limit) = self.fuel_limit.filter(|limit| fuel_used > *limit) {
+			return Err(error(format!("Fuel limit of {} instructions exceeded", limit)));
+		}
+		if let Some(limit) = self.recursion_limit.filter(|limit| call_depth > *limit) {
+			return Err(error(format!("Recursion limit of {} nested calls exceeded", limit)));
+		}
+		if let Some(limit) = self.memory_limit.filter(|limit| heap.used_memory() > *limit) {
+			heap.collect(); // Only live objects count towards the limit
+			if heap.used_memory() > limit {
+				return Err(error(format!("Memory limit of {}B exceeded", limit)));
+			}
+		}
+		Ok(())
+	}
+}
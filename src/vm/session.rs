@@ -0,0 +1,231 @@
+
+use std::cell::RefCell;
+use std::convert::TryFrom;
+use std::rc::Rc;
+
+use crate::{HissyError, ErrorType};
+use crate::compiler::{Compiler, CompiledFunction, Program, Type, ModuleResolver};
+use crate::compiler::analysis::{self, Completion};
+use crate::parser::{ast::Block, lexer::BlockStyle, symbol::Symbol};
+use super::{VM, run_until_exit, with_trace};
+use super::gc::{GCHeap, GCRef};
+use super::host::HostEnv;
+use super::object::List;
+use super::value::Value;
+
+
+fn error_str(s: &str) -> HissyError {
+	HissyError(ErrorType::Execution, String::from(s), 0)
+}
+
+/// The result of evaluating an input in a [`Session`].
+#[derive(Debug)]
+pub enum Evaluation {
+	/// The value of the last expression of the input, or nil if it does not end with an expression.
+	Value(Value),
+	/// The script called `exit` with this code.
+	Exit(i32),
+}
+
+// Lets the compiler of each input use the resolver of the session
+struct SharedResolver(Rc<RefCell<dyn ModuleResolver>>);
+
+impl ModuleResolver for SharedResolver {
+	fn resolve(&mut self, name: &str, importer: Option<&str>) -> Result<(String, String), String> {
+		self.0.borrow_mut().resolve(name, importer)
+	}
+
+	fn embed(&mut self, path: &str, importer: Option<&str>) -> Result<String, String> {
+		self.0.borrow_mut().embed(path, importer)
+	}
+}
+
+/// An interactive session, in which Hissy code is compiled and run one input at a time, as in a shell.
+///
+/// The variables, functions and enums declared at the top level of an input are visible in the following
+/// inputs. Each input is compiled as a function taking the bindings of the session as arguments,
+/// so functions see the values their captured variables had at the end of the input which defined them.
+/// Inputs are all loaded into the same VM, so that functions defined by previous inputs can be called.
+pub struct Session {
+	bindings: Vec<(Symbol, Type, Value)>,
+	enums: Block, // Declarations of the enums of previous inputs
+	last: Option<Program>,
+	vm: Option<VM>,
+	style: BlockStyle,
+	resolver: Option<Rc<RefCell<dyn ModuleResolver>>>,
+	host: HostEnv,
+	heap: GCHeap, // Declared last, so that the bindings are dropped first
+}
+
+impl Session {
+	/// Creates an empty session, which is not allowed to do anything on the host process.
+	pub fn new() -> Session {
+		Session {
+			bindings: vec![],
+			enums: vec![],
+			last: None,
+			vm: None,
+			style: BlockStyle::Indentation,
+			resolver: None,
+			host: HostEnv::default(),
+			heap: GCHeap::new(),
+		}
+	}
+
+	/// Sets the block style of the inputs.
+	pub fn set_block_style(&mut self, style: BlockStyle) {
+		self.style = style;
+	}
+
+	/// Sets the resolver used to import modules and embed files in inputs.
+	pub fn set_resolver(&mut self, resolver: impl ModuleResolver + 'static) {
+		self.resolver = Some(Rc::new(RefCell::new(resolver)));
+	}
+
+	/// Sets what inputs are allowed to do on the host process.
+	pub fn set_host(&mut self, host: HostEnv) {
+		self.host = host;
+	}
+
+	/// Compiles and runs an input.
+	///
+	/// If it fails, the bindings of the session are left as they were before the input,
+	/// but changes it made to existing objects are kept.
+	pub fn eval(&mut self, input: &str) -> Result<Evaluation, HissyError> {
+		let params: Vec<(Symbol, Type)> = self.bindings.iter().map(|(id, ty, _)| (*id, ty.clone())).collect();
+		let (program, enums, names) = self.compiler().compile_session_input(input, &self.enums, &params)?;
+		self.last = Some(program.clone());
+
+		let args = self.bindings.iter().map(|(_, _, val)| val.clone()).collect();
+		let vm = match &mut self.vm {
+			Some(vm) => {
+				vm.start_next(&mut self.heap, program, args);
+				vm
+			},
+			None => self.vm.insert(VM::with_args(&mut self.heap, program, args)),
+		};
+		let res = run_until_exit(&mut self.heap, vm, &self.host);
+		let res = with_trace(vm, res)?;
+		let values = GCRef::<List>::try_from(vm.result().clone()).ok()
+			.map(|list| list.get_copy())
+			.filter(|values| res.is_none() && values.len() == names.len() + 1);
+		let evaluation = match (res, values) {
+			(Some(code), _) => Evaluation::Exit(code),
+			(None, Some(values)) => {
+				self.enums.extend(enums);
+				self.bindings = names.into_iter().zip(values[1..].iter().cloned()).map(|((id, ty), val)| (id, ty, val)).collect();
+				Evaluation::Value(values[0].clone())
+			},
+			(None, None) => return Err(error_str("Cannot return from the top level of a session")),
+		};
+		self.heap.collect();
+		Ok(evaluation)
+	}
+
+	/// Compiles a snippet in the scope of the session without running it, eg. to find the type of an expression
+	/// or to inspect its bytecode.
+	///
+	/// The snippet is compiled as with [`Compiler::compile_function`], with the bindings of the session as parameters.
+	pub fn compile_snippet(&self, input: &str) -> Result<CompiledFunction, HissyError> {
+		let params: Vec<(&str, Type)> = self.bindings.iter().map(|(id, ty, _)| (&**id, ty.clone())).collect();
+		self.compiler().compile_function_in(input, &self.enums, &params)
+	}
+
+	fn compiler(&self) -> Compiler {
+		let mut compiler = Compiler::new(true);
+		compiler.set_block_style(self.style);
+		if let Some(resolver) = &self.resolver {
+			compiler.set_resolver(SharedResolver(resolver.clone()));
+		}
+		compiler
+	}
+
+	/// Lists the bindings of the session, with their types and values.
+	pub fn bindings(&self) -> impl Iterator<Item = (&str, &Type, &Value)> {
+		self.bindings.iter().map(|(id, ty, val)| (&**id, ty, val))
+	}
+
+	/// Lists the bindings in scope at a byte offset in a new input, like
+	/// [`completions_with`](crate::compiler::analysis::completions_with); those of the session are local.
+	pub fn completions(&self, input: &str, offset: usize) -> Result<Vec<Completion>, HissyError> {
+		let params = self.bindings.iter().map(|(id, ty, _)| (*id, ty.clone())).collect();
+		analysis::completions_in(input, offset, self.style, params)
+	}
+
+	/// Returns the program compiled from the last input, even if it failed.
+	pub fn last_program(&self) -> Option<&Program> {
+		self.last.as_ref()
+	}
+
+	/// Returns the heap holding the values of the session.
+	pub fn heap(&self) -> &GCHeap {
+		&self.heap
+	}
+
+	/// Forgets all bindings and enums, and frees the values which are no longer referenced.
+	pub fn reset(&mut self) {
+		self.bindings.clear();
+		self.enums.clear();
+		self.last = None;
+		self.vm = None;
+		self.heap.collect();
+	}
+}
+
+impl Default for Session {
+	fn default() -> Session {
+		Session::new()
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::convert::TryFrom;
+
+	fn eval_int(session: &mut Session, input: &str) -> i32 {
+		match session.eval(input).unwrap() {
+			Evaluation::Value(val) => i32::try_from(&val).unwrap(),
+			Evaluation::Exit(_) => panic!("Unexpected exit"),
+		}
+	}
+
+	#[test]
+	fn test_session() {
+		let mut session = Session::new();
+		assert!(matches!(session.eval("let x = 2\nlet l = []\n").unwrap(), Evaluation::Value(val) if val.is_nil()));
+		assert_eq!(eval_int(&mut session, "x * 3"), 6);
+
+		// Functions, reassignments, and objects are kept between inputs
+		eval_int(&mut session, "let double(n: Int) -> Int:\n\treturn 2 * n\nx = double(x)\nl.add(x)\n0");
+		assert_eq!(eval_int(&mut session, "l.size() + x"), 5);
+		assert_eq!(eval_int(&mut session, "double(x)"), 8);
+		let names: Vec<&str> = session.bindings().map(|(name, _, _)| name).collect();
+		assert_eq!(names, vec!["double", "l", "x"]);
+
+		// Failed inputs leave the bindings unchanged, and types are still checked
+		assert!(session.eval("let y = 1\nassert(false)\n").is_err());
+		assert!(session.eval("y").is_err());
+		assert!(matches!(session.eval("x = \"a\"").err().unwrap().0, ErrorType::Compilation));
+
+		// Redefinitions replace the binding
+		assert_eq!(eval_int(&mut session, "let l = 3\nl + x"), 7);
+
+		session.eval("enum Color: Red, Green\nlet c = Color.Red\n").unwrap();
+		assert_eq!(eval_int(&mut session, "match c:\n\tColor.Red:\n\t\t1\n\tColor.Green:\n\t\t2\n0"), 0);
+
+		// Snippets are compiled in the scope of the session, but not run
+		let snippet = session.compile_snippet("l * double(x)").unwrap();
+		assert_eq!(format!("{:?}", snippet.ret_type()), "Int");
+		assert!(session.compile_snippet("x + \"a\"").is_err());
+		assert_eq!(format!("{:?}", session.compile_snippet("Color.Green").unwrap().ret_type()), "Color");
+
+		let completions = session.completions("let z = 1\n", 10).unwrap();
+		assert!(completions.iter().any(|c| c.name == "double" && c.kind == analysis::BindingKind::Local));
+
+		session.reset();
+		assert!(session.eval("x").is_err());
+		assert!(session.last_program().is_none());
+	}
+}
@@ -0,0 +1,80 @@
+
+use std::convert::TryFrom;
+use std::time::Duration;
+
+use super::instr::InstrType;
+
+
+/// Upper bounds of the buckets of the GC pause histogram; the last bucket counts longer pauses.
+pub const GC_PAUSE_BUCKETS: [Duration; 5] = [
+	Duration::from_micros(10),
+	Duration::from_micros(100),
+	Duration::from_millis(1),
+	Duration::from_millis(10),
+	Duration::from_millis(100),
+];
+
+/// Counters about the execution of a [`VM`](super::VM), to guide optimization work.
+///
+/// They are only collected after calling [`VM::enable_stats`](super::VM::enable_stats),
+/// since counting slows down execution.
+#[derive(Debug, Clone)]
+pub struct VmStats {
+	/// Number of instructions executed, by opcode
+	instructions: Vec<u64>,
+	/// Number of calls to each chunk of the program, including the call to the main chunk
+	pub calls: Vec<u64>,
+	/// Number of calls to native functions, including methods of built-in types
+	pub native_calls: u64,
+	/// Number of collections started by the VM, by duration; see [`GC_PAUSE_BUCKETS`]
+	pub gc_pauses: [u64; GC_PAUSE_BUCKETS.len() + 1],
+	/// Total time spent in collections started by the VM
+	pub gc_time: Duration,
+}
+
+impl Default for VmStats {
+	fn default() -> VmStats {
+		VmStats {
+			instructions: vec![0; InstrType::all().count()],
+			calls: vec![],
+			native_calls: 0,
+			gc_pauses: [0; GC_PAUSE_BUCKETS.len() + 1],
+			gc_time: Duration::default(),
+		}
+	}
+}
+
+impl VmStats {
+	pub(super) fn count_instruction(&mut self, instr: InstrType) {
+		self.instructions[instr as usize] += 1;
+	}
+
+	pub(super) fn count_call(&mut self, chunk_id: usize) {
+		if self.calls.len() <= chunk_id {
+			self.calls.resize(chunk_id + 1, 0);
+		}
+		self.calls[chunk_id] += 1;
+	}
+
+	pub(super) fn count_gc_pause(&mut self, pause: Duration) {
+		let bucket = GC_PAUSE_BUCKETS.iter().position(|bound| pause < *bound).unwrap_or(GC_PAUSE_BUCKETS.len());
+		self.gc_pauses[bucket] += 1;
+		self.gc_time += pause;
+	}
+
+	/// Returns the total number of instructions executed.
+	pub fn total_instructions(&self) -> u64 {
+		self.instructions.iter().sum()
+	}
+
+	/// Returns the number of instructions executed by name (as in `hissy isa`), the most frequent first,
+	/// leaving out instructions which were never executed.
+	pub fn instruction_mix(&self) -> Vec<(String, u64)> {
+		let mut mix: Vec<(String, u64)> = self.instructions.iter().enumerate()
+			.filter(|(_, count)| **count > 0)
+			.map(|(opcode, count)| (format!("{:?}", InstrType::try_from(opcode as u8).unwrap()), *count))
+			.collect();
+		mix.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+		mix
+	}
+}
@@ -0,0 +1,424 @@
+
+use std::convert::TryFrom;
+use std::fmt;
+
+use crate::{prim_ty, HissyError, ErrorType};
+use crate::compiler::{Type, PrimitiveType};
+use super::value::Value;
+use super::gc::{GCHeap, GCRef, Traceable};
+use super::object::{NativeFunction, List, Namespace};
+
+
+fn error(s: String) -> HissyError {
+	HissyError(ErrorType::Execution, s, 0)
+}
+fn error_str(s: &str) -> HissyError {
+	error(String::from(s))
+}
+
+
+/// A dense, immutable n-dimensional array of reals, stored contiguously in row-major order.
+#[derive(Clone, PartialEq)]
+pub struct Tensor {
+	shape: Vec<usize>,
+	data: Vec<f64>,
+}
+
+// The largest number of values in a tensor (1 GiB of reals)
+const MAX_SIZE: usize = 1 << 27;
+
+// Only for shapes which have been checked with checked_size
+fn shape_size(shape: &[usize]) -> usize {
+	shape.iter().product()
+}
+
+fn checked_size(shape: &[usize]) -> Result<usize, HissyError> {
+	shape.iter().try_fold(1usize, |size, dim| size.checked_mul(*dim))
+		.filter(|size| *size <= MAX_SIZE)
+		.ok_or_else(|| error(format!("Tensor of shape {:?} has more than {} values", shape, MAX_SIZE)))
+}
+
+fn shape_strides(shape: &[usize]) -> Vec<usize> {
+	let mut strides = vec![1; shape.len()];
+	for i in (0..shape.len().saturating_sub(1)).rev() {
+		strides[i] = strides[i+1] * shape[i+1];
+	}
+	strides
+}
+
+impl Tensor {
+	pub fn new(shape: Vec<usize>, data: Vec<f64>) -> Result<Tensor, HissyError> {
+		if checked_size(&shape)? != data.len() {
+			return Err(error(format!("Cannot make tensor of shape {:?} from {} values", shape, data.len())));
+		}
+		Ok(Tensor { shape, data })
+	}
+
+	pub fn filled(shape: Vec<usize>, x: f64) -> Result<Tensor, HissyError> {
+		let data = vec![x; checked_size(&shape)?];
+		Ok(Tensor { shape, data })
+	}
+
+	/// Builds a tensor from (possibly nested) lists of numbers.
+	pub fn from_value(val: &Value) -> Result<Tensor, HissyError> {
+		// `ancestors` holds the lists being flattened, outermost first
+		fn flatten(val: &Value, depth: usize, shape: &mut Vec<usize>, data: &mut Vec<f64>,
+				ancestors: &mut Vec<*const ()>) -> Result<(), HissyError> {
+			if let Ok(list) = GCRef::<List>::try_from(val.clone()) {
+				let key = list.pointer as *const ();
+				if ancestors.contains(&key) {
+					return Err(error_str("Cannot make tensor from a list containing itself"));
+				}
+				let values = list.get_copy();
+				if depth == shape.len() {
+					if !data.is_empty() {
+						return Err(error_str("Ragged nested lists cannot be made into a tensor"));
+					}
+					shape.push(values.len());
+					// Lists shared between several rows can describe more values than fit in memory
+					checked_size(shape)?;
+				} else if shape[depth] != values.len() {
+					return Err(error_str("Ragged nested lists cannot be made into a tensor"));
+				}
+				ancestors.push(key);
+				for val in &values {
+					flatten(val, depth + 1, shape, data, ancestors)?;
+				}
+				ancestors.pop();
+				Ok(())
+			} else if val.is_numeric() {
+				if depth != shape.len() {
+					return Err(error_str("Ragged nested lists cannot be made into a tensor"));
+				}
+				data.push(val.cast_real());
+				Ok(())
+			} else {
+				Err(error(format!("Cannot make tensor from {}", val.repr())))
+			}
+		}
+
+		let (mut shape, mut data) = (vec![], vec![]);
+		flatten(val, 0, &mut shape, &mut data, &mut vec![])?;
+		Tensor::new(shape, data)
+	}
+
+	/// Converts the tensor back to nested lists (or a single real for 0-dimensional tensors).
+	pub fn to_value(&self, heap: &mut GCHeap) -> Value {
+		fn build(heap: &mut GCHeap, shape: &[usize], data: &[f64]) -> Value {
+			if shape.is_empty() {
+				return Value::from(data[0]);
+			}
+			let chunk_size = shape_size(&shape[1..]);
+			let values: Vec<Value> = (0..shape[0])
+				.map(|i| build(heap, &shape[1..], &data[i*chunk_size .. (i+1)*chunk_size]))
+				.collect();
+			let list = List::new();
+			list.extend(&values);
+			heap.make_value(list)
+		}
+		build(heap, &self.shape, &self.data)
+	}
+
+	pub fn shape(&self) -> &[usize] {
+		&self.shape
+	}
+
+	pub fn data(&self) -> &[f64] {
+		&self.data
+	}
+
+	/// Applies `f` element-wise, broadcasting the operands following the usual NumPy rules:
+	/// shapes are aligned on their last dimension, and dimensions of size 1 are stretched.
+	pub fn broadcast(&self, other: &Tensor, f: impl Fn(f64, f64) -> f64) -> Result<Tensor, HissyError> {
+		if self.shape == other.shape {
+			let data = self.data.iter().zip(&other.data).map(|(a, b)| f(*a, *b)).collect();
+			return Ok(Tensor { shape: self.shape.clone(), data });
+		}
+
+		let ndim = self.shape.len().max(other.shape.len());
+		let pad = |shape: &[usize]| -> Vec<usize> {
+			let mut padded = vec![1; ndim - shape.len()];
+			padded.extend(shape);
+			padded
+		};
+		let (shape1, shape2) = (pad(&self.shape), pad(&other.shape));
+		let mut shape = vec![];
+		for (d1, d2) in shape1.iter().zip(&shape2) {
+			if d1 == d2 || *d2 == 1 {
+				shape.push(*d1);
+			} else if *d1 == 1 {
+				shape.push(*d2);
+			} else {
+				return Err(error(format!("Cannot broadcast tensors of shapes {:?} and {:?}", self.shape, other.shape)));
+			}
+		}
+
+		// Stretched dimensions get a stride of 0
+		let broadcast_strides = |src_shape: &[usize]| -> Vec<usize> {
+			shape_strides(src_shape).iter().zip(src_shape)
+				.map(|(stride, dim)| if *dim == 1 { 0 } else { *stride })
+				.collect()
+		};
+		let (strides1, strides2) = (broadcast_strides(&shape1), broadcast_strides(&shape2));
+
+		let size = checked_size(&shape)?;
+		let mut data = Vec::with_capacity(size);
+		let mut index = vec![0; ndim];
+		for _ in 0..size {
+			let idx1: usize = index.iter().zip(&strides1).map(|(i, s)| i * s).sum();
+			let idx2: usize = index.iter().zip(&strides2).map(|(i, s)| i * s).sum();
+			data.push(f(self.data[idx1], other.data[idx2]));
+			for d in (0..ndim).rev() {
+				index[d] += 1;
+				if index[d] < shape[d] { break; }
+				index[d] = 0;
+			}
+		}
+		Ok(Tensor { shape, data })
+	}
+
+	/// Multiplies two matrices (2-dimensional tensors).
+	pub fn matmul(&self, other: &Tensor) -> Result<Tensor, HissyError> {
+		let (n, m, p) = match (self.shape.as_slice(), other.shape.as_slice()) {
+			([n, m1], [m2, p]) if m1 == m2 => (*n, *m1, *p),
+			_ => return Err(error(format!("Cannot multiply matrices of shapes {:?} and {:?}", self.shape, other.shape))),
+		};
+		let mut data = vec![0.0; n * p];
+		for i in 0..n {
+			for k in 0..m {
+				let a = self.data[i*m + k];
+				for j in 0..p {
+					data[i*p + j] += a * other.data[k*p + j];
+				}
+			}
+		}
+		Ok(Tensor { shape: vec![n, p], data })
+	}
+
+	/// Transposes a matrix (2-dimensional tensor).
+	pub fn transpose(&self) -> Result<Tensor, HissyError> {
+		let (n, m) = match self.shape.as_slice() {
+			[n, m] => (*n, *m),
+			_ => return Err(error(format!("Cannot transpose tensor of shape {:?}", self.shape))),
+		};
+		let mut data = Vec::with_capacity(n * m);
+		for j in 0..m {
+			for i in 0..n {
+				data.push(self.data[i*m + j]);
+			}
+		}
+		Ok(Tensor { shape: vec![m, n], data })
+	}
+}
+
+impl Traceable for Tensor {
+	fn external_size(&self) -> usize {
+		self.data.len() * std::mem::size_of::<f64>() + self.shape.len() * std::mem::size_of::<usize>()
+	}
+}
+
+impl fmt::Debug for Tensor {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> fmt::Result {
+		fn write_dim(f: &mut std::fmt::Formatter<'_>, shape: &[usize], data: &[f64]) -> fmt::Result {
+			if shape.is_empty() {
+				return write!(f, "{}", Value::from(data[0]).repr());
+			}
+			let chunk_size = shape_size(&shape[1..]);
+			write!(f, "[")?;
+			for i in 0..shape[0] {
+				if i != 0 { write!(f, ", ")?; }
+				write_dim(f, &shape[1..], &data[i*chunk_size .. (i+1)*chunk_size])?;
+			}
+			write!(f, "]")
+		}
+		write!(f, "tensor(")?;
+		write_dim(f, &self.shape, &self.data)?;
+		write!(f, ")")
+	}
+}
+
+
+// Accepts either a tensor or a number (as a 0-dimensional tensor)
+fn tensor_arg(val: &Value) -> Result<Tensor, HissyError> {
+	if let Ok(t) = GCRef::<Tensor>::try_from(val.clone()) {
+		Ok((*t).clone())
+	} else if val.is_numeric() {
+		Tensor::filled(vec![], val.cast_real())
+	} else {
+		Err(error(format!("Expected tensor or number, got {}", val.repr())))
+	}
+}
+
+fn shape_arg(args: &[Value]) -> Result<Vec<usize>, HissyError> {
+	args.iter().map(|arg| {
+		i32::try_from(arg).ok().and_then(|d| usize::try_from(d).ok())
+			.ok_or_else(|| error(format!("Expected non-negative integer dimension, got {}", arg.repr())))
+	}).collect()
+}
+
+pub(crate) fn list() -> Vec<(String, Type)> {
+	let binary_method = || Type::TypedFunction(vec![Type::Any], Box::new(Type::Tensor));
+	vec![
+		(String::from("Tensor"), Type::Namespace(vec![
+			(String::from("shape"), Type::TypedFunction(vec![], Box::new(Type::List(Box::new(prim_ty!(Int)))))),
+			(String::from("add"), binary_method()),
+			(String::from("sub"), binary_method()),
+			(String::from("mul"), binary_method()),
+			(String::from("div"), binary_method()),
+			(String::from("matmul"), Type::TypedFunction(vec![Type::Tensor], Box::new(Type::Tensor))),
+			(String::from("transpose"), Type::TypedFunction(vec![], Box::new(Type::Tensor))),
+			(String::from("sum"), Type::TypedFunction(vec![], Box::new(prim_ty!(Real)))),
+			(String::from("to_list"), Type::TypedFunction(vec![], Box::new(Type::Any))),
+		])),
+		(String::from("tensor"), Type::TypedFunction(vec![Type::Any], Box::new(Type::Tensor))),
+		(String::from("zeros"), Type::UntypedFunction(Box::new(Type::Tensor))),
+		(String::from("ones"), Type::UntypedFunction(Box::new(Type::Tensor))),
+	]
+}
+
+pub(crate) fn create(heap: &mut GCHeap) -> Vec<Value> {
+	let mut res = vec![];
+
+	macro_rules! method {
+		(|$heap:ident, $this:ident, $args:ident| $body:expr) => {
+			heap.make_value(NativeFunction::new(|$heap, $args| {
+				let $this = GCRef::<Tensor>::try_from($args[0].clone()).unwrap();
+				$body
+			}))
+		};
+	}
+	macro_rules! elementwise {
+		($fn:expr) => {
+			method!(|heap, this, args| {
+				let other = tensor_arg(&args[1])?;
+				Ok(heap.make_value(this.broadcast(&other, $fn)?))
+			})
+		};
+	}
+
+	let methods = vec![
+		method!(|heap, this, _args| {
+			let dims: Vec<Value> = this.shape.iter().map(|d| Value::from(*d as i32)).collect();
+			let list = List::new();
+			list.extend(&dims);
+			Ok(heap.make_value(list))
+		}),
+		elementwise!(|a, b| a + b),
+		elementwise!(|a, b| a - b),
+		elementwise!(|a, b| a * b),
+		elementwise!(|a, b| a / b),
+		method!(|heap, this, args| {
+			let other = GCRef::<Tensor>::try_from(args[1].clone())
+				.map_err(|_| error(format!("Expected tensor, got {}", args[1].repr())))?;
+			Ok(heap.make_value(this.matmul(&other)?))
+		}),
+		method!(|heap, this, _args| Ok(heap.make_value(this.transpose()?))),
+		method!(|_heap, this, _args| Ok(Value::from(this.data.iter().sum::<f64>()))),
+		method!(|heap, this, _args| Ok(this.to_value(heap))),
+	];
+	res.push(heap.make_value(Namespace(methods)));
+
+	res.push(heap.make_value(NativeFunction::new(|heap, args| {
+		if args.len() != 1 {
+			return Err(error(format!("Expected 1 argument, got {}", args.len())));
+		}
+		Ok(heap.make_value(Tensor::from_value(&args[0])?))
+	})));
+	res.push(heap.make_value(NativeFunction::new(|heap, args| {
+		Ok(heap.make_value(Tensor::filled(shape_arg(&args)?, 0.0)?))
+	})));
+	res.push(heap.make_value(NativeFunction::new(|heap, args| {
+		Ok(heap.make_value(Tensor::filled(shape_arg(&args)?, 1.0)?))
+	})));
+
+	res
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_broadcast() {
+		let a = Tensor::new(vec![2, 3], vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0]).unwrap();
+		let row = Tensor::new(vec![3], vec![10.0, 20.0, 30.0]).unwrap();
+		let col = Tensor::new(vec![2, 1], vec![100.0, 200.0]).unwrap();
+		let scalar = Tensor::filled(vec![], 2.0).unwrap();
+
+		let res = a.broadcast(&row, |x, y| x + y).unwrap();
+		assert_eq!(res.shape(), &[2, 3]);
+		assert_eq!(res.data(), &[11.0, 22.0, 33.0, 14.0, 25.0, 36.0]);
+
+		let res = a.broadcast(&col, |x, y| x + y).unwrap();
+		assert_eq!(res.data(), &[101.0, 102.0, 103.0, 204.0, 205.0, 206.0]);
+
+		let res = col.broadcast(&row, |x, y| x * y).unwrap();
+		assert_eq!(res.shape(), &[2, 3]);
+		assert_eq!(res.data(), &[1000.0, 2000.0, 3000.0, 2000.0, 4000.0, 6000.0]);
+
+		let res = scalar.broadcast(&a, |x, y| x * y).unwrap();
+		assert_eq!(res.data(), &[2.0, 4.0, 6.0, 8.0, 10.0, 12.0]);
+
+		assert!(a.broadcast(&Tensor::filled(vec![2], 0.0).unwrap(), |x, y| x + y).is_err());
+		
+		// The shape of the result may have more values than fit in memory
+		let big = 1 << 20;
+		let col = Tensor::filled(vec![big, 1], 0.0).unwrap();
+		let row = Tensor::filled(vec![1, big], 0.0).unwrap();
+		assert!(col.broadcast(&row, |x, y| x + y).is_err());
+		assert!(Tensor::filled(vec![big, big], 0.0).is_err());
+		assert!(Tensor::filled(vec![usize::MAX, 2], 0.0).is_err());
+		assert!(Tensor::new(vec![usize::MAX, 2, 0], vec![]).is_err());
+	}
+
+	#[test]
+	fn test_matmul() {
+		let a = Tensor::new(vec![2, 3], vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0]).unwrap();
+		let b = a.transpose().unwrap();
+		assert_eq!(b.shape(), &[3, 2]);
+		assert_eq!(b.data(), &[1.0, 4.0, 2.0, 5.0, 3.0, 6.0]);
+		let c = a.matmul(&b).unwrap();
+		assert_eq!(c.shape(), &[2, 2]);
+		assert_eq!(c.data(), &[14.0, 32.0, 32.0, 77.0]);
+		assert!(a.matmul(&a).is_err());
+	}
+
+	#[test]
+	fn test_list_conversion() {
+		let mut heap = GCHeap::new();
+		{
+			let row1 = heap.make_value(List::new());
+			GCRef::<List>::try_from(row1.clone()).unwrap().extend(&[Value::from(1), Value::from(2.5)]);
+			let row2 = heap.make_value(List::new());
+			GCRef::<List>::try_from(row2.clone()).unwrap().extend(&[Value::from(3), Value::from(4)]);
+			let rows = heap.make_value(List::new());
+			GCRef::<List>::try_from(rows.clone()).unwrap().extend(&[row1, row2.clone()]);
+
+			let t = Tensor::from_value(&rows).unwrap();
+			assert_eq!(t.shape(), &[2, 2]);
+			assert_eq!(t.data(), &[1.0, 2.5, 3.0, 4.0]);
+			assert_eq!(t.to_value(&mut heap).repr(), "[[1.0, 2.5], [3.0, 4.0]]");
+
+			GCRef::<List>::try_from(row2).unwrap().extend(&[Value::from(5)]);
+			assert!(Tensor::from_value(&rows).is_err());
+			
+			// A list containing itself would describe an infinite number of dimensions
+			let cycle = heap.make_ref(List::new());
+			cycle.extend(&[Value::from(cycle.clone())]);
+			let err = Tensor::from_value(&Value::from(cycle)).err().unwrap();
+			assert_eq!(err.1, "Cannot make tensor from a list containing itself");
+			
+			// Sharing rows doubles the number of values at each level
+			let mut shared = Value::from(1);
+			for _ in 0..30 {
+				let list = heap.make_value(List::new());
+				GCRef::<List>::try_from(list.clone()).unwrap().extend(&[shared.clone(), shared]);
+				shared = list;
+			}
+			assert!(Tensor::from_value(&shared).is_err());
+		}
+		heap.collect();
+		assert!(heap.is_empty());
+	}
+}
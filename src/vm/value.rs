@@ -1,18 +1,23 @@
 
-use std::cell::Cell;
+use std::cell::{Cell, RefCell};
 use std::fmt;
 use num_enum::TryFromPrimitive;
 use std::convert::TryFrom;
+use std::ops::Deref;
 
+use crate::parser::symbol::Symbol;
 use super::gc::{GC, GCRef, GCWrapper};
+use super::object::List;
 
 
 /// A Hissy value.
 /// 
-/// This value can be of the following types: nil, bool, int, real, or a GC object.
+/// This value can be of the following types: nil, bool, int, real, char, symbol, string, or a GC object.
 /// In the latter case, `Value` is the untyped equivalent of a [`GCRef`], and can be converted to/from one.
 /// 
 /// Internally, `Value`s are stored using NaN-tagging/boxing, so that non-object values are stored without heap allocation.
+/// Strings of up to [`SHORT_STR_MAX`] bytes are also stored inline, longer ones are `String` objects;
+/// [`GCHeap::make_string`](super::gc::GCHeap::make_string) chooses the representation, and [`Value::as_str`] reads both.
 pub struct Value(Cell<u64>);
 
 #[derive(TryFromPrimitive, PartialEq)]
@@ -24,6 +29,9 @@ pub(super) enum ValueType {
 	Int,
 	Root,
 	Ref,
+	ShortStr,
+	Char,
+	Symbol, // Shares the tag of Char, see SYMBOL_FLAG
 }
 
 const TAG_SIZE: i8 = 16; // in bits
@@ -31,10 +39,92 @@ const TAG_POS:  i8 = 64 - TAG_SIZE;
 const TAG_MIN:   u64 = 0xfff8 << TAG_POS;
 const DATA_MASK: u64 = std::u64::MAX >> TAG_SIZE;
 
+// All tags are taken, so symbols are chars with this bit set in their payload
+const SYMBOL_FLAG: u64 = 1 << (TAG_POS - 1);
+
 const fn base_value(t: ValueType) -> u64 {
+	if let ValueType::Symbol = t {
+		return base_value(ValueType::Char) | SYMBOL_FLAG;
+	}
 	TAG_MIN + ((t as u64) << TAG_POS)
 }
 
+// Object pointers are normally stored directly in the payload of Values, which requires them
+// to fit in its 48 bits. This does not hold on platforms which tag the upper bits of heap pointers,
+// so there (or with the "pointer-table" feature), payloads are indices into a global table instead.
+#[cfg(not(any(feature = "pointer-table", all(target_os = "android", target_arch = "aarch64"))))]
+pub(super) mod pointers {
+	use super::DATA_MASK;
+	
+	pub fn encode(pointer: *mut ()) -> u64 {
+		let pointer = pointer as u64;
+		assert!(pointer & DATA_MASK == pointer, "Object pointer has too many bits to fit in Value");
+		pointer
+	}
+	
+	pub fn decode(payload: u64) -> *mut () {
+		payload as *mut ()
+	}
+	
+	pub fn release(_pointer: *mut ()) {}
+}
+
+#[cfg(any(feature = "pointer-table", all(target_os = "android", target_arch = "aarch64")))]
+pub(super) mod pointers {
+	use std::collections::HashMap;
+	use std::sync::{OnceLock, RwLock};
+	
+	// Shared by all heaps, since Values are not tied to a heap
+	#[derive(Default)]
+	struct PointerTable {
+		pointers: Vec<usize>,
+		indices: HashMap<usize, u64>,
+		free: Vec<u64>,
+	}
+	
+	fn table() -> &'static RwLock<PointerTable> {
+		static TABLE: OnceLock<RwLock<PointerTable>> = OnceLock::new();
+		TABLE.get_or_init(|| RwLock::new(PointerTable::default()))
+	}
+	
+	pub fn encode(pointer: *mut ()) -> u64 {
+		let pointer = pointer as usize;
+		if let Some(&idx) = table().read().unwrap().indices.get(&pointer) {
+			return idx;
+		}
+		let mut table = table().write().unwrap();
+		if let Some(&idx) = table.indices.get(&pointer) {
+			return idx;
+		}
+		let idx = if let Some(idx) = table.free.pop() {
+			table.pointers[idx as usize] = pointer;
+			idx
+		} else {
+			table.pointers.push(pointer);
+			table.pointers.len() as u64 - 1
+		};
+		assert!(idx & super::DATA_MASK == idx, "Too many objects to fit their indices in Value");
+		table.indices.insert(pointer, idx);
+		idx
+	}
+	
+	pub fn decode(payload: u64) -> *mut () {
+		table().read().unwrap().pointers[payload as usize] as *mut ()
+	}
+	
+	// Called when an object is freed, so that its index can be reused
+	pub fn release(pointer: *mut ()) {
+		let mut table = table().write().unwrap();
+		if let Some(idx) = table.indices.remove(&(pointer as usize)) {
+			table.free.push(idx);
+		}
+	}
+}
+
+/// The maximum length in bytes of strings stored inline in a [`Value`].
+pub const SHORT_STR_MAX: usize = 5;
+const SHORT_STR_LEN_POS: usize = 8 * SHORT_STR_MAX; // The length is stored above the bytes
+
 // A primitive (non-object value) will never have its interior mutated
 #[allow(clippy::declare_interior_mutable_const)]
 pub const NIL: Value = Value::from_value(base_value(ValueType::Nil));
@@ -48,7 +138,10 @@ impl Value {
 		if self.0.get() < TAG_MIN {
 			ValueType::Real
 		} else {
-			ValueType::try_from((self.0.get() - TAG_MIN) >> TAG_POS).unwrap()
+			match ValueType::try_from((self.0.get() - TAG_MIN) >> TAG_POS).unwrap() {
+				ValueType::Char if self.0.get() & SYMBOL_FLAG != 0 => ValueType::Symbol,
+				t => t,
+			}
 		}
 	}
 	
@@ -56,10 +149,45 @@ impl Value {
 		self.get_type() == ValueType::Nil
 	}
 	
+	// Stores a string inline, if it is short enough
+	pub(super) fn short_str(s: &str) -> Option<Value> {
+		if s.len() > SHORT_STR_MAX {
+			return None;
+		}
+		let payload = s.bytes().enumerate()
+			.fold((s.len() as u64) << SHORT_STR_LEN_POS, |payload, (i, b)| payload | u64::from(b) << (8 * i));
+		Some(Value::from_value(base_value(ValueType::ShortStr) + payload))
+	}
+	
+	/// Returns whether the `Value` is a string, whether stored inline or in a `String` object.
+	pub fn is_string(&self) -> bool {
+		match self.get_type() {
+			ValueType::ShortStr => true,
+			ValueType::Root | ValueType::Ref => self.get_pointer().unwrap().is_a::<String>(),
+			_ => false,
+		}
+	}
+	
+	/// Returns the contents of the `Value` if it is a string, whether stored inline or in a `String` object.
+	///
+	/// The contents are borrowed without copying them: the returned [`ValueStr`] roots the string,
+	/// so the `&str` it dereferences to stays valid while it is alive, even if the heap is collected.
+	pub fn as_str(&self) -> Option<ValueStr> {
+		match self.get_type() {
+			ValueType::ShortStr => {
+				let payload = self.0.get() & DATA_MASK;
+				let mut bytes = [0; SHORT_STR_MAX];
+				bytes.copy_from_slice(&payload.to_le_bytes()[..SHORT_STR_MAX]);
+				Some(ValueStr::Short(bytes, (payload >> SHORT_STR_LEN_POS) as usize))
+			},
+			ValueType::Root | ValueType::Ref => GCRef::<String>::try_from(self.clone()).ok().map(ValueStr::Object),
+			_ => None,
+		}
+	}
+	
 	pub(super) fn from_pointer(pointer: *const GCWrapper, root: bool) -> Value {
-		let pointer = pointer as *mut () as u64; // Erases fat pointer data
-		assert!(pointer & DATA_MASK == pointer, "Object pointer has too many bits to fit in Value");
-		let new_val = Value::from_value(base_value(if root { ValueType::Root } else { ValueType::Ref }) + pointer);
+		let payload = pointers::encode(pointer as *mut ()); // Erases fat pointer data
+		let new_val = Value::from_value(base_value(if root { ValueType::Root } else { ValueType::Ref }) + payload);
 		if root { new_val.get_pointer().unwrap().signal_root() }
 		new_val
 	}
@@ -67,7 +195,7 @@ impl Value {
 	pub(super) fn get_pointer(&self) -> Option<&GCWrapper> {
 		let t = self.get_type();
 		if t == ValueType::Root || t == ValueType::Ref {
-			let pointer = GCWrapper::fatten_pointer((self.0.get() & DATA_MASK) as *mut ());
+			let pointer = GCWrapper::fatten_pointer(pointers::decode(self.0.get() & DATA_MASK));
 			// Safety: as long as the GC algorithm is well-behaved (it frees a reference
 			// before or in the same cycle as the referee), and the collecting process
 			// does not call this function, self.pointer will be valid.
@@ -100,7 +228,26 @@ impl Value {
 		}
 	}
 	
-	/// Outputs a string representation of the `Value` depending on its internal type.
+	/// Returns the name of the type of the `Value`. Objects of unregistered types are named "Object".
+	/// 
+	/// See [`registry`](../registry/index.html) for the names of built-in object types.
+	pub fn type_name(&self) -> &'static str {
+		match self.get_type() {
+			ValueType::Nil => "Nil",
+			ValueType::Bool => "Bool",
+			ValueType::Int => "Int",
+			ValueType::Real => "Real",
+			ValueType::ShortStr => "String",
+			ValueType::Char => "Char",
+			ValueType::Symbol => "Symbol",
+			ValueType::Root | ValueType::Ref => self.get_pointer().unwrap().type_info().map_or("Object", |info| info.name),
+		}
+	}
+	
+	/// Outputs a string representation of the `Value` depending on its internal type,
+	/// with the default [`ReprOptions`].
+	/// 
+	/// Objects which contain themselves are shown as `...` where they appear again.
 	pub fn repr(&self) -> String {
 		match self.get_type() {
 			ValueType::Bool => bool::try_from(self).unwrap().to_string(),
@@ -116,7 +263,132 @@ impl Value {
 				}
 			},
 			ValueType::Nil => "nil".to_string(),
-			ValueType::Root | ValueType::Ref => self.get_pointer().unwrap().debug(),
+			ValueType::ShortStr => format!("{:?}", &*self.as_str().unwrap()),
+			ValueType::Char => format!("{:?}", char::try_from(self).unwrap()),
+			ValueType::Symbol => format!(":{}", Symbol::try_from(self).unwrap()),
+			ValueType::Root | ValueType::Ref => {
+				let pointer = self.get_pointer().unwrap();
+				let key = pointer as *const GCWrapper as *const ();
+				if REPR_STACK.with(|stack| stack.borrow().contains(&key)) {
+					return String::from("...");
+				}
+				REPR_STACK.with(|stack| stack.borrow_mut().push(key));
+				let _guard = ReprGuard;
+				pointer.debug()
+			},
+		}
+	}
+	
+	/// Outputs a string representation of the `Value`, abbreviating nested objects according to `options`.
+	pub fn repr_with(&self, options: ReprOptions) -> String {
+		let prev = REPR_OPTIONS.with(|opts| opts.replace(options));
+		let res = self.repr();
+		REPR_OPTIONS.with(|opts| opts.set(prev));
+		res
+	}
+	
+	/// Outputs a multi-line representation of the `Value`, with the default [`ReprOptions`].
+	/// 
+	/// Lists which do not fit in [`PRETTY_WIDTH`] characters are written with one item per line,
+	/// indented by `indent` spaces per level of nesting; other values are written as with [`Value::repr`].
+	pub fn pretty(&self, indent: usize) -> String {
+		self.pretty_with(indent, ReprOptions::default())
+	}
+	
+	/// Outputs a multi-line representation of the `Value` like [`Value::pretty`], abbreviating nested objects according to `options`.
+	pub fn pretty_with(&self, indent: usize, options: ReprOptions) -> String {
+		let prev = REPR_OPTIONS.with(|opts| opts.replace(options));
+		let mut res = String::new();
+		self.write_pretty(&mut res, indent, 0);
+		REPR_OPTIONS.with(|opts| opts.set(prev));
+		res
+	}
+	
+	fn write_pretty(&self, out: &mut String, indent: usize, level: usize) {
+		let compact = self.repr();
+		let list = match GCRef::<List>::try_from(self.clone()) {
+			Ok(list) if indent * level + compact.chars().count() > PRETTY_WIDTH => list,
+			_ => return out.push_str(&compact),
+		};
+		let key = list.pointer as *const ();
+		let (options, depth) = repr_context();
+		if REPR_STACK.with(|stack| stack.borrow().contains(&key)) || depth >= options.max_depth {
+			return out.push_str(&compact);
+		}
+		REPR_STACK.with(|stack| stack.borrow_mut().push(key));
+		let _guard = ReprGuard;
+		out.push_str("[\n");
+		let items = list.get_copy();
+		for (i, item) in items.iter().enumerate() {
+			out.push_str(&" ".repeat(indent * (level + 1)));
+			if i == options.max_items {
+				out.push_str("...\n");
+				break;
+			}
+			item.write_pretty(out, indent, level + 1);
+			out.push_str(if i + 1 < items.len() { ",\n" } else { "\n" });
+		}
+		out.push_str(&" ".repeat(indent * level));
+		out.push(']');
+	}
+}
+
+
+/// Limits on the representation of nested objects, see [`Value::repr_with`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ReprOptions {
+	/// Objects nested deeper than this are abbreviated, eg. as `[...]` for lists
+	pub max_depth: usize,
+	/// Only this many items of a container are shown, followed by `...`
+	pub max_items: usize,
+}
+
+impl Default for ReprOptions {
+	/// The options used by [`Value::repr`]: only very deep nesting is abbreviated.
+	fn default() -> ReprOptions {
+		ReprOptions { max_depth: 64, max_items: usize::MAX }
+	}
+}
+
+/// The width under which [`Value::pretty`] writes lists on a single line, including indentation.
+pub const PRETTY_WIDTH: usize = 60;
+
+thread_local! {
+	static REPR_OPTIONS: Cell<ReprOptions> = Cell::new(ReprOptions::default());
+	// Objects whose representation is being built, outermost first
+	static REPR_STACK: RefCell<Vec<*const ()>> = const { RefCell::new(vec![]) };
+}
+
+// Removes an object from the representation stack, even if its Debug implementation panics
+struct ReprGuard;
+
+impl Drop for ReprGuard {
+	fn drop(&mut self) {
+		REPR_STACK.with(|stack| stack.borrow_mut().pop());
+	}
+}
+
+/// Returns the options of the representation being built, and the number of objects it is nested in,
+/// for the `Debug` implementations of containers, which should call [`Value::repr`] on their items.
+pub fn repr_context() -> (ReprOptions, usize) {
+	(REPR_OPTIONS.with(Cell::get), REPR_STACK.with(|stack| stack.borrow().len()))
+}
+
+
+/// The contents of a string [`Value`], returned by [`Value::as_str`], which keep a string object alive.
+pub enum ValueStr {
+	Short([u8; SHORT_STR_MAX], usize),
+	Object(GCRef<String>),
+}
+
+impl Deref for ValueStr {
+	type Target = str;
+	
+	fn deref(&self) -> &str {
+		match self {
+			// Inline strings are only created from valid UTF-8
+			ValueStr::Short(bytes, len) => std::str::from_utf8(&bytes[..*len]).unwrap(),
+			ValueStr::Object(s) => s,
 		}
 	}
 }
@@ -195,6 +467,20 @@ impl From<bool> for Value {
 	}
 }
 
+/// Converts a `char` into a `Value` directly (no heap allocation is performed).
+impl From<char> for Value {
+	fn from(c: char) -> Self {
+		Value::from_value(base_value(ValueType::Char) + u64::from(c))
+	}
+}
+
+/// Converts a [`Symbol`] into a `Value` directly (no heap allocation is performed).
+impl From<Symbol> for Value {
+	fn from(sym: Symbol) -> Self {
+		Value::from_value(base_value(ValueType::Symbol) + u64::from(sym.index()))
+	}
+}
+
 /// Attempts to convert a `Value` to an `i32`. Fails if the `Value` does not contain an integer.
 impl TryFrom<&Value> for i32 {
 	type Error = &'static str;
@@ -233,6 +519,29 @@ impl TryFrom<&Value> for bool {
 	}
 }
 
+/// Attempts to convert a `Value` to a `char`. Fails if the Value does not contain a character.
+impl TryFrom<&Value> for char {
+	type Error = &'static str;
+	fn try_from(value: &Value) -> std::result::Result<Self, &'static str> {
+		if value.get_type() == ValueType::Char {
+			char::from_u32((value.0.get() & DATA_MASK) as u32).ok_or("Invalid character Value")
+		} else {
+			Err("Value is not a character")
+		}
+	}
+}
+
+/// Attempts to convert a `Value` to a [`Symbol`]. Fails if the Value does not contain a symbol.
+impl TryFrom<&Value> for Symbol {
+	type Error = &'static str;
+	fn try_from(value: &Value) -> std::result::Result<Self, &'static str> {
+		if value.get_type() == ValueType::Symbol {
+			Ok(Symbol::from_index((value.0.get() & DATA_MASK & !SYMBOL_FLAG) as u32))
+		} else {
+			Err("Value is not a symbol")
+		}
+	}
+}
 
 
 #[cfg(test)]
@@ -273,4 +582,136 @@ mod tests {
 		assert_eq!(bool::try_from(&Value::from(true)), Ok(true));
 		assert_eq!(bool::try_from(&Value::from(false)), Ok(false));
 	}
-}
\ No newline at end of file
+
+	#[test]
+	fn test_chars() {
+		for &c in &['a', '\0', 'é', '🐍', char::MAX] {
+			assert_eq!(char::try_from(&Value::from(c)), Ok(c));
+		}
+		assert!(char::try_from(&Value::from(97)).is_err());
+		assert_ne!(Value::from('a'), Value::from(97));
+	}
+
+	#[test]
+	fn test_symbols() {
+		let red = Symbol::intern("red");
+		assert_eq!(Symbol::try_from(&Value::from(red)), Ok(red));
+		assert_eq!(Value::from(red), Value::from(Symbol::intern("red")));
+		assert_ne!(Value::from(red), Value::from(Symbol::intern("green")));
+		assert!(char::try_from(&Value::from(red)).is_err());
+		assert!(Symbol::try_from(&Value::from('r')).is_err());
+		assert_eq!(Value::from(red).repr(), ":red");
+	}
+
+	#[test]
+	fn test_objects() {
+		let mut heap = crate::vm::gc::GCHeap::new();
+		for round in 0..3 { // Later rounds may reuse the memory (or table indices) of freed objects
+			let values: Vec<Value> = (0..100).map(|i| heap.make_value(format!("{}-{}", round, i))).collect();
+			heap.collect();
+			for (i, val) in values.iter().enumerate() {
+				let s = GCRef::<String>::try_from(val.clone()).unwrap();
+				assert_eq!(*s, format!("{}-{}", round, i));
+				assert_eq!(val.clone(), *val);
+			}
+			drop(values);
+			heap.collect();
+			assert!(heap.is_empty());
+		}
+	}
+
+	#[test]
+	fn test_short_strings() {
+		let mut heap = crate::vm::gc::GCHeap::new();
+		for s in &["", "a", "hissy"] {
+			let val = heap.make_string(s);
+			assert!(val.is_string());
+			assert_eq!(&*val.as_str().unwrap(), *s);
+		}
+		assert!(heap.is_empty());
+		let long = heap.make_string("hissss");
+		assert_eq!(&*long.as_str().unwrap(), "hissss");
+		assert!(!heap.is_empty());
+		assert_eq!(heap.make_string("abc"), heap.make_value(String::from("abc")));
+		assert!(Value::from(1).as_str().is_none());
+	}
+
+	#[test]
+	fn test_owned_strings() {
+		let mut heap = crate::vm::gc::GCHeap::new();
+		let text = "hiss".repeat(1000);
+		let ptr = text.as_ptr();
+		let val = heap.alloc_str_from(text);
+		// The string was moved into the heap, and is borrowed back as is
+		let view = val.as_str().unwrap();
+		assert_eq!(view.as_ptr(), ptr);
+		drop(val);
+		heap.collect();
+		assert_eq!(view.len(), 4000);
+		drop(view);
+		heap.collect();
+		assert!(heap.is_empty());
+		assert!(heap.alloc_str_from(String::from("hi")).as_str().is_some() && heap.is_empty());
+	}
+	
+	#[test]
+	fn test_repr_cycles() {
+		use crate::vm::object::List;
+		let mut heap = crate::vm::gc::GCHeap::new();
+		let outer = heap.make_ref(List::new());
+		let inner = heap.make_ref(List::new());
+		inner.extend(&[Value::from(1), Value::from(outer.clone())]);
+		outer.extend(&[Value::from(inner.clone()), Value::from(inner.clone()), Value::from(2)]);
+		let outer = Value::from(outer);
+		// Lists shown again inside themselves are abbreviated, but not lists shown twice side by side
+		assert_eq!(outer.repr(), "[[1, ...], [1, ...], 2]");
+		assert_eq!(Value::from(inner.clone()).repr(), "[1, [..., ..., 2]]");
+		assert_eq!(outer.repr_with(ReprOptions { max_depth: 1, max_items: 2 }), "[[...], [...], ...]");
+		assert_eq!(outer.repr_with(ReprOptions { max_depth: 2, max_items: 1 }), "[[1, ...], ...]");
+		assert_eq!(outer.repr(), "[[1, ...], [1, ...], 2]");
+		drop((outer, inner));
+		heap.collect();
+		assert!(heap.is_empty());
+	}
+	
+	#[test]
+	fn test_pretty() {
+		let mut heap = crate::vm::gc::GCHeap::new();
+		let short = heap.make_ref(List::new());
+		short.extend(&[Value::from(1), Value::from(2)]);
+		let long = heap.make_ref(List::new());
+		let text = heap.make_string("a string long enough to be split over lines");
+		long.extend(&[text, Value::from(short.clone()), Value::from(short.clone())]);
+		long.extend(&[Value::from(long.clone())]);
+		assert_eq!(Value::from(short.clone()).pretty(2), "[1, 2]");
+		assert_eq!(Value::from(long.clone()).pretty(4),
+			"[\n    \"a string long enough to be split over lines\",\n    [1, 2],\n    [1, 2],\n    ...\n]");
+		let options = ReprOptions { max_depth: 8, max_items: 1 };
+		assert_eq!(Value::from(long.clone()).pretty_with(2, options),
+			"[\"a string long enough to be split over lines\", ...]");
+		drop((short, long));
+		heap.collect();
+		assert!(heap.is_empty());
+	}
+	
+	#[test]
+	fn test_heap_snapshot() {
+		use crate::vm::object::List;
+		let mut heap = crate::vm::gc::GCHeap::new();
+		let list = heap.make_ref(List::new());
+		let text = heap.make_string("a \"long\" string\nwhich isn't inline");
+		list.extend(&[text.clone(), text, Value::from(1)]);
+		let mut out = vec![];
+		heap.dump_snapshot(&mut out).unwrap();
+		let out = String::from_utf8(out).unwrap();
+		assert!(out.contains(r#"{"id": 0, "type": "List", "size": "#), "{}", out);
+		assert!(out.contains(r#""roots": 1, "repr": "[\"a \\\"long\\\" string\\nwhich isn't inline\", \"a"#), "{}", out);
+		assert!(out.contains(r#""refs": [1, 1]}"#), "{}", out);
+		assert!(out.contains(r#"{"id": 1, "type": "String", "size": "#), "{}", out);
+		assert!(out.contains(r#""roots": 0, "repr": "\"a \\\"long\\\" string\\nwhich isn't inline\"", "refs": []}"#), "{}", out);
+		// Taking a snapshot does not mark objects
+		drop(list);
+		heap.collect();
+		assert!(heap.is_empty());
+	}
+}
@@ -0,0 +1,171 @@
+
+use std::convert::TryFrom;
+use std::fmt;
+
+use super::value::Value;
+use super::gc::{GCHeap, GCRef, Traceable};
+
+
+macro_rules! vector_type {
+	($name:ident, $n:literal, $ctor:literal) => {
+		/// A fixed-size vector of reals, stored inline in its GC object.
+		#[derive(Clone, Copy, PartialEq)]
+		pub struct $name(pub [f64; $n]);
+
+		impl $name {
+			fn map(&self, f: impl Fn(f64) -> f64) -> $name {
+				let mut res = self.0;
+				for x in res.iter_mut() {
+					*x = f(*x);
+				}
+				$name(res)
+			}
+
+			fn zip(&self, other: &$name, f: impl Fn(f64, f64) -> f64) -> $name {
+				let mut res = self.0;
+				for (x, y) in res.iter_mut().zip(other.0.iter()) {
+					*x = f(*x, *y);
+				}
+				$name(res)
+			}
+
+			pub fn dot(&self, other: &$name) -> f64 {
+				self.0.iter().zip(other.0.iter()).map(|(x, y)| x * y).sum()
+			}
+
+			pub fn length(&self) -> f64 {
+				self.dot(self).sqrt()
+			}
+		}
+
+		impl Traceable for $name {}
+
+		impl fmt::Debug for $name {
+			fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> fmt::Result {
+				write!(f, concat!($ctor, "("))?;
+				for (i, x) in self.0.iter().enumerate() {
+					if i != 0 { write!(f, ", ")?; }
+					write!(f, "{}", Value::from(*x).repr())?;
+				}
+				write!(f, ")")
+			}
+		}
+	};
+}
+
+vector_type!(Vec2, 2, "vec2");
+vector_type!(Vec3, 3, "vec3");
+
+impl Vec3 {
+	pub fn cross(&self, other: &Vec3) -> Vec3 {
+		let ([x1, y1, z1], [x2, y2, z2]) = (self.0, other.0);
+		Vec3([y1*z2 - z1*y2, z1*x2 - x1*z2, x1*y2 - y1*x2])
+	}
+}
+
+
+enum Operand {
+	Vec2(Vec2),
+	Vec3(Vec3),
+	Scalar(f64),
+}
+
+fn get_operand(val: &Value) -> Option<Operand> {
+	if val.is_numeric() {
+		Some(Operand::Scalar(val.cast_real()))
+	} else if let Ok(v) = GCRef::<Vec2>::try_from(val.clone()) {
+		Some(Operand::Vec2(*v))
+	} else if let Ok(v) = GCRef::<Vec3>::try_from(val.clone()) {
+		Some(Operand::Vec3(*v))
+	} else {
+		None
+	}
+}
+
+macro_rules! vector_op {
+	($name:ident, $fn:expr, vec_vec: $vv:expr, vec_scalar: $vs:expr, scalar_vec: $sv:expr) => {
+		/// Applies the operation to vector operands, allocating the result in the heap.
+		/// Returns `None` if neither operand is a vector, or if the operation is not defined on them.
+		pub fn $name(heap: &mut GCHeap, a: &Value, b: &Value) -> Option<Value> {
+			let f = $fn;
+			match (get_operand(a)?, get_operand(b)?) {
+				(Operand::Vec2(a), Operand::Vec2(b)) if $vv => Some(heap.make_value(a.zip(&b, f))),
+				(Operand::Vec3(a), Operand::Vec3(b)) if $vv => Some(heap.make_value(a.zip(&b, f))),
+				(Operand::Vec2(a), Operand::Scalar(b)) if $vs => Some(heap.make_value(a.map(|x| f(x, b)))),
+				(Operand::Vec3(a), Operand::Scalar(b)) if $vs => Some(heap.make_value(a.map(|x| f(x, b)))),
+				(Operand::Scalar(a), Operand::Vec2(b)) if $sv => Some(heap.make_value(b.map(|x| f(a, x)))),
+				(Operand::Scalar(a), Operand::Vec3(b)) if $sv => Some(heap.make_value(b.map(|x| f(a, x)))),
+				_ => None,
+			}
+		}
+	};
+}
+
+vector_op!(add, |a, b| a + b, vec_vec: true, vec_scalar: false, scalar_vec: false);
+vector_op!(sub, |a, b| a - b, vec_vec: true, vec_scalar: false, scalar_vec: false);
+vector_op!(mul, |a, b| a * b, vec_vec: true, vec_scalar: true, scalar_vec: true);
+vector_op!(div, |a, b| a / b, vec_vec: true, vec_scalar: true, scalar_vec: false);
+
+/// Negates a vector, allocating the result in the heap. Returns `None` for non-vectors.
+pub fn neg(heap: &mut GCHeap, a: &Value) -> Option<Value> {
+	match get_operand(a)? {
+		Operand::Vec2(a) => Some(heap.make_value(a.map(|x| -x))),
+		Operand::Vec3(a) => Some(heap.make_value(a.map(|x| -x))),
+		Operand::Scalar(_) => None,
+	}
+}
+
+/// Compares two vectors component-wise. Returns `None` if either value is not a vector.
+pub fn eq(a: &Value, b: &Value) -> Option<bool> {
+	match (get_operand(a)?, get_operand(b)?) {
+		(Operand::Vec2(a), Operand::Vec2(b)) => Some(a == b),
+		(Operand::Vec3(a), Operand::Vec3(b)) => Some(a == b),
+		(Operand::Scalar(_), Operand::Scalar(_)) => None,
+		_ => Some(false),
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_vector_ops() {
+		let mut heap = GCHeap::new();
+		{
+			let a = heap.make_value(Vec2([1.0, 2.0]));
+			let b = heap.make_value(Vec2([3.0, -1.0]));
+			let c = heap.make_value(Vec3([1.0, 0.0, 0.0]));
+
+			let sum = add(&mut heap, &a, &b).unwrap();
+			assert_eq!(*GCRef::<Vec2>::try_from(sum).unwrap(), Vec2([4.0, 1.0]));
+			let scaled = mul(&mut heap, &Value::from(2), &a).unwrap();
+			assert_eq!(*GCRef::<Vec2>::try_from(scaled).unwrap(), Vec2([2.0, 4.0]));
+			let halved = div(&mut heap, &b, &Value::from(2.0)).unwrap();
+			assert_eq!(*GCRef::<Vec2>::try_from(halved).unwrap(), Vec2([1.5, -0.5]));
+			let negated = neg(&mut heap, &c).unwrap();
+			assert_eq!(*GCRef::<Vec3>::try_from(negated).unwrap(), Vec3([-1.0, 0.0, 0.0]));
+
+			assert!(add(&mut heap, &a, &c).is_none());
+			assert!(add(&mut heap, &a, &Value::from(1)).is_none());
+			assert!(div(&mut heap, &Value::from(1), &a).is_none());
+			assert!(add(&mut heap, &Value::from(1), &Value::from(1)).is_none());
+
+			assert_eq!(eq(&a, &heap.make_value(Vec2([1.0, 2.0]))), Some(true));
+			assert_eq!(eq(&a, &b), Some(false));
+			assert_eq!(eq(&a, &c), Some(false));
+		}
+		heap.collect();
+		assert!(heap.is_empty());
+	}
+
+	#[test]
+	fn test_vector_products() {
+		let (a, b) = (Vec3([1.0, 0.0, 0.0]), Vec3([0.0, 1.0, 0.0]));
+		assert_eq!(a.cross(&b), Vec3([0.0, 0.0, 1.0]));
+		assert_eq!(b.cross(&a), Vec3([0.0, 0.0, -1.0]));
+		assert_eq!(a.dot(&b), 0.0);
+		assert_eq!(Vec2([3.0, 4.0]).length(), 5.0);
+	}
+}